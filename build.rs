@@ -0,0 +1,73 @@
+//! Packs `rootfs/` into a `newc` cpio archive that the kernel embeds as its
+//! fallback initramfs when QEMU is not given `-initrd`.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const ROOTFS_DIR: &str = "rootfs";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", ROOTFS_DIR);
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initramfs.cpio");
+    let mut archive = Vec::new();
+    let mut ino = 1;
+    let root = Path::new(ROOTFS_DIR);
+    if root.is_dir() {
+        add_tree(&mut archive, root, root, &mut ino).expect("failed to pack rootfs");
+    }
+    push_entry(&mut archive, "TRAILER!!!", 0, 0, 0, &[]);
+    fs::write(&out, archive).expect("failed to write initramfs.cpio");
+}
+
+/// Adds every entry below `dir` in sorted order, so the archive only
+/// changes when the tree does.
+fn add_tree(archive: &mut Vec<u8>, root: &Path, dir: &Path, ino: &mut u32) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        let meta = fs::symlink_metadata(&path)?;
+        let name = path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/");
+        let perm = meta.permissions().mode() & 0o7777;
+        let mtime = meta.mtime() as u32;
+        *ino += 1;
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            let target = target.to_string_lossy();
+            push_entry(archive, &name, *ino, 0o120000 | 0o777, mtime, target.as_bytes());
+        } else if meta.is_dir() {
+            push_entry(archive, &name, *ino, 0o040000 | perm, mtime, &[]);
+            add_tree(archive, root, &path, ino)?;
+        } else {
+            let data = fs::read(&path)?;
+            push_entry(archive, &name, *ino, 0o100000 | perm, mtime, &data);
+        }
+    }
+    Ok(())
+}
+
+fn push_entry(archive: &mut Vec<u8>, name: &str, ino: u32, mode: u32, mtime: u32, data: &[u8]) {
+    let nlink = if mode & 0o170000 == 0o040000 { 2 } else { 1 };
+    let fields = [
+        ino, mode, 0, 0, nlink, mtime, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad4(archive);
+    archive.extend_from_slice(data);
+    pad4(archive);
+}
+
+fn pad4(archive: &mut Vec<u8>) {
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+}
@@ -6,6 +6,32 @@ RUSTFLAGS="-C link-arg=-Tstar.ld -C linker=rust-lld" \
 
 cp target/riscv64gc-unknown-none-elf/debug/star star.elf
 
+# Kernel command line, e.g. BOOTARGS="root=/dev/vda" ./run.sh
+# Attach disk.img as a virtio-blk disk (vda) when there is one.
+DISK=""
+if [ -f disk.img ]; then
+    DISK="-drive file=disk.img,if=none,format=raw,id=hd0 -device virtio-blk-device,drive=hd0"
+fi
+
+# And snapshot.img for ramfs snapshots (see README).
+if [ -f snapshot.img ]; then
+    DISK="$DISK -drive file=snapshot.img,if=none,format=raw,id=hd1 -device virtio-blk-device,drive=hd1"
+fi
+
+# A virtio-rng device feeds the kernel's entropy pool.
+RNG="-object rng-random,filename=/dev/urandom,id=rng0 -device virtio-rng-device,rng=rng0"
+
+# A virtio-serial port for a test harness, on the Unix socket named by
+# HARNESS (see README).
+SERIAL=""
+if [ -n "$HARNESS" ]; then
+    SERIAL="-device virtio-serial-device -chardev socket,path=$HARNESS,server=on,wait=off,id=harness"
+    SERIAL="$SERIAL -device virtserialport,chardev=harness,name=org.star.harness"
+fi
+
+# Anything else for QEMU goes in QEMU_ARGS, such as
+# "-fw_cfg name=opt/star/config,file=config.txt" (see README).
+
 qemu-system-riscv64 \
     -machine virt \
     -cpu rv64 \
@@ -16,4 +42,9 @@ qemu-system-riscv64 \
     -d cpu_reset,unimp,guest_errors,int -D qemu.log \
     -serial mon:stdio \
     --no-reboot \
+    $DISK \
+    $RNG \
+    $SERIAL \
+    $QEMU_ARGS \
+    ${BOOTARGS:+-append "$BOOTARGS"} \
     -kernel star.elf
\ No newline at end of file
@@ -0,0 +1,322 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::vfs::FsError;
+
+pub const SECTOR_SIZE: usize = 512;
+const SECTORS_PER_PAGE: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+/// Pages a buffer cache holds before it starts evicting.
+pub const DEFAULT_CACHE_PAGES: usize = 64;
+
+/// A disk or partition addressed in 512-byte sectors. Buffers are always a
+/// whole number of sectors.
+pub trait BlockDevice {
+    fn name(&self) -> &str;
+
+    fn sector_count(&self) -> u64;
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError>;
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), FsError>;
+
+    /// Makes completed writes durable.
+    fn flush(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+pub type BlockRef = Arc<dyn BlockDevice>;
+
+/// Rejects transfers that are not whole sectors or run past the end.
+pub fn check_range(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<(), FsError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(FsError::InvalidArgument);
+    }
+    match sector.checked_add((len / SECTOR_SIZE) as u64) {
+        Some(end) if end <= device.sector_count() => Ok(()),
+        _ => Err(FsError::InvalidArgument),
+    }
+}
+
+/// A window onto part of another device, as described by a partition table.
+pub struct Partition {
+    name: String,
+    parent: BlockRef,
+    start: u64,
+    count: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        check_range(self, sector, buf.len())?;
+        self.parent.read_sectors(self.start + sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), FsError> {
+        check_range(self, sector, data.len())?;
+        self.parent.write_sectors(self.start + sector, data)
+    }
+
+    fn flush(&self) -> Result<(), FsError> {
+        self.parent.flush()
+    }
+
+    fn read_only(&self) -> bool {
+        self.parent.read_only()
+    }
+}
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// More entries than this are ignored; 128 is what every tool writes.
+const GPT_MAX_ENTRIES: u32 = 128;
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads the partition table of `device` and returns `(number, first,
+/// count)` for each partition, numbered from 1 by its slot in the table so
+/// empty slots leave gaps. A protective MBR leads to the GPT;
+/// otherwise the four primary MBR entries are used and extended
+/// partitions are not followed.
+pub fn scan_partitions(device: &dyn BlockDevice) -> Result<Vec<(usize, u64, u64)>, FsError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    device.read_sectors(0, &mut sector)?;
+    if u16::from_le_bytes([sector[510], sector[511]]) != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let entries: Vec<&[u8]> = (0..4).map(|i| &sector[MBR_TABLE_OFFSET + 16 * i..MBR_TABLE_OFFSET + 16 * (i + 1)]).collect();
+    if entries.iter().any(|entry| entry[4] == MBR_TYPE_GPT_PROTECTIVE) {
+        return scan_gpt(device);
+    }
+    Ok(entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0 && !matches!(entry[4], 0x05 | 0x0f | 0x85))
+        .map(|(index, entry)| (index + 1, le_u32(entry, 8) as u64, le_u32(entry, 12) as u64))
+        .filter(|&(_, first, count)| count > 0 && first + count <= device.sector_count())
+        .collect())
+}
+
+fn scan_gpt(device: &dyn BlockDevice) -> Result<Vec<(usize, u64, u64)>, FsError> {
+    let mut header = [0u8; SECTOR_SIZE];
+    device.read_sectors(1, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(FsError::InvalidArgument);
+    }
+    let entries_lba = le_u64(&header, 72);
+    let entry_count = core::cmp::min(le_u32(&header, 80), GPT_MAX_ENTRIES) as usize;
+    let entry_size = le_u32(&header, 84) as usize;
+    if !(128..=SECTOR_SIZE).contains(&entry_size) || !SECTOR_SIZE.is_multiple_of(entry_size) {
+        return Err(FsError::InvalidArgument);
+    }
+    let per_sector = SECTOR_SIZE / entry_size;
+    let mut found = Vec::new();
+    let mut sector = [0u8; SECTOR_SIZE];
+    for index in 0..entry_count {
+        if index % per_sector == 0 {
+            device.read_sectors(entries_lba + (index / per_sector) as u64, &mut sector)?;
+        }
+        let entry = &sector[(index % per_sector) * entry_size..];
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (first, last) = (le_u64(entry, 32), le_u64(entry, 40));
+        if first <= last && last < device.sector_count() {
+            found.push((index + 1, first, last - first + 1));
+        }
+    }
+    Ok(found)
+}
+
+/// One cached page of a device.
+struct CacheEntry {
+    page: u64,
+    frame: Frame,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// A write-back cache of page-sized runs of sectors in front of a block
+/// device, addressed in bytes. Dirty pages reach the disk when they are
+/// evicted (least recently used first) or on `sync`. The frames are
+/// allocated as the cache fills and then reused, never freed.
+pub struct BufferCache {
+    device: BlockRef,
+    capacity: usize,
+    entries: RefCell<Vec<CacheEntry>>,
+    clock: Cell<u64>,
+}
+
+impl BufferCache {
+    pub fn new(device: BlockRef, capacity: usize) -> Self {
+        Self {
+            device,
+            capacity,
+            entries: RefCell::new(Vec::with_capacity(capacity)),
+            clock: Cell::new(0),
+        }
+    }
+
+    /// Device size in bytes.
+    pub fn size(&self) -> u64 {
+        self.device.sector_count() * SECTOR_SIZE as u64
+    }
+
+    /// Sectors of `page` that exist on the device; only the last page of
+    /// an odd-sized device is short.
+    fn sectors_in(&self, page: u64) -> usize {
+        let first = page * SECTORS_PER_PAGE;
+        core::cmp::min(SECTORS_PER_PAGE, self.device.sector_count().saturating_sub(first)) as usize
+    }
+
+    fn write_back(&self, entry: &mut CacheEntry) -> Result<(), FsError> {
+        if entry.dirty {
+            let len = self.sectors_in(entry.page) * SECTOR_SIZE;
+            self.device.write_sectors(entry.page * SECTORS_PER_PAGE, &entry.frame.as_slice()[..len])?;
+            entry.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Runs `op` on the cached copy of `page`, loading it first unless the
+    /// caller is about to overwrite all of it.
+    fn with_page<R>(&self, page: u64, overwrite: bool, op: impl FnOnce(&mut CacheEntry) -> R) -> Result<R, FsError> {
+        let tick = self.clock.get() + 1;
+        self.clock.set(tick);
+        let mut entries = self.entries.borrow_mut();
+        let index = match entries.iter().position(|e| e.page == page) {
+            Some(index) => index,
+            None => {
+                let index = if entries.len() < self.capacity {
+                    let frame = Frame::alloc_zeroed().ok_or(FsError::NoSpace)?;
+                    entries.push(CacheEntry { page, frame, dirty: false, last_used: 0 });
+                    entries.len() - 1
+                } else {
+                    let victim = (0..entries.len()).min_by_key(|&i| entries[i].last_used).unwrap();
+                    self.write_back(&mut entries[victim])?;
+                    entries[victim].page = page;
+                    victim
+                };
+                let entry = &mut entries[index];
+                if !overwrite {
+                    let len = self.sectors_in(page) * SECTOR_SIZE;
+                    if let Err(err) = self.device.read_sectors(page * SECTORS_PER_PAGE, &mut entry.frame.as_mut_slice()[..len]) {
+                        entry.page = u64::MAX;
+                        return Err(err);
+                    }
+                }
+                index
+            }
+        };
+        let entry = &mut entries[index];
+        entry.last_used = tick;
+        Ok(op(entry))
+    }
+
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size()) {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let chunk = core::cmp::min(PAGE_SIZE - in_page, buf.len() - done);
+            self.with_page(pos / PAGE_SIZE as u64, false, |entry| {
+                buf[done..done + chunk].copy_from_slice(&entry.frame.as_slice()[in_page..in_page + chunk]);
+            })?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        if self.device.read_only() {
+            return Err(FsError::PermissionDenied);
+        }
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > self.size()) {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let page = pos / PAGE_SIZE as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let chunk = core::cmp::min(PAGE_SIZE - in_page, data.len() - done);
+            let whole = in_page == 0 && chunk == self.sectors_in(page) * SECTOR_SIZE;
+            self.with_page(page, whole, |entry| {
+                entry.frame.as_mut_slice()[in_page..in_page + chunk].copy_from_slice(&data[done..done + chunk]);
+                entry.dirty = true;
+            })?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty page back and flushes the device.
+    pub fn sync(&self) -> Result<(), FsError> {
+        let mut entries = self.entries.borrow_mut();
+        for entry in entries.iter_mut() {
+            self.write_back(entry)?;
+        }
+        self.device.flush()
+    }
+}
+
+static mut DEVICES: Vec<BlockRef> = Vec::new();
+
+fn devices() -> &'static mut Vec<BlockRef> {
+    unsafe { &mut *core::ptr::addr_of_mut!(DEVICES) }
+}
+
+/// Adds a whole disk, then one device per partition found on it, named
+/// after the disk with the partition number appended (`vda1`, `vda2`, ...).
+pub fn register_disk(disk: BlockRef) {
+    devices().push(disk.clone());
+    match scan_partitions(&*disk) {
+        Ok(partitions) => {
+            for (number, start, count) in partitions {
+                let name = format!("{}{}", disk.name(), number);
+                devices().push(Arc::new(Partition { name, parent: disk.clone(), start, count }));
+            }
+        }
+        Err(err) => {
+            crate::print_fail!("{}: bad partition table: {}", disk.name(), err.to_string());
+        }
+    }
+}
+
+pub fn block_devices() -> &'static [BlockRef] {
+    devices()
+}
+
+/// Finds a device by name; a `/dev/` prefix is accepted so mount sources
+/// read the usual way.
+pub fn find_block_device(name: &str) -> Option<BlockRef> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    devices().iter().find(|dev| dev.name() == name).cloned()
+}
@@ -0,0 +1,139 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use crate::random::Random;
+use crate::vfs::{self, File, FileRef, FsError, InodeRef, POLLIN, POLLOUT};
+
+pub const MEM_MAJOR: u32 = 1;
+pub const TTY_MAJOR: u32 = 5;
+
+/// A driver behind one or more device nodes. Reads and writes have no
+/// offset; seeking a device is up to the driver's own state.
+pub trait CharDevice {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError>;
+
+    fn ioctl(&self, _cmd: usize, _arg: usize) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> u16 {
+        POLLIN | POLLOUT
+    }
+
+    /// Called for every open of the node. A driver can refuse it, or hand
+    /// the new file an instance of its own, as `/dev/ptmx` does.
+    fn open(&self) -> Result<Option<DeviceRef>, FsError> {
+        Ok(None)
+    }
+}
+
+pub type DeviceRef = Arc<dyn CharDevice>;
+
+/// Registered drivers by device number.
+static mut DEVICES: BTreeMap<u64, DeviceRef> = BTreeMap::new();
+
+fn devices() -> &'static mut BTreeMap<u64, DeviceRef> {
+    unsafe { &mut *core::ptr::addr_of_mut!(DEVICES) }
+}
+
+/// Registers `device` as `major:minor` and creates `/dev/<name>` for it.
+/// `name` may contain slashes to place the node in a subdirectory.
+pub fn register_char_device(name: &str, major: u32, minor: u32, mode: u32, device: DeviceRef) -> Result<(), FsError> {
+    let rdev = vfs::makedev(major, minor);
+    if devices().contains_key(&rdev) {
+        return Err(FsError::Busy);
+    }
+    crate::devfs::add_node(name, mode, rdev)?;
+    devices().insert(rdev, device);
+    Ok(())
+}
+
+/// Drops the driver registered as `major:minor` and its `/dev/<name>`.
+pub fn unregister_char_device(name: &str, major: u32, minor: u32) -> Result<(), FsError> {
+    devices().remove(&vfs::makedev(major, minor)).ok_or(FsError::NoDevice)?;
+    crate::devfs::remove_node(name)
+}
+
+/// Opens the driver behind a device node, wherever the node lives.
+pub fn open(inode: InodeRef, flags: usize) -> Result<FileRef, FsError> {
+    let rdev = inode.metadata().rdev;
+    let device = devices().get(&rdev).cloned().ok_or(FsError::NoDevice)?;
+    let device = match device.open()? {
+        Some(instance) => instance,
+        None => device,
+    };
+    Ok(Arc::new(DeviceFile { inode, device, flags }))
+}
+
+/// An open device node; every operation goes straight to the driver.
+struct DeviceFile {
+    inode: InodeRef,
+    device: DeviceRef,
+    flags: usize,
+}
+
+impl File for DeviceFile {
+    fn flags(&self) -> usize {
+        self.flags
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.device.read(buf)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        self.device.write(data)
+    }
+
+    fn inode(&self) -> Option<InodeRef> {
+        Some(self.inode.clone())
+    }
+
+    fn ioctl(&self, cmd: usize, arg: usize) -> Result<usize, FsError> {
+        self.device.ioctl(cmd, arg)
+    }
+
+    fn poll(&self) -> u16 {
+        self.device.poll()
+    }
+}
+
+/// `/dev/null`: reads hit end of file, writes vanish.
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// `/dev/zero`: an endless supply of zero bytes.
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Registers the memory devices, the console and the pty multiplexer.
+pub fn register_builtin_devices() -> Result<(), FsError> {
+    let console: DeviceRef = crate::tty::console();
+    register_char_device("null", MEM_MAJOR, 3, 0o666, Arc::new(Null))?;
+    register_char_device("zero", MEM_MAJOR, 5, 0o666, Arc::new(Zero))?;
+    register_char_device("random", MEM_MAJOR, 8, 0o666, Arc::new(Random { wait_for_seed: true }))?;
+    register_char_device("urandom", MEM_MAJOR, 9, 0o666, Arc::new(Random { wait_for_seed: false }))?;
+    register_char_device("tty", TTY_MAJOR, 0, 0o666, console.clone())?;
+    register_char_device("console", TTY_MAJOR, 1, 0o600, console)?;
+    register_char_device("ptmx", TTY_MAJOR, 2, 0o666, Arc::new(crate::pty::Ptmx))
+}
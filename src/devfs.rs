@@ -0,0 +1,81 @@
+use alloc::sync::Arc;
+use crate::ramfs::RamFs;
+use crate::vfs::{self, FileSystem, FsError, InodeKind, InodeRef};
+
+/// The one devfs instance; every mount of `devfs` shows the same nodes.
+static mut DEVFS: Option<Arc<RamFs>> = None;
+
+fn devfs() -> Arc<RamFs> {
+    unsafe { (*core::ptr::addr_of!(DEVFS)).clone().unwrap() }
+}
+
+/// The directory `name` goes in, made if missing, and its last component.
+fn parent_dir(name: &str) -> Result<(InodeRef, &str), FsError> {
+    let mut dir = devfs().root();
+    let (parents, leaf) = match name.rfind('/') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => ("", name),
+    };
+    for component in parents.split('/').filter(|c| !c.is_empty()) {
+        dir = match dir.lookup(component) {
+            Ok(next) => next,
+            Err(FsError::NotFound) => dir.create(component, InodeKind::Directory, 0o755)?,
+            Err(err) => return Err(err),
+        };
+    }
+    Ok((dir, leaf))
+}
+
+/// Creates the device node `name` below the devfs root, making any
+/// directories in between. Goes straight to the inodes, so drivers can add
+/// nodes whatever the credentials of the task that triggered it.
+pub fn add_node(name: &str, mode: u32, rdev: u64) -> Result<InodeRef, FsError> {
+    let (dir, leaf) = parent_dir(name)?;
+    dir.mknod(leaf, mode, rdev)
+}
+
+/// Creates a symlink `name` to `target`, like `add_node`.
+pub fn add_symlink(name: &str, target: &str) -> Result<InodeRef, FsError> {
+    let (dir, leaf) = parent_dir(name)?;
+    dir.symlink(leaf, target)
+}
+
+/// Removes the device node `name` again.
+pub fn remove_node(name: &str) -> Result<(), FsError> {
+    let mut dir = devfs().root();
+    let (parents, leaf) = match name.rfind('/') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => ("", name),
+    };
+    for component in parents.split('/').filter(|c| !c.is_empty()) {
+        dir = dir.lookup(component)?;
+    }
+    dir.unlink(leaf, false)
+}
+
+fn mount_devfs(_source: &str, _data: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(devfs())
+}
+
+pub fn init_devfs() -> bool {
+    unsafe {
+        DEVFS = Some(RamFs::with_name("devfs"));
+    }
+    vfs::register_filesystem("devfs", mount_devfs);
+    let root = vfs::root();
+    let result = match vfs::mkdir(&root, "dev", 0o755) {
+        Ok(_) | Err(FsError::AlreadyExists) => vfs::mount("devfs", "/dev", "devfs", ""),
+        Err(err) => Err(err),
+    }
+    .and_then(|_| crate::chardev::register_builtin_devices());
+    match result {
+        Ok(()) => {
+            crate::print_ok!("Devfs mounted on /dev");
+            true
+        }
+        Err(err) => {
+            crate::print_fail!("Devfs setup failed: {}", err.to_string());
+            false
+        }
+    }
+}
@@ -0,0 +1,1297 @@
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::Cell;
+use crate::block::{self, BufferCache, DEFAULT_CACHE_PAGES};
+use crate::time::{self, Timespec};
+use crate::vfs::{
+    self, DirEntry, FileSystem, FsError, Inode, InodeKind, InodeRef, Metadata, SetAttr, MAX_NAME_LEN,
+    RENAME_EXCHANGE, RENAME_NOREPLACE, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GROUP_DESC_SIZE: u64 = 32;
+
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const FEATURE_RO_COMPAT_BTREE_DIR: u32 = 0x4;
+/// Hashed directory index flag. The index is not maintained here, so it
+/// is dropped from any directory this driver changes.
+const INDEX_FL: u32 = 0x1000;
+
+const DIRECT_BLOCKS: u64 = 12;
+/// Symlink targets shorter than this live in the block pointers.
+const FAST_SYMLINK_MAX: usize = 60;
+
+const FT_UNKNOWN: u8 = 0;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_SYMLINK: u8 = 7;
+
+const ZEROES: [u8; 512] = [0; 512];
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Space a directory entry with a name of `name_len` bytes needs.
+fn rec_len_for(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+fn file_type(kind: InodeKind) -> u8 {
+    match kind {
+        InodeKind::File => FT_REG_FILE,
+        InodeKind::Directory => FT_DIR,
+        InodeKind::Symlink => FT_SYMLINK,
+        InodeKind::CharDevice => FT_CHRDEV,
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains(['/', '\0']) {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// The first 128 bytes of an on-disk inode, which every revision shares.
+/// Larger inodes keep whatever follows untouched.
+#[derive(Clone)]
+struct RawInode([u8; GOOD_OLD_INODE_SIZE]);
+
+impl RawInode {
+    fn u16(&self, offset: usize) -> u16 {
+        le_u16(&self.0, offset)
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        le_u32(&self.0, offset)
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn mode(&self) -> u32 {
+        self.u16(0) as u32
+    }
+
+    fn kind(&self) -> InodeKind {
+        match self.mode() & S_IFMT {
+            S_IFDIR => InodeKind::Directory,
+            S_IFLNK => InodeKind::Symlink,
+            S_IFCHR => InodeKind::CharDevice,
+            _ => InodeKind::File,
+        }
+    }
+
+    fn uid(&self) -> u32 {
+        self.u16(2) as u32 | (self.u16(120) as u32) << 16
+    }
+
+    fn set_uid(&mut self, uid: u32) {
+        self.set_u16(2, uid as u16);
+        self.set_u16(120, (uid >> 16) as u16);
+    }
+
+    fn gid(&self) -> u32 {
+        self.u16(24) as u32 | (self.u16(122) as u32) << 16
+    }
+
+    fn set_gid(&mut self, gid: u32) {
+        self.set_u16(24, gid as u16);
+        self.set_u16(122, (gid >> 16) as u16);
+    }
+
+    /// Regular files keep the high half of their size where directories
+    /// keep their ACL block.
+    fn size(&self) -> u64 {
+        let high = if self.mode() & S_IFMT == S_IFREG { self.u32(108) as u64 } else { 0 };
+        self.u32(4) as u64 | high << 32
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set_u32(4, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            self.set_u32(108, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        self.u16(26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.set_u16(26, links);
+    }
+
+    fn time(&self, offset: usize) -> Timespec {
+        Timespec::new(self.u32(offset) as i64, 0)
+    }
+
+    fn set_time(&mut self, offset: usize, ts: Timespec) {
+        self.set_u32(offset, ts.sec.clamp(0, u32::MAX as i64) as u32);
+    }
+
+    fn touch(&mut self, now: Timespec) {
+        self.set_time(INODE_MTIME, now);
+        self.set_time(INODE_CTIME, now);
+    }
+
+    /// Adjusts `i_blocks`, which counts 512-byte sectors.
+    fn add_blocks(&mut self, block_size: usize, count: i64) {
+        let sectors = count * (block_size / 512) as i64;
+        self.set_u32(28, (self.u32(28) as i64 + sectors).max(0) as u32);
+    }
+
+    fn flags(&self) -> u32 {
+        self.u32(32)
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        self.u32(40 + 4 * index)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        self.set_u32(40 + 4 * index, block);
+    }
+
+    /// Symlinks short enough to sit in the block pointers own no blocks
+    /// (other than an extended attribute block).
+    fn is_fast_symlink(&self, block_size: usize) -> bool {
+        let xattr_sectors = if self.u32(104) != 0 { (block_size / 512) as u32 } else { 0 };
+        self.kind() == InodeKind::Symlink && self.u32(28) == xattr_sectors
+    }
+
+    /// Device numbers use the old 8:8 encoding in the first pointer when
+    /// they fit, and the new 12:20 one in the second otherwise.
+    fn rdev(&self) -> u64 {
+        let (old, new) = (self.block(0), self.block(1));
+        if old != 0 {
+            vfs::makedev((old >> 8) & 0xff, old & 0xff)
+        } else {
+            vfs::makedev((new >> 8) & 0xfff, (new & 0xff) | ((new >> 12) & 0xfff00))
+        }
+    }
+
+    fn set_rdev(&mut self, rdev: u64) {
+        let (major, minor) = (vfs::major(rdev), vfs::minor(rdev));
+        if major < 256 && minor < 256 {
+            self.set_block(0, major << 8 | minor);
+            self.set_block(1, 0);
+        } else {
+            self.set_block(0, 0);
+            self.set_block(1, (minor & 0xff) | major << 8 | (minor & !0xff) << 12);
+        }
+    }
+}
+
+const INODE_ATIME: usize = 8;
+const INODE_CTIME: usize = 12;
+const INODE_MTIME: usize = 16;
+const INODE_DTIME: usize = 20;
+
+/// One directory entry as found on disk.
+struct DirSlot {
+    /// Device offset of the entry.
+    offset: u64,
+    /// Device offset of the entry before it in the same block, if any.
+    prev: Option<u64>,
+    ino: u32,
+    rec_len: usize,
+    name_len: usize,
+    file_type: u8,
+}
+
+/// A mounted ext2 volume (revision 0 or 1, with the `filetype`,
+/// `sparse_super` and `large_file` features). Inodes are read and written
+/// straight through the buffer cache, so there is no inode table in
+/// memory and the inode number is the on-disk one.
+pub struct Ext2Fs {
+    dev: usize,
+    cache: BufferCache,
+    block_size: usize,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    group_count: u32,
+    filetype: bool,
+    read_only: bool,
+    free_blocks: Cell<u32>,
+    free_inodes: Cell<u32>,
+    this: Weak<Ext2Fs>,
+}
+
+impl Ext2Fs {
+    fn new(device: block::BlockRef, read_only: bool) -> Result<Arc<Self>, FsError> {
+        let mut read_only = read_only || device.read_only();
+        let cache = BufferCache::new(device, DEFAULT_CACHE_PAGES);
+        let mut sb = [0u8; 1024];
+        cache.read(SUPERBLOCK_OFFSET, &mut sb)?;
+        if le_u16(&sb, 56) != EXT2_MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        let log_block_size = le_u32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(FsError::InvalidArgument);
+        }
+        let block_size = 1024usize << log_block_size;
+        let revision = le_u32(&sb, 76);
+        let (first_ino, inode_size) = if revision == GOOD_OLD_REV {
+            (GOOD_OLD_FIRST_INO, GOOD_OLD_INODE_SIZE)
+        } else {
+            (le_u32(&sb, 84), le_u16(&sb, 88) as usize)
+        };
+        let (incompat, ro_compat) = if revision == GOOD_OLD_REV { (0, 0) } else { (le_u32(&sb, 96), le_u32(&sb, 100)) };
+        if incompat & !FEATURE_INCOMPAT_FILETYPE != 0 {
+            return Err(FsError::NotSupported);
+        }
+        let known_ro_compat = FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE | FEATURE_RO_COMPAT_BTREE_DIR;
+        if ro_compat & !known_ro_compat != 0 {
+            read_only = true;
+        }
+
+        let blocks_count = le_u32(&sb, 4);
+        let inodes_count = le_u32(&sb, 0);
+        let first_data_block = le_u32(&sb, 20);
+        let blocks_per_group = le_u32(&sb, 32);
+        let inodes_per_group = le_u32(&sb, 40);
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || blocks_count <= first_data_block
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || first_ino <= ROOT_INO
+            || blocks_count as u64 * block_size as u64 > cache.size()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if inodes_count > group_count.saturating_mul(inodes_per_group) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let fs = Self {
+            dev: vfs::alloc_dev(),
+            cache,
+            block_size,
+            blocks_count,
+            inodes_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            group_count,
+            filetype: incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            read_only,
+            free_blocks: Cell::new(le_u32(&sb, 12)),
+            free_inodes: Cell::new(le_u32(&sb, 16)),
+            this: Weak::new(),
+        };
+        if fs.read_inode(ROOT_INO)?.kind() != InodeKind::Directory {
+            return Err(FsError::InvalidArgument);
+        }
+        if !read_only {
+            let mounts = le_u16(&sb, 52).wrapping_add(1);
+            fs.cache.write(SUPERBLOCK_OFFSET + 52, &mounts.to_le_bytes())?;
+            fs.cache.write(SUPERBLOCK_OFFSET + 44, &(time::now().sec as u32).to_le_bytes())?;
+        }
+        Ok(Arc::new_cyclic(|this| Self { this: this.clone(), ..fs }))
+    }
+
+    fn node(&self, ino: u32) -> InodeRef {
+        Arc::new(Ext2Node { fs: self.this.upgrade().unwrap(), ino })
+    }
+
+    fn writable(&self) -> Result<(), FsError> {
+        if self.read_only { Err(FsError::ReadOnly) } else { Ok(()) }
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        self.cache.read(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_u32(&self, offset: u64, value: u32) -> Result<(), FsError> {
+        self.cache.write(offset, &value.to_le_bytes())
+    }
+
+    fn write_u16(&self, offset: u64, value: u16) -> Result<(), FsError> {
+        self.cache.write(offset, &value.to_le_bytes())
+    }
+
+    fn zero(&self, offset: u64, len: usize) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < len {
+            let chunk = core::cmp::min(ZEROES.len(), len - done);
+            self.cache.write(offset + done as u64, &ZEROES[..chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Device offset of group `group`'s descriptor, which follows the
+    /// block holding the superblock.
+    fn group_desc(&self, group: u32) -> u64 {
+        self.block_offset(self.first_data_block + 1) + group as u64 * GROUP_DESC_SIZE
+    }
+
+    /// Adds `delta` to a 16-bit counter of a group descriptor.
+    fn adjust_group_count(&self, group: u32, field: u64, delta: i32) -> Result<(), FsError> {
+        let offset = self.group_desc(group) + field;
+        let mut bytes = [0u8; 2];
+        self.cache.read(offset, &mut bytes)?;
+        self.write_u16(offset, (u16::from_le_bytes(bytes) as i32 + delta).max(0) as u16)
+    }
+
+    fn inode_offset(&self, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::NotFound);
+        }
+        let group = (ino - 1) / self.inodes_per_group;
+        let index = (ino - 1) % self.inodes_per_group;
+        let table = self.read_u32(self.group_desc(group) + 8)?;
+        Ok(self.block_offset(table) + index as u64 * self.inode_size as u64)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<RawInode, FsError> {
+        let mut raw = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        self.cache.read(self.inode_offset(ino)?, &mut raw.0)?;
+        Ok(raw)
+    }
+
+    /// Reads an inode that a directory entry or handle refers to; a freed
+    /// one means the file has gone.
+    fn live_inode(&self, ino: u32) -> Result<RawInode, FsError> {
+        let raw = self.read_inode(ino)?;
+        if raw.links() == 0 || raw.mode() == 0 {
+            return Err(FsError::NotFound);
+        }
+        Ok(raw)
+    }
+
+    fn write_inode(&self, ino: u32, raw: &RawInode) -> Result<(), FsError> {
+        self.cache.write(self.inode_offset(ino)?, &raw.0)
+    }
+
+    fn group_of_inode(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group
+    }
+
+    /// Finds a clear bit below `count` in the bitmap at block `bitmap`,
+    /// sets it and returns its index.
+    fn take_bit(&self, bitmap: u32, count: u32) -> Result<Option<u32>, FsError> {
+        let base = self.block_offset(bitmap);
+        let mut chunk = [0u8; 64];
+        for start in (0..count.div_ceil(8)).step_by(chunk.len()) {
+            let len = core::cmp::min(chunk.len() as u32, count.div_ceil(8) - start) as usize;
+            self.cache.read(base + start as u64, &mut chunk[..len])?;
+            for (i, &byte) in chunk[..len].iter().enumerate() {
+                if byte == 0xff {
+                    continue;
+                }
+                let bit = byte.trailing_ones();
+                let index = (start + i as u32) * 8 + bit;
+                if index >= count {
+                    return Ok(None);
+                }
+                self.cache.write(base + (start as u64) + i as u64, &[byte | 1 << bit])?;
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    fn clear_bit(&self, bitmap: u32, index: u32) -> Result<(), FsError> {
+        let offset = self.block_offset(bitmap) + (index / 8) as u64;
+        let mut byte = [0u8; 1];
+        self.cache.read(offset, &mut byte)?;
+        self.cache.write(offset, &[byte[0] & !(1 << (index % 8))])
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        core::cmp::min(self.blocks_per_group, self.blocks_count - self.first_data_block - group * self.blocks_per_group)
+    }
+
+    /// Allocates a zeroed block, preferring group `goal`.
+    fn alloc_block(&self, goal: u32) -> Result<u32, FsError> {
+        for i in 0..self.group_count {
+            let group = (goal + i) % self.group_count;
+            let desc = self.group_desc(group);
+            let mut free = [0u8; 2];
+            self.cache.read(desc + 12, &mut free)?;
+            if u16::from_le_bytes(free) == 0 {
+                continue;
+            }
+            let bitmap = self.read_u32(desc)?;
+            if let Some(index) = self.take_bit(bitmap, self.blocks_in_group(group))? {
+                self.adjust_group_count(group, 12, -1)?;
+                self.free_blocks.set(self.free_blocks.get().saturating_sub(1));
+                let block = self.first_data_block + group * self.blocks_per_group + index;
+                self.zero(self.block_offset(block), self.block_size)?;
+                return Ok(block);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), FsError> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Io);
+        }
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let bitmap = self.read_u32(self.group_desc(group))?;
+        self.clear_bit(bitmap, (block - self.first_data_block) % self.blocks_per_group)?;
+        self.adjust_group_count(group, 12, 1)?;
+        self.free_blocks.set(self.free_blocks.get() + 1);
+        Ok(())
+    }
+
+    /// Allocates an inode number, preferring group `goal`. The inode itself
+    /// is zeroed.
+    fn alloc_inode(&self, goal: u32, is_dir: bool) -> Result<u32, FsError> {
+        for i in 0..self.group_count {
+            let group = (goal + i) % self.group_count;
+            let desc = self.group_desc(group);
+            let mut free = [0u8; 2];
+            self.cache.read(desc + 14, &mut free)?;
+            if u16::from_le_bytes(free) == 0 {
+                continue;
+            }
+            let bitmap = self.read_u32(desc + 4)?;
+            if let Some(index) = self.take_bit(bitmap, self.inodes_per_group)? {
+                self.adjust_group_count(group, 14, -1)?;
+                if is_dir {
+                    self.adjust_group_count(group, 16, 1)?;
+                }
+                self.free_inodes.set(self.free_inodes.get().saturating_sub(1));
+                let ino = group * self.inodes_per_group + index + 1;
+                self.zero(self.inode_offset(ino)?, self.inode_size)?;
+                return Ok(ino);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, ino: u32, is_dir: bool) -> Result<(), FsError> {
+        let group = self.group_of_inode(ino);
+        let bitmap = self.read_u32(self.group_desc(group) + 4)?;
+        self.clear_bit(bitmap, (ino - 1) % self.inodes_per_group)?;
+        self.adjust_group_count(group, 14, 1)?;
+        if is_dir {
+            self.adjust_group_count(group, 16, -1)?;
+        }
+        self.free_inodes.set(self.free_inodes.get() + 1);
+        Ok(())
+    }
+
+    /// Splits logical block `index` into the block pointer it starts from
+    /// and the indices into each level of indirect blocks below it.
+    fn block_path(&self, index: u64) -> Result<(usize, [u64; 3], usize), FsError> {
+        let per = (self.block_size / 4) as u64;
+        if index < DIRECT_BLOCKS {
+            return Ok((index as usize, [0; 3], 0));
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < per {
+            return Ok((12, [index, 0, 0], 1));
+        }
+        let index = index - per;
+        if index < per * per {
+            return Ok((13, [index / per, index % per, 0], 2));
+        }
+        let index = index - per * per;
+        if index < per * per * per {
+            return Ok((14, [index / (per * per), index / per % per, index % per], 3));
+        }
+        Err(FsError::FileTooLarge)
+    }
+
+    /// The block holding logical block `index` of an inode, 0 for a hole.
+    fn bmap(&self, raw: &RawInode, index: u64) -> Result<u32, FsError> {
+        let (slot, path, depth) = self.block_path(index)?;
+        let mut block = raw.block(slot);
+        for &entry in &path[..depth] {
+            if block == 0 {
+                break;
+            }
+            block = self.read_u32(self.block_offset(block) + entry * 4)?;
+        }
+        Ok(block)
+    }
+
+    /// Like `bmap`, but fills holes (and missing indirect blocks) with
+    /// newly allocated blocks near the inode.
+    fn bmap_alloc(&self, ino: u32, raw: &mut RawInode, index: u64) -> Result<u32, FsError> {
+        let (slot, path, depth) = self.block_path(index)?;
+        let goal = self.group_of_inode(ino);
+        let mut block = raw.block(slot);
+        if block == 0 {
+            block = self.alloc_block(goal)?;
+            raw.set_block(slot, block);
+            raw.add_blocks(self.block_size, 1);
+        }
+        for &entry in &path[..depth] {
+            let offset = self.block_offset(block) + entry * 4;
+            let mut next = self.read_u32(offset)?;
+            if next == 0 {
+                next = self.alloc_block(goal)?;
+                self.write_u32(offset, next)?;
+                raw.add_blocks(self.block_size, 1);
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Frees everything below the indirect block `block` (of the given
+    /// depth) past its first `keep` data blocks. Returns whether the block
+    /// itself is now unused.
+    fn free_branch(&self, raw: &mut RawInode, block: u32, depth: u32, keep: u64) -> Result<bool, FsError> {
+        let per = (self.block_size / 4) as u64;
+        let span = per.pow(depth - 1);
+        for i in 0..per {
+            let child_keep = keep.saturating_sub(i * span);
+            if child_keep >= span {
+                continue;
+            }
+            let offset = self.block_offset(block) + i * 4;
+            let child = self.read_u32(offset)?;
+            if child == 0 {
+                continue;
+            }
+            if depth == 1 || self.free_branch(raw, child, depth - 1, child_keep)? {
+                self.free_block(child)?;
+                raw.add_blocks(self.block_size, -1);
+                self.write_u32(offset, 0)?;
+            }
+        }
+        Ok(keep == 0)
+    }
+
+    /// Frees the data blocks of an inode past its first `keep`.
+    fn free_blocks_from(&self, raw: &mut RawInode, keep: u64) -> Result<(), FsError> {
+        for slot in keep.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = raw.block(slot as usize);
+            if block != 0 {
+                self.free_block(block)?;
+                raw.add_blocks(self.block_size, -1);
+                raw.set_block(slot as usize, 0);
+            }
+        }
+        let per = (self.block_size / 4) as u64;
+        let (mut base, mut span) = (DIRECT_BLOCKS, per);
+        for (slot, depth) in [(12, 1), (13, 2), (14, 3)] {
+            let block = raw.block(slot);
+            let local_keep = keep.saturating_sub(base);
+            if block != 0 && local_keep < span && self.free_branch(raw, block, depth, local_keep)? {
+                self.free_block(block)?;
+                raw.add_blocks(self.block_size, -1);
+                raw.set_block(slot, 0);
+            }
+            base += span;
+            span *= per;
+        }
+        Ok(())
+    }
+
+    /// Whether the block pointers of an inode point at blocks it owns,
+    /// rather than holding a device number or a short symlink.
+    fn owns_blocks(&self, raw: &RawInode) -> bool {
+        match raw.kind() {
+            InodeKind::File | InodeKind::Directory => true,
+            InodeKind::Symlink => !raw.is_fast_symlink(self.block_size),
+            InodeKind::CharDevice => false,
+        }
+    }
+
+    /// Returns an inode whose last link has gone to the free pool.
+    fn release(&self, ino: u32, raw: &mut RawInode) -> Result<(), FsError> {
+        if self.owns_blocks(raw) {
+            self.free_blocks_from(raw, 0)?;
+        }
+        let is_dir = raw.kind() == InodeKind::Directory;
+        raw.set_links(0);
+        raw.set_time(INODE_DTIME, time::now());
+        self.write_inode(ino, raw)?;
+        self.free_inode(ino, is_dir)
+    }
+
+    /// Reads `buf.len()` bytes at `offset` of an inode's data, with holes
+    /// reading as zeroes. The caller bounds the range by the file size.
+    fn read_data(&self, raw: &RawInode, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_block = (pos % self.block_size as u64) as usize;
+            let chunk = core::cmp::min(self.block_size - in_block, buf.len() - done);
+            match self.bmap(raw, pos / self.block_size as u64)? {
+                0 => buf[done..done + chunk].fill(0),
+                block => self.cache.read(self.block_offset(block) + in_block as u64, &mut buf[done..done + chunk])?,
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset`, allocating blocks as needed. Returns how
+    /// much was written before running out of space.
+    fn write_data(&self, ino: u32, raw: &mut RawInode, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let in_block = (pos % self.block_size as u64) as usize;
+            let chunk = core::cmp::min(self.block_size - in_block, data.len() - done);
+            let block = match self.bmap_alloc(ino, raw, pos / self.block_size as u64) {
+                Ok(block) => block,
+                Err(err) if done == 0 => return Err(err),
+                Err(_) => break,
+            };
+            self.cache.write(self.block_offset(block) + in_block as u64, &data[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    /// Calls `visit(slot, name)` for each entry of a directory, free ones
+    /// included, until it returns `Some`.
+    fn scan_dir<R>(
+        &self,
+        dir: &RawInode,
+        mut visit: impl FnMut(&DirSlot, &[u8]) -> Option<R>,
+    ) -> Result<Option<R>, FsError> {
+        let mut header = [0u8; 8];
+        let mut name = [0u8; MAX_NAME_LEN];
+        for index in 0..dir.size() / self.block_size as u64 {
+            let block = self.bmap(dir, index)?;
+            if block == 0 {
+                continue;
+            }
+            let base = self.block_offset(block);
+            let mut pos = 0;
+            let mut prev = None;
+            while pos + 8 <= self.block_size {
+                let offset = base + pos as u64;
+                self.cache.read(offset, &mut header)?;
+                let rec_len = le_u16(&header, 4) as usize;
+                let name_len = header[6] as usize;
+                if rec_len < 8 || rec_len % 4 != 0 || pos + rec_len > self.block_size || name_len + 8 > rec_len {
+                    return Err(FsError::Io);
+                }
+                self.cache.read(offset + 8, &mut name[..name_len])?;
+                let slot = DirSlot {
+                    offset,
+                    prev,
+                    ino: le_u32(&header, 0),
+                    rec_len,
+                    name_len,
+                    file_type: if self.filetype { header[7] } else { FT_UNKNOWN },
+                };
+                if let Some(result) = visit(&slot, &name[..name_len]) {
+                    return Ok(Some(result));
+                }
+                prev = Some(offset);
+                pos += rec_len;
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_entry(&self, dir: &RawInode, name: &str) -> Result<Option<DirSlot>, FsError> {
+        self.scan_dir(dir, |slot, entry| {
+            (slot.ino != 0 && entry == name.as_bytes()).then_some(DirSlot { prev: slot.prev, ..*slot })
+        })
+    }
+
+    fn write_entry(&self, offset: u64, ino: u32, rec_len: usize, name: &str, file_type: u8) -> Result<(), FsError> {
+        let mut header = [0u8; 8];
+        header[0..4].copy_from_slice(&ino.to_le_bytes());
+        header[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+        header[6] = name.len() as u8;
+        header[7] = if self.filetype { file_type } else { 0 };
+        self.cache.write(offset, &header)?;
+        self.cache.write(offset + 8, name.as_bytes())
+    }
+
+    /// Adds `name` to a directory, in the slack of an existing entry if
+    /// one has room, otherwise in a new block. The caller writes `dir`.
+    fn add_entry(&self, dir_ino: u32, dir: &mut RawInode, name: &str, ino: u32, file_type: u8) -> Result<(), FsError> {
+        let needed = rec_len_for(name.len());
+        let found = self.scan_dir(dir, |slot, _| {
+            let used = if slot.ino == 0 { 0 } else { rec_len_for(slot.name_len) };
+            (slot.rec_len - used >= needed).then_some((slot.offset, slot.rec_len, used))
+        })?;
+        match found {
+            Some((offset, rec_len, 0)) => self.write_entry(offset, ino, rec_len, name, file_type)?,
+            Some((offset, rec_len, used)) => {
+                self.write_u16(offset + 4, used as u16)?;
+                self.write_entry(offset + used as u64, ino, rec_len - used, name, file_type)?;
+            }
+            None => {
+                let size = dir.size();
+                let block = self.bmap_alloc(dir_ino, dir, size / self.block_size as u64)?;
+                self.write_entry(self.block_offset(block), ino, self.block_size, name, file_type)?;
+                dir.set_size(size + self.block_size as u64);
+            }
+        }
+        self.dir_changed(dir);
+        Ok(())
+    }
+
+    /// Takes an entry out of its block, merging its space into the entry
+    /// before it. The caller writes `dir`.
+    fn remove_entry(&self, dir: &mut RawInode, slot: &DirSlot) -> Result<(), FsError> {
+        match slot.prev {
+            Some(prev) => {
+                let mut bytes = [0u8; 2];
+                self.cache.read(prev + 4, &mut bytes)?;
+                self.write_u16(prev + 4, u16::from_le_bytes(bytes) + slot.rec_len as u16)?;
+            }
+            None => self.write_u32(slot.offset, 0)?,
+        }
+        self.dir_changed(dir);
+        Ok(())
+    }
+
+    /// Points an existing entry at another inode.
+    fn retarget_entry(&self, dir: &mut RawInode, slot: &DirSlot, ino: u32, file_type: u8) -> Result<(), FsError> {
+        self.write_u32(slot.offset, ino)?;
+        if self.filetype {
+            self.cache.write(slot.offset + 7, &[file_type])?;
+        }
+        self.dir_changed(dir);
+        Ok(())
+    }
+
+    fn dir_changed(&self, dir: &mut RawInode) {
+        dir.set_u32(32, dir.flags() & !INDEX_FL);
+        dir.touch(time::now());
+    }
+
+    fn is_empty_dir(&self, dir: &RawInode) -> Result<bool, FsError> {
+        let other = self.scan_dir(dir, |slot, name| (slot.ino != 0 && name != b"." && name != b"..").then_some(()))?;
+        Ok(other.is_none())
+    }
+
+    /// Drops one link to `ino` after its entry in `parent` went away:
+    /// a directory loses both its links and its `..` in the parent.
+    fn unlinked(&self, parent: &mut RawInode, ino: u32) -> Result<(), FsError> {
+        let mut raw = self.read_inode(ino)?;
+        if raw.kind() == InodeKind::Directory {
+            parent.set_links(parent.links().saturating_sub(1));
+            raw.set_links(0);
+        } else {
+            raw.set_links(raw.links().saturating_sub(1));
+        }
+        raw.set_time(INODE_CTIME, time::now());
+        if raw.links() == 0 {
+            self.release(ino, &mut raw)
+        } else {
+            self.write_inode(ino, &raw)
+        }
+    }
+
+    /// Whether directory `ancestor` is `ino` or one of its parents.
+    fn is_ancestor(&self, ancestor: u32, mut ino: u32) -> Result<bool, FsError> {
+        loop {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            if ino == ROOT_INO {
+                return Ok(false);
+            }
+            let dir = self.read_inode(ino)?;
+            ino = self.find_entry(&dir, "..")?.ok_or(FsError::Io)?.ino;
+        }
+    }
+
+    /// Creates an inode of `mode` in directory `dir_ino` and links it in as
+    /// `name`. `init` fills in the new inode's contents.
+    fn create_inode(
+        &self,
+        dir_ino: u32,
+        name: &str,
+        mode: u32,
+        init: impl FnOnce(u32, &mut RawInode) -> Result<(), FsError>,
+    ) -> Result<u32, FsError> {
+        self.writable()?;
+        check_name(name)?;
+        let mut dir = self.live_inode(dir_ino)?;
+        if dir.kind() != InodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if self.find_entry(&dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let ino = self.alloc_inode(self.group_of_inode(dir_ino), is_dir)?;
+        let now = time::now();
+        let (uid, gid) = vfs::current_cred();
+        let mut raw = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        raw.set_u16(0, mode as u16);
+        raw.set_uid(uid);
+        raw.set_gid(gid);
+        raw.set_links(if is_dir { 2 } else { 1 });
+        raw.set_time(INODE_ATIME, now);
+        raw.touch(now);
+        let kind = raw.kind();
+        let linked = init(ino, &mut raw)
+            .and_then(|_| self.write_inode(ino, &raw))
+            .and_then(|_| self.add_entry(dir_ino, &mut dir, name, ino, file_type(kind)));
+        if let Err(err) = linked {
+            self.release(ino, &mut raw)?;
+            return Err(err);
+        }
+        if is_dir {
+            dir.set_links(dir.links() + 1);
+        }
+        self.write_inode(dir_ino, &dir)?;
+        Ok(ino)
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.node(ROOT_INO)
+    }
+
+    fn dev(&self) -> usize {
+        self.dev
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Writes the free counts and write time into the superblock, then
+    /// the cache out to the disk.
+    fn sync(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.write_u32(SUPERBLOCK_OFFSET + 12, self.free_blocks.get())?;
+        self.write_u32(SUPERBLOCK_OFFSET + 16, self.free_inodes.get())?;
+        self.write_u32(SUPERBLOCK_OFFSET + 48, time::now().sec as u32)?;
+        self.cache.sync()
+    }
+}
+
+/// A handle on one ext2 inode.
+pub struct Ext2Node {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+}
+
+impl Ext2Node {
+    fn dir(&self) -> Result<RawInode, FsError> {
+        let raw = self.fs.live_inode(self.ino)?;
+        if raw.kind() == InodeKind::Directory { Ok(raw) } else { Err(FsError::NotADirectory) }
+    }
+
+    fn file(&self) -> Result<RawInode, FsError> {
+        let raw = self.fs.live_inode(self.ino)?;
+        match raw.kind() {
+            InodeKind::File => Ok(raw),
+            InodeKind::Directory => Err(FsError::IsADirectory),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn same_fs<'a>(&self, other: &'a InodeRef) -> Result<&'a Ext2Node, FsError> {
+        match other.as_any().downcast_ref::<Ext2Node>() {
+            Some(node) if Arc::ptr_eq(&node.fs, &self.fs) => Ok(node),
+            _ => Err(FsError::CrossDevice),
+        }
+    }
+}
+
+impl Inode for Ext2Node {
+    fn metadata(&self) -> Metadata {
+        let raw = self.fs.read_inode(self.ino).unwrap_or(RawInode([0; GOOD_OLD_INODE_SIZE]));
+        let kind = raw.kind();
+        Metadata {
+            dev: self.fs.dev,
+            ino: self.ino as u64,
+            kind,
+            mode: raw.mode() & 0o7777,
+            uid: raw.uid(),
+            gid: raw.gid(),
+            nlink: raw.links() as u32,
+            size: raw.size() as usize,
+            rdev: if kind == InodeKind::CharDevice { raw.rdev() } else { 0 },
+            atime: raw.time(INODE_ATIME),
+            mtime: raw.time(INODE_MTIME),
+            ctime: raw.time(INODE_CTIME),
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let raw = self.file()?;
+        let size = raw.size();
+        if offset as u64 >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len() as u64, size - offset as u64) as usize;
+        self.fs.read_data(&raw, offset as u64, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        self.fs.writable()?;
+        let mut raw = self.file()?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let written = self.fs.write_data(self.ino, &mut raw, offset as u64, data);
+        if let Ok(written) = written {
+            raw.set_size(core::cmp::max(raw.size(), (offset + written) as u64));
+            raw.touch(time::now());
+        }
+        self.fs.write_inode(self.ino, &raw)?;
+        written
+    }
+
+    /// Shrinking frees the blocks past the end and clears the rest of the
+    /// last block, so growing again later reads zeroes.
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        self.fs.writable()?;
+        let mut raw = self.file()?;
+        let len = len as u64;
+        let block_size = self.fs.block_size as u64;
+        if len < raw.size() {
+            self.fs.free_blocks_from(&mut raw, len.div_ceil(block_size))?;
+            if !len.is_multiple_of(block_size) {
+                let block = self.fs.bmap(&raw, len / block_size)?;
+                if block != 0 {
+                    let tail = len % block_size;
+                    self.fs.zero(self.fs.block_offset(block) + tail, (block_size - tail) as usize)?;
+                }
+            }
+        } else {
+            self.fs.block_path(len.saturating_sub(1) / block_size)?;
+        }
+        raw.set_size(len);
+        raw.touch(time::now());
+        self.fs.write_inode(self.ino, &raw)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let dir = self.dir()?;
+        if name.is_empty() {
+            return Ok(self.fs.node(self.ino));
+        }
+        let slot = self.fs.find_entry(&dir, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(slot.ino))
+    }
+
+    fn create(&self, name: &str, kind: InodeKind, mode: u32) -> Result<InodeRef, FsError> {
+        let mode = mode & 0o7777;
+        let ino = match kind {
+            InodeKind::File => self.fs.create_inode(self.ino, name, S_IFREG | mode, |_, _| Ok(()))?,
+            InodeKind::Directory => {
+                let parent = self.ino;
+                let fs = &self.fs;
+                fs.create_inode(parent, name, S_IFDIR | mode, |ino, raw| {
+                    let block = fs.bmap_alloc(ino, raw, 0)?;
+                    let base = fs.block_offset(block);
+                    fs.write_entry(base, ino, 12, ".", FT_DIR)?;
+                    fs.write_entry(base + 12, parent, fs.block_size - 12, "..", FT_DIR)?;
+                    raw.set_size(fs.block_size as u64);
+                    Ok(())
+                })?
+            }
+            _ => return Err(FsError::InvalidArgument),
+        };
+        Ok(self.fs.node(ino))
+    }
+
+    fn set_attr(&self, attr: &SetAttr) -> Result<(), FsError> {
+        self.fs.writable()?;
+        let mut raw = self.fs.live_inode(self.ino)?;
+        if let Some(mode) = attr.mode {
+            raw.set_u16(0, ((raw.mode() & S_IFMT) | (mode & 0o7777)) as u16);
+        }
+        if let Some(uid) = attr.uid {
+            raw.set_uid(uid);
+        }
+        if let Some(gid) = attr.gid {
+            raw.set_gid(gid);
+        }
+        if let Some(atime) = attr.atime {
+            raw.set_time(INODE_ATIME, atime);
+        }
+        if let Some(mtime) = attr.mtime {
+            raw.set_time(INODE_MTIME, mtime);
+        }
+        raw.set_time(INODE_CTIME, time::now());
+        self.fs.write_inode(self.ino, &raw)
+    }
+
+    fn link(&self, name: &str, target: &InodeRef) -> Result<(), FsError> {
+        self.fs.writable()?;
+        check_name(name)?;
+        let target = self.same_fs(target)?.ino;
+        let mut dir = self.dir()?;
+        let mut raw = self.fs.live_inode(target)?;
+        if raw.kind() == InodeKind::Directory {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.fs.find_entry(&dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        if raw.links() == u16::MAX {
+            return Err(FsError::TooManyLinks);
+        }
+        self.fs.add_entry(self.ino, &mut dir, name, target, file_type(raw.kind()))?;
+        self.fs.write_inode(self.ino, &dir)?;
+        raw.set_links(raw.links() + 1);
+        raw.set_time(INODE_CTIME, time::now());
+        self.fs.write_inode(target, &raw)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, FsError> {
+        if target.is_empty() || target.len() >= self.fs.block_size {
+            return Err(FsError::InvalidArgument);
+        }
+        let fs = &self.fs;
+        let ino = fs.create_inode(self.ino, name, S_IFLNK | 0o777, |ino, raw| {
+            if target.len() < FAST_SYMLINK_MAX {
+                raw.0[40..40 + target.len()].copy_from_slice(target.as_bytes());
+            } else {
+                let block = fs.bmap_alloc(ino, raw, 0)?;
+                fs.cache.write(fs.block_offset(block), target.as_bytes())?;
+            }
+            raw.set_size(target.len() as u64);
+            Ok(())
+        })?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn mknod(&self, name: &str, mode: u32, rdev: u64) -> Result<InodeRef, FsError> {
+        let ino = self.fs.create_inode(self.ino, name, S_IFCHR | (mode & 0o7777), |_, raw| {
+            raw.set_rdev(rdev);
+            Ok(())
+        })?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let raw = self.fs.live_inode(self.ino)?;
+        if raw.kind() != InodeKind::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        let len = raw.size() as usize;
+        if len >= self.fs.block_size {
+            return Err(FsError::Io);
+        }
+        let mut target = Vec::with_capacity(len);
+        if raw.is_fast_symlink(self.fs.block_size) {
+            target.extend_from_slice(&raw.0[40..40 + len.min(FAST_SYMLINK_MAX)]);
+        } else {
+            target.resize(len, 0);
+            self.fs.read_data(&raw, 0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+
+    fn unlink(&self, name: &str, remove_dir: bool) -> Result<(), FsError> {
+        self.fs.writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut dir = self.dir()?;
+        let slot = self.fs.find_entry(&dir, name)?.ok_or(FsError::NotFound)?;
+        let child = self.fs.read_inode(slot.ino)?;
+        let is_dir = child.kind() == InodeKind::Directory;
+        match (is_dir, remove_dir) {
+            (true, false) => return Err(FsError::IsADirectory),
+            (false, true) => return Err(FsError::NotADirectory),
+            (true, true) if !self.fs.is_empty_dir(&child)? => return Err(FsError::NotEmpty),
+            _ => {}
+        }
+        self.fs.remove_entry(&mut dir, &slot)?;
+        self.fs.unlinked(&mut dir, slot.ino)?;
+        self.fs.write_inode(self.ino, &dir)
+    }
+
+    /// Moves `old_name` to `new_name` in `new_dir`. An existing destination
+    /// is replaced unless `RENAME_NOREPLACE` is given; `RENAME_EXCHANGE`
+    /// swaps the two entries.
+    fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str, flags: usize) -> Result<(), FsError> {
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || flags == RENAME_NOREPLACE | RENAME_EXCHANGE {
+            return Err(FsError::InvalidArgument);
+        }
+        self.fs.writable()?;
+        check_name(new_name)?;
+        let fs = &self.fs;
+        let target = self.same_fs(new_dir)?.ino;
+        let same_dir = target == self.ino;
+        let mut old_dir = self.dir()?;
+        let mut new_dir = if same_dir { old_dir.clone() } else { fs.live_inode(target)? };
+        if new_dir.kind() != InodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let src = fs.find_entry(&old_dir, old_name)?.ok_or(FsError::NotFound)?;
+        let src_raw = fs.read_inode(src.ino)?;
+        let src_is_dir = src_raw.kind() == InodeKind::Directory;
+        if src_is_dir && fs.is_ancestor(src.ino, target)? {
+            return Err(FsError::InvalidArgument);
+        }
+        let dst = fs.find_entry(&new_dir, new_name)?;
+        if dst.as_ref().is_some_and(|dst| dst.ino == src.ino) {
+            return Ok(());
+        }
+
+        // Moving a directory to another parent repoints its `..` and moves
+        // the link that entry represents.
+        let reparent = |ino: u32, from: &mut RawInode, to: &mut RawInode, parent: u32| -> Result<(), FsError> {
+            let raw = fs.read_inode(ino)?;
+            let dotdot = fs.find_entry(&raw, "..")?.ok_or(FsError::Io)?;
+            fs.write_u32(dotdot.offset, parent)?;
+            from.set_links(from.links().saturating_sub(1));
+            to.set_links(to.links() + 1);
+            Ok(())
+        };
+
+        if flags & RENAME_EXCHANGE != 0 {
+            let dst = dst.ok_or(FsError::NotFound)?;
+            let dst_raw = fs.read_inode(dst.ino)?;
+            let dst_is_dir = dst_raw.kind() == InodeKind::Directory;
+            if dst_is_dir && fs.is_ancestor(dst.ino, self.ino)? {
+                return Err(FsError::InvalidArgument);
+            }
+            if same_dir {
+                fs.retarget_entry(&mut old_dir, &src, dst.ino, file_type(dst_raw.kind()))?;
+                fs.retarget_entry(&mut old_dir, &dst, src.ino, file_type(src_raw.kind()))?;
+            } else {
+                fs.retarget_entry(&mut old_dir, &src, dst.ino, file_type(dst_raw.kind()))?;
+                fs.retarget_entry(&mut new_dir, &dst, src.ino, file_type(src_raw.kind()))?;
+                if src_is_dir {
+                    reparent(src.ino, &mut old_dir, &mut new_dir, target)?;
+                }
+                if dst_is_dir {
+                    reparent(dst.ino, &mut new_dir, &mut old_dir, self.ino)?;
+                }
+            }
+        } else {
+            if let Some(dst) = &dst {
+                if flags & RENAME_NOREPLACE != 0 {
+                    return Err(FsError::AlreadyExists);
+                }
+                let dst_raw = fs.read_inode(dst.ino)?;
+                match (src_is_dir, dst_raw.kind() == InodeKind::Directory) {
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, true) if !fs.is_empty_dir(&dst_raw)? => return Err(FsError::NotEmpty),
+                    _ => {}
+                }
+            }
+            match &dst {
+                Some(dst) => fs.retarget_entry(&mut new_dir, dst, src.ino, file_type(src_raw.kind()))?,
+                None => fs.add_entry(target, &mut new_dir, new_name, src.ino, file_type(src_raw.kind()))?,
+            }
+            if same_dir {
+                // The new entry went into the same blocks: look the old
+                // one up again so `prev` is current.
+                let src = fs.find_entry(&new_dir, old_name)?.ok_or(FsError::Io)?;
+                fs.remove_entry(&mut new_dir, &src)?;
+            } else {
+                fs.remove_entry(&mut old_dir, &src)?;
+                if src_is_dir {
+                    reparent(src.ino, &mut old_dir, &mut new_dir, target)?;
+                }
+            }
+            if let Some(dst) = dst {
+                fs.unlinked(&mut new_dir, dst.ino)?;
+            }
+        }
+
+        let mut moved = fs.read_inode(src.ino)?;
+        moved.set_time(INODE_CTIME, time::now());
+        fs.write_inode(src.ino, &moved)?;
+        if same_dir {
+            fs.write_inode(self.ino, &new_dir)
+        } else {
+            fs.write_inode(self.ino, &old_dir)?;
+            fs.write_inode(target, &new_dir)
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.dir()?;
+        let mut entries = Vec::new();
+        self.fs.scan_dir(&dir, |slot, name| {
+            if slot.ino != 0 {
+                entries.push((slot.ino, slot.file_type, String::from_utf8_lossy(name).into_owned()));
+            }
+            None::<()>
+        })?;
+        let mut list = Vec::with_capacity(entries.len());
+        for (ino, file_type, name) in entries {
+            let kind = match file_type {
+                FT_REG_FILE => InodeKind::File,
+                FT_DIR => InodeKind::Directory,
+                FT_CHRDEV => InodeKind::CharDevice,
+                FT_SYMLINK => InodeKind::Symlink,
+                _ => self.fs.read_inode(ino)?.kind(),
+            };
+            list.push(DirEntry { name, ino: ino as u64, kind });
+        }
+        Ok(list)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Mounts the ext2 volume on the block device named by `source`. The only
+/// option is `ro`.
+fn mount_ext2(source: &str, data: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let mut read_only = false;
+    for option in data.split(',').filter(|o| !o.is_empty()) {
+        match option {
+            "ro" => read_only = true,
+            "rw" => read_only = false,
+            _ => return Err(FsError::InvalidArgument),
+        }
+    }
+    let device = block::find_block_device(source).ok_or(FsError::NoDevice)?;
+    Ok(Ext2Fs::new(device, read_only)?)
+}
+
+pub fn init_ext2() -> bool {
+    vfs::register_filesystem("ext2", mount_ext2);
+    crate::print_ok!("ext2 filesystem driver registered");
+    true
+}
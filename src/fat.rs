@@ -0,0 +1,1399 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use crate::block::{self, BufferCache, DEFAULT_CACHE_PAGES};
+use crate::time::{self, Timespec};
+use crate::vfs::{
+    self, DirEntry, FileSystem, FsError, Inode, InodeKind, InodeRef, Metadata, SetAttr, MAX_NAME_LEN,
+    RENAME_EXCHANGE, RENAME_NOREPLACE,
+};
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const DIRENT_SIZE: usize = 32;
+const END_OF_DIR: u8 = 0x00;
+const DELETED: u8 = 0xe5;
+/// Stands in for a leading 0xe5 byte, which would read as deleted.
+const KANJI_E5: u8 = 0x05;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+/// Byte offsets of the UTF-16 characters in a long-name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// `NTRes` bits marking an 8.3 base name or extension as all lowercase.
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+/// Punctuation allowed in short names besides letters and digits.
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// Seconds from the Unix epoch to 1980-01-01, the earliest FAT date.
+const FAT_EPOCH: i64 = 315_532_800;
+
+const ROOT_INO: u64 = 1;
+
+const ZEROES: [u8; 512] = [0; 512];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Mount options, given as `-o` style data: `ro`, `uid=`, `gid=` and
+/// octal `umask=`, `fmask=`, `dmask=`.
+struct Options {
+    read_only: bool,
+    uid: u32,
+    gid: u32,
+    fmask: u32,
+    dmask: u32,
+}
+
+fn parse_options(data: &str) -> Result<Options, FsError> {
+    let mut options = Options { read_only: false, uid: 0, gid: 0, fmask: 0o022, dmask: 0o022 };
+    for option in data.split(',').filter(|o| !o.is_empty()) {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+        let decimal = || value.and_then(|v| v.parse::<u32>().ok()).ok_or(FsError::InvalidArgument);
+        let octal = || value.and_then(|v| u32::from_str_radix(v, 8).ok()).ok_or(FsError::InvalidArgument);
+        match key {
+            "ro" => options.read_only = true,
+            "rw" => options.read_only = false,
+            "uid" => options.uid = decimal()?,
+            "gid" => options.gid = decimal()?,
+            "umask" => {
+                options.fmask = octal()?;
+                options.dmask = options.fmask;
+            }
+            "fmask" => options.fmask = octal()?,
+            "dmask" => options.dmask = octal()?,
+            _ => return Err(FsError::InvalidArgument),
+        }
+    }
+    Ok(options)
+}
+
+/// Directory-entry timestamps are local date/time words; this kernel keeps
+/// no time zone, so they are taken as UTC.
+fn to_fat_time(ts: Timespec) -> (u16, u16) {
+    let max = time::days_from_civil(2107, 12, 31) * 86_400 + 86_399;
+    let secs = ts.sec.clamp(FAT_EPOCH, max);
+    let (year, month, day) = time::civil_from_days(secs.div_euclid(86_400));
+    let of_day = secs.rem_euclid(86_400) as u16;
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((of_day / 3600) << 11) | ((of_day / 60 % 60) << 5) | ((of_day % 60) / 2);
+    (date, time)
+}
+
+fn from_fat_time(date: u16, time: u16) -> Timespec {
+    if date == 0 {
+        return Timespec::new(FAT_EPOCH, 0);
+    }
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).clamp(1, 12) as u32;
+    let day = (date & 0x1f).max(1) as u32;
+    let of_day = (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    Timespec::new(time::days_from_civil(year, month, day) * 86_400 + of_day, 0)
+}
+
+/// The checksum of an 8.3 name stored in each of its long-name entries.
+fn short_name_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+fn is_short_name_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&b) || b >= 0x80
+}
+
+/// The 8.3 form of `name` if it can be stored without a long name, with
+/// the `NTRes` case bits needed to give the same name back.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !name.is_ascii() {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut ntres = 0;
+    let (base_field, ext_field) = short.split_at_mut(8);
+    for (part, field, lower_bit) in [(base, base_field, NTRES_LOWER_BASE), (ext, ext_field, NTRES_LOWER_EXT)] {
+        let has_lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        if has_lower && part.bytes().any(|b| b.is_ascii_uppercase()) {
+            return None;
+        }
+        if has_lower {
+            ntres |= lower_bit;
+        }
+        for (slot, b) in field.iter_mut().zip(part.bytes()) {
+            *slot = b.to_ascii_uppercase();
+            if !is_short_name_char(*slot) {
+                return None;
+            }
+        }
+    }
+    if short[0] == DELETED {
+        short[0] = KANJI_E5;
+    }
+    Some((short, ntres))
+}
+
+/// The `NAME~N.EXT` short name for a name that needs a long entry: the
+/// first free `N` among `taken`.
+fn generated_short_name(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], FsError> {
+    let name = name.trim_start_matches(['.', ' ']);
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let clean = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|&b| b != b' ' && b != b'.')
+            .map(|b| {
+                let b = b.to_ascii_uppercase();
+                if is_short_name_char(b) && b < 0x80 { b } else { b'_' }
+            })
+            .collect()
+    };
+    let (mut base, ext) = (clean(base), clean(ext));
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let mut short = [b' '; 11];
+    for (slot, &b) in short[8..].iter_mut().zip(ext.iter()) {
+        *slot = b;
+    }
+    for n in 1..1_000_000u32 {
+        let mut tail = [0u8; 8];
+        let mut len = 0;
+        let mut rest = n;
+        while rest > 0 {
+            tail[7 - len] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+            len += 1;
+        }
+        let keep = core::cmp::min(base.len(), 7 - len);
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep] = b'~';
+        short[keep + 1..keep + 1 + len].copy_from_slice(&tail[8 - len..]);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::AlreadyExists)
+}
+
+/// Rejects names FAT cannot hold.
+fn check_name(name: &str) -> Result<(), FsError> {
+    let invalid = |c: char| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c);
+    if name.is_empty() || name.encode_utf16().count() > MAX_NAME_LEN || name.chars().any(invalid) {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// A name found in a directory, with the slots it occupies: the first
+/// long-name entry (or the short entry itself) through the short entry.
+struct RawEntry {
+    name: String,
+    first_slot: usize,
+    slot: usize,
+    dirent: [u8; DIRENT_SIZE],
+}
+
+/// Reassembles long names from the entries preceding each short entry.
+struct DirParser {
+    units: [u16; 20 * LONG_NAME_CHARS],
+    checksum: u8,
+    /// Ordinal of the long entry expected next; 0 once complete.
+    remaining: u8,
+    first_slot: usize,
+    in_long_name: bool,
+}
+
+impl DirParser {
+    fn new() -> Self {
+        Self { units: [0; 20 * LONG_NAME_CHARS], checksum: 0, remaining: 0, first_slot: 0, in_long_name: false }
+    }
+
+    /// Feeds one slot, returning an entry when a short entry completes one.
+    /// `.` and `..` and volume labels are skipped.
+    fn feed(&mut self, slot: usize, raw: &[u8; DIRENT_SIZE]) -> Option<RawEntry> {
+        if raw[0] == DELETED || raw[0] == END_OF_DIR {
+            self.in_long_name = false;
+            return None;
+        }
+        if raw[11] & 0x3f == ATTR_LONG_NAME {
+            let ordinal = raw[0] & !LAST_LONG_ENTRY;
+            if raw[0] & LAST_LONG_ENTRY != 0 && (1..=20).contains(&ordinal) {
+                self.units.fill(0xffff);
+                self.checksum = raw[13];
+                self.remaining = ordinal;
+                self.first_slot = slot;
+                self.in_long_name = true;
+            }
+            if !self.in_long_name || ordinal != self.remaining || raw[13] != self.checksum {
+                self.in_long_name = false;
+                return None;
+            }
+            let base = (ordinal as usize - 1) * LONG_NAME_CHARS;
+            for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                self.units[base + i] = le_u16(raw, offset);
+            }
+            self.remaining -= 1;
+            return None;
+        }
+        let long = self.in_long_name && self.remaining == 0 && self.checksum == short_name_checksum(raw);
+        self.in_long_name = false;
+        if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            return None;
+        }
+        let name = if long {
+            let len = self.units.iter().position(|&u| u == 0 || u == 0xffff).unwrap_or(self.units.len());
+            char::decode_utf16(self.units[..len].iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect()
+        } else {
+            short_name_string(raw)
+        };
+        Some(RawEntry { name, first_slot: if long { self.first_slot } else { slot }, slot, dirent: *raw })
+    }
+}
+
+fn short_name_string(raw: &[u8; DIRENT_SIZE]) -> String {
+    let mut name = String::new();
+    let part = |bytes: &[u8], lower: bool, name: &mut String| {
+        let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |p| p + 1);
+        for (i, &b) in bytes[..len].iter().enumerate() {
+            let b = if i == 0 && b == KANJI_E5 { DELETED } else { b };
+            name.push(if lower { b.to_ascii_lowercase() } else { b } as char);
+        }
+    };
+    part(&raw[..8], raw[12] & NTRES_LOWER_BASE != 0, &mut name);
+    if raw[8..11].iter().any(|&b| b != b' ') {
+        name.push('.');
+        part(&raw[8..11], raw[12] & NTRES_LOWER_EXT != 0, &mut name);
+    }
+    name
+}
+
+/// What the driver remembers about a file or directory it has looked up.
+/// The directory entry on disk is rewritten from this whenever it changes.
+#[derive(Clone)]
+struct FatInode {
+    parent: u64,
+    first_slot: usize,
+    slot: usize,
+    attr: u8,
+    /// First cluster; 0 for an empty file or the fixed FAT12/16 root.
+    cluster: u32,
+    /// File size; for directories, the bytes in their cluster chain.
+    size: u32,
+    atime: Timespec,
+    mtime: Timespec,
+    ctime: Timespec,
+}
+
+impl FatInode {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Inode numbers are handed out on first lookup and stay with the file
+/// across renames; `by_slot` finds them again from a directory entry.
+struct Inodes {
+    nodes: BTreeMap<u64, FatInode>,
+    by_slot: BTreeMap<(u64, usize), u64>,
+    next_ino: u64,
+}
+
+impl Inodes {
+    fn get(&self, ino: u64) -> Result<FatInode, FsError> {
+        self.nodes.get(&ino).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn remove(&mut self, parent: u64, slot: usize) {
+        if let Some(ino) = self.by_slot.remove(&(parent, slot)) {
+            self.nodes.remove(&ino);
+        }
+    }
+}
+
+/// A mounted FAT12, FAT16 or FAT32 volume. All access goes through a
+/// buffer cache over the block device, so changes reach the disk when the
+/// cache is synced (on `sync` or unmount) or evicts them.
+pub struct FatFs {
+    dev: usize,
+    cache: BufferCache,
+    fat_type: FatType,
+    cluster_size: usize,
+    fat_offset: u64,
+    fat_bytes: u64,
+    num_fats: u32,
+    /// The fixed root directory region of FAT12/16.
+    root_offset: u64,
+    root_bytes: usize,
+    data_offset: u64,
+    cluster_count: u32,
+    fsinfo_offset: Option<u64>,
+    free_count: Cell<u32>,
+    next_free: Cell<u32>,
+    options: Options,
+    inodes: RefCell<Inodes>,
+    this: Weak<FatFs>,
+}
+
+impl FatFs {
+    /// Reads the boot sector of `device` and checks that it describes a
+    /// FAT volume that fits on it.
+    fn new(device: block::BlockRef, options: Options) -> Result<Arc<Self>, FsError> {
+        let read_only = options.read_only || device.read_only();
+        let cache = BufferCache::new(device, DEFAULT_CACHE_PAGES);
+        let mut bpb = [0u8; 512];
+        cache.read(0, &mut bpb)?;
+        let bytes_per_sector = le_u16(&bpb, 11) as u64;
+        let sectors_per_cluster = bpb[13] as u64;
+        let reserved = le_u16(&bpb, 14) as u64;
+        let num_fats = bpb[16] as u32;
+        let root_entries = le_u16(&bpb, 17) as u64;
+        let total = match le_u16(&bpb, 19) {
+            0 => le_u32(&bpb, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match le_u16(&bpb, 22) {
+            0 => le_u32(&bpb, 36) as u64,
+            n => n as u64,
+        };
+        if bpb[510] != 0x55
+            || bpb[511] != 0xaa
+            || !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+            || fat_sectors == 0
+            || total * bytes_per_sector > cache.size()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let root_sectors = (root_entries * DIRENT_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved + num_fats as u64 * fat_sectors + root_sectors;
+        if data_sector >= total {
+            return Err(FsError::InvalidArgument);
+        }
+        let cluster_count = ((total - data_sector) / sectors_per_cluster) as u32;
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let (root_cluster, fsinfo_sector) = match fat_type {
+            FatType::Fat32 => (le_u32(&bpb, 44), le_u16(&bpb, 48) as u64),
+            _ => (0, 0),
+        };
+        if fat_type == FatType::Fat32 && (root_cluster < 2 || root_cluster >= cluster_count + 2) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut fs = Self {
+            dev: vfs::alloc_dev(),
+            cache,
+            fat_type,
+            cluster_size: (bytes_per_sector * sectors_per_cluster) as usize,
+            fat_offset: reserved * bytes_per_sector,
+            fat_bytes: fat_sectors * bytes_per_sector,
+            num_fats,
+            root_offset: (reserved + num_fats as u64 * fat_sectors) * bytes_per_sector,
+            root_bytes: (root_entries as usize) * DIRENT_SIZE,
+            data_offset: data_sector * bytes_per_sector,
+            cluster_count,
+            fsinfo_offset: None,
+            free_count: Cell::new(FSINFO_UNKNOWN),
+            next_free: Cell::new(2),
+            options: Options { read_only, ..options },
+            inodes: RefCell::new(Inodes { nodes: BTreeMap::new(), by_slot: BTreeMap::new(), next_ino: ROOT_INO + 1 }),
+            this: Weak::new(),
+        };
+        if fat_type == FatType::Fat32 && fsinfo_sector != 0 && fsinfo_sector < reserved {
+            let offset = fsinfo_sector * bytes_per_sector;
+            let mut info = [0u8; 512];
+            fs.cache.read(offset, &mut info)?;
+            if le_u32(&info, 0) == FSINFO_LEAD_SIG && le_u32(&info, 484) == FSINFO_STRUCT_SIG {
+                fs.fsinfo_offset = Some(offset);
+                let free = le_u32(&info, 488);
+                if free <= cluster_count {
+                    fs.free_count.set(free);
+                }
+                let next = le_u32(&info, 492);
+                if next >= 2 && next < cluster_count + 2 {
+                    fs.next_free.set(next);
+                }
+            }
+        }
+
+        let size = if root_cluster == 0 { fs.root_bytes as u32 } else { fs.chain_bytes(root_cluster)? };
+        let root = FatInode {
+            parent: ROOT_INO,
+            first_slot: 0,
+            slot: 0,
+            attr: ATTR_DIRECTORY,
+            cluster: root_cluster,
+            size,
+            atime: Timespec::new(FAT_EPOCH, 0),
+            mtime: Timespec::new(FAT_EPOCH, 0),
+            ctime: Timespec::new(FAT_EPOCH, 0),
+        };
+        fs.inodes.get_mut().nodes.insert(ROOT_INO, root);
+        Ok(Arc::new_cyclic(|this| {
+            fs.this = this.clone();
+            fs
+        }))
+    }
+
+    fn node(&self, ino: u64) -> InodeRef {
+        Arc::new(FatNode { fs: self.this.upgrade().unwrap(), ino })
+    }
+
+    fn writable(&self) -> Result<(), FsError> {
+        if self.options.read_only { Err(FsError::ReadOnly) } else { Ok(()) }
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size as u64
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster as u64 + cluster as u64 / 2;
+                self.cache.read(self.fat_offset + offset, &mut bytes[..2])?;
+                let word = le_u16(&bytes, 0) as u32;
+                Ok(if cluster & 1 == 1 { word >> 4 } else { word & 0xfff })
+            }
+            FatType::Fat16 => {
+                self.cache.read(self.fat_offset + cluster as u64 * 2, &mut bytes[..2])?;
+                Ok(le_u16(&bytes, 0) as u32)
+            }
+            FatType::Fat32 => {
+                self.cache.read(self.fat_offset + cluster as u64 * 4, &mut bytes)?;
+                Ok(le_u32(&bytes, 0) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    /// Sets the entry for `cluster` in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for copy in 0..self.num_fats as u64 {
+            let base = self.fat_offset + copy * self.fat_bytes;
+            let mut bytes = [0u8; 4];
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = base + cluster as u64 + cluster as u64 / 2;
+                    self.cache.read(offset, &mut bytes[..2])?;
+                    let old = le_u16(&bytes, 0);
+                    let value = value as u16 & 0xfff;
+                    let word = if cluster & 1 == 1 { (old & 0x000f) | value << 4 } else { (old & 0xf000) | value };
+                    self.cache.write(offset, &word.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.cache.write(base + cluster as u64 * 2, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    let offset = base + cluster as u64 * 4;
+                    self.cache.read(offset, &mut bytes)?;
+                    let word = (le_u32(&bytes, 0) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.cache.write(offset, &word.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end.
+    /// Bad or out-of-range links also end the chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let next = self.fat_entry(cluster)?;
+        Ok(if self.is_valid_cluster(next) { Some(next) } else { None })
+    }
+
+    /// Bytes covered by the chain starting at `first`.
+    fn chain_bytes(&self, first: u32) -> Result<u32, FsError> {
+        let mut count = 0u32;
+        let mut cluster = if self.is_valid_cluster(first) { Some(first) } else { None };
+        while let Some(c) = cluster {
+            count += 1;
+            if count > self.cluster_count {
+                return Err(FsError::Io);
+            }
+            cluster = self.next_cluster(c)?;
+        }
+        Ok(count.saturating_mul(self.cluster_size as u32))
+    }
+
+    /// Takes a free cluster, marks it as the end of a chain and links it
+    /// after `prev`.
+    fn alloc_cluster(&self, prev: Option<u32>) -> Result<u32, FsError> {
+        let start = self.next_free.get();
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (start - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster)?;
+            }
+            self.next_free.set(if cluster + 1 < self.cluster_count + 2 { cluster + 1 } else { 2 });
+            if self.free_count.get() != FSINFO_UNKNOWN {
+                self.free_count.set(self.free_count.get().saturating_sub(1));
+            }
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<(), FsError> {
+        let mut cluster = if self.is_valid_cluster(first) { Some(first) } else { None };
+        while let Some(c) = cluster {
+            cluster = self.next_cluster(c)?;
+            self.set_fat_entry(c, 0)?;
+            if self.free_count.get() != FSINFO_UNKNOWN {
+                self.free_count.set(self.free_count.get() + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the chain starting at `first` (0 for none) at least `clusters`
+    /// long and returns its possibly new first cluster. On failure the
+    /// clusters added so far are given back.
+    fn extend_chain(&self, first: u32, clusters: usize) -> Result<u32, FsError> {
+        let mut count = 0;
+        let mut last = None;
+        let mut cluster = if self.is_valid_cluster(first) { Some(first) } else { None };
+        while let Some(c) = cluster {
+            count += 1;
+            last = Some(c);
+            if count >= clusters {
+                return Ok(first);
+            }
+            cluster = self.next_cluster(c)?;
+        }
+        let old_last = last;
+        let mut added = None;
+        while count < clusters {
+            match self.alloc_cluster(last) {
+                Ok(c) => {
+                    added.get_or_insert(c);
+                    last = Some(c);
+                    count += 1;
+                }
+                Err(err) => {
+                    if let Some(added) = added {
+                        self.free_chain(added)?;
+                    }
+                    if let Some(old_last) = old_last {
+                        self.set_fat_entry(old_last, self.end_of_chain())?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(if old_last.is_some() { first } else { added.unwrap_or(0) })
+    }
+
+    /// Cuts the chain starting at `first` down to `clusters` clusters and
+    /// returns its first cluster, 0 if nothing is left.
+    fn shrink_chain(&self, first: u32, clusters: usize) -> Result<u32, FsError> {
+        if clusters == 0 {
+            self.free_chain(first)?;
+            return Ok(0);
+        }
+        let mut last = first;
+        for _ in 1..clusters {
+            last = match self.next_cluster(last)? {
+                Some(next) => next,
+                None => return Ok(first),
+            };
+        }
+        if let Some(rest) = self.next_cluster(last)? {
+            self.set_fat_entry(last, self.end_of_chain())?;
+            self.free_chain(rest)?;
+        }
+        Ok(first)
+    }
+
+    /// Calls `op(device_offset, position, len)` for each piece of the
+    /// `len` bytes at `offset` in the chain starting at `first`. Returns
+    /// how many bytes the chain covered.
+    fn for_each_extent(
+        &self,
+        first: u32,
+        offset: usize,
+        len: usize,
+        mut op: impl FnMut(u64, usize, usize) -> Result<(), FsError>,
+    ) -> Result<usize, FsError> {
+        let mut cluster = if self.is_valid_cluster(first) { Some(first) } else { None };
+        for _ in 0..offset / self.cluster_size {
+            cluster = match cluster {
+                Some(c) => self.next_cluster(c)?,
+                None => return Ok(0),
+            };
+        }
+        let mut done = 0;
+        let mut in_cluster = offset % self.cluster_size;
+        while let Some(c) = cluster {
+            if done == len {
+                break;
+            }
+            let chunk = core::cmp::min(self.cluster_size - in_cluster, len - done);
+            op(self.cluster_offset(c) + in_cluster as u64, done, chunk)?;
+            done += chunk;
+            in_cluster = 0;
+            if done < len {
+                cluster = self.next_cluster(c)?;
+            }
+        }
+        Ok(done)
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        let base = self.cluster_offset(cluster);
+        for offset in (0..self.cluster_size).step_by(ZEROES.len()) {
+            self.cache.write(base + offset as u64, &ZEROES)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `visit(slot, device_offset, entry)` for every slot of the
+    /// directory starting at `dir` (0 for the fixed root) until it
+    /// returns `Some`.
+    fn scan_dir<R>(
+        &self,
+        dir: u32,
+        mut visit: impl FnMut(usize, u64, &[u8; DIRENT_SIZE]) -> Option<R>,
+    ) -> Result<Option<R>, FsError> {
+        let mut raw = [0u8; DIRENT_SIZE];
+        if dir == 0 {
+            for slot in 0..self.root_bytes / DIRENT_SIZE {
+                let offset = self.root_offset + (slot * DIRENT_SIZE) as u64;
+                self.cache.read(offset, &mut raw)?;
+                if let Some(result) = visit(slot, offset, &raw) {
+                    return Ok(Some(result));
+                }
+            }
+            return Ok(None);
+        }
+        let per_cluster = self.cluster_size / DIRENT_SIZE;
+        let mut cluster = Some(dir);
+        let mut index = 0;
+        while let Some(c) = cluster {
+            if index as u32 >= self.cluster_count {
+                return Err(FsError::Io);
+            }
+            for i in 0..per_cluster {
+                let offset = self.cluster_offset(c) + (i * DIRENT_SIZE) as u64;
+                self.cache.read(offset, &mut raw)?;
+                if let Some(result) = visit(index * per_cluster + i, offset, &raw) {
+                    return Ok(Some(result));
+                }
+            }
+            index += 1;
+            cluster = self.next_cluster(c)?;
+        }
+        Ok(None)
+    }
+
+    fn slot_offset(&self, dir: u32, slot: usize) -> Result<u64, FsError> {
+        self.scan_dir(dir, |index, offset, _| (index == slot).then_some(offset))?.ok_or(FsError::Io)
+    }
+
+    /// Finds `name` in `dir`, ignoring case as FAT does.
+    fn find(&self, dir: u32, name: &str) -> Result<Option<RawEntry>, FsError> {
+        let mut parser = DirParser::new();
+        let found = self.scan_dir(dir, |slot, _, raw| {
+            if raw[0] == END_OF_DIR {
+                return Some(None);
+            }
+            parser.feed(slot, raw).filter(|e| names_match(&e.name, name)).map(Some)
+        })?;
+        Ok(found.flatten())
+    }
+
+    fn entries(&self, dir: u32) -> Result<Vec<RawEntry>, FsError> {
+        let mut parser = DirParser::new();
+        let mut entries = Vec::new();
+        self.scan_dir(dir, |slot, _, raw| {
+            if raw[0] == END_OF_DIR {
+                return Some(());
+            }
+            if let Some(entry) = parser.feed(slot, raw) {
+                entries.push(entry);
+            }
+            None
+        })?;
+        Ok(entries)
+    }
+
+    /// Finds `count` consecutive free slots in `dir`, growing it by whole
+    /// clusters if it is full. The fixed FAT12/16 root cannot grow.
+    fn alloc_slots(&self, dir_ino: u64, dir: u32, count: usize) -> Result<usize, FsError> {
+        let (mut run_start, mut run_len, mut total) = (0, 0, 0);
+        let mut past_end = false;
+        let mut last_cluster = dir;
+        let found = self.scan_dir(dir, |slot, offset, raw| {
+            total = slot + 1;
+            if dir != 0 {
+                last_cluster = ((offset - self.data_offset) / self.cluster_size as u64) as u32 + 2;
+            }
+            past_end |= raw[0] == END_OF_DIR;
+            if past_end || raw[0] == DELETED {
+                if run_len == 0 {
+                    run_start = slot;
+                }
+                run_len += 1;
+                if run_len == count {
+                    return Some(run_start);
+                }
+            } else {
+                run_len = 0;
+            }
+            None
+        })?;
+        if let Some(first) = found {
+            return Ok(first);
+        }
+        if dir == 0 {
+            return Err(FsError::NoSpace);
+        }
+        if run_len == 0 {
+            run_start = total;
+        }
+        let per_cluster = self.cluster_size / DIRENT_SIZE;
+        let needed = (count - run_len).div_ceil(per_cluster);
+        let clusters = (total / per_cluster) + needed;
+        self.extend_chain(dir, clusters)?;
+        let mut cluster = self.next_cluster(last_cluster)?;
+        while let Some(c) = cluster {
+            self.zero_cluster(c)?;
+            cluster = self.next_cluster(c)?;
+        }
+        let mut inodes = self.inodes.borrow_mut();
+        if let Some(inode) = inodes.nodes.get_mut(&dir_ino) {
+            inode.size = (clusters * self.cluster_size) as u32;
+        }
+        Ok(run_start)
+    }
+
+    /// Writes the entries for `name` into the directory `dir_ino`, with
+    /// `dirent` supplying everything but the short name. Returns the first
+    /// and the short-entry slot.
+    fn add_entry(&self, dir_ino: u64, name: &str, mut dirent: [u8; DIRENT_SIZE]) -> Result<(usize, usize), FsError> {
+        check_name(name)?;
+        let dir = self.inodes.borrow().get(dir_ino)?.cluster;
+        let mut taken: Vec<[u8; 11]> = Vec::new();
+        let mut parser = DirParser::new();
+        let mut exists = false;
+        self.scan_dir(dir, |slot, _, raw| {
+            if raw[0] == END_OF_DIR {
+                return Some(());
+            }
+            if raw[0] != DELETED && raw[11] & 0x3f != ATTR_LONG_NAME {
+                taken.push(raw[..11].try_into().unwrap());
+            }
+            exists |= parser.feed(slot, raw).is_some_and(|e| names_match(&e.name, name));
+            None
+        })?;
+        if exists {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short, ntres, long) = match exact_short_name(name) {
+            Some((short, ntres)) if !taken.contains(&short) => (short, ntres, false),
+            _ => (generated_short_name(name, &taken)?, 0, true),
+        };
+        dirent[..11].copy_from_slice(&short);
+        dirent[12] = ntres;
+        let units: Vec<u16> = if long { name.encode_utf16().collect() } else { Vec::new() };
+        let long_count = units.len().div_ceil(LONG_NAME_CHARS);
+        let first = self.alloc_slots(dir_ino, dir, long_count + 1)?;
+
+        let checksum = short_name_checksum(&short);
+        for i in 0..long_count {
+            let ordinal = (long_count - i) as u8;
+            let mut raw = [0u8; DIRENT_SIZE];
+            raw[0] = if i == 0 { ordinal | LAST_LONG_ENTRY } else { ordinal };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            for (j, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let index = (ordinal as usize - 1) * LONG_NAME_CHARS + j;
+                let unit = match index.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[index],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.cache.write(self.slot_offset(dir, first + i)?, &raw)?;
+        }
+        self.cache.write(self.slot_offset(dir, first + long_count)?, &dirent)?;
+        Ok((first, first + long_count))
+    }
+
+    /// Marks the slots of an entry as deleted.
+    fn remove_entry(&self, dir: u32, entry: &RawEntry) -> Result<(), FsError> {
+        for slot in entry.first_slot..=entry.slot {
+            self.cache.write(self.slot_offset(dir, slot)?, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    fn dirent_cluster(&self, raw: &[u8; DIRENT_SIZE]) -> u32 {
+        let high = if self.fat_type == FatType::Fat32 { le_u16(raw, 20) as u32 } else { 0 };
+        high << 16 | le_u16(raw, 26) as u32
+    }
+
+    /// The inode number for an entry of `parent`, loading it on first use.
+    fn inode_for(&self, parent: u64, entry: &RawEntry) -> Result<u64, FsError> {
+        if let Some(&ino) = self.inodes.borrow().by_slot.get(&(parent, entry.slot)) {
+            return Ok(ino);
+        }
+        let raw = &entry.dirent;
+        let attr = raw[11];
+        let cluster = self.dirent_cluster(raw);
+        let mtime = from_fat_time(le_u16(raw, 24), le_u16(raw, 22));
+        let size = if attr & ATTR_DIRECTORY != 0 { self.chain_bytes(cluster)? } else { le_u32(raw, 28) };
+        let inode = FatInode {
+            parent,
+            first_slot: entry.first_slot,
+            slot: entry.slot,
+            attr,
+            cluster,
+            size,
+            atime: from_fat_time(le_u16(raw, 18), 0),
+            mtime,
+            ctime: mtime,
+        };
+        let mut inodes = self.inodes.borrow_mut();
+        let ino = inodes.next_ino;
+        inodes.next_ino += 1;
+        inodes.nodes.insert(ino, inode);
+        inodes.by_slot.insert((parent, entry.slot), ino);
+        Ok(ino)
+    }
+
+    /// A fresh short entry for a new file or directory.
+    fn new_dirent(&self, attr: u8, cluster: u32, now: Timespec) -> [u8; DIRENT_SIZE] {
+        let mut raw = [0u8; DIRENT_SIZE];
+        let (date, time) = to_fat_time(now);
+        raw[11] = attr;
+        raw[14..16].copy_from_slice(&time.to_le_bytes());
+        raw[16..18].copy_from_slice(&date.to_le_bytes());
+        raw[18..20].copy_from_slice(&date.to_le_bytes());
+        raw[22..24].copy_from_slice(&time.to_le_bytes());
+        raw[24..26].copy_from_slice(&date.to_le_bytes());
+        self.set_dirent_cluster(&mut raw, cluster);
+        raw
+    }
+
+    fn set_dirent_cluster(&self, raw: &mut [u8; DIRENT_SIZE], cluster: u32) {
+        if self.fat_type == FatType::Fat32 {
+            raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        }
+        raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    /// Writes an inode's attributes, first cluster, size and times back to
+    /// its directory entry. The root has no entry.
+    fn store(&self, ino: u64) -> Result<(), FsError> {
+        if ino == ROOT_INO {
+            return Ok(());
+        }
+        let (inode, dir) = {
+            let inodes = self.inodes.borrow();
+            let inode = inodes.get(ino)?;
+            let dir = inodes.get(inode.parent)?.cluster;
+            (inode, dir)
+        };
+        let offset = self.slot_offset(dir, inode.slot)?;
+        let mut raw = [0u8; DIRENT_SIZE];
+        self.cache.read(offset, &mut raw)?;
+        raw[11] = inode.attr;
+        self.set_dirent_cluster(&mut raw, inode.cluster);
+        let (date, time) = to_fat_time(inode.mtime);
+        raw[22..24].copy_from_slice(&time.to_le_bytes());
+        raw[24..26].copy_from_slice(&date.to_le_bytes());
+        raw[18..20].copy_from_slice(&to_fat_time(inode.atime).0.to_le_bytes());
+        let size = if inode.is_dir() { 0 } else { inode.size };
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        self.cache.write(offset, &raw)
+    }
+
+    fn update(&self, ino: u64, op: impl FnOnce(&mut FatInode)) -> Result<(), FsError> {
+        {
+            let mut inodes = self.inodes.borrow_mut();
+            op(inodes.nodes.get_mut(&ino).ok_or(FsError::NotFound)?);
+        }
+        self.store(ino)
+    }
+
+    /// Records a change to the contents of directory `ino`.
+    fn touch_dir(&self, ino: u64) -> Result<(), FsError> {
+        let now = time::now();
+        self.update(ino, |inode| {
+            inode.mtime = now;
+            inode.ctime = now;
+        })
+    }
+
+    fn is_empty_dir(&self, cluster: u32) -> Result<bool, FsError> {
+        Ok(self.entries(cluster)?.is_empty())
+    }
+
+    /// Writes `data` at `offset` of file `ino`, growing its chain first.
+    fn write_file(&self, ino: u64, offset: usize, data: &[u8]) -> Result<(), FsError> {
+        let inode = self.inodes.borrow().get(ino)?;
+        let clusters = (offset + data.len()).div_ceil(self.cluster_size);
+        let first = self.extend_chain(inode.cluster, clusters)?;
+        if first != inode.cluster {
+            self.update(ino, |inode| inode.cluster = first)?;
+        }
+        self.for_each_extent(first, offset, data.len(), |device_offset, pos, len| {
+            self.cache.write(device_offset, &data[pos..pos + len])
+        })?;
+        Ok(())
+    }
+
+    /// Fills `from..to` of file `ino` with zeroes.
+    fn zero_range(&self, ino: u64, from: usize, to: usize) -> Result<(), FsError> {
+        let mut pos = from;
+        while pos < to {
+            let len = core::cmp::min(ZEROES.len(), to - pos);
+            self.write_file(ino, pos, &ZEROES[..len])?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    fn ancestors_include(&self, ancestor: u64, mut ino: u64) -> bool {
+        let inodes = self.inodes.borrow();
+        loop {
+            if ino == ancestor {
+                return true;
+            }
+            match inodes.nodes.get(&ino) {
+                Some(inode) if ino != ROOT_INO => ino = inode.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Drops an entry and the clusters it owns.
+    fn delete(&self, dir_ino: u64, dir: u32, entry: &RawEntry) -> Result<(), FsError> {
+        self.remove_entry(dir, entry)?;
+        self.free_chain(self.dirent_cluster(&entry.dirent))?;
+        self.inodes.borrow_mut().remove(dir_ino, entry.slot);
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.node(ROOT_INO)
+    }
+
+    fn dev(&self) -> usize {
+        self.dev
+    }
+
+    fn read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    /// Updates the FAT32 free-cluster hints, then writes out the cache.
+    fn sync(&self) -> Result<(), FsError> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        if let Some(offset) = self.fsinfo_offset {
+            self.cache.write(offset + 488, &self.free_count.get().to_le_bytes())?;
+            self.cache.write(offset + 492, &self.next_free.get().to_le_bytes())?;
+        }
+        self.cache.sync()
+    }
+}
+
+fn names_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.chars().zip(b.chars()).all(|(x, y)| x.to_lowercase().eq(y.to_lowercase()))
+}
+
+/// A handle on one file or directory of a FAT volume.
+pub struct FatNode {
+    fs: Arc<FatFs>,
+    ino: u64,
+}
+
+impl FatNode {
+    fn inode(&self) -> Result<FatInode, FsError> {
+        self.fs.inodes.borrow().get(self.ino)
+    }
+
+    fn dir(&self) -> Result<FatInode, FsError> {
+        let inode = self.inode()?;
+        if inode.is_dir() { Ok(inode) } else { Err(FsError::NotADirectory) }
+    }
+
+    fn file(&self) -> Result<FatInode, FsError> {
+        let inode = self.inode()?;
+        if inode.is_dir() { Err(FsError::IsADirectory) } else { Ok(inode) }
+    }
+
+    fn same_fs<'a>(&self, other: &'a InodeRef) -> Result<&'a FatNode, FsError> {
+        match other.as_any().downcast_ref::<FatNode>() {
+            Some(node) if Arc::ptr_eq(&node.fs, &self.fs) => Ok(node),
+            _ => Err(FsError::CrossDevice),
+        }
+    }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Metadata {
+        let inode = self.inode().ok();
+        let options = &self.fs.options;
+        let (kind, mode) = match &inode {
+            Some(inode) if inode.is_dir() => (InodeKind::Directory, 0o777 & !options.dmask),
+            Some(inode) if inode.attr & ATTR_READ_ONLY != 0 => (InodeKind::File, 0o555 & !options.fmask),
+            _ => (InodeKind::File, 0o777 & !options.fmask),
+        };
+        Metadata {
+            dev: self.fs.dev,
+            ino: self.ino,
+            kind,
+            mode,
+            uid: options.uid,
+            gid: options.gid,
+            nlink: if kind == InodeKind::Directory { 2 } else { 1 },
+            size: inode.as_ref().map_or(0, |i| i.size as usize),
+            rdev: 0,
+            atime: inode.as_ref().map_or(Timespec::default(), |i| i.atime),
+            mtime: inode.as_ref().map_or(Timespec::default(), |i| i.mtime),
+            ctime: inode.as_ref().map_or(Timespec::default(), |i| i.ctime),
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.file()?;
+        let size = inode.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len(), size - offset);
+        self.fs.for_each_extent(inode.cluster, offset, len, |device_offset, pos, chunk| {
+            self.fs.cache.read(device_offset, &mut buf[pos..pos + chunk])
+        })
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        self.fs.writable()?;
+        let inode = self.file()?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(data.len()).filter(|&end| end <= u32::MAX as usize).ok_or(FsError::FileTooLarge)?;
+        let size = inode.size as usize;
+        if offset > size {
+            self.fs.zero_range(self.ino, size, offset)?;
+        }
+        self.fs.write_file(self.ino, offset, data)?;
+        let now = time::now();
+        self.fs.update(self.ino, |inode| {
+            inode.size = core::cmp::max(inode.size, end as u32);
+            inode.attr |= ATTR_ARCHIVE;
+            inode.mtime = now;
+            inode.ctime = now;
+        })?;
+        Ok(data.len())
+    }
+
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        self.fs.writable()?;
+        let inode = self.file()?;
+        if len > u32::MAX as usize {
+            return Err(FsError::FileTooLarge);
+        }
+        let size = inode.size as usize;
+        if len > size {
+            self.fs.zero_range(self.ino, size, len)?;
+        } else if len < size {
+            let first = self.fs.shrink_chain(inode.cluster, len.div_ceil(self.fs.cluster_size))?;
+            self.fs.update(self.ino, |inode| inode.cluster = first)?;
+        }
+        let now = time::now();
+        self.fs.update(self.ino, |inode| {
+            inode.size = len as u32;
+            inode.attr |= ATTR_ARCHIVE;
+            inode.mtime = now;
+            inode.ctime = now;
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let dir = self.dir()?;
+        match name {
+            "" | "." => return Ok(self.fs.node(self.ino)),
+            ".." => return Ok(self.fs.node(dir.parent)),
+            _ => {}
+        }
+        let entry = self.fs.find(dir.cluster, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(self.fs.inode_for(self.ino, &entry)?))
+    }
+
+    fn create(&self, name: &str, kind: InodeKind, mode: u32) -> Result<InodeRef, FsError> {
+        self.fs.writable()?;
+        let dir = self.dir()?;
+        let now = time::now();
+        let read_only = if mode & 0o222 == 0 { ATTR_READ_ONLY } else { 0 };
+        let (attr, cluster) = match kind {
+            InodeKind::File => (ATTR_ARCHIVE | read_only, 0),
+            InodeKind::Directory => {
+                let cluster = self.fs.alloc_cluster(None)?;
+                let init = self.fs.zero_cluster(cluster).and_then(|_| {
+                    let mut dot = self.fs.new_dirent(ATTR_DIRECTORY, cluster, now);
+                    dot[..11].copy_from_slice(b".          ");
+                    let parent = if self.ino == ROOT_INO { 0 } else { dir.cluster };
+                    let mut dotdot = self.fs.new_dirent(ATTR_DIRECTORY, parent, now);
+                    dotdot[..11].copy_from_slice(b"..         ");
+                    let base = self.fs.cluster_offset(cluster);
+                    self.fs.cache.write(base, &dot)?;
+                    self.fs.cache.write(base + DIRENT_SIZE as u64, &dotdot)
+                });
+                if let Err(err) = init {
+                    self.fs.free_chain(cluster)?;
+                    return Err(err);
+                }
+                (ATTR_DIRECTORY, cluster)
+            }
+            _ => return Err(FsError::NotSupported),
+        };
+        let dirent = self.fs.new_dirent(attr, cluster, now);
+        let (first_slot, slot) = match self.fs.add_entry(self.ino, name, dirent) {
+            Ok(slots) => slots,
+            Err(err) => {
+                self.fs.free_chain(cluster)?;
+                return Err(err);
+            }
+        };
+        self.fs.touch_dir(self.ino)?;
+        let entry = RawEntry { name: name.into(), first_slot, slot, dirent };
+        Ok(self.fs.node(self.fs.inode_for(self.ino, &entry)?))
+    }
+
+    /// FAT keeps only a read-only flag and two timestamps; ownership comes
+    /// from the mount options and cannot be changed per file.
+    fn set_attr(&self, attr: &SetAttr) -> Result<(), FsError> {
+        self.fs.writable()?;
+        let options = &self.fs.options;
+        if attr.uid.is_some_and(|uid| uid != options.uid) || attr.gid.is_some_and(|gid| gid != options.gid) {
+            return Err(FsError::PermissionDenied);
+        }
+        let now = time::now();
+        self.fs.update(self.ino, |inode| {
+            if let Some(mode) = attr.mode {
+                if !inode.is_dir() {
+                    inode.attr = if mode & 0o222 == 0 { inode.attr | ATTR_READ_ONLY } else { inode.attr & !ATTR_READ_ONLY };
+                }
+            }
+            if let Some(atime) = attr.atime {
+                inode.atime = atime;
+            }
+            if let Some(mtime) = attr.mtime {
+                inode.mtime = mtime;
+            }
+            inode.ctime = now;
+        })
+    }
+
+    fn link(&self, _name: &str, _target: &InodeRef) -> Result<(), FsError> {
+        self.dir()?;
+        Err(FsError::NotSupported)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, FsError> {
+        self.dir()?;
+        Err(FsError::NotSupported)
+    }
+
+    fn mknod(&self, _name: &str, _mode: u32, _rdev: u64) -> Result<InodeRef, FsError> {
+        self.dir()?;
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, name: &str, remove_dir: bool) -> Result<(), FsError> {
+        self.fs.writable()?;
+        let dir = self.dir()?;
+        let entry = self.fs.find(dir.cluster, name)?.ok_or(FsError::NotFound)?;
+        let is_dir = entry.dirent[11] & ATTR_DIRECTORY != 0;
+        match (is_dir, remove_dir) {
+            (true, false) => return Err(FsError::IsADirectory),
+            (false, true) => return Err(FsError::NotADirectory),
+            (true, true) if !self.fs.is_empty_dir(self.fs.dirent_cluster(&entry.dirent))? => {
+                return Err(FsError::NotEmpty)
+            }
+            _ => {}
+        }
+        self.fs.delete(self.ino, dir.cluster, &entry)?;
+        self.fs.touch_dir(self.ino)
+    }
+
+    /// Writes the entry under its new name before removing the old one.
+    /// `RENAME_EXCHANGE` is not supported.
+    fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str, flags: usize) -> Result<(), FsError> {
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(if flags & RENAME_EXCHANGE != 0 { FsError::NotSupported } else { FsError::InvalidArgument });
+        }
+        self.fs.writable()?;
+        let target = self.same_fs(new_dir)?;
+        let (old_dir, new_dir) = (self.dir()?, target.dir()?);
+        let src = self.fs.find(old_dir.cluster, old_name)?.ok_or(FsError::NotFound)?;
+        let src_ino = self.fs.inode_for(self.ino, &src)?;
+        let src_is_dir = src.dirent[11] & ATTR_DIRECTORY != 0;
+        if src_is_dir && self.fs.ancestors_include(src_ino, target.ino) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        match self.fs.find(new_dir.cluster, new_name)? {
+            Some(dst) if target.ino == self.ino && dst.slot == src.slot => {
+                if old_name == new_name {
+                    return Ok(());
+                }
+                // Only the case changes: the old entry has to go first.
+                self.fs.remove_entry(old_dir.cluster, &src)?;
+                let (first_slot, slot) = self.fs.add_entry(self.ino, new_name, src.dirent)?;
+                let mut inodes = self.fs.inodes.borrow_mut();
+                inodes.by_slot.remove(&(self.ino, src.slot));
+                inodes.by_slot.insert((self.ino, slot), src_ino);
+                if let Some(inode) = inodes.nodes.get_mut(&src_ino) {
+                    inode.first_slot = first_slot;
+                    inode.slot = slot;
+                }
+                return Ok(());
+            }
+            Some(_) if flags & RENAME_NOREPLACE != 0 => return Err(FsError::AlreadyExists),
+            Some(dst) => {
+                let dst_is_dir = dst.dirent[11] & ATTR_DIRECTORY != 0;
+                match (src_is_dir, dst_is_dir) {
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, true) if !self.fs.is_empty_dir(self.fs.dirent_cluster(&dst.dirent))? => {
+                        return Err(FsError::NotEmpty)
+                    }
+                    _ => {}
+                }
+                self.fs.delete(target.ino, new_dir.cluster, &dst)?;
+            }
+            None => {}
+        }
+
+        let (first_slot, slot) = self.fs.add_entry(target.ino, new_name, src.dirent)?;
+        // Growing a directory only appends clusters, so `src` still names
+        // the old entry even when both live in the same directory.
+        self.fs.remove_entry(old_dir.cluster, &src)?;
+        if src_is_dir && target.ino != self.ino {
+            let mut dotdot = [0u8; DIRENT_SIZE];
+            let cluster = self.fs.dirent_cluster(&src.dirent);
+            let offset = self.fs.cluster_offset(cluster) + DIRENT_SIZE as u64;
+            self.fs.cache.read(offset, &mut dotdot)?;
+            let parent_cluster = if target.ino == ROOT_INO { 0 } else { new_dir.cluster };
+            self.fs.set_dirent_cluster(&mut dotdot, parent_cluster);
+            self.fs.cache.write(offset, &dotdot)?;
+        }
+        {
+            let mut inodes = self.fs.inodes.borrow_mut();
+            inodes.by_slot.remove(&(self.ino, src.slot));
+            inodes.by_slot.insert((target.ino, slot), src_ino);
+            if let Some(inode) = inodes.nodes.get_mut(&src_ino) {
+                inode.parent = target.ino;
+                inode.first_slot = first_slot;
+                inode.slot = slot;
+                inode.ctime = time::now();
+            }
+        }
+        self.fs.touch_dir(self.ino)?;
+        if target.ino != self.ino {
+            self.fs.touch_dir(target.ino)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.dir()?;
+        let entries = self.fs.entries(dir.cluster)?;
+        let mut list = Vec::with_capacity(entries.len() + 2);
+        list.push(DirEntry { name: String::from("."), ino: self.ino, kind: InodeKind::Directory });
+        list.push(DirEntry { name: String::from(".."), ino: dir.parent, kind: InodeKind::Directory });
+        for entry in entries {
+            let kind = if entry.dirent[11] & ATTR_DIRECTORY != 0 { InodeKind::Directory } else { InodeKind::File };
+            let ino = self.fs.inode_for(self.ino, &entry)?;
+            list.push(DirEntry { name: entry.name, ino, kind });
+        }
+        Ok(list)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Mounts the FAT volume on the block device named by `source`, e.g.
+/// `/dev/vda1`.
+fn mount_vfat(source: &str, data: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let options = parse_options(data)?;
+    let device = block::find_block_device(source).ok_or(FsError::NoDevice)?;
+    Ok(FatFs::new(device, options)?)
+}
+
+pub fn init_fat() -> bool {
+    vfs::register_filesystem("vfat", mount_vfat);
+    crate::print_ok!("FAT filesystem driver registered");
+    true
+}
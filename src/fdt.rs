@@ -0,0 +1,224 @@
+use alloc::vec::Vec;
+
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Largest blob we are willing to parse; QEMU's `virt` tree is a few KB.
+const MAX_FDT_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Property {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+/// One node of the device tree. Names and values borrow straight from the
+/// blob the firmware left in memory, which is never reclaimed.
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// Full node name including the unit address, e.g. `uart@10000000`.
+    pub name: &'static str,
+    pub parent: Option<usize>,
+    pub props: Vec<Property>,
+}
+
+impl Node {
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        self.props.iter().find(|p| p.name == name).map(|p| p.value)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be_u32(self.prop(name)?)
+    }
+
+    /// Whether any entry of the `compatible` string list equals `compat`.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop("compatible")
+            .is_some_and(|list| list.split(|&b| b == 0).any(|entry| entry == compat.as_bytes()))
+    }
+
+    /// The first `reg` entry as `(address, size)`. Assumes the two-cell
+    /// addresses and sizes every bus on QEMU `virt` uses.
+    pub fn reg(&self) -> Option<(usize, usize)> {
+        let value = self.prop("reg")?;
+        if value.len() < 16 {
+            return None;
+        }
+        Some((read_cells(value, 2) as usize, read_cells(&value[8..], 2) as usize))
+    }
+
+    /// The first interrupt number in `interrupts`.
+    pub fn interrupt(&self) -> Option<u32> {
+        self.prop_u32("interrupts")
+    }
+
+    /// Reads a NUL-terminated string property such as `bootargs`.
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        let value = self.prop(name)?;
+        core::str::from_utf8(value.strip_suffix(&[0]).unwrap_or(value)).ok()
+    }
+
+    /// Reads a one- or two-cell integer, as used by `linux,initrd-start`.
+    pub fn prop_u64(&self, name: &str) -> Option<u64> {
+        let value = self.prop(name)?;
+        match value.len() {
+            4 => be_u32(value).map(u64::from),
+            8 => Some(read_cells(value, 2)),
+            _ => None,
+        }
+    }
+
+    /// The part of the name before `@`.
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+}
+
+pub struct DeviceTree {
+    pub nodes: Vec<Node>,
+}
+
+impl DeviceTree {
+    /// Parses the flattened tree at `addr`, checking the header first.
+    pub fn parse(addr: usize) -> Option<Self> {
+        if addr == 0 {
+            return None;
+        }
+        let header = unsafe { core::slice::from_raw_parts(addr as *const u8, 40) };
+        if be_u32(&header[0..])? != FDT_MAGIC {
+            return None;
+        }
+        let total = be_u32(&header[4..])? as usize;
+        if !(40..=MAX_FDT_SIZE).contains(&total) {
+            return None;
+        }
+        let blob: &'static [u8] = unsafe { core::slice::from_raw_parts(addr as *const u8, total) };
+        let struct_off = be_u32(&blob[8..])? as usize;
+        let strings_off = be_u32(&blob[12..])? as usize;
+        let struct_size = be_u32(&blob[36..])? as usize;
+        let structs = blob.get(struct_off..struct_off.checked_add(struct_size)?)?;
+        let strings = blob.get(strings_off..)?;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+        let mut pos = 0;
+        loop {
+            let token = be_u32(structs.get(pos..)?)?;
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let rest = structs.get(pos..)?;
+                    let len = rest.iter().position(|&b| b == 0)?;
+                    let name = core::str::from_utf8(&rest[..len]).ok()?;
+                    pos = align4(pos + len + 1);
+                    nodes.push(Node { name, parent: stack.last().copied(), props: Vec::new() });
+                    stack.push(nodes.len() - 1);
+                }
+                FDT_END_NODE => {
+                    stack.pop()?;
+                }
+                FDT_PROP => {
+                    let len = be_u32(structs.get(pos..)?)? as usize;
+                    let name_off = be_u32(structs.get(pos + 4..)?)? as usize;
+                    let value = structs.get(pos + 8..pos + 8 + len)?;
+                    pos = align4(pos + 8 + len);
+                    let name_bytes = strings.get(name_off..)?;
+                    let name_len = name_bytes.iter().position(|&b| b == 0)?;
+                    let name = core::str::from_utf8(&name_bytes[..name_len]).ok()?;
+                    let node = *stack.last()?;
+                    nodes[node].props.push(Property { name, value });
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => return None,
+            }
+        }
+        Some(DeviceTree { nodes })
+    }
+
+    /// Looks a node up by absolute path. A component without `@` also
+    /// matches a node with a unit address, so `/soc/uart` finds
+    /// `/soc/uart@10000000`.
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        let mut current = 0;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current = self.children(current).find(|&i| {
+                let node = &self.nodes[i];
+                node.name == component || (!component.contains('@') && node.base_name() == component)
+            })?;
+        }
+        self.nodes.get(current)
+    }
+
+    /// Every node compatible with `compat`, in tree order.
+    pub fn find_compatible<'a>(&'a self, compat: &'a str) -> impl Iterator<Item = &'a Node> + 'a {
+        self.nodes.iter().filter(move |node| node.is_compatible(compat))
+    }
+
+    /// The node a `phandle` reference such as `regmap` points at.
+    pub fn find_phandle(&self, phandle: u32) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.prop_u32("phandle") == Some(phandle))
+    }
+
+    pub fn children(&self, parent: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(move |&i| self.nodes[i].parent == Some(parent))
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn read_cells(bytes: &[u8], cells: usize) -> u64 {
+    (0..cells).fold(0u64, |acc, i| (acc << 32) | be_u32(&bytes[i * 4..]).unwrap_or(0) as u64)
+}
+
+fn align4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+static mut DEVICE_TREE: Option<DeviceTree> = None;
+
+/// The tree the firmware passed at boot, if it could be parsed.
+pub fn tree() -> Option<&'static DeviceTree> {
+    unsafe { DEVICE_TREE.as_ref() }
+}
+
+/// The kernel command line from `/chosen/bootargs`, empty if there is none.
+pub fn bootargs() -> &'static str {
+    tree()
+        .and_then(|tree| tree.find("/chosen"))
+        .and_then(|chosen| chosen.prop_str("bootargs"))
+        .unwrap_or("")
+}
+
+/// The value of a `key=value` word on the command line. A bare `key`
+/// gives an empty value.
+pub fn bootarg(key: &str) -> Option<&'static str> {
+    bootargs().split_whitespace().find_map(|word| match word.split_once('=') {
+        Some((name, value)) if name == key => Some(value),
+        None if word == key => Some(""),
+        _ => None,
+    })
+}
+
+pub fn init_fdt(dtb_addr: usize) -> bool {
+    match DeviceTree::parse(dtb_addr) {
+        Some(tree) => {
+            crate::print_ok!("Device tree at {:#x}: {} nodes", dtb_addr, tree.nodes.len());
+            unsafe {
+                DEVICE_TREE = Some(tree);
+            }
+            true
+        }
+        None => {
+            crate::print_fail!("No valid device tree at {:#x}", dtb_addr);
+            false
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{fence, Ordering};
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::time::Timespec;
+use crate::vfs::{self, DirEntry, FileSystem, FsError, Inode, InodeKind, InodeRef, Metadata};
+
+/// QEMU's fw_cfg MMIO registers. The selector and DMA address are
+/// big-endian; the data register streams bytes.
+const REG_DATA: usize = 0x00;
+const REG_SELECTOR: usize = 0x08;
+const REG_DMA: usize = 0x10;
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_ID: u16 = 0x0001;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+/// What the DMA address register reads back as when DMA is there.
+const DMA_SIGNATURE: u64 = 0x5145_4d55_2043_4647;
+
+const DMA_CTL_ERROR: u32 = 0x01;
+const DMA_CTL_READ: u32 = 0x02;
+const DMA_CTL_SKIP: u32 = 0x04;
+const DMA_CTL_SELECT: u32 = 0x08;
+
+const DMA_BOUNCE_OFFSET: usize = 64;
+
+/// A file directory entry: size, selector, reserved, then the name.
+const FILE_ENTRY_LEN: usize = 64;
+const FILE_NAME_OFFSET: usize = 8;
+
+/// Where the entries show up at boot.
+const MOUNT_POINT: &str = "/host/fw_cfg";
+
+/// One named item from the file directory.
+struct Entry {
+    name: String,
+    size: usize,
+    select: u16,
+}
+
+/// The device. Reads go through DMA when it is offered, otherwise a byte
+/// at a time through the data register.
+struct FwCfg {
+    base: usize,
+    /// The DMA access descriptor, then a bounce buffer: whoever asked for
+    /// the data may not have handed us a physical address. `None` without
+    /// DMA.
+    dma: Option<Frame>,
+    entries: Vec<Entry>,
+    tree: Tree,
+    /// When the entries were read, given as every node's times.
+    found_at: Timespec,
+}
+
+impl FwCfg {
+    fn select(&self, key: u16) {
+        unsafe { core::ptr::write_volatile((self.base + REG_SELECTOR) as *mut u16, key.to_be()) }
+    }
+
+    fn read_byte(&self) -> u8 {
+        unsafe { core::ptr::read_volatile((self.base + REG_DATA) as *const u8) }
+    }
+
+    /// Runs one DMA transfer of `len` bytes at `addr` and waits for the
+    /// device to clear the control word.
+    fn dma_transfer(&self, page: &Frame, control: u32, addr: usize, len: usize) -> Result<(), FsError> {
+        let access = page.addr();
+        unsafe {
+            core::ptr::write_volatile(access as *mut u32, control.to_be());
+            core::ptr::write_volatile((access + 4) as *mut u32, (len as u32).to_be());
+            core::ptr::write_volatile((access + 8) as *mut u64, (addr as u64).to_be());
+            fence(Ordering::SeqCst);
+            // Writing the low half starts the transfer.
+            core::ptr::write_volatile((self.base + REG_DMA) as *mut u32, ((access as u64 >> 32) as u32).to_be());
+            core::ptr::write_volatile((self.base + REG_DMA + 4) as *mut u32, (access as u32).to_be());
+        }
+        loop {
+            fence(Ordering::SeqCst);
+            let control = u32::from_be(unsafe { core::ptr::read_volatile(access as *const u32) });
+            if control & DMA_CTL_ERROR != 0 {
+                return Err(FsError::Io);
+            }
+            if control == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reads `buf.len()` bytes of item `key` from `offset` on.
+    fn read(&self, key: u16, offset: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        match &self.dma {
+            Some(page) => {
+                self.dma_transfer(page, (key as u32) << 16 | DMA_CTL_SELECT | DMA_CTL_SKIP, 0, offset)?;
+                let bounce = page.addr() + DMA_BOUNCE_OFFSET;
+                for chunk in buf.chunks_mut(PAGE_SIZE - DMA_BOUNCE_OFFSET) {
+                    self.dma_transfer(page, DMA_CTL_READ, bounce, chunk.len())?;
+                    chunk.copy_from_slice(&page.as_slice()[DMA_BOUNCE_OFFSET..DMA_BOUNCE_OFFSET + chunk.len()]);
+                }
+                Ok(())
+            }
+            None => {
+                self.select(key);
+                for _ in 0..offset {
+                    self.read_byte();
+                }
+                for byte in buf.iter_mut() {
+                    *byte = self.read_byte();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn read_entries(&self) -> Result<Vec<Entry>, FsError> {
+        let mut count = [0u8; 4];
+        self.read(FW_CFG_FILE_DIR, 0, &mut count)?;
+        let count = u32::from_be_bytes(count) as usize;
+        let mut raw = alloc::vec![0u8; count * FILE_ENTRY_LEN];
+        self.read(FW_CFG_FILE_DIR, 4, &mut raw)?;
+        let entries = raw
+            .chunks(FILE_ENTRY_LEN)
+            .filter_map(|raw| {
+                let name = &raw[FILE_NAME_OFFSET..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                Some(Entry {
+                    name: String::from(core::str::from_utf8(name).ok()?),
+                    size: u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize,
+                    select: u16::from_be_bytes([raw[4], raw[5]]),
+                })
+            })
+            .collect();
+        Ok(entries)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    Dir(usize),
+    File(usize),
+}
+
+impl Node {
+    fn ino(&self) -> u64 {
+        match *self {
+            Node::Dir(index) => 1 + index as u64,
+            Node::File(index) => 0x1_0000 + index as u64,
+        }
+    }
+
+    fn kind(&self) -> InodeKind {
+        match self {
+            Node::Dir(_) => InodeKind::Directory,
+            Node::File(_) => InodeKind::File,
+        }
+    }
+}
+
+struct Dir {
+    parent: usize,
+    children: Vec<(String, Node)>,
+}
+
+/// The entries' names, split at `/` into directories. Directory 0 is the
+/// root.
+struct Tree {
+    dirs: Vec<Dir>,
+}
+
+impl Tree {
+    fn build(entries: &[Entry]) -> Self {
+        let mut tree = Tree { dirs: alloc::vec![Dir { parent: 0, children: Vec::new() }] };
+        for (index, entry) in entries.iter().enumerate() {
+            let mut dir = 0;
+            let mut components = entry.name.split('/').filter(|c| !c.is_empty() && *c != "." && *c != "..").peekable();
+            while let Some(component) = components.next() {
+                let existing = tree.dirs[dir].children.iter().find(|(name, _)| name == component).map(|&(_, node)| node);
+                if components.peek().is_none() {
+                    if existing.is_none() {
+                        tree.dirs[dir].children.push((String::from(component), Node::File(index)));
+                    }
+                    break;
+                }
+                dir = match existing {
+                    Some(Node::Dir(next)) => next,
+                    // A file already has this name; the entry is dropped.
+                    Some(Node::File(_)) => break,
+                    None => {
+                        let next = tree.dirs.len();
+                        tree.dirs.push(Dir { parent: dir, children: Vec::new() });
+                        tree.dirs[dir].children.push((String::from(component), Node::Dir(next)));
+                        next
+                    }
+                };
+            }
+        }
+        tree
+    }
+}
+
+static mut FW_CFG: Option<FwCfg> = None;
+
+fn fw_cfg() -> Option<&'static FwCfg> {
+    unsafe { (*core::ptr::addr_of!(FW_CFG)).as_ref() }
+}
+
+/// The fw_cfg entries as a read-only filesystem.
+pub struct FwCfgFs {
+    dev: usize,
+}
+
+impl FileSystem for FwCfgFs {
+    fn name(&self) -> &'static str {
+        "fw_cfg"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(FwCfgNode { dev: self.dev, node: Node::Dir(0) })
+    }
+
+    fn dev(&self) -> usize {
+        self.dev
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+pub struct FwCfgNode {
+    dev: usize,
+    node: Node,
+}
+
+impl FwCfgNode {
+    fn dir(&self) -> Result<&'static Dir, FsError> {
+        match self.node {
+            Node::Dir(index) => Ok(&fw_cfg().ok_or(FsError::NoDevice)?.tree.dirs[index]),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn entry(node: Node, name: &str) -> DirEntry {
+        DirEntry { name: String::from(name), ino: node.ino(), kind: node.kind() }
+    }
+}
+
+impl Inode for FwCfgNode {
+    fn metadata(&self) -> Metadata {
+        let (mode, nlink, size) = match self.node {
+            Node::Dir(_) => (0o555, 2, 0),
+            Node::File(index) => (0o444, 1, fw_cfg().map_or(0, |dev| dev.entries[index].size)),
+        };
+        let time = fw_cfg().map_or(Timespec::default(), |dev| dev.found_at);
+        Metadata {
+            dev: self.dev,
+            ino: self.node.ino(),
+            kind: self.node.kind(),
+            mode,
+            uid: 0,
+            gid: 0,
+            nlink,
+            size,
+            rdev: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let index = match self.node {
+            Node::File(index) => index,
+            Node::Dir(_) => return Err(FsError::IsADirectory),
+        };
+        let device = fw_cfg().ok_or(FsError::NoDevice)?;
+        let entry = &device.entries[index];
+        if offset >= entry.size {
+            return Ok(0);
+        }
+        let len = buf.len().min(entry.size - offset);
+        device.read(entry.select, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _len: usize) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create(&self, _name: &str, _kind: InodeKind, _mode: u32) -> Result<InodeRef, FsError> {
+        self.dir()?;
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str, _remove_dir: bool) -> Result<(), FsError> {
+        self.dir()?;
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let dir = self.dir()?;
+        let node = match name {
+            "." => self.node,
+            ".." => Node::Dir(dir.parent),
+            _ => dir.children.iter().find(|(n, _)| n == name).map(|&(_, node)| node).ok_or(FsError::NotFound)?,
+        };
+        Ok(Arc::new(FwCfgNode { dev: self.dev, node }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.dir()?;
+        let mut list = alloc::vec![Self::entry(self.node, "."), Self::entry(Node::Dir(dir.parent), "..")];
+        list.extend(dir.children.iter().map(|(name, node)| Self::entry(*node, name)));
+        Ok(list)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn mount_fw_cfg(_source: &str, _data: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    fw_cfg().ok_or(FsError::NoDevice)?;
+    Ok(Arc::new(FwCfgFs { dev: vfs::alloc_dev() }))
+}
+
+/// Finds the device, reads its file directory and mounts it on
+/// `/host/fw_cfg`, on whichever root filesystem the boot ended up with.
+/// Having no fw_cfg is not an error.
+pub fn init_fw_cfg() -> bool {
+    let base = match crate::fdt::tree()
+        .and_then(|tree| tree.find_compatible("qemu,fw-cfg-mmio").next())
+        .and_then(|node| node.reg())
+    {
+        Some((base, _)) => base,
+        None => {
+            crate::print_info!("No fw_cfg device in the device tree");
+            return true;
+        }
+    };
+    let mut device = FwCfg { base, dma: None, entries: Vec::new(), tree: Tree { dirs: Vec::new() }, found_at: crate::time::now() };
+    let mut signature = [0u8; 4];
+    let _ = device.read(FW_CFG_SIGNATURE, 0, &mut signature);
+    if &signature != b"QEMU" {
+        crate::print_fail!("fw_cfg at {:#x}: bad signature", base);
+        return true;
+    }
+    let mut id = [0u8; 4];
+    let _ = device.read(FW_CFG_ID, 0, &mut id);
+    let dma_signature = unsafe { u64::from_be(core::ptr::read_volatile((base + REG_DMA) as *const u64)) };
+    if u32::from_le_bytes(id) & FW_CFG_VERSION_DMA != 0 && dma_signature == DMA_SIGNATURE {
+        device.dma = Frame::alloc_zeroed();
+    }
+    device.entries = match device.read_entries() {
+        Ok(entries) => entries,
+        Err(err) => {
+            crate::print_fail!("fw_cfg at {:#x}: cannot read the file directory: {}", base, err.to_string());
+            return true;
+        }
+    };
+    device.tree = Tree::build(&device.entries);
+    let (count, dma) = (device.entries.len(), device.dma.is_some());
+    unsafe {
+        FW_CFG = Some(device);
+    }
+
+    vfs::register_filesystem("fw_cfg", mount_fw_cfg);
+    let root = vfs::root();
+    let result = ["/host", MOUNT_POINT]
+        .iter()
+        .try_for_each(|dir| match vfs::mkdir(&root, dir, 0o755) {
+            Ok(_) | Err(FsError::AlreadyExists) => Ok(()),
+            Err(err) => Err(err),
+        })
+        .and_then(|_| vfs::mount("fw_cfg", MOUNT_POINT, "fw_cfg", "ro"));
+    match result {
+        Ok(()) => {
+            crate::print_ok!(
+                "fw_cfg at {:#x}{}: {} entries on {}",
+                base,
+                if dma { " (DMA)" } else { "" },
+                count,
+                MOUNT_POINT
+            );
+            true
+        }
+        // A read-only root without `/host` is no reason to stop booting;
+        // the entries can still be mounted elsewhere.
+        Err(err) => {
+            crate::print_fail!("fw_cfg: cannot mount on {}: {}", MOUNT_POINT, err.to_string());
+            true
+        }
+    }
+}
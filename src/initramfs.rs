@@ -0,0 +1,209 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use crate::time::Timespec;
+use crate::tar::{self, Member, MemberKind};
+use crate::vfs::{self, FsError, InodeRef, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+
+/// `rootfs/` packed by `build.rs`, used when the bootloader passes no initrd.
+/// An initrd may be either a `newc` cpio archive or a tarball.
+static EMBEDDED_INITRAMFS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const NEWC_CRC_MAGIC: &[u8] = b"070702";
+const NEWC_HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// One member of a `newc` archive, borrowing its name and data.
+pub struct CpioEntry<'a> {
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub rdev: u64,
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// Iterates over the members of a `newc` archive up to the trailer. A
+/// malformed header ends the walk with `Some(Err(..))`.
+pub struct CpioReader<'a> {
+    archive: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> CpioReader<'a> {
+    pub fn new(archive: &'a [u8]) -> Self {
+        Self { archive, pos: 0, done: false }
+    }
+
+    fn field(header: &[u8], index: usize) -> Result<u32, FsError> {
+        let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+        let text = core::str::from_utf8(digits).map_err(|_| FsError::InvalidArgument)?;
+        u32::from_str_radix(text, 16).map_err(|_| FsError::InvalidArgument)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<CpioEntry<'a>>, FsError> {
+        let header = self
+            .archive
+            .get(self.pos..self.pos + NEWC_HEADER_LEN)
+            .ok_or(FsError::InvalidArgument)?;
+        if &header[..6] != NEWC_MAGIC && &header[..6] != NEWC_CRC_MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        let name_size = Self::field(header, 11)? as usize;
+        let file_size = Self::field(header, 6)? as usize;
+        let name_start = self.pos + NEWC_HEADER_LEN;
+        let name_bytes = self
+            .archive
+            .get(name_start..name_start + name_size)
+            .ok_or(FsError::InvalidArgument)?;
+        let name = core::str::from_utf8(name_bytes.strip_suffix(&[0]).unwrap_or(name_bytes))
+            .map_err(|_| FsError::InvalidArgument)?;
+        let data_start = align4(name_start + name_size);
+        let data = self
+            .archive
+            .get(data_start..data_start + file_size)
+            .ok_or(FsError::InvalidArgument)?;
+        self.pos = align4(data_start + file_size);
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(CpioEntry {
+            ino: Self::field(header, 0)?,
+            mode: Self::field(header, 1)?,
+            uid: Self::field(header, 2)?,
+            gid: Self::field(header, 3)?,
+            nlink: Self::field(header, 4)?,
+            mtime: Self::field(header, 5)?,
+            rdev: vfs::makedev(Self::field(header, 9)?, Self::field(header, 10)?),
+            name,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for CpioReader<'a> {
+    type Item = Result<CpioEntry<'a>, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn align4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+/// Extracts one member. Hard links share an inode number in the archive;
+/// the data usually comes with the last of them, so every later name is
+/// linked to the first one and any data found is written through.
+fn extract(root: &InodeRef, entry: &CpioEntry, links: &mut BTreeMap<u32, String>) -> Result<bool, FsError> {
+    let path = entry.name.trim_start_matches("./").trim_start_matches('/');
+    if path.is_empty() || path == "." {
+        return Ok(false);
+    }
+    let mut member = Member {
+        path: String::from(path),
+        kind: MemberKind::File,
+        link: String::new(),
+        mode: entry.mode & 0o7777,
+        uid: entry.uid,
+        gid: entry.gid,
+        mtime: Timespec::new(entry.mtime as i64, 0),
+        data_offset: 0,
+        size: entry.data.len(),
+        rdev: 0,
+    };
+    match entry.mode & S_IFMT {
+        S_IFDIR => member.kind = MemberKind::Directory,
+        S_IFREG if entry.nlink > 1 => match links.get(&entry.ino) {
+            Some(first) => {
+                member.kind = MemberKind::HardLink;
+                member.link = first.clone();
+            }
+            None => {
+                links.insert(entry.ino, member.path.clone());
+            }
+        },
+        S_IFREG => {}
+        S_IFLNK => {
+            member.kind = MemberKind::Symlink;
+            member.link = String::from(core::str::from_utf8(entry.data).map_err(|_| FsError::InvalidArgument)?);
+            member.size = 0;
+        }
+        S_IFCHR => {
+            member.kind = MemberKind::CharDevice;
+            member.rdev = entry.rdev;
+            member.size = 0;
+        }
+        _ => return Ok(false),
+    }
+    tar::extract_member(root, &member, entry.data)?;
+    Ok(true)
+}
+
+/// Unpacks a `newc` archive below `root`, returning how many members were
+/// created. Block devices, FIFOs and sockets are skipped.
+pub fn unpack(archive: &[u8], root: &InodeRef) -> Result<usize, FsError> {
+    let mut links = BTreeMap::new();
+    let mut count = 0;
+    for entry in CpioReader::new(archive) {
+        if extract(root, &entry?, &mut links)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// The initrd QEMU loaded for `-initrd`, as advertised in `/chosen`.
+fn boot_initrd() -> Option<&'static [u8]> {
+    let chosen = crate::fdt::tree()?.find("/chosen")?;
+    let start = chosen.prop_u64("linux,initrd-start")? as usize;
+    let end = chosen.prop_u64("linux,initrd-end")? as usize;
+    if end <= start {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(start as *const u8, end - start) })
+}
+
+pub fn init_initramfs() -> bool {
+    let (archive, source) = match boot_initrd() {
+        Some(initrd) => (initrd, "initrd"),
+        None => (EMBEDDED_INITRAMFS, "embedded archive"),
+    };
+    let root = vfs::root();
+    let result = if tar::is_tar(archive) {
+        tar::extract(archive, &root)
+    } else {
+        unpack(archive, &root)
+    };
+    match result {
+        Ok(count) => {
+            if vfs::resolve(&root, "tmp").is_err() {
+                let _ = vfs::mkdir(&root, "tmp", 0o1777);
+            }
+            crate::print_ok!("Initramfs unpacked from {}: {} entries", source, count);
+            true
+        }
+        Err(err) => {
+            crate::print_fail!("Initramfs unpack failed: {}", err.to_string());
+            false
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::scheduler::TaskContext;
 use core::arch::asm;
+use core::ptr::{addr_of, addr_of_mut};
 
 pub const INTERRUPT_USER_SOFTWARE: usize = 0;
 pub const INTERRUPT_SUPERVISOR_SOFTWARE: usize = 1;
@@ -26,6 +27,57 @@ pub const EXCEPTION_INSTRUCTION_PAGE_FAULT: usize = 12;
 pub const EXCEPTION_LOAD_PAGE_FAULT: usize = 13;
 pub const EXCEPTION_STORE_PAGE_FAULT: usize = 15;
 
+/// Traps taken since boot, indexed by cause code. Interrupts and
+/// exceptions number their causes separately.
+static mut INTERRUPT_COUNTS: [u64; 16] = [0; 16];
+static mut EXCEPTION_COUNTS: [u64; 16] = [0; 16];
+
+pub fn count_trap(is_interrupt: bool, code: usize) {
+    unsafe {
+        let counts = &mut *if is_interrupt { addr_of_mut!(INTERRUPT_COUNTS) } else { addr_of_mut!(EXCEPTION_COUNTS) };
+        if let Some(count) = counts.get_mut(code) {
+            *count += 1;
+        }
+    }
+}
+
+pub fn trap_count(is_interrupt: bool, code: usize) -> u64 {
+    unsafe {
+        let counts = &*if is_interrupt { addr_of!(INTERRUPT_COUNTS) } else { addr_of!(EXCEPTION_COUNTS) };
+        counts.get(code).copied().unwrap_or(0)
+    }
+}
+
+/// Human-readable name of a trap cause, as listed in `/proc/interrupts`.
+pub fn cause_name(is_interrupt: bool, code: usize) -> Option<&'static str> {
+    let name = if is_interrupt {
+        match code {
+            INTERRUPT_SUPERVISOR_SOFTWARE => "Supervisor software",
+            INTERRUPT_SUPERVISOR_TIMER => "Supervisor timer",
+            INTERRUPT_SUPERVISOR_EXTERNAL => "Supervisor external",
+            _ => return None,
+        }
+    } else {
+        match code {
+            EXCEPTION_INSTRUCTION_MISALIGNED => "Instruction address misaligned",
+            EXCEPTION_INSTRUCTION_ACCESS_FAULT => "Instruction access fault",
+            EXCEPTION_ILLEGAL_INSTRUCTION => "Illegal instruction",
+            EXCEPTION_BREAKPOINT => "Breakpoint",
+            EXCEPTION_LOAD_MISALIGNED => "Load address misaligned",
+            EXCEPTION_LOAD_ACCESS_FAULT => "Load access fault",
+            EXCEPTION_STORE_MISALIGNED => "Store address misaligned",
+            EXCEPTION_STORE_ACCESS_FAULT => "Store access fault",
+            EXCEPTION_ECALL_U => "Environment call from U-mode",
+            EXCEPTION_ECALL_S => "Environment call from S-mode",
+            EXCEPTION_INSTRUCTION_PAGE_FAULT => "Instruction page fault",
+            EXCEPTION_LOAD_PAGE_FAULT => "Load page fault",
+            EXCEPTION_STORE_PAGE_FAULT => "Store page fault",
+            _ => return None,
+        }
+    };
+    Some(name)
+}
+
 pub struct InterruptManager {
     timer_enabled: bool,
     timer_interval: u64,
@@ -74,6 +126,10 @@ impl InterruptManager {
         true
     }
 
+    /// Unmasks timer and external interrupts in `sie`. They are taken while
+    /// user code runs; `sstatus.SIE` stays clear because the trap handler
+    /// cannot resume interrupted kernel code, so the kernel picks up device
+    /// interrupts with `plic::dispatch` while it waits instead.
     pub fn enable_supervisor_interrupts(&mut self) -> bool {
         crate::print_info!("Enabling supervisor interrupts...");
         
@@ -82,17 +138,17 @@ impl InterruptManager {
             asm!("csrr {}, sstatus", out(reg) sstatus);
             crate::print_info!("Current sstatus: {:#x}", sstatus);
             
-            sstatus |= 1 << 1;
+            sstatus &= !(1 << 1);
             asm!("csrw sstatus, {}", in(reg) sstatus);
-            crate::print_info!("Set SIE bit in sstatus: {:#x}", sstatus);
+            crate::print_info!("Cleared SIE bit in sstatus: {:#x}", sstatus);
             
             let mut sie: usize;
             asm!("csrr {}, sie", out(reg) sie);
             crate::print_info!("Current SIE register: {:#x}", sie);
             
-            sie |= 1 << 5;
+            sie |= 1 << INTERRUPT_SUPERVISOR_TIMER | 1 << INTERRUPT_SUPERVISOR_EXTERNAL;
             asm!("csrw sie, {}", in(reg) sie);
-            crate::print_info!("Set STIE bit in SIE register: {:#x}", sie);
+            crate::print_info!("Set STIE and SEIE bits in SIE register: {:#x}", sie);
         }
         crate::print_ok!("Supervisor interrupts enabled");
         true
@@ -104,15 +160,12 @@ impl InterruptManager {
         
         crate::print_info!("Setting up timer with interval: {} us", interval_us);
         
-        unsafe {
-            let clint_base = 0x2000000 as *mut u64;
-            let mtime = core::ptr::read_volatile(clint_base);
-            crate::print_info!("Current time from CLINT: {}", mtime);
-            
-            let mtimecmp = mtime + interval_us;
-            core::ptr::write_volatile(clint_base.add(1), mtimecmp);
-            crate::print_info!("Set mtimecmp to: {}", mtimecmp);
+        let next = crate::time::read_time().wrapping_add(interval_us);
+        if let Err(err) = crate::sbi::set_timer(next) {
+            crate::print_fail!("set_timer: {}", err.to_string());
+            return false;
         }
+        crate::print_info!("Next timer interrupt at: {}", next);
         
         crate::print_ok!("Timer interrupt configured");
         true
@@ -123,17 +176,12 @@ impl InterruptManager {
             return;
         }
 
-        const CLINT_BASE: usize = 0x2000000;
-        unsafe {
-            let clint = CLINT_BASE as *mut u64;
-            let mtime = core::ptr::read_volatile(clint);
-            let next = mtime.wrapping_add(self.timer_interval);
-            core::ptr::write_volatile(clint.add(1), next);
-        }
+        let next = crate::time::read_time().wrapping_add(self.timer_interval);
+        let _ = crate::sbi::set_timer(next);
     }
 
     pub fn handle_external_interrupt(&mut self) {
-        crate::println!("External interrupt received");
+        crate::plic::dispatch();
     }
 }
 
@@ -184,4 +232,10 @@ pub fn handle_timer_interrupt() {
     unsafe {
         INTERRUPT_MANAGER.handle_timer_interrupt();
     }
+}
+
+pub fn handle_external_interrupt() {
+    unsafe {
+        INTERRUPT_MANAGER.handle_external_interrupt();
+    }
 }
\ No newline at end of file
@@ -14,6 +14,32 @@ mod vm;
 mod user;
 mod user_loader;
 mod ramfs;
+mod vfs;
+mod time;
+mod fdt;
+mod initramfs;
+mod tar;
+mod chardev;
+mod devfs;
+mod procfs;
+mod plic;
+mod virtio;
+mod block;
+mod virtio_blk;
+mod virtio_rng;
+mod virtio_console;
+mod random;
+mod fat;
+mod uart;
+mod sbi;
+mod signal;
+mod tty;
+mod pty;
+mod rtc;
+mod power;
+mod ext2;
+mod snapshot;
+mod fw_cfg;
 
 use core::arch::asm;
 use core::alloc::{Layout, GlobalAlloc};
@@ -57,12 +83,50 @@ fn init_trap_handler() {
     crate::print_ok!("Trap handler set up at: {:#x}", trap_addr);
 }
 
-fn init_kernel_systems() {
+fn init_kernel_systems(dtb: usize) {
+    if !sbi::init_sbi() {
+        crate::print_fail!("SBI initialization failed");
+        panic!("SBI initialization failed");
+    }
+    
     if !memory::init_memory() {
         crate::print_fail!("Memory management initialization failed");
         panic!("Memory management initialization failed");
     }
     
+    // Boards without a device tree still boot; drivers fall back to defaults.
+    fdt::init_fdt(dtb);
+    
+    if !plic::init_plic() {
+        crate::print_fail!("PLIC initialization failed");
+        panic!("PLIC initialization failed");
+    }
+    
+    if !uart::init_uart() {
+        crate::print_fail!("UART initialization failed");
+        panic!("UART initialization failed");
+    }
+    
+    if !rtc::init_rtc() {
+        crate::print_fail!("RTC initialization failed");
+        panic!("RTC initialization failed");
+    }
+    
+    if !time::init_time() {
+        crate::print_fail!("Timekeeping initialization failed");
+        panic!("Timekeeping initialization failed");
+    }
+    
+    if !random::init_random() {
+        crate::print_fail!("Entropy pool initialization failed");
+        panic!("Entropy pool initialization failed");
+    }
+    
+    if !power::init_power() {
+        crate::print_fail!("Power control initialization failed");
+        panic!("Power control initialization failed");
+    }
+    
     if !interrupts::init_interrupts() {
         crate::print_fail!("Interrupt system initialization failed");
         panic!("Interrupt system initialization failed");
@@ -78,32 +142,82 @@ fn init_kernel_systems() {
         panic!("User mode initialization failed");
     }
     
+    if !vfs::init_vfs() {
+        crate::print_fail!("VFS initialization failed");
+        panic!("VFS initialization failed");
+    }
+    
     if !ramfs::init_ramfs() {
         crate::print_fail!("RAMFS initialization failed");
         panic!("RAMFS initialization failed");
     }
-}
+    
+    if !initramfs::init_initramfs() {
+        crate::print_fail!("Initramfs initialization failed");
+        panic!("Initramfs initialization failed");
+    }
+    
+    if !devfs::init_devfs() {
+        crate::print_fail!("Devfs initialization failed");
+        panic!("Devfs initialization failed");
+    }
+    
+    if !virtio::init_virtio() {
+        crate::print_fail!("Virtio initialization failed");
+        panic!("Virtio initialization failed");
+    }
+
+    if !snapshot::init_snapshot() {
+        crate::print_fail!("Snapshot restore failed");
+        panic!("Snapshot restore failed");
+    }
+
+    if !fat::init_fat() {
+        crate::print_fail!("FAT driver registration failed");
+        panic!("FAT driver registration failed");
+    }
+
+    if !ext2::init_ext2() {
+        crate::print_fail!("ext2 driver registration failed");
+        panic!("ext2 driver registration failed");
+    }
+    
+    if !procfs::init_procfs() {
+        crate::print_fail!("Procfs initialization failed");
+        panic!("Procfs initialization failed");
+    }
 
-fn setup_sample_files() {
-    let fs = crate::ramfs::ramfs_mut();
-    fs.create_file("hello.txt", b"Hello, World!\nThis is a text file.\n");
-    fs.create_file("readme.md", b"# S.T.A.R. Kernel\n\nA simple RISC-V kernel implementation.\n");
-    fs.create_file("config.bin", b"\x00\x01\x02\x03\xFF\xFE\xFD\xFC");
-    fs.create_file("kernel.elf", b"ELF\x7F\x45\x4C\x46\x02\x01\x01\x00");
+    if !vfs::init_boot_root() {
+        crate::print_fail!("Root filesystem setup failed");
+        panic!("Root filesystem setup failed");
+    }
+    
+    if !fw_cfg::init_fw_cfg() {
+        crate::print_fail!("fw_cfg initialization failed");
+        panic!("fw_cfg initialization failed");
+    }
+    
+    if !scheduler::init_stdio() {
+        crate::print_fail!("Standard stream setup failed");
+        panic!("Standard stream setup failed");
+    }
 }
 
-fn main() -> ! {
+/// Entered from `boot` with the firmware's arguments still in `a0`/`a1`:
+/// the hart id and the address of the device tree blob.
+extern "C" fn main(_hartid: usize, dtb: usize) -> ! {
     init_bss();
     init_trap_handler();
     
     println!("S.T.A.R. booting...");
-    init_kernel_systems();
+    init_kernel_systems(dtb);
     
     crate::print_ok!("Kernel initialization complete!");
-    setup_sample_files();
     
     crate::user::launch_shell();
-    loop {}
+
+    // Leaving the shell is a clean shutdown.
+    power::shutdown(power::PowerAction::PowerOff)
 }
 
 fn test_memory_management() {
@@ -126,9 +240,9 @@ fn test_memory_management() {
     vec.push(1337);
     println!("Vec allocated: {:?}", vec);
     
-    let (free_pages, used_heap, total_heap) = memory::get_memory_stats();
+    let stats = memory::get_memory_stats();
     println!("Memory stats - Free pages: {}, Heap used: {}/{} bytes", 
-             free_pages, used_heap, total_heap);
+             stats.free_pages, stats.heap_used, stats.heap_size);
 }
 
 fn test_timer_interrupts() {
@@ -149,11 +263,7 @@ use core::panic::PanicInfo;
 #[panic_handler]
 pub fn panic_handler(info: &PanicInfo) -> ! {
     println!("panic: {}", info);
-    loop {
-        unsafe {
-            core::arch::asm!("wfi");
-        }
-    }
+    power::fail(1)
 }
 
 #[alloc_error_handler]
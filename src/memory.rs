@@ -3,11 +3,12 @@ use core::alloc::{GlobalAlloc, Layout};
 
 pub const PAGE_SIZE: usize = 4096;
 pub const KERNEL_HEAP_SIZE: usize = 1024 * 1024;
-pub const MAX_PAGES: usize = 256;
+pub const MAX_PAGES: usize = 2048;
 pub const KERNEL_START: usize = 0x80200000;
 
 pub struct PageAllocator {
     bitmap: [u64; MAX_PAGES / 64],
+    start_addr: usize,
     total_pages: usize,
     free_pages: usize,
 }
@@ -16,18 +17,20 @@ impl PageAllocator {
     pub const fn new() -> Self {
         Self {
             bitmap: [0; MAX_PAGES / 64],
+            start_addr: 0,
             total_pages: 0,
             free_pages: 0,
         }
     }
 
-    pub fn init(&mut self, _start_addr: usize, size: usize) -> bool {
+    pub fn init(&mut self, start_addr: usize, size: usize) -> bool {
         if size < PAGE_SIZE {
             crate::print_fail!("Page allocator: Invalid size {}", size);
             return false;
         }
         
-        self.total_pages = size / PAGE_SIZE;
+        self.start_addr = start_addr;
+        self.total_pages = core::cmp::min(size / PAGE_SIZE, MAX_PAGES);
         self.free_pages = self.total_pages;
         
         for i in 0..self.bitmap.len() {
@@ -42,23 +45,45 @@ impl PageAllocator {
         for (word_idx, word) in self.bitmap.iter_mut().enumerate() {
             if *word != u64::MAX {
                 let bit_idx = word.trailing_ones() as usize;
-                if bit_idx < 64 {
+                let page_idx = word_idx * 64 + bit_idx;
+                if bit_idx < 64 && page_idx < self.total_pages {
                     *word |= 1u64 << bit_idx;
                     self.free_pages -= 1;
-                    let page_idx = word_idx * 64 + bit_idx;
-                    return Some(page_idx * PAGE_SIZE);
+                    return Some(self.start_addr + page_idx * PAGE_SIZE);
                 }
             }
         }
         None
     }
 
+    /// Allocates `count` physically contiguous pages, for device rings
+    /// that cannot be scattered.
+    pub fn alloc_contiguous(&mut self, count: usize) -> Option<usize> {
+        let is_used = |bitmap: &[u64], page: usize| bitmap[page / 64] & (1u64 << (page % 64)) != 0;
+        let mut run = 0;
+        for page in 0..self.total_pages {
+            run = if is_used(&self.bitmap, page) { 0 } else { run + 1 };
+            if run == count {
+                let first = page + 1 - count;
+                for p in first..=page {
+                    self.bitmap[p / 64] |= 1u64 << (p % 64);
+                }
+                self.free_pages -= count;
+                return Some(self.start_addr + first * PAGE_SIZE);
+            }
+        }
+        None
+    }
+
     pub fn dealloc_page(&mut self, addr: usize) {
-        let page_idx = addr / PAGE_SIZE;
+        if addr < self.start_addr {
+            return;
+        }
+        let page_idx = (addr - self.start_addr) / PAGE_SIZE;
         let word_idx = page_idx / 64;
         let bit_idx = page_idx % 64;
         
-        if word_idx < self.bitmap.len() {
+        if page_idx < self.total_pages && self.bitmap[word_idx] & (1u64 << bit_idx) != 0 {
             self.bitmap[word_idx] &= !(1u64 << bit_idx);
             self.free_pages += 1;
         }
@@ -67,6 +92,63 @@ impl PageAllocator {
     pub fn get_free_pages(&self) -> usize {
         self.free_pages
     }
+
+    pub fn get_total_pages(&self) -> usize {
+        self.total_pages
+    }
+}
+
+/// A physical page frame owned by the kernel. The page is returned to the
+/// page allocator when the last `Arc<Frame>` referencing it is dropped, so
+/// the same frame can back a ramfs file and any number of user mappings.
+#[derive(Debug)]
+pub struct Frame {
+    addr: usize,
+}
+
+impl Frame {
+    /// Allocates a zero-filled frame.
+    pub fn alloc_zeroed() -> Option<Self> {
+        let addr = alloc_page()?;
+        unsafe {
+            ptr::write_bytes(addr as *mut u8, 0, PAGE_SIZE);
+        }
+        Some(Self { addr })
+    }
+
+    /// Allocates a frame holding a copy of `other`.
+    pub fn alloc_copy(other: &Frame) -> Option<Self> {
+        let addr = alloc_page()?;
+        unsafe {
+            ptr::copy_nonoverlapping(other.addr as *const u8, addr as *mut u8, PAGE_SIZE);
+        }
+        Some(Self { addr })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn ppn(&self) -> usize {
+        self.addr / PAGE_SIZE
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.addr as *const u8, PAGE_SIZE) }
+    }
+
+    /// Frames may be aliased by user mappings, so writes go through a raw
+    /// pointer rather than requiring exclusive access to the `Frame`.
+    #[allow(clippy::mut_from_ref)]
+    pub fn as_mut_slice(&self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.addr as *mut u8, PAGE_SIZE) }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        dealloc_page(self.addr);
+    }
 }
 
 pub struct BumpAllocator {
@@ -116,6 +198,10 @@ impl BumpAllocator {
     pub fn get_used_bytes(&self) -> usize {
         self.next - self.heap_start
     }
+
+    pub fn get_total_bytes(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
 }
 
 pub static mut PAGE_ALLOCATOR: PageAllocator = PageAllocator::new();
@@ -139,17 +225,23 @@ pub fn init_memory() -> bool {
         extern "C" {
             static __heap_start: u8;
             static __heap_end: u8;
+            static __frames_start: u8;
+            static __frames_end: u8;
         }
         
         let heap_start = &__heap_start as *const u8 as usize;
         let heap_end = &__heap_end as *const u8 as usize;
         let heap_size = heap_end - heap_start;
+        let frames_start = &__frames_start as *const u8 as usize;
+        let frames_end = &__frames_end as *const u8 as usize;
         
         crate::print_info!("Heap region: {:#x} - {:#x} ({} bytes)", 
                           heap_start, heap_end, heap_size);
+        crate::print_info!("Frame region: {:#x} - {:#x} ({} bytes)",
+                          frames_start, frames_end, frames_end - frames_start);
         
         let page_allocator = &mut PAGE_ALLOCATOR;
-        if !page_allocator.init(heap_start, heap_size) {
+        if !page_allocator.init(frames_start, frames_end - frames_start) {
             return false;
         }
         
@@ -163,6 +255,16 @@ pub fn init_memory() -> bool {
     true
 }
 
+/// First address past the kernel image, heap, stack and frame region.
+pub fn kernel_end() -> usize {
+    unsafe {
+        extern "C" {
+            static __kernel_end: u8;
+        }
+        &__kernel_end as *const u8 as usize
+    }
+}
+
 pub fn alloc_page() -> Option<usize> {
     unsafe {
         let allocator = &mut PAGE_ALLOCATOR;
@@ -170,6 +272,19 @@ pub fn alloc_page() -> Option<usize> {
     }
 }
 
+/// Zero-filled contiguous pages, given back one at a time with
+/// `dealloc_page`.
+pub fn alloc_contiguous(count: usize) -> Option<usize> {
+    let addr = unsafe {
+        let allocator = &mut PAGE_ALLOCATOR;
+        allocator.alloc_contiguous(count)?
+    };
+    unsafe {
+        ptr::write_bytes(addr as *mut u8, 0, count * PAGE_SIZE);
+    }
+    Some(addr)
+}
+
 pub fn dealloc_page(addr: usize) {
     unsafe {
         let allocator = &mut PAGE_ALLOCATOR;
@@ -177,14 +292,23 @@ pub fn dealloc_page(addr: usize) {
     }
 }
 
-pub fn get_memory_stats() -> (usize, usize, usize) {
+/// A snapshot of the frame allocator and the kernel heap.
+pub struct MemoryStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub heap_size: usize,
+    pub heap_used: usize,
+}
+
+pub fn get_memory_stats() -> MemoryStats {
     unsafe {
         let page_allocator = &PAGE_ALLOCATOR;
         let heap_allocator = &HEAP_ALLOCATOR;
-        (
-            page_allocator.get_free_pages(),
-            heap_allocator.get_used_bytes(),
-            KERNEL_HEAP_SIZE
-        )
+        MemoryStats {
+            total_pages: page_allocator.get_total_pages(),
+            free_pages: page_allocator.get_free_pages(),
+            heap_size: heap_allocator.get_total_bytes(),
+            heap_used: heap_allocator.get_used_bytes(),
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,186 @@
+use alloc::vec::Vec;
+
+/// Where QEMU `virt` puts the PLIC when the device tree does not say.
+const DEFAULT_BASE: usize = 0x0c00_0000;
+const DEFAULT_NDEV: u32 = 95;
+/// Hart 0's supervisor context on QEMU `virt` (context 0 is M-mode).
+const DEFAULT_CONTEXT: usize = 1;
+
+const PRIORITY_OFFSET: usize = 0x0;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// `scause` code of a supervisor external interrupt, as it appears in the
+/// PLIC's `interrupts-extended` list.
+const SUPERVISOR_EXTERNAL: u32 = 9;
+
+/// The platform-level interrupt controller, seen from one hart context.
+pub struct Plic {
+    base: usize,
+    context: usize,
+    ndev: u32,
+}
+
+impl Plic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        if irq > 0 && irq <= self.ndev {
+            unsafe { core::ptr::write_volatile(self.reg(PRIORITY_OFFSET + 4 * irq as usize), priority) }
+        }
+    }
+
+    /// Routes `irq` to this context.
+    pub fn enable(&self, irq: u32) {
+        if irq == 0 || irq > self.ndev {
+            return;
+        }
+        let word = self.reg(ENABLE_OFFSET + ENABLE_STRIDE * self.context + 4 * (irq as usize / 32));
+        unsafe { core::ptr::write_volatile(word, core::ptr::read_volatile(word) | 1 << (irq % 32)) }
+    }
+
+    /// Stops routing `irq` to this context.
+    pub fn disable(&self, irq: u32) {
+        if irq == 0 || irq > self.ndev {
+            return;
+        }
+        let word = self.reg(ENABLE_OFFSET + ENABLE_STRIDE * self.context + 4 * (irq as usize / 32));
+        unsafe { core::ptr::write_volatile(word, core::ptr::read_volatile(word) & !(1 << (irq % 32))) }
+    }
+
+    /// Interrupts at or below `threshold` are masked for this context.
+    pub fn set_threshold(&self, threshold: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(CONTEXT_OFFSET + CONTEXT_STRIDE * self.context), threshold) }
+    }
+
+    /// Takes the highest-priority pending interrupt, if any.
+    pub fn claim(&self) -> Option<u32> {
+        let irq = unsafe { core::ptr::read_volatile(self.reg(CONTEXT_OFFSET + CONTEXT_STRIDE * self.context + 4)) };
+        if irq == 0 { None } else { Some(irq) }
+    }
+
+    /// Lets the source raise its next interrupt.
+    pub fn complete(&self, irq: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(CONTEXT_OFFSET + CONTEXT_STRIDE * self.context + 4), irq) }
+    }
+}
+
+static mut PLIC: Option<Plic> = None;
+
+pub fn plic() -> Option<&'static Plic> {
+    unsafe { (*core::ptr::addr_of!(PLIC)).as_ref() }
+}
+
+/// Called with the source number of the interrupt being serviced.
+pub type IrqHandler = fn(irq: u32);
+
+struct Registration {
+    irq: u32,
+    handler: IrqHandler,
+    count: u64,
+}
+
+static mut HANDLERS: Vec<Registration> = Vec::new();
+
+fn handlers() -> &'static mut Vec<Registration> {
+    unsafe { &mut *core::ptr::addr_of_mut!(HANDLERS) }
+}
+
+/// Sends `irq` to `handler` and enables it at priority 1 for the boot
+/// hart, replacing any earlier handler. Fails if the source does not exist.
+pub fn register_irq(irq: u32, handler: IrqHandler) -> bool {
+    let plic = match plic() {
+        Some(plic) if irq > 0 && irq <= plic.ndev => plic,
+        _ => return false,
+    };
+    match handlers().iter_mut().find(|r| r.irq == irq) {
+        Some(registration) => registration.handler = handler,
+        None => handlers().push(Registration { irq, handler, count: 0 }),
+    }
+    plic.set_priority(irq, 1);
+    plic.enable(irq);
+    true
+}
+
+/// Masks `irq` and forgets its handler.
+pub fn unregister_irq(irq: u32) {
+    if let Some(plic) = plic() {
+        plic.disable(irq);
+        plic.set_priority(irq, 0);
+    }
+    handlers().retain(|r| r.irq != irq);
+}
+
+/// Every registered source with the number of interrupts it has raised.
+pub fn irq_counts() -> impl Iterator<Item = (u32, u64)> {
+    handlers().iter().map(|r| (r.irq, r.count))
+}
+
+/// Claims every pending interrupt, runs its handler and completes it. A
+/// source nobody registered is masked so it cannot fire forever.
+///
+/// The trap handler calls this for external interrupts taken from user
+/// mode. The kernel itself runs with interrupts masked, so drivers
+/// waiting on a device call it in their wait loop instead.
+pub fn dispatch() {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    while let Some(irq) = plic.claim() {
+        match handlers().iter_mut().find(|r| r.irq == irq) {
+            Some(registration) => {
+                registration.count += 1;
+                crate::random::add_interrupt_jitter(irq as usize);
+                (registration.handler)(irq);
+            }
+            None => {
+                crate::println!("IRQ {} has no handler, masking it", irq);
+                plic.disable(irq);
+            }
+        }
+        plic.complete(irq);
+    }
+}
+
+/// Finds the PLIC and the boot hart's supervisor context in the device
+/// tree: the context index is the position of the first `(intc, 9)` pair
+/// in `interrupts-extended`.
+fn probe() -> Plic {
+    let node = crate::fdt::tree().and_then(|tree| {
+        tree.find_compatible("riscv,plic0").next().or_else(|| tree.find_compatible("sifive,plic-1.0.0").next())
+    });
+    let node = match node {
+        Some(node) => node,
+        None => return Plic { base: DEFAULT_BASE, context: DEFAULT_CONTEXT, ndev: DEFAULT_NDEV },
+    };
+    let context = node
+        .prop("interrupts-extended")
+        .and_then(|cells| {
+            cells
+                .as_chunks::<8>()
+                .0
+                .iter()
+                .position(|pair| u32::from_be_bytes([pair[4], pair[5], pair[6], pair[7]]) == SUPERVISOR_EXTERNAL)
+        })
+        .unwrap_or(DEFAULT_CONTEXT);
+    Plic {
+        base: node.reg().map_or(DEFAULT_BASE, |(base, _)| base),
+        context,
+        ndev: node.prop_u32("riscv,ndev").unwrap_or(DEFAULT_NDEV),
+    }
+}
+
+pub fn init_plic() -> bool {
+    let plic = probe();
+    plic.set_threshold(0);
+    crate::print_ok!("PLIC at {:#x}: {} sources, context {}", plic.base, plic.ndev, plic.context);
+    unsafe {
+        PLIC = Some(plic);
+    }
+    true
+}
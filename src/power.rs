@@ -0,0 +1,150 @@
+use crate::sbi::{ResetReason, ResetType};
+
+/// Values QEMU's `sifive_test` finisher acts on. A failure carries the
+/// exit status for QEMU in the upper half.
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerAction {
+    PowerOff,
+    Reboot,
+}
+
+/// A `syscon-poweroff` or `syscon-reboot` node: one register write that
+/// does the job.
+#[derive(Clone, Copy)]
+struct SysconWrite {
+    addr: usize,
+    value: u32,
+}
+
+impl SysconWrite {
+    fn run(&self) {
+        unsafe { core::ptr::write_volatile(self.addr as *mut u32, self.value) }
+    }
+}
+
+struct Devices {
+    poweroff: Option<SysconWrite>,
+    reboot: Option<SysconWrite>,
+    /// The `sifive_test` register itself, the only way to hand QEMU an
+    /// exit status.
+    finisher: Option<usize>,
+}
+
+static mut DEVICES: Devices = Devices { poweroff: None, reboot: None, finisher: None };
+
+fn devices() -> &'static Devices {
+    unsafe { &*core::ptr::addr_of!(DEVICES) }
+}
+
+fn probe_syscon(compat: &str) -> Option<SysconWrite> {
+    let tree = crate::fdt::tree()?;
+    let node = tree.find_compatible(compat).next()?;
+    let regmap = tree.find_phandle(node.prop_u32("regmap")?)?;
+    Some(SysconWrite {
+        addr: regmap.reg()?.0 + node.prop_u32("offset").unwrap_or(0) as usize,
+        value: node.prop_u32("value")?,
+    })
+}
+
+pub fn init_power() -> bool {
+    let finisher = crate::fdt::tree()
+        .and_then(|tree| tree.find_compatible("sifive,test0").next())
+        .and_then(|node| node.reg())
+        .map(|(base, _)| base);
+    let found = Devices {
+        poweroff: probe_syscon("syscon-poweroff"),
+        reboot: probe_syscon("syscon-reboot"),
+        finisher,
+    };
+    let srst = crate::sbi::info().is_some_and(|info| info.has(crate::sbi::Extension::Srst));
+    crate::print_ok!(
+        "Power control: SBI SRST {}, syscon poweroff {}, syscon reboot {}",
+        if srst { "yes" } else { "no" },
+        if found.poweroff.is_some() { "yes" } else { "no" },
+        if found.reboot.is_some() { "yes" } else { "no" }
+    );
+    unsafe {
+        DEVICES = found;
+    }
+    true
+}
+
+/// Resets through SBI, then the syscon registers, then the finisher. Only
+/// a failed shutdown carries `exit_code`, which only the finisher can pass
+/// on, so that one goes to it first.
+fn reset(kind: ResetType, reason: ResetReason, exit_code: u16) -> ! {
+    crate::uart::flush();
+    let devices = devices();
+    if reason == ResetReason::SystemFailure && kind == ResetType::Shutdown {
+        if let Some(addr) = devices.finisher {
+            unsafe { core::ptr::write_volatile(addr as *mut u32, FINISHER_FAIL | (exit_code as u32) << 16) }
+        }
+    }
+    let err = crate::sbi::system_reset(kind, reason);
+    let syscon = match kind {
+        ResetType::Shutdown => devices.poweroff,
+        _ => devices.reboot,
+    };
+    if let Some(write) = syscon {
+        write.run();
+    }
+    if let Some(addr) = devices.finisher {
+        let value = match kind {
+            ResetType::Shutdown => FINISHER_PASS,
+            _ => FINISHER_RESET,
+        };
+        unsafe { core::ptr::write_volatile(addr as *mut u32, value) }
+    }
+    crate::println!("Reset failed ({}), halting", err.to_string());
+    halt()
+}
+
+/// Stops here for good, without powering off.
+pub fn halt() -> ! {
+    crate::uart::flush();
+    loop {
+        unsafe {
+            core::arch::asm!("wfi");
+        }
+    }
+}
+
+/// A clean shutdown: everything is written back before the power goes.
+pub fn shutdown(action: PowerAction) -> ! {
+    if let Err(err) = crate::vfs::sync_all() {
+        crate::print_fail!("sync: {}", err.to_string());
+    }
+    crate::snapshot::save_at_shutdown();
+    match action {
+        PowerAction::PowerOff => {
+            crate::println!("Powering off.");
+            reset(ResetType::Shutdown, ResetReason::None, 0)
+        }
+        PowerAction::Reboot => {
+            crate::println!("Rebooting.");
+            reset(ResetType::ColdReboot, ResetReason::None, 0)
+        }
+    }
+}
+
+/// The end of a panic. With `panic=<seconds>` on the command line the
+/// machine reboots after that long, as Linux does; otherwise it powers off
+/// and QEMU exits with `exit_code`. Nothing is synced: the kernel's state
+/// can no longer be trusted.
+pub fn fail(exit_code: u16) -> ! {
+    let delay = crate::fdt::bootarg("panic").and_then(|secs| secs.parse::<u64>().ok()).unwrap_or(0);
+    if delay > 0 {
+        crate::println!("Rebooting in {} seconds..", delay);
+        crate::uart::flush();
+        let deadline = crate::time::read_time() + delay * crate::time::timebase_freq();
+        while crate::time::read_time() < deadline {
+            core::hint::spin_loop();
+        }
+        reset(ResetType::ColdReboot, ResetReason::SystemFailure, exit_code)
+    }
+    reset(ResetType::Shutdown, ResetReason::SystemFailure, exit_code)
+}
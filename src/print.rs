@@ -1,30 +1,28 @@
-use core::arch::asm;
+/// Console output: the UART once its driver is up, SBI before that.
+pub fn write_bytes(data: &[u8]) {
+    if crate::uart::is_ready() {
+        crate::uart::write(data);
+    } else {
+        crate::sbi::console_write(data);
+    }
+}
 
-/// SBI console output function
-pub fn sbi_putchar(ch: u8) {
-    unsafe {
-        asm!(
-            "ecall",
-            in("a6") 0,
-            in("a7") 1,
-            inout("a0") ch as usize => _,
-            out("a1") _
-        );
+/// The next console input byte, if one has arrived.
+pub fn try_getchar() -> Option<u8> {
+    if crate::uart::is_ready() {
+        crate::uart::getchar()
+    } else {
+        crate::sbi::console_getchar()
     }
 }
 
-/// SBI console input function
-pub fn sbi_getchar() -> i32 {
-    unsafe {
-        let result: i32;
-        asm!(
-            "ecall",
-            in("a6") 0,
-            in("a7") 2,
-            out("a0") result,
-            out("a1") _
-        );
-        result
+/// Waits for console input. With the UART driver up the hart sleeps
+/// between interrupts; the SBI console can only be polled.
+pub fn wait_for_input() {
+    if crate::uart::is_ready() {
+        crate::uart::wait();
+    } else {
+        core::hint::spin_loop();
     }
 }
 
@@ -32,9 +30,7 @@ pub struct Printer;
 
 impl core::fmt::Write for Printer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for byte in s.bytes() {
-            sbi_putchar(byte);
-        }
+        write_bytes(s.as_bytes());
         Ok(())
     }
 }
@@ -0,0 +1,406 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+use crate::memory::PAGE_SIZE;
+use crate::scheduler::{Task, TaskState, TASKS};
+use crate::vfs::{self, DirEntry, FileSystem, FsError, Inode, InodeKind, InodeRef, Metadata};
+use crate::vm::{Backing, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+type GlobalFile = (&'static str, fn() -> String);
+type TaskFile = (&'static str, fn(&Task) -> String);
+
+/// Files at the top of `/proc`, rendered from scratch on every read.
+const GLOBAL_FILES: &[GlobalFile] = &[
+    ("cmdline", cmdline),
+    ("interrupts", interrupts),
+    ("meminfo", meminfo),
+    ("mounts", mounts),
+    ("uptime", uptime),
+];
+
+/// Files inside every `/proc/<pid>`.
+const TASK_FILES: &[TaskFile] = &[("maps", maps), ("status", status)];
+
+/// Everything procfs can name. Nothing is stored: a node for a task that
+/// has since exited simply stops resolving.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    Root,
+    SelfLink,
+    Global(usize),
+    TaskDir(usize),
+    TaskFile(usize, usize),
+    FdDir(usize),
+    Fd(usize, usize),
+}
+
+impl Node {
+    fn ino(&self) -> u64 {
+        let task_base = |pid: usize| (pid as u64 + 1) << 32;
+        match *self {
+            Node::Root => 1,
+            Node::SelfLink => 2,
+            Node::Global(index) => 3 + index as u64,
+            Node::TaskDir(pid) => task_base(pid),
+            Node::TaskFile(pid, index) => task_base(pid) + 1 + index as u64,
+            Node::FdDir(pid) => task_base(pid) + 0x100,
+            Node::Fd(pid, fd) => task_base(pid) + 0x1000 + fd as u64,
+        }
+    }
+
+    fn kind(&self) -> InodeKind {
+        match self {
+            Node::Root | Node::TaskDir(_) | Node::FdDir(_) => InodeKind::Directory,
+            Node::Global(_) | Node::TaskFile(..) => InodeKind::File,
+            Node::SelfLink | Node::Fd(..) => InodeKind::Symlink,
+        }
+    }
+
+    fn parent(&self) -> Node {
+        match *self {
+            Node::Root | Node::SelfLink | Node::Global(_) | Node::TaskDir(_) => Node::Root,
+            Node::TaskFile(pid, _) | Node::FdDir(pid) => Node::TaskDir(pid),
+            Node::Fd(pid, _) => Node::FdDir(pid),
+        }
+    }
+
+    fn pid(&self) -> Option<usize> {
+        match *self {
+            Node::TaskDir(pid) | Node::TaskFile(pid, _) | Node::FdDir(pid) | Node::Fd(pid, _) => Some(pid),
+            _ => None,
+        }
+    }
+
+    /// Generates the contents of a regular file.
+    fn render(&self) -> Result<String, FsError> {
+        match *self {
+            Node::Global(index) => Ok((GLOBAL_FILES[index].1)()),
+            Node::TaskFile(pid, index) => Ok((TASK_FILES[index].1)(task(pid)?)),
+            Node::Root | Node::TaskDir(_) | Node::FdDir(_) => Err(FsError::IsADirectory),
+            Node::SelfLink | Node::Fd(..) => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+fn tasks() -> impl Iterator<Item = &'static Task> {
+    unsafe { (*core::ptr::addr_of!(TASKS)).iter().filter(|t| t.active) }
+}
+
+fn task(pid: usize) -> Result<&'static Task, FsError> {
+    tasks().find(|t| t.pid == pid).ok_or(FsError::NotFound)
+}
+
+fn kb(bytes: usize) -> usize {
+    bytes / 1024
+}
+
+fn cmdline() -> String {
+    format!("{}\n", crate::fdt::bootargs())
+}
+
+/// One line per trap cause: interrupts by their code, exceptions by their
+/// code prefixed with `E`.
+fn interrupts() -> String {
+    let mut out = String::from("          CPU0\n");
+    for (is_interrupt, prefix) in [(true, ""), (false, "E")] {
+        for code in 0..16 {
+            if let Some(name) = crate::interrupts::cause_name(is_interrupt, code) {
+                let label = format!("{}{}", prefix, code);
+                let count = crate::interrupts::trap_count(is_interrupt, code);
+                let _ = writeln!(out, "{:>4}: {:>10}   {}", label, count, name);
+            }
+        }
+    }
+    for (irq, count) in crate::plic::irq_counts() {
+        let _ = writeln!(out, "{:>4}: {:>10}   PLIC", format!("P{}", irq), count);
+    }
+    out
+}
+
+fn meminfo() -> String {
+    let stats = crate::memory::get_memory_stats();
+    let mut out = String::new();
+    for (name, bytes) in [
+        ("MemTotal", stats.total_pages * PAGE_SIZE),
+        ("MemFree", stats.free_pages * PAGE_SIZE),
+        ("MemUsed", (stats.total_pages - stats.free_pages) * PAGE_SIZE),
+        ("HeapTotal", stats.heap_size),
+        ("HeapUsed", stats.heap_used),
+        ("HeapFree", stats.heap_size - stats.heap_used),
+    ] {
+        let _ = writeln!(out, "{:<16}{:>8} kB", format!("{}:", name), kb(bytes));
+    }
+    out
+}
+
+fn mounts() -> String {
+    let mut out = String::new();
+    for mount in vfs::mounts() {
+        let options = if mount.read_only { "ro" } else { "rw" };
+        let _ = writeln!(out, "{} {} {} {} 0 0", mount.source, mount.path, mount.fs.name(), options);
+    }
+    out
+}
+
+/// Seconds since boot; idle time is not tracked and always reads zero.
+fn uptime() -> String {
+    let now = crate::time::monotonic();
+    format!("{}.{:02} 0.00\n", now.sec, now.nsec / 10_000_000)
+}
+
+fn maps(task: &Task) -> String {
+    let mut out = String::new();
+    let regions = match &task.mm {
+        Some(mm) => &mm.regions,
+        None => return out,
+    };
+    for region in regions {
+        let perm = |bit: usize, c: char| if region.prot & bit != 0 { c } else { '-' };
+        let (offset, dev, ino, path) = match &region.backing {
+            Backing::Anonymous => (0, 0, 0, String::new()),
+            Backing::File { inode, offset } => {
+                let meta = inode.metadata();
+                (*offset, meta.dev, meta.ino, vfs::path_of(inode).unwrap_or_default())
+            }
+        };
+        let _ = writeln!(
+            out,
+            "{:08x}-{:08x} {}{}{}{} {:08x} 00:{:02x} {:<10} {}",
+            region.start,
+            region.end,
+            perm(PROT_READ, 'r'),
+            perm(PROT_WRITE, 'w'),
+            perm(PROT_EXEC, 'x'),
+            if region.is_shared() { 's' } else { 'p' },
+            offset,
+            dev,
+            ino,
+            path
+        );
+    }
+    out
+}
+
+fn status(task: &Task) -> String {
+    let state = match task.state {
+        TaskState::Ready | TaskState::Running => "R (running)",
+        TaskState::Waiting => "S (sleeping)",
+        TaskState::Exited => "Z (zombie)",
+        TaskState::Stopped => "T (stopped)",
+    };
+    let (mut size, mut resident) = (0, 0);
+    if let Some(mm) = &task.mm {
+        for region in &mm.regions {
+            size += region.end - region.start;
+            resident += region.frames.iter().filter(|f| f.is_some()).count() * PAGE_SIZE;
+        }
+    }
+    let (uid, gid) = (task.uid, task.gid);
+    let mut out = String::new();
+    let _ = writeln!(out, "State:\t{}", state);
+    let _ = writeln!(out, "Pid:\t{}", task.pid);
+    let _ = writeln!(out, "PPid:\t{}", task.ppid);
+    let _ = writeln!(out, "NSpgid:\t{}", task.pgid);
+    let _ = writeln!(out, "NSsid:\t{}", task.sid);
+    let _ = writeln!(out, "Uid:\t{}\t{}\t{}\t{}", uid, uid, uid, uid);
+    let _ = writeln!(out, "Gid:\t{}\t{}\t{}\t{}", gid, gid, gid, gid);
+    let _ = writeln!(out, "FDSize:\t{}", task.fds.len());
+    let _ = writeln!(out, "VmSize:\t{:>8} kB", kb(size));
+    let _ = writeln!(out, "VmRSS:\t{:>8} kB", kb(resident));
+    out
+}
+
+pub struct ProcFs {
+    dev: usize,
+}
+
+impl ProcFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ProcFs { dev: vfs::alloc_dev() })
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(ProcNode { dev: self.dev, node: Node::Root })
+    }
+
+    fn dev(&self) -> usize {
+        self.dev
+    }
+}
+
+pub struct ProcNode {
+    dev: usize,
+    node: Node,
+}
+
+impl ProcNode {
+    fn child(&self, node: Node) -> InodeRef {
+        Arc::new(ProcNode { dev: self.dev, node })
+    }
+
+    fn entry(node: Node, name: String) -> DirEntry {
+        DirEntry { name, ino: node.ino(), kind: node.kind() }
+    }
+}
+
+impl Inode for ProcNode {
+    /// Files report the length they would render to right now, so callers
+    /// that size a buffer from `metadata` read the whole thing.
+    fn metadata(&self) -> Metadata {
+        let owner = self.node.pid().and_then(|pid| task(pid).ok()).map_or((0, 0), |t| (t.uid, t.gid));
+        let (mode, nlink) = match self.node.kind() {
+            InodeKind::Directory => (0o555, 2),
+            InodeKind::Symlink if self.node == Node::SelfLink => (0o777, 1),
+            InodeKind::Symlink => (0o700, 1),
+            _ => (0o444, 1),
+        };
+        let size = match self.node.kind() {
+            InodeKind::File => self.node.render().map_or(0, |s| s.len()),
+            _ => 0,
+        };
+        let now = crate::time::now();
+        Metadata {
+            dev: self.dev,
+            ino: self.node.ino(),
+            kind: self.node.kind(),
+            mode,
+            uid: owner.0,
+            gid: owner.1,
+            nlink,
+            size,
+            rdev: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let text = self.node.render()?;
+        let bytes = text.as_bytes();
+        if offset >= bytes.len() {
+            return Ok(0);
+        }
+        let len = core::cmp::min(buf.len(), bytes.len() - offset);
+        buf[..len].copy_from_slice(&bytes[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let node = match (self.node, name) {
+            (_, ".") => self.node,
+            (_, "..") => self.node.parent(),
+            (Node::Root, "self") => Node::SelfLink,
+            (Node::Root, _) => match GLOBAL_FILES.iter().position(|(n, _)| *n == name) {
+                Some(index) => Node::Global(index),
+                None => {
+                    let pid = name.parse().map_err(|_| FsError::NotFound)?;
+                    task(pid)?;
+                    Node::TaskDir(pid)
+                }
+            },
+            (Node::TaskDir(pid), "fd") => Node::FdDir(pid),
+            (Node::TaskDir(pid), _) => {
+                let index = TASK_FILES.iter().position(|(n, _)| *n == name).ok_or(FsError::NotFound)?;
+                Node::TaskFile(pid, index)
+            }
+            (Node::FdDir(pid), _) => {
+                let fd = name.parse().map_err(|_| FsError::NotFound)?;
+                task(pid)?.get_fd(fd).ok_or(FsError::NotFound)?;
+                Node::Fd(pid, fd)
+            }
+            _ => return Err(FsError::NotADirectory),
+        };
+        Ok(self.child(node))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut list = alloc::vec![
+            Self::entry(self.node, String::from(".")),
+            Self::entry(self.node.parent(), String::from("..")),
+        ];
+        match self.node {
+            Node::Root => {
+                list.push(Self::entry(Node::SelfLink, String::from("self")));
+                for (index, (name, _)) in GLOBAL_FILES.iter().enumerate() {
+                    list.push(Self::entry(Node::Global(index), String::from(*name)));
+                }
+                for task in tasks() {
+                    list.push(Self::entry(Node::TaskDir(task.pid), format!("{}", task.pid)));
+                }
+            }
+            Node::TaskDir(pid) => {
+                task(pid)?;
+                list.push(Self::entry(Node::FdDir(pid), String::from("fd")));
+                for (index, (name, _)) in TASK_FILES.iter().enumerate() {
+                    list.push(Self::entry(Node::TaskFile(pid, index), String::from(*name)));
+                }
+            }
+            Node::FdDir(pid) => {
+                for (fd, file) in task(pid)?.fds.iter().enumerate() {
+                    if file.is_some() {
+                        list.push(Self::entry(Node::Fd(pid, fd), format!("{}", fd)));
+                    }
+                }
+            }
+            _ => return Err(FsError::NotADirectory),
+        }
+        Ok(list)
+    }
+
+    /// `self` points at the calling task's directory; a descriptor points
+    /// at the path of whatever it has open.
+    fn read_link(&self) -> Result<String, FsError> {
+        match self.node {
+            Node::SelfLink => Ok(format!("{}", crate::scheduler::current_task().pid)),
+            Node::Fd(pid, fd) => {
+                let file = task(pid)?.get_fd(fd).ok_or(FsError::NotFound)?;
+                Ok(file
+                    .inode()
+                    .and_then(|inode| vfs::path_of(&inode))
+                    .unwrap_or_else(|| String::from("anon_inode:[file]")))
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn mount_procfs(_source: &str, _data: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(ProcFs::new())
+}
+
+pub fn init_procfs() -> bool {
+    vfs::register_filesystem("proc", mount_procfs);
+    let root = vfs::root();
+    let result = match vfs::mkdir(&root, "proc", 0o555) {
+        Ok(_) | Err(FsError::AlreadyExists) => vfs::mount("proc", "/proc", "proc", ""),
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => {
+            crate::print_ok!("Procfs mounted on /proc");
+            true
+        }
+        Err(err) => {
+            crate::print_fail!("Procfs setup failed: {}", err.to_string());
+            false
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cell::{Cell, RefCell};
+use crate::chardev::{self, CharDevice, DeviceRef};
+use crate::tty::{read_user, write_user, Queue, Tty, TtyPort};
+use crate::vfs::{FsError, POLLIN, POLLOUT};
+
+pub const PTS_MAJOR: u32 = 136;
+
+pub const TIOCGPTN: usize = 0x8004_5430;
+pub const TIOCSPTLCK: usize = 0x4004_5431;
+pub const TIOCGPTLCK: usize = 0x8004_5439;
+
+const MAX_PTYS: usize = 16;
+const BUFFER_SIZE: usize = 4096;
+
+/// The bytes in flight between the two ends of a pair. This is the port
+/// under the slave's line discipline.
+struct Channel {
+    /// Written by the master, waiting for the line discipline.
+    to_slave: RefCell<Queue<u8, BUFFER_SIZE>>,
+    /// Slave output, echo included, waiting for the master to read it.
+    to_master: RefCell<Queue<u8, BUFFER_SIZE>>,
+    master_open: Cell<bool>,
+}
+
+impl TtyPort for Channel {
+    /// Output the master has not made room for is dropped.
+    fn write(&self, data: &[u8]) {
+        let mut queue = self.to_master.borrow_mut();
+        for &byte in data {
+            if !queue.push(byte) {
+                break;
+            }
+        }
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        self.to_slave.borrow_mut().pop()
+    }
+
+    /// Whatever would write more input is another task, and a task cannot
+    /// sleep in the kernel, so an empty read fails rather than waits.
+    fn wait(&self) -> Result<(), FsError> {
+        if self.master_open.get() { Err(FsError::WouldBlock) } else { Err(FsError::Io) }
+    }
+}
+
+struct Pair {
+    index: usize,
+    channel: Arc<Channel>,
+    tty: Arc<Tty>,
+    /// Set when the master is opened; the slave cannot be opened until
+    /// `TIOCSPTLCK` clears it.
+    locked: Cell<bool>,
+}
+
+impl Pair {
+    fn slave_name(&self) -> String {
+        format!("pts/{}", self.index)
+    }
+}
+
+/// Pairs by index. They are kept for reuse once closed, since the heap
+/// never gets memory back.
+static mut PAIRS: [Option<Arc<Pair>>; MAX_PTYS] = [const { None }; MAX_PTYS];
+
+/// A pair nobody is using: a fresh one, or one whose master is closed and
+/// whose slave is no longer open anywhere.
+fn alloc_pair() -> Option<Arc<Pair>> {
+    let pairs = unsafe { &mut *core::ptr::addr_of_mut!(PAIRS) };
+    for (index, slot) in pairs.iter_mut().enumerate() {
+        match slot {
+            Some(pair) if Arc::strong_count(pair) == 1 => {
+                pair.tty.reset();
+                pair.channel.to_slave.borrow_mut().clear();
+                pair.channel.to_master.borrow_mut().clear();
+                return Some(pair.clone());
+            }
+            Some(_) => {}
+            None => {
+                let channel = Arc::new(Channel {
+                    to_slave: RefCell::new(Queue::new(0)),
+                    to_master: RefCell::new(Queue::new(0)),
+                    master_open: Cell::new(false),
+                });
+                let pair = Arc::new(Pair {
+                    index,
+                    tty: Tty::new(channel.clone()),
+                    channel,
+                    locked: Cell::new(true),
+                });
+                *slot = Some(pair.clone());
+                return Some(pair);
+            }
+        }
+    }
+    None
+}
+
+/// `/dev/ptmx`. Every open allocates a pair, creates its `/dev/pts/N`
+/// and becomes the master side.
+pub struct Ptmx;
+
+impl CharDevice for Ptmx {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NoDevice)
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NoDevice)
+    }
+
+    fn open(&self) -> Result<Option<DeviceRef>, FsError> {
+        let pair = alloc_pair().ok_or(FsError::NoSpace)?;
+        pair.locked.set(true);
+        pair.channel.master_open.set(true);
+        let slave: DeviceRef = Arc::new(Slave { pair: pair.clone() });
+        chardev::register_char_device(&pair.slave_name(), PTS_MAJOR, pair.index as u32, 0o620, slave)?;
+        Ok(Some(Arc::new(Master { pair })))
+    }
+}
+
+/// The master side of a pair. Dropped with the last file that has it
+/// open, which hangs up the slave.
+struct Master {
+    pair: Arc<Pair>,
+}
+
+impl CharDevice for Master {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut queue = self.pair.channel.to_master.borrow_mut();
+        if queue.is_empty() && !buf.is_empty() {
+            return Err(FsError::WouldBlock);
+        }
+        let count = queue.len().min(buf.len());
+        for byte in &mut buf[..count] {
+            *byte = queue.pop().unwrap();
+        }
+        Ok(count)
+    }
+
+    /// Input for the slave. It goes through the line discipline straight
+    /// away, so echo is ready to read back at once.
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        let mut written = 0;
+        while written < data.len() {
+            let pushed = {
+                let mut queue = self.pair.channel.to_slave.borrow_mut();
+                let count = queue.free().min(data.len() - written);
+                for &byte in &data[written..written + count] {
+                    queue.push(byte);
+                }
+                count
+            };
+            self.pair.tty.process_input();
+            if pushed == 0 {
+                break;
+            }
+            written += pushed;
+        }
+        if written == 0 && !data.is_empty() {
+            return Err(FsError::WouldBlock);
+        }
+        Ok(written)
+    }
+
+    /// The pty ioctls, and the slave's terminal ioctls on its behalf.
+    fn ioctl(&self, cmd: usize, arg: usize) -> Result<usize, FsError> {
+        match cmd {
+            TIOCGPTN => write_user(arg, self.pair.index as u32),
+            TIOCSPTLCK => {
+                self.pair.locked.set(read_user::<i32>(arg)? != 0);
+                Ok(0)
+            }
+            TIOCGPTLCK => write_user(arg, self.pair.locked.get() as i32),
+            _ => self.pair.tty.ioctl(cmd, arg),
+        }
+    }
+
+    fn poll(&self) -> u16 {
+        let channel = &self.pair.channel;
+        let mut events = 0;
+        if !channel.to_master.borrow().is_empty() {
+            events |= POLLIN;
+        }
+        if channel.to_slave.borrow().free() > 0 {
+            events |= POLLOUT;
+        }
+        events
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        self.pair.channel.master_open.set(false);
+        self.pair.tty.hangup();
+        let _ = chardev::unregister_char_device(&self.pair.slave_name(), PTS_MAJOR, self.pair.index as u32);
+    }
+}
+
+/// `/dev/pts/N`: the terminal a program on the pty runs on.
+struct Slave {
+    pair: Arc<Pair>,
+}
+
+impl CharDevice for Slave {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.pair.tty.read(buf)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        if !self.pair.channel.master_open.get() {
+            return Err(FsError::Io);
+        }
+        self.pair.tty.write(data)
+    }
+
+    fn ioctl(&self, cmd: usize, arg: usize) -> Result<usize, FsError> {
+        match cmd {
+            TIOCGPTN => write_user(arg, self.pair.index as u32),
+            _ => self.pair.tty.ioctl(cmd, arg),
+        }
+    }
+
+    fn poll(&self) -> u16 {
+        self.pair.tty.poll()
+    }
+
+    fn open(&self) -> Result<Option<DeviceRef>, FsError> {
+        if self.pair.locked.get() || !self.pair.channel.master_open.get() {
+            return Err(FsError::Io);
+        }
+        Ok(None)
+    }
+}
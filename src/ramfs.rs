@@ -1,125 +1,746 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::{Ref, RefCell};
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::time::{self, Timespec};
+use crate::vfs::{
+    DirEntry, FileSystem, FsError, Inode, InodeKind, InodeRef, Metadata, SetAttr,
+    RENAME_EXCHANGE, RENAME_NOREPLACE,
+};
+
+/// Largest size a ramfs file may grow to.
+pub const MAX_FILE_SIZE: usize = 1 << 40;
+
+/// File contents stored one page frame at a time, keyed by page index.
+/// Pages that were never written are holes and read back as zeroes, so a
+/// write far past the end only costs the pages it touches. Frames are
+/// reference counted so that `mmap` can hand the very same pages to user
+/// space.
+#[derive(Debug, Clone, Default)]
+pub struct FileData {
+    pages: BTreeMap<usize, Arc<Frame>>,
+}
+
+impl FileData {
+    pub fn new() -> Self {
+        FileData { pages: BTreeMap::new() }
+    }
+
+    pub fn page(&self, index: usize) -> Option<&Arc<Frame>> {
+        self.pages.get(&index)
+    }
+
+    /// The pages that hold data, in index order.
+    pub fn pages(&self) -> impl Iterator<Item = (usize, &Arc<Frame>)> {
+        self.pages.iter().map(|(&index, frame)| (index, frame))
+    }
+
+    /// Returns the frame backing page `index`, filling in a hole if needed.
+    pub fn page_or_alloc(&mut self, index: usize) -> Option<Arc<Frame>> {
+        if let Some(frame) = self.pages.get(&index) {
+            return Some(frame.clone());
+        }
+        let frame = Arc::new(Frame::alloc_zeroed()?);
+        self.pages.insert(index, frame.clone());
+        Some(frame)
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let page_off = pos % PAGE_SIZE;
+            let chunk = core::cmp::min(PAGE_SIZE - page_off, buf.len() - done);
+            match self.page(pos / PAGE_SIZE) {
+                Some(frame) => buf[done..done + chunk]
+                    .copy_from_slice(&frame.as_slice()[page_off..page_off + chunk]),
+                None => buf[done..done + chunk].fill(0),
+            }
+            done += chunk;
+        }
+        done
+    }
+
+    /// Copies `data` in at `offset` and returns how much was stored, which
+    /// is short only when frames run out.
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> usize {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done;
+            let page_off = pos % PAGE_SIZE;
+            let chunk = core::cmp::min(PAGE_SIZE - page_off, data.len() - done);
+            let frame = match self.page_or_alloc(pos / PAGE_SIZE) {
+                Some(frame) => frame,
+                None => break,
+            };
+            frame.as_mut_slice()[page_off..page_off + chunk]
+                .copy_from_slice(&data[done..done + chunk]);
+            done += chunk;
+        }
+        done
+    }
+
+    /// Drops every page past `len` and zeroes the tail of the last page, so a
+    /// later extension reads back zeroes rather than stale bytes.
+    pub fn truncate(&mut self, len: usize) {
+        let keep = len.div_ceil(PAGE_SIZE);
+        self.pages.split_off(&keep);
+        if !len.is_multiple_of(PAGE_SIZE) {
+            if let Some(frame) = self.page(len / PAGE_SIZE) {
+                frame.as_mut_slice()[len % PAGE_SIZE..].fill(0);
+            }
+        }
+    }
+}
+
+pub type InodeId = usize;
+
+pub const ROOT_INODE: InodeId = 0;
 
 #[derive(Debug, Clone)]
-pub struct RamFile {
-    pub name: String,
-    pub data: Vec<u8>,
+pub enum NodeKind {
+    File(FileData),
+    Directory {
+        entries: BTreeMap<String, InodeId>,
+        parent: InodeId,
+    },
+    Symlink(String),
+    /// A device node; opening it goes to the driver registered for `rdev`.
+    Device(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct RamInode {
+    pub ino: InodeId,
+    pub kind: NodeKind,
     pub size: usize,
-    pub created_at: u64,
-    pub file_type: FileType,
+    /// Names referring to a file or symlink. Directories have exactly one
+    /// until removed, and report their link count from their
+    /// subdirectories instead.
+    pub links: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum FileType {
-    Text,
-    Binary,
-    Executable,
-    Directory,
-    Unknown,
+impl RamInode {
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, NodeKind::Directory { .. })
+    }
+
+    pub fn kind(&self) -> InodeKind {
+        match self.kind {
+            NodeKind::File(_) => InodeKind::File,
+            NodeKind::Directory { .. } => InodeKind::Directory,
+            NodeKind::Symlink(_) => InodeKind::Symlink,
+            NodeKind::Device(_) => InodeKind::CharDevice,
+        }
+    }
+
+    pub fn entries(&self) -> Option<&BTreeMap<String, InodeId>> {
+        match &self.kind {
+            NodeKind::Directory { entries, .. } => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let data = match &self.kind {
+            NodeKind::File(data) => data,
+            _ => return 0,
+        };
+        if offset >= self.size {
+            return 0;
+        }
+        let len = core::cmp::min(buf.len(), self.size - offset);
+        data.read_at(offset, &mut buf[..len])
+    }
+
+    /// Writes at any offset; writing past the end leaves a hole that reads
+    /// back as zeroes.
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        let file_data = match &mut self.kind {
+            NodeKind::File(file_data) => file_data,
+            NodeKind::Directory { .. } => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::InvalidArgument),
+        };
+        match offset.checked_add(data.len()) {
+            Some(end) if end <= MAX_FILE_SIZE => {}
+            _ => return Err(FsError::FileTooLarge),
+        }
+        let written = file_data.write_at(offset, data);
+        if written == 0 && !data.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        self.size = core::cmp::max(self.size, offset + written);
+        self.touch_modified();
+        Ok(written)
+    }
+
+    /// Shrinks or sparsely extends the file to `len` bytes.
+    pub fn truncate(&mut self, len: usize) -> Result<(), FsError> {
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        match &mut self.kind {
+            NodeKind::File(data) => data.truncate(len),
+            NodeKind::Directory { .. } => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::InvalidArgument),
+        }
+        self.size = len;
+        self.touch_modified();
+        Ok(())
+    }
+
+    /// Content changed: bump `mtime` and `ctime` together.
+    fn touch_modified(&mut self) {
+        let now = time::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
 }
 
-impl FileType {
-    pub fn to_string(&self) -> &'static str {
-        match self {
-            FileType::Text => "TXT",
-            FileType::Binary => "BIN",
-            FileType::Executable => "EXE",
-            FileType::Directory => "DIR",
-            FileType::Unknown => "???",
+/// The inode table behind a ramfs instance. Directories map names to inode
+/// numbers and remember their parent for `..`. An inode outlives its last
+/// name while handles on it remain, and is freed with the last of them.
+/// Inode numbers are never reused, so a stale handle can only ever see
+/// `NotFound`.
+struct Inodes {
+    inodes: Vec<Option<RamInode>>,
+    /// Live `RamNode`s per inode; inodes without any are left out.
+    handles: BTreeMap<InodeId, usize>,
+}
+
+impl Inodes {
+    fn alloc(&mut self, kind: NodeKind, mode: u32, (uid, gid): (u32, u32)) -> InodeId {
+        let ino = self.inodes.len();
+        let now = time::now();
+        let size = match &kind {
+            NodeKind::Symlink(target) => target.len(),
+            _ => 0,
+        };
+        self.inodes.push(Some(RamInode {
+            ino,
+            kind,
+            size,
+            links: 1,
+            mode,
+            uid,
+            gid,
+            atime: now,
+            mtime: now,
+            ctime: now,
+        }));
+        ino
+    }
+
+    /// Link count as `stat` reports it: the number of names for a file, and
+    /// for a directory its own entry, its `.` and the `..` of every
+    /// subdirectory.
+    fn nlink(&self, ino: InodeId) -> u32 {
+        match self.get(ino) {
+            Ok(inode) => match inode.entries() {
+                Some(entries) => 2 + entries.values().filter(|&&e| self.is_dir(e)).count() as u32,
+                None => inode.links,
+            },
+            Err(_) => 0,
         }
     }
+
+    /// Removes one name from `ino`, freeing it once no names or handles
+    /// are left. A directory only ever has the one name.
+    fn drop_link(&mut self, ino: InodeId) {
+        let inode = match self.get_mut(ino) {
+            Ok(inode) => inode,
+            Err(_) => return,
+        };
+        inode.links = if inode.is_dir() { 0 } else { inode.links.saturating_sub(1) };
+        inode.ctime = time::now();
+        self.free_if_unused(ino);
+    }
+
+    fn open(&mut self, ino: InodeId) {
+        *self.handles.entry(ino).or_insert(0) += 1;
+    }
+
+    fn close(&mut self, ino: InodeId) {
+        if let Some(count) = self.handles.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                self.handles.remove(&ino);
+            }
+        }
+        self.free_if_unused(ino);
+    }
+
+    fn free_if_unused(&mut self, ino: InodeId) {
+        let unlinked = self.get(ino).is_ok_and(|i| i.links == 0);
+        if unlinked && !self.handles.contains_key(&ino) {
+            self.inodes[ino] = None;
+        }
+    }
+
+    fn touch_dir(&mut self, dir: InodeId) {
+        if let Ok(inode) = self.get_mut(dir) {
+            inode.touch_modified();
+        }
+    }
+
+    fn get(&self, ino: InodeId) -> Result<&RamInode, FsError> {
+        self.inodes.get(ino).and_then(|i| i.as_ref()).ok_or(FsError::NotFound)
+    }
+
+    fn get_mut(&mut self, ino: InodeId) -> Result<&mut RamInode, FsError> {
+        self.inodes.get_mut(ino).and_then(|i| i.as_mut()).ok_or(FsError::NotFound)
+    }
+
+    /// A directory's entries for changing. A removed directory still open
+    /// somewhere takes no new ones.
+    fn entries_mut(&mut self, dir: InodeId) -> Result<&mut BTreeMap<String, InodeId>, FsError> {
+        match self.get_mut(dir)? {
+            RamInode { links: 0, .. } => Err(FsError::NotFound),
+            RamInode { kind: NodeKind::Directory { entries, .. }, .. } => Ok(entries),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn parent_of(&self, dir: InodeId) -> Result<InodeId, FsError> {
+        match &self.get(dir)?.kind {
+            NodeKind::Directory { parent, .. } => Ok(*parent),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn set_parent(&mut self, dir: InodeId, new_parent: InodeId) {
+        if let Ok(RamInode { kind: NodeKind::Directory { parent, .. }, .. }) = self.get_mut(dir) {
+            *parent = new_parent;
+        }
+    }
+
+    fn is_dir(&self, ino: InodeId) -> bool {
+        self.get(ino).is_ok_and(|i| i.is_dir())
+    }
+
+    fn is_empty_dir(&self, ino: InodeId) -> bool {
+        self.get(ino).ok().and_then(|i| i.entries()).is_some_and(|e| e.is_empty())
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        let entries = self.get(dir)?.entries().ok_or(FsError::NotADirectory)?;
+        match name {
+            "" | "." => Ok(dir),
+            ".." => self.parent_of(dir),
+            _ => entries.get(name).copied().ok_or(FsError::NotFound),
+        }
+    }
+
+    fn create(&mut self, dir: InodeId, name: &str, node: NodeKind, mode: u32) -> Result<InodeId, FsError> {
+        if self.entries_mut(dir)?.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let ino = self.alloc(node, mode, crate::vfs::current_cred());
+        self.entries_mut(dir)?.insert(name.into(), ino);
+        self.touch_dir(dir);
+        Ok(ino)
+    }
+
+    /// Adds `name` in `dir` as another name for the non-directory `target`.
+    fn link(&mut self, dir: InodeId, name: &str, target: InodeId) -> Result<(), FsError> {
+        if self.is_dir(target) {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.entries_mut(dir)?.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = self.get_mut(target)?;
+        inode.links += 1;
+        inode.ctime = time::now();
+        self.entries_mut(dir)?.insert(name.into(), target);
+        self.touch_dir(dir);
+        Ok(())
+    }
+
+    fn unlink(&mut self, dir: InodeId, name: &str, remove_dir: bool) -> Result<(), FsError> {
+        let ino = self.lookup(dir, name)?;
+        match (self.is_dir(ino), remove_dir) {
+            (true, false) => return Err(FsError::IsADirectory),
+            (false, true) => return Err(FsError::NotADirectory),
+            (true, true) if !self.is_empty_dir(ino) => return Err(FsError::NotEmpty),
+            _ => {}
+        }
+        self.entries_mut(dir)?.remove(name);
+        self.drop_link(ino);
+        self.touch_dir(dir);
+        Ok(())
+    }
+
+    fn is_ancestor(&self, ancestor: InodeId, mut dir: InodeId) -> bool {
+        loop {
+            if dir == ancestor {
+                return true;
+            }
+            match self.parent_of(dir) {
+                Ok(parent) if parent != dir => dir = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Moves an entry in one step, so the destination name never goes
+    /// missing. An existing destination is replaced (and loses a link)
+    /// unless `RENAME_NOREPLACE` is given; `RENAME_EXCHANGE` swaps the two.
+    fn rename(
+        &mut self,
+        old_dir: InodeId,
+        old_name: &str,
+        new_dir: InodeId,
+        new_name: &str,
+        flags: usize,
+    ) -> Result<(), FsError> {
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0
+            || flags == RENAME_NOREPLACE | RENAME_EXCHANGE
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let src = self.lookup(old_dir, old_name)?;
+        let dst = self.lookup(new_dir, new_name).ok();
+        let src_is_dir = self.is_dir(src);
+        if src_is_dir && self.is_ancestor(src, new_dir) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if flags & RENAME_EXCHANGE != 0 {
+            let dst = dst.ok_or(FsError::NotFound)?;
+            if self.is_dir(dst) && self.is_ancestor(dst, old_dir) {
+                return Err(FsError::InvalidArgument);
+            }
+            self.entries_mut(old_dir)?.insert(old_name.into(), dst);
+            self.entries_mut(new_dir)?.insert(new_name.into(), src);
+            self.set_parent(dst, old_dir);
+            self.set_parent(src, new_dir);
+            self.touch_dir(old_dir);
+            self.touch_dir(new_dir);
+            return Ok(());
+        }
+
+        if let Some(dst) = dst {
+            if flags & RENAME_NOREPLACE != 0 {
+                return Err(FsError::AlreadyExists);
+            }
+            if dst == src {
+                return Ok(());
+            }
+            match (src_is_dir, self.is_dir(dst)) {
+                (true, false) => return Err(FsError::NotADirectory),
+                (false, true) => return Err(FsError::IsADirectory),
+                (true, true) if !self.is_empty_dir(dst) => return Err(FsError::NotEmpty),
+                _ => {}
+            }
+            self.drop_link(dst);
+        }
+        self.entries_mut(old_dir)?.remove(old_name);
+        self.entries_mut(new_dir)?.insert(new_name.into(), src);
+        self.set_parent(src, new_dir);
+        if let Ok(inode) = self.get_mut(src) {
+            inode.ctime = time::now();
+        }
+        self.touch_dir(old_dir);
+        self.touch_dir(new_dir);
+        Ok(())
+    }
 }
 
+/// An in-memory filesystem. Every `mount -t ramfs` creates a fresh one.
 pub struct RamFs {
-    pub files: Vec<RamFile>,
+    name: &'static str,
+    dev: usize,
+    inodes: RefCell<Inodes>,
+    this: Weak<RamFs>,
 }
 
 impl RamFs {
-    pub fn new() -> Self {
-        RamFs { files: Vec::new() }
+    pub fn new() -> Arc<Self> {
+        Self::with_name("ramfs")
+    }
+
+    /// A ramfs reporting a different filesystem name, for instances that
+    /// play another role such as devfs.
+    pub fn with_name(name: &'static str) -> Arc<Self> {
+        let mut inodes = Inodes { inodes: Vec::new(), handles: BTreeMap::new() };
+        inodes.alloc(
+            NodeKind::Directory { entries: BTreeMap::new(), parent: ROOT_INODE },
+            0o755,
+            (0, 0),
+        );
+        Arc::new_cyclic(|this| RamFs {
+            name,
+            dev: crate::vfs::alloc_dev(),
+            inodes: RefCell::new(inodes),
+            this: this.clone(),
+        })
+    }
+
+    /// Rebuilds a ramfs from an inode table, indexed by inode number with
+    /// the root at `ROOT_INODE`, as `snapshot` reads one back.
+    pub fn from_table(inodes: Vec<Option<RamInode>>) -> Arc<Self> {
+        Arc::new_cyclic(|this| RamFs {
+            name: "ramfs",
+            dev: crate::vfs::alloc_dev(),
+            inodes: RefCell::new(Inodes { inodes, handles: BTreeMap::new() }),
+            this: this.clone(),
+        })
+    }
+
+    /// The inode table, with freed inodes as `None`. Inodes with no names
+    /// left (`links` 0) are only there for their open handles.
+    pub fn inode_table(&self) -> Ref<'_, [Option<RamInode>]> {
+        Ref::map(self.inodes.borrow(), |inodes| inodes.inodes.as_slice())
     }
-    
-    pub fn create_file(&mut self, name: &str, data: &[u8]) {
-        let file_type = Self::detect_file_type(name, data);
-        self.files.push(RamFile {
-            name: name.into(),
-            data: data.to_vec(),
-            size: data.len(),
-            created_at: Self::get_timestamp(),
-            file_type,
-        });
+
+    fn node(&self, ino: InodeId) -> InodeRef {
+        self.inodes.borrow_mut().open(ino);
+        Arc::new(RamNode { fs: self.this.upgrade().unwrap(), ino })
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn root(&self) -> InodeRef {
+        self.node(ROOT_INODE)
+    }
+
+    fn dev(&self) -> usize {
+        self.dev
     }
-    
-    pub fn read_file(&self, name: &str) -> Option<&[u8]> {
-        self.files.iter().find(|f| f.name == name).map(|f| f.data.as_slice())
+}
+
+/// A handle on one ramfs inode.
+pub struct RamNode {
+    fs: Arc<RamFs>,
+    ino: InodeId,
+}
+
+impl Inode for RamNode {
+    fn metadata(&self) -> Metadata {
+        let inodes = self.fs.inodes.borrow();
+        match inodes.get(self.ino) {
+            Ok(inode) => Metadata {
+                dev: self.fs.dev,
+                ino: self.ino as u64,
+                kind: inode.kind(),
+                mode: inode.mode,
+                uid: inode.uid,
+                gid: inode.gid,
+                nlink: inodes.nlink(self.ino),
+                size: inode.size,
+                rdev: match inode.kind {
+                    NodeKind::Device(rdev) => rdev,
+                    _ => 0,
+                },
+                atime: inode.atime,
+                mtime: inode.mtime,
+                ctime: inode.ctime,
+            },
+            Err(_) => Metadata {
+                dev: self.fs.dev,
+                ino: self.ino as u64,
+                kind: InodeKind::File,
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                nlink: 0,
+                size: 0,
+                rdev: 0,
+                atime: Timespec::default(),
+                mtime: Timespec::default(),
+                ctime: Timespec::default(),
+            },
+        }
     }
-    
-    pub fn write_file(&mut self, name: &str, data: &[u8]) -> bool {
-        if let Some(file) = self.files.iter_mut().find(|f| f.name == name) {
-            file.data = data.to_vec();
-            file.size = data.len();
-            file.file_type = Self::detect_file_type(name, data);
-            true
-        } else {
-            false
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut inodes = self.fs.inodes.borrow_mut();
+        let inode = inodes.get_mut(self.ino)?;
+        match inode.kind() {
+            InodeKind::Directory => return Err(FsError::IsADirectory),
+            InodeKind::File => {}
+            _ => return Err(FsError::InvalidArgument),
         }
+        inode.atime = time::now();
+        Ok(inode.read_at(offset, buf))
     }
-    
-    pub fn list_files(&self) -> Vec<&str> {
-        self.files.iter().map(|f| f.name.as_str()).collect()
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        let mut inodes = self.fs.inodes.borrow_mut();
+        let inode = inodes.get_mut(self.ino)?;
+        inode.write_at(offset, data)
     }
-    
-    pub fn list_files_detailed(&self) -> Vec<&RamFile> {
-        self.files.iter().collect()
+
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        let mut inodes = self.fs.inodes.borrow_mut();
+        inodes.get_mut(self.ino)?.truncate(len)
     }
-    
-    pub fn get_file_info(&self, name: &str) -> Option<&RamFile> {
-        self.files.iter().find(|f| f.name == name)
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let ino = self.fs.inodes.borrow().lookup(self.ino, name)?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn create(&self, name: &str, kind: InodeKind, mode: u32) -> Result<InodeRef, FsError> {
+        let node = match kind {
+            InodeKind::Directory => NodeKind::Directory { entries: BTreeMap::new(), parent: self.ino },
+            InodeKind::File => NodeKind::File(FileData::new()),
+            _ => return Err(FsError::InvalidArgument),
+        };
+        let ino = self.fs.inodes.borrow_mut().create(self.ino, name, node, mode)?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn link(&self, name: &str, target: &InodeRef) -> Result<(), FsError> {
+        let target = match target.as_any().downcast_ref::<RamNode>() {
+            Some(node) if Arc::ptr_eq(&node.fs, &self.fs) => node.ino,
+            _ => return Err(FsError::CrossDevice),
+        };
+        self.fs.inodes.borrow_mut().link(self.ino, name, target)
     }
-    
-    fn detect_file_type(name: &str, data: &[u8]) -> FileType {
-        if name.ends_with(".txt") || name.ends_with(".md") || name.ends_with(".log") {
-            return FileType::Text;
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, FsError> {
+        let node = NodeKind::Symlink(target.into());
+        let ino = self.fs.inodes.borrow_mut().create(self.ino, name, node, 0o777)?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn mknod(&self, name: &str, mode: u32, rdev: u64) -> Result<InodeRef, FsError> {
+        let ino = self.fs.inodes.borrow_mut().create(self.ino, name, NodeKind::Device(rdev), mode)?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let mut inodes = self.fs.inodes.borrow_mut();
+        let inode = inodes.get_mut(self.ino)?;
+        match &inode.kind {
+            NodeKind::Symlink(target) => {
+                let target = target.clone();
+                inode.atime = time::now();
+                Ok(target)
+            }
+            _ => Err(FsError::InvalidArgument),
         }
-        if name.ends_with(".exe") || name.ends_with(".bin") || name.ends_with(".elf") {
-            return FileType::Executable;
+    }
+
+    fn set_attr(&self, attr: &SetAttr) -> Result<(), FsError> {
+        let mut inodes = self.fs.inodes.borrow_mut();
+        let inode = inodes.get_mut(self.ino)?;
+        if let Some(mode) = attr.mode {
+            inode.mode = mode & 0o7777;
+        }
+        if let Some(uid) = attr.uid {
+            inode.uid = uid;
         }
-        if name.ends_with("/") || name.ends_with("\\") {
-            return FileType::Directory;
+        if let Some(gid) = attr.gid {
+            inode.gid = gid;
         }
-        
-        if data.iter().all(|&b| b >= 32 && b <= 126 || b == b'\n' || b == b'\r' || b == b'\t') {
-            FileType::Text
-        } else {
-            FileType::Binary
+        if let Some(atime) = attr.atime {
+            inode.atime = atime;
         }
+        if let Some(mtime) = attr.mtime {
+            inode.mtime = mtime;
+        }
+        inode.ctime = time::now();
+        Ok(())
     }
-    
-    fn get_timestamp() -> u64 {
-        static mut COUNTER: u64 = 0;
-        unsafe {
-            COUNTER += 1;
-            COUNTER
+
+    fn unlink(&self, name: &str, remove_dir: bool) -> Result<(), FsError> {
+        self.fs.inodes.borrow_mut().unlink(self.ino, name, remove_dir)
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str, flags: usize) -> Result<(), FsError> {
+        let new_dir = match new_dir.as_any().downcast_ref::<RamNode>() {
+            Some(node) if Arc::ptr_eq(&node.fs, &self.fs) => node.ino,
+            _ => return Err(FsError::CrossDevice),
+        };
+        self.fs.inodes.borrow_mut().rename(self.ino, old_name, new_dir, new_name, flags)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let inodes = self.fs.inodes.borrow();
+        let entries = inodes.get(self.ino)?.entries().ok_or(FsError::NotADirectory)?;
+        let kind_of = |ino: InodeId| inodes.get(ino).map_or(InodeKind::File, |i| i.kind());
+        let parent = inodes.parent_of(self.ino)?;
+        let mut list = Vec::with_capacity(entries.len() + 2);
+        list.push(DirEntry { name: String::from("."), ino: self.ino as u64, kind: InodeKind::Directory });
+        list.push(DirEntry { name: String::from(".."), ino: parent as u64, kind: InodeKind::Directory });
+        for (name, &ino) in entries {
+            list.push(DirEntry { name: name.clone(), ino: ino as u64, kind: kind_of(ino) });
+        }
+        Ok(list)
+    }
+
+    /// Hands out the file's own frames, allocating holes inside the file.
+    /// Pages past the end of the file are returned as `None`.
+    fn map_pages(&self, first: usize, count: usize) -> Result<Vec<Option<Arc<Frame>>>, FsError> {
+        let mut inodes = self.fs.inodes.borrow_mut();
+        let inode = inodes.get_mut(self.ino)?;
+        let file_pages = inode.size.div_ceil(PAGE_SIZE);
+        let data = match &mut inode.kind {
+            NodeKind::File(data) => data,
+            NodeKind::Directory { .. } => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::InvalidArgument),
+        };
+        let mut frames = Vec::with_capacity(count);
+        for index in first..first + count {
+            if index < file_pages {
+                frames.push(Some(data.page_or_alloc(index).ok_or(FsError::NoSpace)?));
+            } else {
+                frames.push(None);
+            }
         }
+        Ok(frames)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for RamNode {
+    fn drop(&mut self) {
+        self.fs.inodes.borrow_mut().close(self.ino);
     }
 }
 
-pub static mut RAMFS: Option<RamFs> = None;
+fn mount_ramfs(_source: &str, _data: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(RamFs::new())
+}
 
-pub fn init_ramfs() -> bool {
+/// The ramfs the kernel boots with as `/`.
+static mut ROOTFS: Option<Arc<RamFs>> = None;
+
+pub fn rootfs() -> Arc<RamFs> {
+    unsafe { (*core::ptr::addr_of!(ROOTFS)).clone().unwrap() }
+}
+
+/// Records that `fs` took over as the boot ramfs, as after a restore.
+pub fn set_rootfs(fs: Arc<RamFs>) {
     unsafe {
-        RAMFS = Some(RamFs::new());
-        if RAMFS.is_some() {
-            crate::print_ok!("RAMFS initialized");
-            true
-        } else {
-            crate::print_fail!("RAMFS initialization failed");
-            false
-        }
+        ROOTFS = Some(fs);
     }
 }
 
-pub fn ramfs_mut() -> &'static mut RamFs {
-    unsafe { RAMFS.as_mut().unwrap() }
-}
\ No newline at end of file
+pub fn init_ramfs() -> bool {
+    crate::vfs::register_filesystem("ramfs", mount_ramfs);
+    let fs = RamFs::new();
+    set_rootfs(fs.clone());
+    crate::vfs::mount_root("rootfs", fs);
+    crate::print_ok!("RAMFS initialized");
+    true
+}
@@ -0,0 +1,288 @@
+use crate::chardev::CharDevice;
+use crate::time::read_time;
+use crate::vfs::{FsError, POLLIN, POLLOUT};
+
+pub const GRND_NONBLOCK: usize = 0x1;
+pub const GRND_RANDOM: usize = 0x2;
+pub const GRND_INSECURE: usize = 0x4;
+
+pub const RNDGETENTCNT: usize = 0x8004_5200;
+
+/// Credited entropy, in bits, before the pool counts as seeded. Credit is
+/// capped here too: the key cannot hold more.
+const SEED_BITS: usize = 256;
+
+/// How often output pulls fresh bytes from a hardware source.
+const RESEED_INTERVAL_SECS: u64 = 60;
+
+/// Timer samples mixed in at boot. Like interrupt timings they go in
+/// uncredited: on a virtual machine how much they vary says little about
+/// how hard they are to guess.
+const BOOT_JITTER_SAMPLES: usize = 4096;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// Nonces keeping the pool's two uses of its key apart.
+const NONCE_OUTPUT: u64 = 0;
+const NONCE_MIX: u64 = 1;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// One ChaCha20 block, with the original 64-bit counter and nonce.
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, original) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(original);
+    }
+    state
+}
+
+/// The pool is a single ChaCha20 key. Input is XORed into it and the key
+/// replaced by a block it generates; output comes from blocks under the
+/// key, which is then replaced the same way, so nothing handed out can be
+/// recovered from the state left behind.
+struct Pool {
+    key: [u32; 8],
+    entropy_bits: usize,
+    /// `time` CSR reading at the last hardware reseed.
+    last_reseed: u64,
+}
+
+impl Pool {
+    /// Replaces the key with the first half of block 0 under it.
+    fn rekey(&mut self, nonce: u64) {
+        let block = chacha20_block(&self.key, 0, nonce);
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, bytes) in chunk.chunks(4).enumerate() {
+                let mut word = [0u8; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                self.key[i] ^= u32::from_le_bytes(word);
+            }
+            self.rekey(NONCE_MIX);
+        }
+    }
+
+    fn credit(&mut self, bits: usize) {
+        self.entropy_bits = (self.entropy_bits + bits).min(SEED_BITS);
+    }
+
+    fn generate(&mut self, buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(64).enumerate() {
+            let block = chacha20_block(&self.key, i as u64 + 1, NONCE_OUTPUT);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.rekey(NONCE_OUTPUT);
+    }
+}
+
+static mut POOL: Pool = Pool { key: [0; 8], entropy_bits: 0, last_reseed: 0 };
+
+fn pool() -> &'static mut Pool {
+    unsafe { &mut *core::ptr::addr_of_mut!(POOL) }
+}
+
+/// Interrupt timings gathered since the pool last took them. They are
+/// never credited, but cost nothing to collect.
+struct Jitter {
+    words: [u64; 4],
+    count: usize,
+}
+
+static mut JITTER: Jitter = Jitter { words: [0; 4], count: 0 };
+
+fn jitter() -> &'static mut Jitter {
+    unsafe { &mut *core::ptr::addr_of_mut!(JITTER) }
+}
+
+fn words_to_bytes(words: &[u64; 4]) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (chunk, word) in bytes.chunks_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Records when an interrupt from `source` arrived. Called from the trap
+/// path, so it only touches the jitter words and never the pool.
+pub fn add_interrupt_jitter(source: usize) {
+    let jitter = jitter();
+    let slot = jitter.count % jitter.words.len();
+    jitter.words[slot] = jitter.words[slot].rotate_left(7) ^ read_time() ^ (source as u64).rotate_left(48);
+    jitter.count += 1;
+}
+
+/// Mixes `data` into the pool, crediting it with `bits` of entropy.
+pub fn add_entropy(data: &[u8], bits: usize) {
+    let pool = pool();
+    pool.mix(data);
+    pool.credit(bits);
+}
+
+pub fn entropy_bits() -> usize {
+    pool().entropy_bits
+}
+
+/// Whether the pool has been credited enough to be unpredictable.
+pub fn is_ready() -> bool {
+    entropy_bits() >= SEED_BITS
+}
+
+/// Brings in the jitter gathered so far and, every so often or while the
+/// pool is short of credit, fresh bytes from the hardware generator. The
+/// hardware is read before the pool is touched, since waiting on it can
+/// take interrupts.
+fn reseed() {
+    let jitter = jitter();
+    if jitter.count > 0 {
+        let bytes = words_to_bytes(&jitter.words);
+        jitter.count = 0;
+        pool().mix(&bytes);
+    }
+    let now = read_time();
+    let interval = RESEED_INTERVAL_SECS * crate::time::timebase_freq();
+    if is_ready() && now.wrapping_sub(pool().last_reseed) < interval {
+        return;
+    }
+    let mut seed = [0u8; 32];
+    let count = crate::virtio_rng::read(&mut seed);
+    if count > 0 {
+        let pool = pool();
+        pool.mix(&seed[..count]);
+        pool.credit(count * 8);
+        pool.last_reseed = now;
+    }
+}
+
+/// Fills `buf` from the pool, seeded or not.
+pub fn fill(buf: &mut [u8]) {
+    reseed();
+    pool().generate(buf);
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// A uniform value in `0..bound`.
+pub fn below(bound: usize) -> usize {
+    if bound <= 1 {
+        return 0;
+    }
+    let bound = bound as u64;
+    // Values in the last partial multiple of `bound` would bias the result.
+    let limit = u64::MAX - u64::MAX % bound;
+    loop {
+        let value = next_u64();
+        if value < limit {
+            return (value % bound) as usize;
+        }
+    }
+}
+
+/// Times a short loop over and over and mixes in how long it took, which
+/// varies with cache and pipeline state and with where the timer ticks land.
+fn collect_boot_jitter() {
+    let mut words = [0u64; 4];
+    for i in 0..BOOT_JITTER_SAMPLES {
+        let start = read_time();
+        let mut x = start;
+        for _ in 0..(start & 0x3f) + 16 {
+            x = x.rotate_left(5) ^ x.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+        let delta = read_time().wrapping_sub(start);
+        words[i % 4] = words[i % 4].rotate_left(13) ^ delta ^ core::hint::black_box(x);
+        if i % 16 == 15 {
+            pool().mix(&words_to_bytes(&words));
+        }
+    }
+}
+
+/// Seeds the pool from what there is before any driver is up. Only the
+/// firmware's `/chosen/rng-seed` is credited; the clocks and timer jitter
+/// are mixed in for what they are worth. A virtio-rng device tops it up
+/// once it is probed.
+pub fn init_random() -> bool {
+    let pool = pool();
+    let seed = crate::fdt::tree()
+        .and_then(|tree| tree.find("/chosen"))
+        .and_then(|chosen| chosen.prop("rng-seed"))
+        .unwrap_or(&[]);
+    pool.mix(seed);
+    pool.credit(seed.len() * 8);
+    let clocks = [read_time(), crate::time::realtime().as_nanos(), 0, 0];
+    pool.mix(&words_to_bytes(&clocks));
+    collect_boot_jitter();
+    if is_ready() {
+        crate::print_ok!("Entropy pool seeded: {} bits from the firmware", seed.len() * 8);
+    } else {
+        crate::print_info!("Entropy pool not yet seeded ({} bits), waiting for a hardware source", entropy_bits());
+    }
+    true
+}
+
+/// `/dev/random` and `/dev/urandom`. They read the same pool; `/dev/random`
+/// refuses to until the pool is seeded. Writes are mixed in uncredited.
+pub struct Random {
+    pub wait_for_seed: bool,
+}
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.wait_for_seed && !is_ready() {
+            return Err(FsError::WouldBlock);
+        }
+        fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        add_entropy(data, 0);
+        Ok(data.len())
+    }
+
+    fn ioctl(&self, cmd: usize, arg: usize) -> Result<usize, FsError> {
+        match cmd {
+            RNDGETENTCNT => crate::tty::write_user(arg, entropy_bits() as i32),
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn poll(&self) -> u16 {
+        if self.wait_for_seed && !is_ready() { POLLOUT } else { POLLIN | POLLOUT }
+    }
+}
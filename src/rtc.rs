@@ -0,0 +1,72 @@
+/// Goldfish RTC registers. Reading `TIME_LOW` latches the high half, and
+/// writing `TIME_LOW` sets the clock from both.
+const REG_TIME_LOW: usize = 0x00;
+const REG_TIME_HIGH: usize = 0x04;
+const REG_IRQ_ENABLED: usize = 0x10;
+const REG_CLEAR_ALARM: usize = 0x14;
+
+/// The Goldfish RTC on QEMU `virt`: nanoseconds since the Unix epoch.
+pub struct Rtc {
+    base: usize,
+}
+
+impl Rtc {
+    fn read_reg(&self, reg: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    pub fn read_ns(&self) -> u64 {
+        let low = self.read_reg(REG_TIME_LOW);
+        let high = self.read_reg(REG_TIME_HIGH);
+        (high as u64) << 32 | low as u64
+    }
+
+    pub fn set_ns(&self, ns: u64) {
+        self.write_reg(REG_TIME_HIGH, (ns >> 32) as u32);
+        self.write_reg(REG_TIME_LOW, ns as u32);
+    }
+}
+
+static mut RTC: Option<Rtc> = None;
+
+pub fn rtc() -> Option<&'static Rtc> {
+    unsafe { (*core::ptr::addr_of!(RTC)).as_ref() }
+}
+
+pub fn init_rtc() -> bool {
+    let base = match crate::fdt::tree()
+        .and_then(|tree| tree.find_compatible("google,goldfish-rtc").next())
+        .and_then(|node| node.reg())
+    {
+        Some((base, _)) => base,
+        None => {
+            crate::print_info!("No RTC in the device tree, the clock starts at the epoch");
+            return true;
+        }
+    };
+    let rtc = Rtc { base };
+    // Alarms are not used; make sure none is left armed.
+    rtc.write_reg(REG_IRQ_ENABLED, 0);
+    rtc.write_reg(REG_CLEAR_ALARM, 0);
+    let secs = rtc.read_ns() / crate::time::NSEC_PER_SEC;
+    unsafe {
+        RTC = Some(rtc);
+    }
+    let (year, month, day) = crate::time::civil_from_days((secs / 86_400) as i64);
+    let rest = secs % 86_400;
+    crate::print_ok!(
+        "RTC at {:#x}: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        base,
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    );
+    true
+}
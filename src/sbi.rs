@@ -0,0 +1,347 @@
+use core::arch::asm;
+
+/// Extension IDs from the SBI specification.
+const EID_LEGACY_SET_TIMER: usize = 0x00;
+const EID_LEGACY_PUTCHAR: usize = 0x01;
+const EID_LEGACY_GETCHAR: usize = 0x02;
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x5449_4d45;
+const EID_IPI: usize = 0x73_5049;
+const EID_RFENCE: usize = 0x5246_4e43;
+const EID_HSM: usize = 0x48_534d;
+const EID_SRST: usize = 0x5352_5354;
+const EID_DBCN: usize = 0x4442_434e;
+
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+
+const DBCN_CONSOLE_WRITE: usize = 0;
+const DBCN_CONSOLE_READ: usize = 1;
+
+/// Errors an SBI call reports in `a0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoSharedMemory,
+    InvalidState,
+    BadRange,
+    Timeout,
+    Io,
+    Unknown(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            -9 => SbiError::NoSharedMemory,
+            -10 => SbiError::InvalidState,
+            -11 => SbiError::BadRange,
+            -12 => SbiError::Timeout,
+            -13 => SbiError::Io,
+            other => SbiError::Unknown(other),
+        }
+    }
+
+    pub fn to_string(self) -> &'static str {
+        match self {
+            SbiError::Failed => "Failed",
+            SbiError::NotSupported => "Not supported",
+            SbiError::InvalidParam => "Invalid parameter",
+            SbiError::Denied => "Denied",
+            SbiError::InvalidAddress => "Invalid address",
+            SbiError::AlreadyAvailable => "Already available",
+            SbiError::AlreadyStarted => "Already started",
+            SbiError::AlreadyStopped => "Already stopped",
+            SbiError::NoSharedMemory => "Shared memory not available",
+            SbiError::InvalidState => "Invalid state",
+            SbiError::BadRange => "Bad range",
+            SbiError::Timeout => "Timed out",
+            SbiError::Io => "I/O error",
+            SbiError::Unknown(_) => "Unknown SBI error",
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+/// Calls function `fid` of extension `eid` and returns `a1` on success.
+fn call(eid: usize, fid: usize, args: [usize; 5]) -> SbiResult<usize> {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    if error == 0 { Ok(value) } else { Err(SbiError::from_code(error)) }
+}
+
+/// Legacy (v0.1) calls return their result in `a0` alone.
+fn legacy_call(eid: usize, arg: usize) -> isize {
+    let result: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg => result,
+            out("a1") _,
+            in("a6") 0,
+            in("a7") eid,
+        );
+    }
+    result
+}
+
+/// Extensions the kernel knows how to use. Indices into `Info::extensions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    Time,
+    Ipi,
+    Rfence,
+    Hsm,
+    Srst,
+    Dbcn,
+    LegacyConsole,
+}
+
+impl Extension {
+    const ALL: [Extension; 7] = [
+        Extension::Time,
+        Extension::Ipi,
+        Extension::Rfence,
+        Extension::Hsm,
+        Extension::Srst,
+        Extension::Dbcn,
+        Extension::LegacyConsole,
+    ];
+
+    fn eid(&self) -> usize {
+        match self {
+            Extension::Time => EID_TIME,
+            Extension::Ipi => EID_IPI,
+            Extension::Rfence => EID_RFENCE,
+            Extension::Hsm => EID_HSM,
+            Extension::Srst => EID_SRST,
+            Extension::Dbcn => EID_DBCN,
+            Extension::LegacyConsole => EID_LEGACY_PUTCHAR,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::Time => "TIME",
+            Extension::Ipi => "IPI",
+            Extension::Rfence => "RFENCE",
+            Extension::Hsm => "HSM",
+            Extension::Srst => "SRST",
+            Extension::Dbcn => "DBCN",
+            Extension::LegacyConsole => "legacy console",
+        }
+    }
+}
+
+/// What the firmware reported when probed.
+#[derive(Debug, Clone, Copy)]
+pub struct Info {
+    pub spec_major: usize,
+    pub spec_minor: usize,
+    pub impl_id: usize,
+    pub impl_version: usize,
+    extensions: u32,
+}
+
+impl Info {
+    pub fn has(&self, ext: Extension) -> bool {
+        self.extensions & (1 << ext as u32) != 0
+    }
+
+    pub fn impl_name(&self) -> &'static str {
+        match self.impl_id {
+            0 => "Berkeley Boot Loader",
+            1 => "OpenSBI",
+            2 => "Xvisor",
+            3 => "KVM",
+            4 => "RustSBI",
+            5 => "Diosix",
+            6 => "Coffer",
+            7 => "Xen",
+            8 => "PolarFire HSS",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Filled in by `init_sbi`. Until then only the legacy calls are used,
+/// which every v0.1-compatible firmware implements.
+static mut INFO: Option<Info> = None;
+
+pub fn info() -> Option<Info> {
+    unsafe { *core::ptr::addr_of!(INFO) }
+}
+
+fn has(ext: Extension) -> bool {
+    info().is_some_and(|info| info.has(ext))
+}
+
+/// Queries the base extension. A v0.1 firmware has none and fails the
+/// very first call.
+fn probe() -> Option<Info> {
+    let version = call(EID_BASE, BASE_GET_SPEC_VERSION, [0; 5]).ok()?;
+    let mut info = Info {
+        spec_major: (version >> 24) & 0x7f,
+        spec_minor: version & 0xff_ffff,
+        impl_id: call(EID_BASE, BASE_GET_IMPL_ID, [0; 5]).unwrap_or(usize::MAX),
+        impl_version: call(EID_BASE, BASE_GET_IMPL_VERSION, [0; 5]).unwrap_or(0),
+        extensions: 0,
+    };
+    for ext in Extension::ALL {
+        if probe_extension(ext.eid()) {
+            info.extensions |= 1 << ext as u32;
+        }
+    }
+    Some(info)
+}
+
+pub fn probe_extension(eid: usize) -> bool {
+    matches!(call(EID_BASE, BASE_PROBE_EXTENSION, [eid, 0, 0, 0, 0]), Ok(value) if value != 0)
+}
+
+/// Writes as much of `data` as the firmware accepts in one call. DBCN
+/// takes a physical address, which is the kernel's own since it is
+/// identity mapped.
+fn dbcn_write(data: &[u8]) -> SbiResult<usize> {
+    let addr = data.as_ptr() as usize;
+    call(EID_DBCN, DBCN_CONSOLE_WRITE, [data.len(), addr, 0, 0, 0])
+}
+
+/// Writes all of `data` to the firmware console: in bulk through DBCN when
+/// it is available, a byte at a time through the legacy call otherwise.
+pub fn console_write(data: &[u8]) {
+    let mut rest = data;
+    if has(Extension::Dbcn) {
+        while !rest.is_empty() {
+            match dbcn_write(rest) {
+                Ok(count) => rest = &rest[count.min(rest.len())..],
+                Err(_) => break,
+            }
+        }
+    }
+    for &byte in rest {
+        legacy_call(EID_LEGACY_PUTCHAR, byte as usize);
+    }
+}
+
+/// Reads whatever console input is pending into `buf`, without blocking.
+pub fn console_read(buf: &mut [u8]) -> SbiResult<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    if has(Extension::Dbcn) {
+        let addr = buf.as_mut_ptr() as usize;
+        return call(EID_DBCN, DBCN_CONSOLE_READ, [buf.len(), addr, 0, 0, 0]);
+    }
+    match legacy_call(EID_LEGACY_GETCHAR, 0) {
+        result if result >= 0 => {
+            buf[0] = result as u8;
+            Ok(1)
+        }
+        _ => Ok(0),
+    }
+}
+
+pub fn console_getchar() -> Option<u8> {
+    let mut byte = 0;
+    match console_read(core::slice::from_mut(&mut byte)) {
+        Ok(1) => Some(byte),
+        _ => None,
+    }
+}
+
+/// Programs the next supervisor timer interrupt for absolute time
+/// `stime_value`, which also clears the pending one.
+pub fn set_timer(stime_value: u64) -> SbiResult<()> {
+    if has(Extension::Time) {
+        call(EID_TIME, 0, [stime_value as usize, 0, 0, 0, 0]).map(|_| ())
+    } else {
+        legacy_call(EID_LEGACY_SET_TIMER, stime_value as usize);
+        Ok(())
+    }
+}
+
+fn require(ext: Extension) -> SbiResult<()> {
+    if has(ext) { Ok(()) } else { Err(SbiError::NotSupported) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/// Resets or powers off the system. Only returns on failure.
+pub fn system_reset(kind: ResetType, reason: ResetReason) -> SbiError {
+    if let Err(err) = require(Extension::Srst) {
+        return err;
+    }
+    match call(EID_SRST, 0, [kind as usize, reason as usize, 0, 0, 0]) {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
+
+pub fn init_sbi() -> bool {
+    let info = match probe() {
+        Some(info) => info,
+        None => {
+            crate::print_info!("SBI v0.1 firmware: legacy console and timer only");
+            return true;
+        }
+    };
+    unsafe {
+        INFO = Some(info);
+    }
+    crate::print_ok!(
+        "SBI v{}.{}, {} {:#x}",
+        info.spec_major,
+        info.spec_minor,
+        info.impl_name(),
+        info.impl_version
+    );
+    crate::print!("[INFO] SBI extensions:");
+    for ext in Extension::ALL {
+        if info.has(ext) {
+            crate::print!(" {}", ext.name());
+        }
+    }
+    crate::println!();
+    true
+}
@@ -1,4 +1,7 @@
+use alloc::vec::Vec;
 use core::arch::asm;
+use crate::vfs::{FileRef, InodeRef};
+use crate::vm::AddressSpace;
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskState {
@@ -6,15 +9,64 @@ pub enum TaskState {
     Running,
     Exited,
     Waiting,
+    Stopped,
 }
 
-#[derive(Clone)]
 pub struct Task {
     pub ctx: TaskContext,
     pub active: bool,
     pub pid: usize,
     pub ppid: usize,
     pub state: TaskState,
+    /// User mappings; `None` while the task runs on the bare identity map.
+    pub mm: Option<AddressSpace>,
+    pub fds: Vec<Option<FileRef>>,
+    /// Working directory; `None` means the root of the VFS.
+    pub cwd: Option<InodeRef>,
+    /// Credentials used for file ownership and permission checks.
+    pub uid: u32,
+    pub gid: u32,
+    /// Process group and session, for job control on a terminal.
+    pub pgid: usize,
+    pub sid: usize,
+}
+
+impl Task {
+    /// Ends the task and drops everything it holds.
+    pub fn exit(&mut self) {
+        self.state = TaskState::Exited;
+        self.active = false;
+        self.mm = None;
+        self.fds.clear();
+    }
+
+    /// Installs `file` at the lowest free descriptor.
+    pub fn alloc_fd(&mut self, file: FileRef) -> usize {
+        match self.fds.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.fds[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.fds.push(Some(file));
+                self.fds.len() - 1
+            }
+        }
+    }
+
+    pub fn get_fd(&self, fd: usize) -> Option<FileRef> {
+        self.fds.get(fd).and_then(|f| f.clone())
+    }
+
+    pub fn close_fd(&mut self, fd: usize) -> bool {
+        match self.fds.get_mut(fd) {
+            Some(slot) if slot.is_some() => {
+                *slot = None;
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -29,15 +81,39 @@ pub struct TaskContext {
 pub const MAX_TASKS: usize = 4;
 
 pub static mut TASKS: [Task; MAX_TASKS] = [
-    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 0, ppid: 0, state: TaskState::Exited },
-    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 1, ppid: 0, state: TaskState::Exited },
-    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 2, ppid: 0, state: TaskState::Exited },
-    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 3, ppid: 0, state: TaskState::Exited },
+    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 0, ppid: 0, state: TaskState::Exited, mm: None, fds: Vec::new(), cwd: None, uid: 0, gid: 0, pgid: 0, sid: 0 },
+    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 1, ppid: 0, state: TaskState::Exited, mm: None, fds: Vec::new(), cwd: None, uid: 0, gid: 0, pgid: 0, sid: 0 },
+    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 2, ppid: 0, state: TaskState::Exited, mm: None, fds: Vec::new(), cwd: None, uid: 0, gid: 0, pgid: 0, sid: 0 },
+    Task { ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 }, active: false, pid: 3, ppid: 0, state: TaskState::Exited, mm: None, fds: Vec::new(), cwd: None, uid: 0, gid: 0, pgid: 0, sid: 0 },
 ];
 
 pub static mut CURRENT_TASK: usize = 0;
 
+/// New tasks get pids drawn at random from `PID_MIN..PID_MAX`, so one
+/// cannot be guessed from the order tasks were started in. 0 and 1 are
+/// the kernel shell and the first user task.
+const PID_MIN: usize = 2;
+const PID_MAX: usize = 32768;
+
+/// A random pid no live task is using as its pid, group or session.
+pub fn alloc_pid() -> usize {
+    let tasks = unsafe { &*core::ptr::addr_of!(TASKS) };
+    loop {
+        let pid = PID_MIN + crate::random::below(PID_MAX - PID_MIN);
+        if !tasks.iter().any(|t| t.active && (t.pid == pid || t.pgid == pid || t.sid == pid)) {
+            return pid;
+        }
+    }
+}
+
 pub fn init_scheduler() -> bool {
+    let stack = match crate::user_loader::initial_stack() {
+        Some(sp) => sp,
+        None => {
+            crate::print_fail!("No memory for the first task's stack");
+            return false;
+        }
+    };
     unsafe {
         TASKS[0] = Task {
             ctx: TaskContext { regs: [0; 32], pc: 0, sp: 0, sstatus: 0, mode: 0 },
@@ -45,12 +121,19 @@ pub fn init_scheduler() -> bool {
             pid: 0,
             ppid: 0,
             state: TaskState::Ready,
+            mm: None,
+            fds: Vec::new(),
+            cwd: None,
+            uid: 0,
+            gid: 0,
+            pgid: 0,
+            sid: 0,
         };
         TASKS[1] = Task {
             ctx: TaskContext {
                 regs: [0; 32],
                 pc: crate::user_loader::USER_PROG_BASE,
-                sp: crate::user_loader::USER_STACK_BASE + crate::user_loader::USER_STACK_SIZE,
+                sp: stack,
                 sstatus: 0,
                 mode: 1,
             },
@@ -58,11 +141,41 @@ pub fn init_scheduler() -> bool {
             pid: 1,
             ppid: 0,
             state: TaskState::Ready,
+            mm: None,
+            fds: Vec::new(),
+            cwd: None,
+            uid: 0,
+            gid: 0,
+            pgid: 1,
+            sid: 1,
         };
     }
     true
 }
 
+/// Opens `/dev/console` once and shares it as descriptors 0, 1 and 2 of
+/// every task set up so far; children inherit them through `fork`.
+pub fn init_stdio() -> bool {
+    let console = match crate::vfs::open(&crate::vfs::root(), "/dev/console", crate::vfs::O_RDWR, 0) {
+        Ok(file) => file,
+        Err(err) => {
+            crate::print_fail!("Cannot open /dev/console: {}", err.to_string());
+            return false;
+        }
+    };
+    unsafe {
+        for task in TASKS.iter_mut().filter(|t| t.active) {
+            task.fds = alloc::vec![Some(console.clone()), Some(console.clone()), Some(console.clone())];
+        }
+    }
+    crate::print_ok!("Standard streams attached to /dev/console");
+    true
+}
+
+pub fn current_task() -> &'static mut Task {
+    unsafe { &mut TASKS[CURRENT_TASK] }
+}
+
 pub fn save_context(dst: &mut TaskContext, regs: &[usize; 32], pc: usize, sp: usize, sstatus: usize) {
     dst.regs.copy_from_slice(regs);
     dst.pc = pc;
@@ -89,7 +202,12 @@ pub fn switch_to_task(next_id: usize) -> ! {
         let task = &TASKS[next_id];
         let rptr = task.ctx.regs.as_ptr();
         let pc = task.ctx.pc;
-        let sstatus = task.ctx.sstatus;
+        let (satp, sstatus) = match &task.mm {
+            Some(mm) => (mm.satp(), task.ctx.sstatus | crate::vm::SSTATUS_SUM),
+            None => (0, task.ctx.sstatus),
+        };
+
+        asm!("csrw satp, {}", "sfence.vma", in(reg) satp);
 
         asm!(
             "mv t0, {rptr}",
@@ -0,0 +1,93 @@
+use crate::scheduler::{Task, TaskState, TASKS};
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+
+/// Highest valid signal number.
+pub const NSIG: u32 = 64;
+
+enum Action {
+    Terminate,
+    Stop,
+    Continue,
+    Ignore,
+}
+
+/// There are no user handlers yet, so every signal takes its default
+/// action.
+fn default_action(sig: u32) -> Action {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => Action::Ignore,
+        SIGCONT => Action::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => Action::Stop,
+        _ => Action::Terminate,
+    }
+}
+
+fn tasks() -> impl Iterator<Item = &'static mut Task> {
+    unsafe { (*core::ptr::addr_of_mut!(TASKS)).iter_mut().filter(|t| t.active) }
+}
+
+/// Applies `sig` to `task` right away. A task killed or stopped in the
+/// middle of a system call finishes it; the trap handler then schedules
+/// something else. Pid 0 is the kernel shell, which signals never touch.
+fn deliver(task: &mut Task, sig: u32) {
+    if task.pid == 0 {
+        return;
+    }
+    match default_action(sig) {
+        Action::Terminate => task.exit(),
+        Action::Stop => task.state = TaskState::Stopped,
+        Action::Continue => {
+            if task.state == TaskState::Stopped {
+                task.state = TaskState::Ready;
+            }
+        }
+        Action::Ignore => {}
+    }
+}
+
+/// Sends `sig` to the task `pid`. Signal 0 only checks that it exists.
+pub fn send(pid: usize, sig: u32) -> bool {
+    if sig > NSIG {
+        return false;
+    }
+    match tasks().find(|t| t.pid == pid) {
+        Some(task) => {
+            if sig != 0 {
+                deliver(task, sig);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sends `sig` to every task in process group `pgid` and returns whether
+/// there were any.
+pub fn send_group(pgid: usize, sig: u32) -> bool {
+    if sig > NSIG {
+        return false;
+    }
+    let mut found = false;
+    for task in tasks().filter(|t| t.pgid == pgid) {
+        if sig != 0 {
+            deliver(task, sig);
+        }
+        found = true;
+    }
+    found
+}
+
+pub fn group_exists(pgid: usize) -> bool {
+    tasks().any(|t| t.pgid == pgid)
+}
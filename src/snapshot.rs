@@ -0,0 +1,569 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::block::{BlockRef, SECTOR_SIZE};
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::ramfs::{FileData, NodeKind, RamFs, RamInode};
+use crate::time::Timespec;
+use crate::vfs::{self, FsError};
+
+const MAGIC: [u8; 8] = *b"STARSNAP";
+const VERSION: u16 = 1;
+const HEADER_SIZE: u64 = SECTOR_SIZE as u64;
+
+const KIND_FILE: u8 = 0;
+const KIND_DIRECTORY: u8 = 1;
+const KIND_SYMLINK: u8 = 2;
+const KIND_DEVICE: u8 = 3;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues a CRC-32 (the zlib one) over `data`. Start from `!0` and
+/// invert the result.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Where the image is kept. Offsets and lengths passed to it are whole
+/// sectors.
+enum Store {
+    Block(BlockRef),
+    Memory { base: usize, size: usize },
+}
+
+impl Store {
+    fn size(&self) -> u64 {
+        match self {
+            Store::Block(device) => device.sector_count() * SECTOR_SIZE as u64,
+            Store::Memory { size, .. } => *size as u64,
+        }
+    }
+
+    fn check(&self, offset: u64, len: usize) -> Result<(), FsError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size() => Ok(()),
+            _ => Err(FsError::NoSpace),
+        }
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.check(offset, buf.len())?;
+        match self {
+            Store::Block(device) => device.read_sectors(offset / SECTOR_SIZE as u64, buf),
+            Store::Memory { base, .. } => {
+                let src = (*base + offset as usize) as *const u8;
+                unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+                Ok(())
+            }
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        self.check(offset, data.len())?;
+        match self {
+            Store::Block(device) => device.write_sectors(offset / SECTOR_SIZE as u64, data),
+            Store::Memory { base, .. } => {
+                let dst = (*base + offset as usize) as *mut u8;
+                unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&self) -> Result<(), FsError> {
+        match self {
+            Store::Block(device) => device.flush(),
+            Store::Memory { .. } => Ok(()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Store::Block(device) => String::from(device.name()),
+            Store::Memory { base, size } => alloc::format!("memory {:#x}+{:#x}", base, size),
+        }
+    }
+}
+
+/// Streams the payload out a page at a time, checksumming as it goes.
+struct Writer<'a> {
+    store: &'a Store,
+    buf: Frame,
+    fill: usize,
+    offset: u64,
+    len: u64,
+    crc: u32,
+}
+
+impl<'a> Writer<'a> {
+    fn new(store: &'a Store) -> Result<Self, FsError> {
+        let buf = Frame::alloc_zeroed().ok_or(FsError::NoSpace)?;
+        Ok(Writer { store, buf, fill: 0, offset: HEADER_SIZE, len: 0, crc: !0 })
+    }
+
+    fn put(&mut self, mut data: &[u8]) -> Result<(), FsError> {
+        self.crc = crc32_update(self.crc, data);
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let chunk = core::cmp::min(PAGE_SIZE - self.fill, data.len());
+            self.buf.as_mut_slice()[self.fill..self.fill + chunk].copy_from_slice(&data[..chunk]);
+            self.fill += chunk;
+            data = &data[chunk..];
+            if self.fill == PAGE_SIZE {
+                self.write_out()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered bytes, padded to a whole sector.
+    fn write_out(&mut self) -> Result<(), FsError> {
+        let len = self.fill.next_multiple_of(SECTOR_SIZE);
+        self.buf.as_mut_slice()[self.fill..len].fill(0);
+        self.store.write(self.offset, &self.buf.as_slice()[..len])?;
+        self.offset += len as u64;
+        self.fill = 0;
+        Ok(())
+    }
+
+    fn put_u8(&mut self, value: u8) -> Result<(), FsError> {
+        self.put(&[value])
+    }
+
+    fn put_u16(&mut self, value: u16) -> Result<(), FsError> {
+        self.put(&value.to_le_bytes())
+    }
+
+    fn put_u32(&mut self, value: u32) -> Result<(), FsError> {
+        self.put(&value.to_le_bytes())
+    }
+
+    fn put_u64(&mut self, value: u64) -> Result<(), FsError> {
+        self.put(&value.to_le_bytes())
+    }
+
+    fn put_time(&mut self, ts: Timespec) -> Result<(), FsError> {
+        self.put(&ts.sec.to_le_bytes())?;
+        self.put_u32(ts.nsec)
+    }
+
+    /// Flushes the tail and returns the payload length and checksum.
+    fn finish(mut self) -> Result<(u64, u32), FsError> {
+        if self.fill > 0 {
+            self.write_out()?;
+        }
+        Ok((self.len, !self.crc))
+    }
+}
+
+/// Reads the payload back, refusing to run past its recorded length.
+struct Reader<'a> {
+    store: &'a Store,
+    buf: Frame,
+    pos: usize,
+    avail: usize,
+    offset: u64,
+    remaining: u64,
+    crc: u32,
+}
+
+impl<'a> Reader<'a> {
+    fn new(store: &'a Store, len: u64) -> Result<Self, FsError> {
+        let buf = Frame::alloc_zeroed().ok_or(FsError::NoSpace)?;
+        Ok(Reader { store, buf, pos: 0, avail: 0, offset: HEADER_SIZE, remaining: len, crc: !0 })
+    }
+
+    fn take(&mut self, out: &mut [u8]) -> Result<(), FsError> {
+        if out.len() as u64 > self.remaining {
+            return Err(FsError::Io);
+        }
+        let mut done = 0;
+        while done < out.len() {
+            if self.pos == self.avail {
+                let left = (self.store.size() - self.offset) as usize;
+                let len = core::cmp::min(PAGE_SIZE, left / SECTOR_SIZE * SECTOR_SIZE);
+                self.store.read(self.offset, &mut self.buf.as_mut_slice()[..len])?;
+                self.offset += len as u64;
+                self.pos = 0;
+                self.avail = len;
+            }
+            let chunk = core::cmp::min(self.avail - self.pos, out.len() - done);
+            out[done..done + chunk].copy_from_slice(&self.buf.as_slice()[self.pos..self.pos + chunk]);
+            self.pos += chunk;
+            done += chunk;
+        }
+        self.crc = crc32_update(self.crc, out);
+        self.remaining -= out.len() as u64;
+        Ok(())
+    }
+
+    /// Reads and discards `len` bytes, for checksumming.
+    fn skip(&mut self, mut len: u64) -> Result<(), FsError> {
+        let mut scratch = [0u8; 256];
+        while len > 0 {
+            let chunk = core::cmp::min(len, scratch.len() as u64) as usize;
+            self.take(&mut scratch[..chunk])?;
+            len -= chunk as u64;
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, FsError> {
+        let mut bytes = [0u8; 1];
+        self.take(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FsError> {
+        let mut bytes = [0u8; 2];
+        self.take(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        self.take(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, FsError> {
+        let mut bytes = [0u8; 8];
+        self.take(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn time(&mut self) -> Result<Timespec, FsError> {
+        let sec = self.u64()? as i64;
+        let nsec = self.u32()?;
+        if nsec >= 1_000_000_000 {
+            return Err(FsError::Io);
+        }
+        Ok(Timespec { sec, nsec })
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, FsError> {
+        let mut bytes = alloc::vec![0u8; len];
+        self.take(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| FsError::Io)
+    }
+}
+
+fn encode(out: &mut Writer, inode: &RamInode) -> Result<(), FsError> {
+    out.put_u32(inode.ino as u32)?;
+    out.put_u8(match inode.kind {
+        NodeKind::File(_) => KIND_FILE,
+        NodeKind::Directory { .. } => KIND_DIRECTORY,
+        NodeKind::Symlink(_) => KIND_SYMLINK,
+        NodeKind::Device(_) => KIND_DEVICE,
+    })?;
+    out.put_u32(inode.mode)?;
+    out.put_u32(inode.uid)?;
+    out.put_u32(inode.gid)?;
+    out.put_u32(inode.links)?;
+    out.put_u64(inode.size as u64)?;
+    out.put_time(inode.atime)?;
+    out.put_time(inode.mtime)?;
+    out.put_time(inode.ctime)?;
+    match &inode.kind {
+        NodeKind::File(data) => {
+            let last = inode.size.div_ceil(PAGE_SIZE);
+            out.put_u32(data.pages().filter(|&(index, _)| index < last).count() as u32)?;
+            for (index, frame) in data.pages().filter(|&(index, _)| index < last) {
+                out.put_u32(index as u32)?;
+                out.put(frame.as_slice())?;
+            }
+        }
+        NodeKind::Directory { entries, parent } => {
+            out.put_u32(*parent as u32)?;
+            out.put_u32(entries.len() as u32)?;
+            for (name, &ino) in entries {
+                out.put_u8(name.len() as u8)?;
+                out.put(name.as_bytes())?;
+                out.put_u32(ino as u32)?;
+            }
+        }
+        NodeKind::Symlink(target) => {
+            out.put_u16(target.len() as u16)?;
+            out.put(target.as_bytes())?;
+        }
+        NodeKind::Device(rdev) => out.put_u64(*rdev)?,
+    }
+    Ok(())
+}
+
+/// Reads one record, returning the inode number it was saved under. Inode
+/// numbers inside it are still the saved ones.
+fn decode(input: &mut Reader) -> Result<(u32, RamInode), FsError> {
+    let ino = input.u32()?;
+    let kind = input.u8()?;
+    let mode = input.u32()?;
+    let uid = input.u32()?;
+    let gid = input.u32()?;
+    let links = input.u32()?;
+    let size = input.u64()?;
+    if size > crate::ramfs::MAX_FILE_SIZE as u64 {
+        return Err(FsError::Io);
+    }
+    let (atime, mtime, ctime) = (input.time()?, input.time()?, input.time()?);
+    let kind = match kind {
+        KIND_FILE => {
+            let mut data = FileData::new();
+            for _ in 0..input.u32()? {
+                let index = input.u32()? as usize;
+                if index >= (size as usize).div_ceil(PAGE_SIZE) || data.page(index).is_some() {
+                    return Err(FsError::Io);
+                }
+                let frame = data.page_or_alloc(index).ok_or(FsError::NoSpace)?;
+                input.take(frame.as_mut_slice())?;
+            }
+            NodeKind::File(data)
+        }
+        KIND_DIRECTORY => {
+            let parent = input.u32()? as usize;
+            let mut entries = BTreeMap::new();
+            for _ in 0..input.u32()? {
+                let len = input.u8()? as usize;
+                let name = input.string(len)?;
+                if name.is_empty() || name.contains('/') || entries.insert(name, input.u32()? as usize).is_some() {
+                    return Err(FsError::Io);
+                }
+            }
+            NodeKind::Directory { entries, parent }
+        }
+        KIND_SYMLINK => {
+            let len = input.u16()? as usize;
+            NodeKind::Symlink(input.string(len)?)
+        }
+        KIND_DEVICE => NodeKind::Device(input.u64()?),
+        _ => return Err(FsError::Io),
+    };
+    let inode = RamInode { ino: 0, kind, size: size as usize, links, mode, uid, gid, atime, mtime, ctime };
+    Ok((ino, inode))
+}
+
+static mut STORE: Option<Store> = None;
+
+fn store() -> Option<&'static Store> {
+    unsafe { (*core::ptr::addr_of!(STORE)).as_ref() }
+}
+
+/// Whether `snapshot=` named somewhere to keep the snapshot.
+pub fn configured() -> bool {
+    store().is_some()
+}
+
+/// Writes the boot ramfs out and returns how many inodes it held.
+///
+/// The image starts with a one-sector header: the magic `STARSNAP`, a u16
+/// version, then at offset 12 the record count (u32), payload length
+/// (u64), payload CRC-32 and a CRC-32 of the 28 bytes before it. The
+/// payload follows in the next sector with one record per live inode in
+/// inode number order: number, kind, mode, uid, gid, links, size and
+/// times, then the pages a file holds, a directory's parent and entries,
+/// a symlink's target or a device number. Everything is little-endian.
+///
+/// The header goes last, so an interrupted save fails its checksum
+/// instead of restoring half a tree.
+pub fn save() -> Result<usize, FsError> {
+    let store = store().ok_or(FsError::NoDevice)?;
+    let fs = crate::ramfs::rootfs();
+    let table = fs.inode_table();
+    let mut out = Writer::new(store)?;
+    let mut count = 0u32;
+    for inode in table.iter().flatten().filter(|inode| inode.links > 0) {
+        encode(&mut out, inode)?;
+        count += 1;
+    }
+    let (len, crc) = out.finish()?;
+    let mut header = [0u8; SECTOR_SIZE];
+    header[0..8].copy_from_slice(&MAGIC);
+    header[8..10].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&count.to_le_bytes());
+    header[16..24].copy_from_slice(&len.to_le_bytes());
+    header[24..28].copy_from_slice(&crc.to_le_bytes());
+    let header_crc = crc32(&header[..28]);
+    header[28..32].copy_from_slice(&header_crc.to_le_bytes());
+    store.flush()?;
+    store.write(0, &header)?;
+    store.flush()?;
+    Ok(count as usize)
+}
+
+/// Reads the snapshot back as an inode table, or `None` if the store has
+/// never held one.
+fn load(store: &Store) -> Result<Option<Vec<Option<RamInode>>>, FsError> {
+    let mut header = [0u8; SECTOR_SIZE];
+    store.read(0, &mut header)?;
+    if header[0..8] != MAGIC {
+        return Ok(None);
+    }
+    let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    if crc32(&header[..28]) != field(28) {
+        return Err(FsError::Io);
+    }
+    if u16::from_le_bytes([header[8], header[9]]) != VERSION {
+        return Err(FsError::NotSupported);
+    }
+    let count = field(12) as usize;
+    let len = u64::from_le_bytes(header[16..24].try_into().unwrap());
+    if len > store.size() - HEADER_SIZE {
+        return Err(FsError::Io);
+    }
+
+    // Check the whole payload before building anything from it.
+    let mut input = Reader::new(store, len)?;
+    input.skip(len)?;
+    if !input.crc != field(24) {
+        return Err(FsError::Io);
+    }
+
+    let mut input = Reader::new(store, len)?;
+    let mut records: Vec<(u32, RamInode)> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let record = decode(&mut input)?;
+        if records.last().is_some_and(|(last, _)| *last >= record.0) {
+            return Err(FsError::Io);
+        }
+        records.push(record);
+    }
+    if input.remaining != 0 || !records.first().is_some_and(|(_, root)| root.is_dir()) {
+        return Err(FsError::Io);
+    }
+
+    // Renumber densely; saved numbers are kept sorted, so a binary search
+    // maps them.
+    let saved: Vec<u32> = records.iter().map(|(ino, _)| *ino).collect();
+    let remap = |ino: usize| saved.binary_search(&(ino as u32)).map_err(|_| FsError::Io);
+    let mut table = Vec::with_capacity(records.len());
+    for (index, (_, mut inode)) in records.into_iter().enumerate() {
+        inode.ino = index;
+        if let NodeKind::Directory { entries, parent } = &mut inode.kind {
+            *parent = remap(*parent)?;
+            for ino in entries.values_mut() {
+                *ino = remap(*ino)?;
+            }
+        }
+        table.push(Some(inode));
+    }
+    Ok(Some(table))
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    let (digits, scale) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 1 << 10),
+        b'M' | b'm' => (&text[..text.len() - 1], 1 << 20),
+        _ => (text, 1),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(scale)
+}
+
+/// Parses `mem:<addr>+<size>` or a block device name. A memory region has
+/// to lie in RAM past the end of the kernel.
+fn parse_store(spec: &str) -> Result<Store, &'static str> {
+    let Some(region) = spec.strip_prefix("mem:") else {
+        return crate::block::find_block_device(spec).map(Store::Block).ok_or("no such block device");
+    };
+    let (base, size) = region
+        .split_once('+')
+        .and_then(|(base, size)| Some((parse_number(base)?, parse_number(size)?)))
+        .ok_or("expected mem:<addr>+<size>")?;
+    if base % SECTOR_SIZE != 0 || size % SECTOR_SIZE != 0 || size < 2 * SECTOR_SIZE {
+        return Err("region must be whole sectors");
+    }
+    let end = base.checked_add(size).ok_or("region wraps around")?;
+    let ram = crate::fdt::tree().and_then(|tree| tree.find("/memory")).and_then(|node| node.reg());
+    if base < crate::memory::kernel_end() || ram.is_some_and(|(start, len)| base < start || end > start + len) {
+        return Err("region overlaps the kernel or is outside RAM");
+    }
+    Ok(Store::Memory { base, size })
+}
+
+/// Prints how a save went, for `sync` and shutdown.
+pub fn report(result: Result<usize, FsError>) {
+    let target = store().map(Store::describe).unwrap_or_default();
+    match result {
+        Ok(count) => {
+            crate::print_ok!("Snapshot of {} inodes saved to {}", count, target);
+        }
+        Err(err) => {
+            crate::print_fail!("Snapshot to {} failed: {}", target, err.to_string());
+        }
+    }
+}
+
+/// Saves the snapshot, if there is one, as part of a clean shutdown.
+pub fn save_at_shutdown() {
+    if configured() {
+        report(save());
+    }
+}
+
+/// Sets up the store named by `snapshot=` and restores the boot ramfs
+/// from it. A missing or damaged snapshot leaves the initramfs in place,
+/// to be overwritten by the next save.
+pub fn init_snapshot() -> bool {
+    let Some(spec) = crate::fdt::bootarg("snapshot") else {
+        return true;
+    };
+    let parsed = match parse_store(spec) {
+        Ok(store) => store,
+        Err(reason) => {
+            crate::print_fail!("snapshot={}: {}", spec, reason);
+            return true;
+        }
+    };
+    let target = parsed.describe();
+    unsafe {
+        STORE = Some(parsed);
+    }
+    let restored = load(store().unwrap()).and_then(|table| match table {
+        Some(table) => {
+            let count = table.len();
+            let fs: Arc<RamFs> = RamFs::from_table(table);
+            vfs::replace_root("rootfs", fs.clone())?;
+            crate::ramfs::set_rootfs(fs);
+            Ok(Some(count))
+        }
+        None => Ok(None),
+    });
+    match restored {
+        Ok(Some(count)) => {
+            crate::print_ok!("Restored {} inodes from the snapshot on {}", count, target);
+        }
+        Ok(None) => {
+            crate::print_info!("No snapshot on {} yet; `sync` or a clean shutdown writes one", target);
+        }
+        Err(err) => {
+            crate::print_fail!("Snapshot on {} not restored: {}", target, err.to_string());
+        }
+    }
+    true
+}
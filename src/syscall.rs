@@ -1,27 +1,163 @@
-use crate::scheduler::{TaskState, TASKS, CURRENT_TASK};
-use crate::ramfs::ramfs_mut;
+use alloc::vec::Vec;
+use crate::scheduler::{current_task, Task, TaskState, TASKS, CURRENT_TASK, MAX_TASKS};
+use crate::time::Timespec;
+use crate::vfs::{self, FsError, InodeKind, InodeRef, Metadata, POLLNVAL, S_IFCHR, S_IFMT, S_IFREG};
+use crate::memory::PAGE_SIZE;
+use crate::vm::{AddressSpace, Backing, MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_WRITE};
 
 pub const SYS_READ: usize = 0;
+pub const SYS_GETCWD: usize = 17;
+pub const SYS_IOCTL: usize = 29;
+pub const SYS_MKNODAT: usize = 33;
+pub const SYS_MKDIRAT: usize = 34;
+pub const SYS_UNLINKAT: usize = 35;
+pub const SYS_SYMLINKAT: usize = 36;
+pub const SYS_LINKAT: usize = 37;
+pub const SYS_READLINKAT: usize = 78;
+pub const SYS_CHDIR: usize = 49;
 pub const SYS_WRITE: usize = 64;
 pub const SYS_OPEN: usize = 2;
-pub const SYS_CLOSE: usize = 3;
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_GETDENTS64: usize = 61;
+pub const SYS_LSEEK: usize = 62;
+pub const SYS_READV: usize = 65;
+pub const SYS_WRITEV: usize = 66;
+pub const SYS_PREAD64: usize = 67;
+pub const SYS_PWRITE64: usize = 68;
+pub const SYS_PPOLL: usize = 73;
+pub const SYS_TRUNCATE: usize = 45;
+pub const SYS_FTRUNCATE: usize = 46;
 pub const SYS_EXIT: usize = 93;
 pub const SYS_FORK: usize = 220;
 pub const SYS_EXECVE: usize = 221;
 pub const SYS_WAIT: usize = 260;
 pub const SYS_GETPID: usize = 172;
+pub const SYS_KILL: usize = 129;
+pub const SYS_CLOCK_SETTIME: usize = 112;
+pub const SYS_CLOCK_GETTIME: usize = 113;
+pub const SYS_CLOCK_GETRES: usize = 114;
+pub const SYS_GETTIMEOFDAY: usize = 169;
+pub const SYS_REBOOT: usize = 142;
+pub const SYS_GETRANDOM: usize = 278;
+pub const SYS_SETPGID: usize = 154;
+pub const SYS_GETPGID: usize = 155;
+pub const SYS_GETSID: usize = 156;
+pub const SYS_SETSID: usize = 157;
 pub const SYS_BRK: usize = 9;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_RENAMEAT2: usize = 276;
+pub const SYS_FCHMOD: usize = 52;
+pub const SYS_FCHMODAT: usize = 53;
+pub const SYS_FCHOWNAT: usize = 54;
+pub const SYS_FCHOWN: usize = 55;
+pub const SYS_NEWFSTATAT: usize = 79;
+pub const SYS_FSTAT: usize = 80;
+pub const SYS_UTIMENSAT: usize = 88;
+pub const SYS_STATX: usize = 291;
+pub const SYS_UMOUNT2: usize = 39;
+pub const SYS_MOUNT: usize = 40;
 pub const SYS_RAMFS_CREATE: usize = 1000;
 pub const SYS_RAMFS_READ: usize = 1001;
 pub const SYS_RAMFS_WRITE: usize = 1002;
 pub const SYS_RAMFS_LIST: usize = 1003;
 
-pub fn handle_syscall(syscall_num: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
+pub const AT_FDCWD: usize = -100isize as usize;
+pub const AT_REMOVEDIR: usize = 0x200;
+pub const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+pub const AT_SYMLINK_FOLLOW: usize = 0x400;
+pub const AT_EMPTY_PATH: usize = 0x1000;
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_MONOTONIC_RAW: usize = 4;
+pub const CLOCK_REALTIME_COARSE: usize = 5;
+pub const CLOCK_MONOTONIC_COARSE: usize = 6;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+pub const LINUX_REBOOT_MAGIC1: usize = 0xfee1_dead;
+pub const LINUX_REBOOT_MAGIC2: usize = 672_274_793;
+pub const LINUX_REBOOT_MAGIC2A: usize = 85_072_278;
+pub const LINUX_REBOOT_MAGIC2B: usize = 369_367_448;
+pub const LINUX_REBOOT_MAGIC2C: usize = 537_993_216;
+pub const LINUX_REBOOT_CMD_RESTART: usize = 0x0123_4567;
+pub const LINUX_REBOOT_CMD_HALT: usize = 0xcdef_0123;
+pub const LINUX_REBOOT_CMD_POWER_OFF: usize = 0x4321_fedc;
+pub const LINUX_REBOOT_CMD_CAD_ON: usize = 0x89ab_cdef;
+pub const LINUX_REBOOT_CMD_CAD_OFF: usize = 0;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+const IOV_MAX: usize = 1024;
+
+pub const UTIME_NOW: i64 = (1 << 30) - 1;
+pub const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+const MAX_PATH: usize = 4096;
+
+pub fn handle_syscall(
+    syscall_num: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    arg6: usize,
+) -> usize {
     match syscall_num {
         SYS_READ => sys_read(arg1, arg2, arg3),
         SYS_WRITE => sys_write(arg1, arg2, arg3),
+        SYS_READV => sys_readv(arg1, arg2, arg3),
+        SYS_WRITEV => sys_writev(arg1, arg2, arg3),
+        SYS_PREAD64 => sys_pread64(arg1, arg2, arg3, arg4),
+        SYS_PWRITE64 => sys_pwrite64(arg1, arg2, arg3, arg4),
+        SYS_LSEEK => sys_lseek(arg1, arg2, arg3),
+        SYS_IOCTL => sys_ioctl(arg1, arg2, arg3),
+        SYS_PPOLL => sys_ppoll(arg1, arg2, arg3),
+        SYS_TRUNCATE => sys_truncate(arg1, arg2, arg3),
+        SYS_FTRUNCATE => sys_ftruncate(arg1, arg2, arg3),
+        SYS_OPENAT => sys_openat(arg1, arg2, arg3, arg4),
+        SYS_CLOSE => sys_close(arg1, arg2, arg3),
+        SYS_MMAP => sys_mmap(arg1, arg2, arg3, arg4, arg5, arg6),
+        SYS_MUNMAP => sys_munmap(arg1, arg2, arg3),
+        SYS_MKNODAT => sys_mknodat(arg1, arg2, arg3, arg4),
+        SYS_MKDIRAT => sys_mkdirat(arg1, arg2, arg3),
+        SYS_UNLINKAT => sys_unlinkat(arg1, arg2, arg3),
+        SYS_RENAMEAT2 => sys_renameat2(arg1, arg2, arg3, arg4, arg5),
+        SYS_LINKAT => sys_linkat(arg1, arg2, arg3, arg4, arg5),
+        SYS_SYMLINKAT => sys_symlinkat(arg1, arg2, arg3),
+        SYS_READLINKAT => sys_readlinkat(arg1, arg2, arg3, arg4),
+        SYS_GETDENTS64 => sys_getdents64(arg1, arg2, arg3),
+        SYS_FSTAT => sys_fstat(arg1, arg2, arg3),
+        SYS_NEWFSTATAT => sys_newfstatat(arg1, arg2, arg3, arg4),
+        SYS_STATX => sys_statx(arg1, arg2, arg3, arg4, arg5),
+        SYS_FCHMOD => sys_fchmod(arg1, arg2, arg3),
+        SYS_FCHMODAT => sys_fchmodat(arg1, arg2, arg3, arg4),
+        SYS_FCHOWN => sys_fchown(arg1, arg2, arg3),
+        SYS_FCHOWNAT => sys_fchownat(arg1, arg2, arg3, arg4, arg5),
+        SYS_UTIMENSAT => sys_utimensat(arg1, arg2, arg3, arg4),
+        SYS_CHDIR => sys_chdir(arg1, arg2, arg3),
+        SYS_GETCWD => sys_getcwd(arg1, arg2, arg3),
+        SYS_MOUNT => sys_mount(arg1, arg2, arg3, arg4, arg5),
+        SYS_UMOUNT2 => sys_umount2(arg1, arg2, arg3),
         SYS_EXIT => sys_exit(arg1, arg2, arg3),
         SYS_GETPID => sys_getpid(arg1, arg2, arg3),
+        SYS_KILL => sys_kill(arg1, arg2, arg3),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(arg1, arg2, arg3),
+        SYS_CLOCK_GETRES => sys_clock_getres(arg1, arg2, arg3),
+        SYS_CLOCK_SETTIME => sys_clock_settime(arg1, arg2, arg3),
+        SYS_GETTIMEOFDAY => sys_gettimeofday(arg1, arg2, arg3),
+        SYS_REBOOT => sys_reboot(arg1, arg2, arg3),
+        SYS_GETRANDOM => sys_getrandom(arg1, arg2, arg3),
+        SYS_SETPGID => sys_setpgid(arg1, arg2, arg3),
+        SYS_GETPGID => sys_getpgid(arg1, arg2, arg3),
+        SYS_GETSID => sys_getsid(arg1, arg2, arg3),
+        SYS_SETSID => sys_setsid(arg1, arg2, arg3),
         SYS_FORK => sys_fork(arg1, arg2, arg3),
         SYS_EXECVE => sys_execve(arg1, arg2, arg3),
         SYS_WAIT => sys_wait(arg1, arg2, arg3),
@@ -34,70 +170,378 @@ pub fn handle_syscall(syscall_num: usize, arg1: usize, arg2: usize, arg3: usize)
 }
 
 fn sys_read(fd: usize, buf: usize, len: usize) -> usize {
-    if fd == 0 {
-        let mut bytes_read = 0;
-        let buffer = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
-        
-        for i in 0..len {
-            let ch = crate::user_loader::getchar();
-            buffer[i] = ch;
-            bytes_read += 1;
-            
-            if ch == b'\n' || ch == b'\r' {
-                break;
-            }
-        }
-        
-        bytes_read
-    } else {
-        0
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    match current_task().get_fd(fd) {
+        Some(file) if file.readable() => file.read(buffer).unwrap_or(usize::MAX),
+        _ => usize::MAX,
     }
 }
 
 fn sys_write(fd: usize, buf: usize, len: usize) -> usize {
-    if fd == 1 {
-        let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
-        if let Ok(s) = core::str::from_utf8(slice) {
-            crate::println!("{}", s);
-            len
-        } else {
+    let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    match current_task().get_fd(fd) {
+        Some(file) if file.writable() => file.write(slice).unwrap_or(usize::MAX),
+        _ => usize::MAX,
+    }
+}
+
+fn sys_ioctl(fd: usize, cmd: usize, arg: usize) -> usize {
+    match current_task().get_fd(fd) {
+        Some(file) => file.ioctl(cmd, arg).unwrap_or(usize::MAX),
+        None => usize::MAX,
+    }
+}
+
+/// Reports readiness for each `struct pollfd` without blocking: every file
+/// here is either always ready or waits inside its own read, so the
+/// timeout has nothing to wait for.
+fn sys_ppoll(fds: usize, nfds: usize, _timeout: usize) -> usize {
+    if nfds > IOV_MAX {
+        return usize::MAX;
+    }
+    let entries = unsafe { core::slice::from_raw_parts_mut(fds as *mut [u8; 8], nfds) };
+    let mut ready = 0;
+    for entry in entries.iter_mut() {
+        let fd = i32::from_ne_bytes(entry[0..4].try_into().unwrap());
+        let events = u16::from_ne_bytes(entry[4..6].try_into().unwrap());
+        let revents = if fd < 0 {
             0
+        } else {
+            match current_task().get_fd(fd as usize) {
+                Some(file) => file.poll() & events,
+                None => POLLNVAL,
+            }
+        };
+        entry[6..8].copy_from_slice(&revents.to_ne_bytes());
+        if revents != 0 {
+            ready += 1;
         }
-    } else {
-        0
+    }
+    ready
+}
+
+/// Reads the `struct iovec` array at `iov` as `(base, len)` pairs.
+fn user_iovecs(iov: usize, iovcnt: usize) -> Option<Vec<(usize, usize)>> {
+    if iovcnt > IOV_MAX {
+        return None;
+    }
+    let entries = unsafe { core::slice::from_raw_parts(iov as *const [usize; 2], iovcnt) };
+    Some(entries.iter().map(|&[base, len]| (base, len)).collect())
+}
+
+/// Runs `op` over each buffer in turn, stopping at the first short transfer
+/// the way a single large read or write would.
+fn for_each_iovec(iov: usize, iovcnt: usize, mut op: impl FnMut(usize, usize) -> usize) -> usize {
+    let iovecs = match user_iovecs(iov, iovcnt) {
+        Some(iovecs) => iovecs,
+        None => return usize::MAX,
+    };
+    let mut total = 0;
+    for (base, len) in iovecs {
+        let n = op(base, len);
+        if n == usize::MAX {
+            return if total == 0 { usize::MAX } else { total };
+        }
+        total += n;
+        if n < len {
+            break;
+        }
+    }
+    total
+}
+
+fn sys_readv(fd: usize, iov: usize, iovcnt: usize) -> usize {
+    for_each_iovec(iov, iovcnt, |base, len| sys_read(fd, base, len))
+}
+
+fn sys_writev(fd: usize, iov: usize, iovcnt: usize) -> usize {
+    for_each_iovec(iov, iovcnt, |base, len| sys_write(fd, base, len))
+}
+
+fn sys_pread64(fd: usize, buf: usize, len: usize, offset: usize) -> usize {
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    match current_task().get_fd(fd) {
+        Some(file) if file.readable() => file.read_at(offset, buffer).unwrap_or(usize::MAX),
+        _ => usize::MAX,
+    }
+}
+
+fn sys_pwrite64(fd: usize, buf: usize, len: usize, offset: usize) -> usize {
+    let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    match current_task().get_fd(fd) {
+        Some(file) if file.writable() => file.write_at(offset, slice).unwrap_or(usize::MAX),
+        _ => usize::MAX,
+    }
+}
+
+/// Seeking past the end is allowed; a later write there leaves a hole.
+fn sys_lseek(fd: usize, offset: usize, whence: usize) -> usize {
+    let file = match current_task().get_fd(fd) {
+        Some(file) => file,
+        None => return usize::MAX,
+    };
+    let inode = match file.inode() {
+        Some(inode) if !vfs::is_dir(&inode) => inode,
+        _ => return usize::MAX,
+    };
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset(),
+        SEEK_END => inode.metadata().size,
+        _ => return usize::MAX,
+    };
+    match base.checked_add_signed(offset as isize) {
+        Some(pos) if pos <= isize::MAX as usize => {
+            file.set_offset(pos);
+            pos
+        }
+        _ => usize::MAX,
+    }
+}
+
+fn sys_truncate(path_ptr: usize, len: usize, _arg3: usize) -> usize {
+    if (len as isize) < 0 {
+        return usize::MAX;
+    }
+    match vfs::truncate(&vfs::cwd(), user_str(path_ptr), len) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_ftruncate(fd: usize, len: usize, _arg3: usize) -> usize {
+    if (len as isize) < 0 {
+        return usize::MAX;
+    }
+    let file = match current_task().get_fd(fd) {
+        Some(file) if file.writable() => file,
+        _ => return usize::MAX,
+    };
+    match file.inode() {
+        Some(inode) if !vfs::is_dir(&inode) && inode.truncate(len).is_ok() => 0,
+        _ => usize::MAX,
     }
 }
 
 fn sys_exit(_status: usize, _arg2: usize, _arg3: usize) -> usize {
     unsafe {
         let cur = CURRENT_TASK;
-        let task = &mut TASKS[cur];
-        task.state = TaskState::Exited;
-        task.active = false;
+        TASKS[cur].exit();
         let next = crate::scheduler::next_task();
         crate::scheduler::switch_to_task(next);
     }
 }
 
 fn sys_getpid(_arg1: usize, _arg2: usize, _arg3: usize) -> usize {
-    unsafe { CURRENT_TASK }
+    current_task().pid
+}
+
+/// The time on `clock`. Nothing is suspended, so boot time and the raw
+/// and coarse clocks are all the monotonic one.
+fn clock_now(clock: usize) -> Option<Timespec> {
+    match clock {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => Some(crate::time::realtime()),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => Some(crate::time::monotonic()),
+        _ => None,
+    }
+}
+
+fn write_timespec(ptr: usize, time: Timespec) {
+    unsafe {
+        core::ptr::write_unaligned(ptr as *mut i64, time.sec);
+        core::ptr::write_unaligned((ptr + 8) as *mut i64, time.nsec as i64);
+    }
+}
+
+fn sys_clock_gettime(clock: usize, tp: usize, _arg3: usize) -> usize {
+    match clock_now(clock) {
+        Some(now) if tp != 0 => {
+            write_timespec(tp, now);
+            0
+        }
+        _ => usize::MAX,
+    }
+}
+
+/// Every clock ticks with the `time` CSR.
+fn sys_clock_getres(clock: usize, res: usize, _arg3: usize) -> usize {
+    if clock_now(clock).is_none() {
+        return usize::MAX;
+    }
+    if res != 0 {
+        let tick = (crate::time::NSEC_PER_SEC / crate::time::timebase_freq()).max(1);
+        write_timespec(res, Timespec::new(0, tick as u32));
+    }
+    0
+}
+
+/// Only the realtime clock can be set, and only by root.
+fn sys_clock_settime(clock: usize, tp: usize, _arg3: usize) -> usize {
+    if clock != CLOCK_REALTIME || tp == 0 || current_task().uid != 0 {
+        return usize::MAX;
+    }
+    let (sec, nsec) = unsafe {
+        (core::ptr::read_unaligned(tp as *const i64), core::ptr::read_unaligned((tp + 8) as *const i64))
+    };
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return usize::MAX;
+    }
+    crate::time::set_realtime(Timespec::new(sec, nsec as u32));
+    0
+}
+
+/// The time zone is always UTC.
+fn sys_gettimeofday(tv: usize, tz: usize, _arg3: usize) -> usize {
+    if tv != 0 {
+        let now = crate::time::realtime();
+        unsafe {
+            core::ptr::write_unaligned(tv as *mut i64, now.sec);
+            core::ptr::write_unaligned((tv + 8) as *mut i64, (now.nsec / 1000) as i64);
+        }
+    }
+    if tz != 0 {
+        unsafe { core::ptr::write_unaligned(tz as *mut [i32; 2], [0, 0]) };
+    }
+    0
+}
+
+/// Reads the entropy pool. Nothing can wait in the kernel, so a pool not
+/// yet seeded fails the call whether or not `GRND_NONBLOCK` is given;
+/// `GRND_INSECURE` reads it anyway.
+fn sys_getrandom(buf: usize, len: usize, flags: usize) -> usize {
+    use crate::random::{GRND_INSECURE, GRND_NONBLOCK, GRND_RANDOM};
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+        || flags & (GRND_RANDOM | GRND_INSECURE) == GRND_RANDOM | GRND_INSECURE
+    {
+        return usize::MAX;
+    }
+    if !crate::random::is_ready() && flags & GRND_INSECURE == 0 {
+        return usize::MAX;
+    }
+    if len == 0 {
+        return 0;
+    }
+    if buf == 0 {
+        return usize::MAX;
+    }
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    crate::random::fill(buffer);
+    len
+}
+
+/// Root only, with the magic numbers. Unlike Linux, restart and power off
+/// sync the filesystems and save the snapshot first. There is no
+/// Ctrl-Alt-Del to configure, so those commands just succeed.
+fn sys_reboot(magic1: usize, magic2: usize, cmd: usize) -> usize {
+    let magic2_ok = matches!(
+        magic2,
+        LINUX_REBOOT_MAGIC2 | LINUX_REBOOT_MAGIC2A | LINUX_REBOOT_MAGIC2B | LINUX_REBOOT_MAGIC2C
+    );
+    if magic1 as u32 as usize != LINUX_REBOOT_MAGIC1 || !magic2_ok || current_task().uid != 0 {
+        return usize::MAX;
+    }
+    match cmd as u32 as usize {
+        LINUX_REBOOT_CMD_RESTART => crate::power::shutdown(crate::power::PowerAction::Reboot),
+        LINUX_REBOOT_CMD_POWER_OFF => crate::power::shutdown(crate::power::PowerAction::PowerOff),
+        LINUX_REBOOT_CMD_HALT => {
+            crate::println!("System halted.");
+            crate::power::halt()
+        }
+        LINUX_REBOOT_CMD_CAD_ON | LINUX_REBOOT_CMD_CAD_OFF => 0,
+        _ => usize::MAX,
+    }
+}
+
+/// `pid` > 0 names one task, 0 the caller's process group and -`pgid`
+/// any other group.
+fn sys_kill(pid: usize, sig: usize, _arg3: usize) -> usize {
+    let pid = pid as isize;
+    let sig = sig as u32;
+    let sent = match pid {
+        0 => crate::signal::send_group(current_task().pgid, sig),
+        p if p > 0 => crate::signal::send(p as usize, sig),
+        -1 => return usize::MAX,
+        p => crate::signal::send_group(-p as usize, sig),
+    };
+    if sent { 0 } else { usize::MAX }
+}
+
+/// Looks up the task `pid`, with 0 meaning the caller.
+fn task_by_pid(pid: usize) -> Option<&'static mut Task> {
+    if pid == 0 {
+        return Some(current_task());
+    }
+    unsafe { (*core::ptr::addr_of_mut!(TASKS)).iter_mut().find(|t| t.active && t.pid == pid) }
+}
+
+/// Moves `pid` (0 for the caller) into group `pgid` (0 for a new group
+/// named after it) within its own session.
+fn sys_setpgid(pid: usize, pgid: usize, _arg3: usize) -> usize {
+    let sid = current_task().sid;
+    let task = match task_by_pid(pid) {
+        Some(task) if task.sid == sid && task.pid != task.sid => task,
+        _ => return usize::MAX,
+    };
+    let pgid = if pgid == 0 { task.pid } else { pgid };
+    let in_session = unsafe { (*core::ptr::addr_of!(TASKS)).iter().any(|t| t.active && t.pgid == pgid && t.sid == sid) };
+    if pgid != task.pid && !in_session {
+        return usize::MAX;
+    }
+    task.pgid = pgid;
+    0
+}
+
+fn sys_getpgid(pid: usize, _arg2: usize, _arg3: usize) -> usize {
+    task_by_pid(pid).map_or(usize::MAX, |task| task.pgid)
+}
+
+fn sys_getsid(pid: usize, _arg2: usize, _arg3: usize) -> usize {
+    task_by_pid(pid).map_or(usize::MAX, |task| task.sid)
+}
+
+/// Starts a new session and group led by the caller, which must not
+/// already lead a group.
+fn sys_setsid(_arg1: usize, _arg2: usize, _arg3: usize) -> usize {
+    let task = current_task();
+    if crate::signal::group_exists(task.pid) {
+        return usize::MAX;
+    }
+    task.pgid = task.pid;
+    task.sid = task.pid;
+    task.pid
 }
 
 fn sys_fork(_arg1: usize, _arg2: usize, _arg3: usize) -> usize {
     unsafe {
         let parent = CURRENT_TASK;
-        for (i, task) in TASKS.iter_mut().enumerate() {
-            if !task.active {
-                *task = TASKS[parent].clone();
-                task.pid = i;
-                task.ppid = parent;
-                task.state = TaskState::Ready;
-                task.active = true;
-                return i;
-            }
-        }
+        let child = match (0..MAX_TASKS).find(|&i| !TASKS[i].active) {
+            Some(i) => i,
+            None => return usize::MAX,
+        };
+        let mm = match TASKS[parent].mm.as_mut() {
+            Some(mm) => match mm.fork() {
+                Some(child_mm) => Some(child_mm),
+                None => return usize::MAX,
+            },
+            None => None,
+        };
+        let pid = crate::scheduler::alloc_pid();
+        TASKS[child] = Task {
+            ctx: TASKS[parent].ctx.clone(),
+            active: true,
+            pid,
+            ppid: TASKS[parent].pid,
+            state: TaskState::Ready,
+            mm,
+            fds: TASKS[parent].fds.clone(),
+            cwd: TASKS[parent].cwd.clone(),
+            uid: TASKS[parent].uid,
+            gid: TASKS[parent].gid,
+            pgid: TASKS[parent].pgid,
+            sid: TASKS[parent].sid,
+        };
+        pid
     }
-    usize::MAX
 }
 
 fn sys_execve(entry: usize, _argv: usize, _envp: usize) -> usize {
@@ -130,9 +574,10 @@ fn sys_ramfs_create(name_ptr: usize, data_ptr: usize, data_len: usize) -> usize
         }
     };
     let data = unsafe { core::slice::from_raw_parts(data_ptr as *const u8, data_len) };
-    let fs = ramfs_mut();
-    fs.create_file(name, data);
-    0
+    match vfs::create_file(&vfs::cwd(), name, data) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
 }
 
 fn sys_ramfs_read(name_ptr: usize, buf_ptr: usize, buf_len: usize) -> usize {
@@ -143,16 +588,10 @@ fn sys_ramfs_read(name_ptr: usize, buf_ptr: usize, buf_len: usize) -> usize {
             Err(_) => "",
         }
     };
-    let fs = ramfs_mut();
-    if let Some(data) = fs.read_file(name) {
-        let copy_len = core::cmp::min(buf_len, data.len());
-        unsafe {
-            core::ptr::copy_nonoverlapping(data.as_ptr(), buf_ptr as *mut u8, copy_len);
-        }
-        copy_len
-    } else {
-        0
-    }
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf_ptr as *mut u8, buf_len) };
+    vfs::resolve(&vfs::cwd(), name)
+        .and_then(|inode| inode.read_at(0, buf))
+        .unwrap_or(0)
 }
 
 fn sys_ramfs_write(name_ptr: usize, data_ptr: usize, data_len: usize) -> usize {
@@ -164,20 +603,20 @@ fn sys_ramfs_write(name_ptr: usize, data_ptr: usize, data_len: usize) -> usize {
         }
     };
     let data = unsafe { core::slice::from_raw_parts(data_ptr as *const u8, data_len) };
-    let fs = ramfs_mut();
-    if fs.write_file(name, data) {
-        0
-    } else {
-        usize::MAX
+    match vfs::write_file(&vfs::cwd(), name, data) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
     }
 }
 
 fn sys_ramfs_list(buf_ptr: usize, buf_len: usize, _unused: usize) -> usize {
-    let fs = ramfs_mut();
-    let files = fs.list_files();
+    let files = match vfs::cwd().read_dir() {
+        Ok(files) => files,
+        Err(_) => return usize::MAX,
+    };
     let mut total = 0;
-    for name in files {
-        let bytes = name.as_bytes();
+    for entry in files.iter().skip(2) {
+        let bytes = entry.name.as_bytes();
         let copy_len = core::cmp::min(buf_len - total, bytes.len());
         if copy_len == 0 { break; }
         unsafe {
@@ -190,4 +629,427 @@ fn sys_ramfs_list(buf_ptr: usize, buf_len: usize, _unused: usize) -> usize {
         }
     }
     total
-}
\ No newline at end of file
+}
+
+/// Reads a NUL-terminated string from user memory.
+fn user_str<'a>(ptr: usize) -> &'a str {
+    let mut len = 0;
+    unsafe {
+        while len < MAX_PATH && *((ptr + len) as *const u8) != 0 {
+            len += 1;
+        }
+        core::str::from_utf8(core::slice::from_raw_parts(ptr as *const u8, len)).unwrap_or("")
+    }
+}
+
+/// Picks the directory a `*at` path is relative to: the task's working
+/// directory for `AT_FDCWD`, otherwise the directory open on `dirfd`.
+fn at_base(dirfd: usize) -> Option<InodeRef> {
+    if dirfd == AT_FDCWD {
+        return Some(vfs::cwd());
+    }
+    current_task()
+        .get_fd(dirfd)?
+        .inode()
+        .filter(vfs::is_dir)
+}
+
+fn sys_openat(dirfd: usize, path_ptr: usize, flags: usize, mode: usize) -> usize {
+    let base = match at_base(dirfd) {
+        Some(base) => base,
+        None => return usize::MAX,
+    };
+    match vfs::open(&base, user_str(path_ptr), flags, mode as u32) {
+        Ok(file) => current_task().alloc_fd(file),
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_close(fd: usize, _arg2: usize, _arg3: usize) -> usize {
+    if current_task().close_fd(fd) {
+        0
+    } else {
+        usize::MAX
+    }
+}
+
+fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> usize {
+    let shared = flags & MAP_SHARED != 0;
+    if shared == (flags & MAP_PRIVATE != 0) || !offset.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return usize::MAX;
+    }
+    let task = current_task();
+    let (backing, frames) = if flags & MAP_ANONYMOUS != 0 {
+        (Backing::Anonymous, Vec::new())
+    } else {
+        let file = match task.get_fd(fd) {
+            Some(file) => file,
+            None => return usize::MAX,
+        };
+        if !file.readable() || (shared && prot & PROT_WRITE != 0 && !file.writable()) {
+            return usize::MAX;
+        }
+        let inode = match file.inode() {
+            Some(inode) => inode,
+            None => return usize::MAX,
+        };
+        let pages = len.div_ceil(PAGE_SIZE);
+        match inode.map_pages(offset / PAGE_SIZE, pages) {
+            Ok(frames) => (Backing::File { inode, offset }, frames),
+            Err(_) => return usize::MAX,
+        }
+    };
+    if task.mm.is_none() {
+        task.mm = AddressSpace::new();
+    }
+    match task.mm.as_mut() {
+        Some(mm) => mm.mmap(addr, len, prot, flags, backing, frames).unwrap_or(usize::MAX),
+        None => usize::MAX,
+    }
+}
+
+fn sys_munmap(addr: usize, len: usize, _arg3: usize) -> usize {
+    match current_task().mm.as_mut() {
+        Some(mm) => {
+            if mm.munmap(addr, len) {
+                0
+            } else {
+                usize::MAX
+            }
+        }
+        None => usize::MAX,
+    }
+}
+
+fn sys_mkdirat(dirfd: usize, path_ptr: usize, mode: usize) -> usize {
+    match at_base(dirfd).map(|base| vfs::mkdir(&base, user_str(path_ptr), mode as u32)) {
+        Some(Ok(_)) => 0,
+        _ => usize::MAX,
+    }
+}
+
+/// Only character devices and regular files can be made; `mode` carries
+/// the file type in its `S_IFMT` bits.
+fn sys_mknodat(dirfd: usize, path_ptr: usize, mode: usize, dev: usize) -> usize {
+    let base = match at_base(dirfd) {
+        Some(base) => base,
+        None => return usize::MAX,
+    };
+    let path = user_str(path_ptr);
+    let perm = mode as u32 & 0o7777;
+    let result = match mode as u32 & S_IFMT {
+        S_IFCHR => vfs::mknod(&base, path, perm, dev as u64),
+        0 | S_IFREG => vfs::create(&base, path, InodeKind::File, perm),
+        _ => Err(FsError::NotSupported),
+    };
+    match result {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_unlinkat(dirfd: usize, path_ptr: usize, flags: usize) -> usize {
+    let remove_dir = flags & AT_REMOVEDIR != 0;
+    match at_base(dirfd).map(|base| vfs::unlink(&base, user_str(path_ptr), remove_dir)) {
+        Some(Ok(())) => 0,
+        _ => usize::MAX,
+    }
+}
+
+fn sys_renameat2(olddirfd: usize, oldpath: usize, newdirfd: usize, newpath: usize, flags: usize) -> usize {
+    let (old_base, new_base) = match (at_base(olddirfd), at_base(newdirfd)) {
+        (Some(old_base), Some(new_base)) => (old_base, new_base),
+        _ => return usize::MAX,
+    };
+    match vfs::rename(&old_base, user_str(oldpath), &new_base, user_str(newpath), flags) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_linkat(olddirfd: usize, oldpath: usize, newdirfd: usize, newpath: usize, flags: usize) -> usize {
+    let (old_base, new_base) = match (at_base(olddirfd), at_base(newdirfd)) {
+        (Some(old_base), Some(new_base)) => (old_base, new_base),
+        _ => return usize::MAX,
+    };
+    let follow = flags & AT_SYMLINK_FOLLOW != 0;
+    match vfs::link(&old_base, user_str(oldpath), &new_base, user_str(newpath), follow) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_symlinkat(target: usize, newdirfd: usize, linkpath: usize) -> usize {
+    match at_base(newdirfd).map(|base| vfs::symlink(user_str(target), &base, user_str(linkpath))) {
+        Some(Ok(_)) => 0,
+        _ => usize::MAX,
+    }
+}
+
+/// Copies the link target without a trailing NUL, truncated to `bufsiz`.
+fn sys_readlinkat(dirfd: usize, path_ptr: usize, buf: usize, bufsiz: usize) -> usize {
+    let target = match at_base(dirfd).map(|base| vfs::read_link(&base, user_str(path_ptr))) {
+        Some(Ok(target)) => target,
+        _ => return usize::MAX,
+    };
+    let len = core::cmp::min(target.len(), bufsiz);
+    unsafe {
+        core::ptr::copy_nonoverlapping(target.as_ptr(), buf as *mut u8, len);
+    }
+    len
+}
+
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// Fills `buf` with `linux_dirent64` records. The descriptor offset is the
+/// index of the next directory entry, so repeated calls continue the listing.
+fn sys_getdents64(fd: usize, buf: usize, count: usize) -> usize {
+    let file = match current_task().get_fd(fd) {
+        Some(file) => file,
+        None => return usize::MAX,
+    };
+    let entries = match file.inode().map(|inode| inode.read_dir()) {
+        Some(Ok(entries)) => entries,
+        _ => return usize::MAX,
+    };
+    let mut written = 0;
+    for (index, entry) in entries.iter().enumerate().skip(file.offset()) {
+        let name = &entry.name;
+        // d_ino (8) + d_off (8) + d_reclen (2) + d_type (1) + name + NUL, 8-byte aligned
+        let reclen = (19 + name.len() + 1 + 7) & !7;
+        if written + reclen > count {
+            if written == 0 {
+                return usize::MAX;
+            }
+            break;
+        }
+        let d_type = match entry.kind {
+            InodeKind::Directory => DT_DIR,
+            InodeKind::File => DT_REG,
+            InodeKind::Symlink => DT_LNK,
+            InodeKind::CharDevice => DT_CHR,
+        };
+        unsafe {
+            let rec = (buf + written) as *mut u8;
+            core::ptr::write_bytes(rec, 0, reclen);
+            core::ptr::write_unaligned(rec as *mut u64, entry.ino);
+            core::ptr::write_unaligned(rec.add(8) as *mut i64, (index + 1) as i64);
+            core::ptr::write_unaligned(rec.add(16) as *mut u16, reclen as u16);
+            *rec.add(18) = d_type;
+            core::ptr::copy_nonoverlapping(name.as_ptr(), rec.add(19), name.len());
+        }
+        written += reclen;
+        file.set_offset(index + 1);
+    }
+    written
+}
+
+fn sys_chdir(path_ptr: usize, _arg2: usize, _arg3: usize) -> usize {
+    match vfs::resolve(&vfs::cwd(), user_str(path_ptr)) {
+        Ok(inode) if vfs::is_dir(&inode) => {
+            current_task().cwd = Some(inode);
+            0
+        }
+        _ => usize::MAX,
+    }
+}
+
+fn sys_getcwd(buf: usize, size: usize, _arg3: usize) -> usize {
+    let path = match vfs::path_of(&vfs::cwd()) {
+        Some(path) => path,
+        None => return usize::MAX,
+    };
+    if path.len() + 1 > size {
+        return usize::MAX;
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(path.as_ptr(), buf as *mut u8, path.len());
+        *((buf + path.len()) as *mut u8) = 0;
+    }
+    path.len() + 1
+}
+
+fn sys_mount(source: usize, target: usize, fstype: usize, _flags: usize, data: usize) -> usize {
+    let source = if source == 0 { "none" } else { user_str(source) };
+    let data = if data == 0 { "" } else { user_str(data) };
+    match vfs::mount(source, user_str(target), user_str(fstype), data) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_umount2(target: usize, _flags: usize, _arg3: usize) -> usize {
+    match vfs::umount(user_str(target)) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+/// Finds the inode a `*at` call names. An empty path with `AT_EMPTY_PATH`
+/// (or a NULL path, as `utimensat` allows) means `dirfd` itself, and
+/// `AT_SYMLINK_NOFOLLOW` stops at a trailing symlink.
+fn at_inode(dirfd: usize, path_ptr: usize, flags: usize) -> Option<InodeRef> {
+    if path_ptr == 0 || (flags & AT_EMPTY_PATH != 0 && user_str(path_ptr).is_empty()) {
+        return fd_inode(dirfd);
+    }
+    let base = at_base(dirfd)?;
+    if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        vfs::resolve_nofollow(&base, user_str(path_ptr)).ok()
+    } else {
+        vfs::resolve(&base, user_str(path_ptr)).ok()
+    }
+}
+
+fn fd_inode(fd: usize) -> Option<InodeRef> {
+    if fd == AT_FDCWD {
+        return Some(vfs::cwd());
+    }
+    current_task().get_fd(fd)?.inode()
+}
+
+/// Writes the riscv64 `struct stat` (128 bytes) for `meta` to `buf`.
+fn write_stat(buf: usize, meta: &Metadata) {
+    unsafe {
+        let st = buf as *mut u8;
+        core::ptr::write_bytes(st, 0, 128);
+        core::ptr::write_unaligned(st as *mut u64, meta.dev as u64);
+        core::ptr::write_unaligned(st.add(8) as *mut u64, meta.ino);
+        core::ptr::write_unaligned(st.add(16) as *mut u32, meta.st_mode());
+        core::ptr::write_unaligned(st.add(20) as *mut u32, meta.nlink);
+        core::ptr::write_unaligned(st.add(24) as *mut u32, meta.uid);
+        core::ptr::write_unaligned(st.add(28) as *mut u32, meta.gid);
+        core::ptr::write_unaligned(st.add(32) as *mut u64, meta.rdev);
+        core::ptr::write_unaligned(st.add(48) as *mut i64, meta.size as i64);
+        core::ptr::write_unaligned(st.add(56) as *mut i32, PAGE_SIZE as i32);
+        core::ptr::write_unaligned(st.add(64) as *mut i64, meta.size.div_ceil(512) as i64);
+        for (off, ts) in [(72, meta.atime), (88, meta.mtime), (104, meta.ctime)] {
+            core::ptr::write_unaligned(st.add(off) as *mut i64, ts.sec);
+            core::ptr::write_unaligned(st.add(off + 8) as *mut u64, ts.nsec as u64);
+        }
+    }
+}
+
+fn sys_fstat(fd: usize, buf: usize, _arg3: usize) -> usize {
+    match current_task().get_fd(fd).and_then(|file| file.inode()) {
+        Some(inode) => {
+            write_stat(buf, &inode.metadata());
+            0
+        }
+        None => usize::MAX,
+    }
+}
+
+fn sys_newfstatat(dirfd: usize, path_ptr: usize, buf: usize, flags: usize) -> usize {
+    match at_inode(dirfd, path_ptr, flags) {
+        Some(inode) => {
+            write_stat(buf, &inode.metadata());
+            0
+        }
+        None => usize::MAX,
+    }
+}
+
+/// Fills a 256-byte `struct statx`. Everything in `STATX_BASIC_STATS` is
+/// always available, so it is reported whatever mask was requested.
+fn sys_statx(dirfd: usize, path_ptr: usize, flags: usize, _mask: usize, buf: usize) -> usize {
+    let meta = match at_inode(dirfd, path_ptr, flags) {
+        Some(inode) => inode.metadata(),
+        None => return usize::MAX,
+    };
+    unsafe {
+        let st = buf as *mut u8;
+        core::ptr::write_bytes(st, 0, 256);
+        core::ptr::write_unaligned(st as *mut u32, STATX_BASIC_STATS);
+        core::ptr::write_unaligned(st.add(4) as *mut u32, PAGE_SIZE as u32);
+        core::ptr::write_unaligned(st.add(16) as *mut u32, meta.nlink);
+        core::ptr::write_unaligned(st.add(20) as *mut u32, meta.uid);
+        core::ptr::write_unaligned(st.add(24) as *mut u32, meta.gid);
+        core::ptr::write_unaligned(st.add(28) as *mut u16, meta.st_mode() as u16);
+        core::ptr::write_unaligned(st.add(32) as *mut u64, meta.ino);
+        core::ptr::write_unaligned(st.add(40) as *mut u64, meta.size as u64);
+        core::ptr::write_unaligned(st.add(48) as *mut u64, meta.size.div_ceil(512) as u64);
+        for (off, ts) in [(64, meta.atime), (96, meta.ctime), (112, meta.mtime)] {
+            core::ptr::write_unaligned(st.add(off) as *mut i64, ts.sec);
+            core::ptr::write_unaligned(st.add(off + 8) as *mut u32, ts.nsec);
+        }
+        core::ptr::write_unaligned(st.add(128) as *mut u32, vfs::major(meta.rdev));
+        core::ptr::write_unaligned(st.add(132) as *mut u32, vfs::minor(meta.rdev));
+        core::ptr::write_unaligned(st.add(140) as *mut u32, meta.dev as u32);
+    }
+    0
+}
+
+fn sys_fchmod(fd: usize, mode: usize, _arg3: usize) -> usize {
+    match current_task().get_fd(fd).and_then(|file| file.inode()) {
+        Some(inode) if vfs::chmod(&inode, mode as u32).is_ok() => 0,
+        _ => usize::MAX,
+    }
+}
+
+fn sys_fchmodat(dirfd: usize, path_ptr: usize, mode: usize, flags: usize) -> usize {
+    match at_inode(dirfd, path_ptr, flags) {
+        Some(inode) if vfs::chmod(&inode, mode as u32).is_ok() => 0,
+        _ => usize::MAX,
+    }
+}
+
+/// `-1` for either id means "leave unchanged".
+fn chown_ids(uid: usize, gid: usize) -> (Option<u32>, Option<u32>) {
+    let keep = |id: usize| if id as u32 == u32::MAX { None } else { Some(id as u32) };
+    (keep(uid), keep(gid))
+}
+
+fn sys_fchown(fd: usize, uid: usize, gid: usize) -> usize {
+    let (uid, gid) = chown_ids(uid, gid);
+    match current_task().get_fd(fd).and_then(|file| file.inode()) {
+        Some(inode) if vfs::chown(&inode, uid, gid).is_ok() => 0,
+        _ => usize::MAX,
+    }
+}
+
+fn sys_fchownat(dirfd: usize, path_ptr: usize, uid: usize, gid: usize, flags: usize) -> usize {
+    let (uid, gid) = chown_ids(uid, gid);
+    match at_inode(dirfd, path_ptr, flags) {
+        Some(inode) if vfs::chown(&inode, uid, gid).is_ok() => 0,
+        _ => usize::MAX,
+    }
+}
+
+/// `times` is NULL (both now) or two `struct timespec`s whose `tv_nsec` may
+/// be `UTIME_NOW` or `UTIME_OMIT`.
+fn sys_utimensat(dirfd: usize, path_ptr: usize, times: usize, flags: usize) -> usize {
+    let inode = match at_inode(dirfd, path_ptr, flags) {
+        Some(inode) => inode,
+        None => return usize::MAX,
+    };
+    let now = crate::time::now();
+    let (atime, mtime, explicit) = if times == 0 {
+        (Some(now), Some(now), false)
+    } else {
+        let read = |i: usize| unsafe {
+            let ts = (times + i * 16) as *const i64;
+            (core::ptr::read_unaligned(ts), core::ptr::read_unaligned(ts.add(1)))
+        };
+        let mut explicit = false;
+        let mut pick = |(sec, nsec): (i64, i64)| match nsec {
+            UTIME_NOW => Some(Some(now)),
+            UTIME_OMIT => Some(None),
+            0..=999_999_999 => {
+                explicit = true;
+                Some(Some(Timespec::new(sec, nsec as u32)))
+            }
+            _ => None,
+        };
+        match (pick(read(0)), pick(read(1))) {
+            (Some(atime), Some(mtime)) => (atime, mtime, explicit),
+            _ => return usize::MAX,
+        }
+    };
+    match vfs::set_times(&inode, atime, mtime, explicit) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
@@ -0,0 +1,598 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::time::Timespec;
+use crate::vfs::{self, FsError, Inode, InodeKind, InodeRef, SetAttr};
+
+pub const BLOCK_SIZE: usize = 512;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHAR_DEVICE: u8 = b'3';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_PAX_LOCAL: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK: u8 = b'K';
+
+/// Where archive bytes are read from: a slice already in memory, such as
+/// the boot initrd, or a file in the VFS.
+pub trait Source {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError>;
+}
+
+impl Source for [u8] {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let available = self.get(offset..).unwrap_or(&[]);
+        let len = core::cmp::min(available.len(), buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        Ok(len)
+    }
+}
+
+impl Source for Arc<dyn Inode> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        Inode::read_at(self.as_ref(), offset, buf)
+    }
+}
+
+fn read_exact<S: Source + ?Sized>(source: &S, offset: usize, buf: &mut [u8]) -> Result<(), FsError> {
+    let mut done = 0;
+    while done < buf.len() {
+        match source.read_at(offset + done, &mut buf[done..])? {
+            0 => return Err(FsError::InvalidArgument),
+            n => done += n,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemberKind {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+    CharDevice,
+}
+
+/// One archive member, in the form shared by the tar and cpio readers.
+/// `link` is the symlink target or, for hard links, the path of the member
+/// this one names again.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub path: String,
+    pub kind: MemberKind,
+    pub link: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: Timespec,
+    /// Where the contents start in the source, and how many bytes.
+    pub data_offset: usize,
+    pub size: usize,
+    /// Device number of a character device member.
+    pub rdev: u64,
+}
+
+/// Fails unless `inode` is a directory proper; a symlink an earlier member
+/// planted must not carry later ones outside the target.
+fn real_dir(inode: &InodeRef) -> Result<(), FsError> {
+    if vfs::is_dir(inode) { Ok(()) } else { Err(FsError::NotADirectory) }
+}
+
+/// Creates every missing directory leading up to `path`, refusing any
+/// component that is not a directory proper.
+fn make_parents(dest: &InodeRef, path: &str) -> Result<(), FsError> {
+    for (end, _) in path.match_indices('/') {
+        match vfs::mkdir(dest, &path[..end], 0o755) {
+            Ok(_) | Err(FsError::AlreadyExists) => {}
+            Err(err) => return Err(err),
+        }
+        real_dir(&vfs::resolve_nofollow(dest, &path[..end])?)?;
+    }
+    Ok(())
+}
+
+/// Checks that the directories leading up to `path` are all directories
+/// proper, without creating any.
+fn check_parents(dest: &InodeRef, path: &str) -> Result<(), FsError> {
+    for (end, _) in path.match_indices('/') {
+        real_dir(&vfs::resolve_nofollow(dest, &path[..end])?)?;
+    }
+    Ok(())
+}
+
+/// Removes whatever non-directory already sits at `path`.
+fn remove_existing(dest: &InodeRef, path: &str) -> Result<(), FsError> {
+    match vfs::resolve_nofollow(dest, path) {
+        Ok(inode) if vfs::is_dir(&inode) => Err(FsError::IsADirectory),
+        Ok(_) => vfs::unlink(dest, path, false),
+        Err(FsError::NotFound) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Strips leading `/` and `./` so members always land below the target.
+/// Paths that climb out with `..` are refused; ones that would through a
+/// symlink are caught as they are extracted.
+fn clean_path(path: &str) -> Result<Option<&str>, FsError> {
+    let mut path = path.trim_end_matches('/');
+    loop {
+        let stripped = path.trim_start_matches('/');
+        let stripped = stripped.strip_prefix("./").unwrap_or(stripped);
+        if stripped.len() == path.len() {
+            break;
+        }
+        path = stripped;
+    }
+    if path.split('/').any(|c| c == "..") {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(if path.is_empty() || path == "." { None } else { Some(path) })
+}
+
+/// Writes `member` below `dest`, copying file contents from `source`.
+/// Existing files are replaced and existing directories reused; mode,
+/// owner and modification time are applied last. Hard links with
+/// contents of their own (as cpio stores them) are linked and then
+/// written through. Returns the created inode.
+pub fn extract_member<S: Source + ?Sized>(dest: &InodeRef, member: &Member, source: &S) -> Result<InodeRef, FsError> {
+    let path = clean_path(&member.path)?.ok_or(FsError::InvalidPath)?;
+    make_parents(dest, path)?;
+    let inode = match member.kind {
+        MemberKind::Directory => match vfs::mkdir(dest, path, member.mode) {
+            Err(FsError::AlreadyExists) => {
+                let inode = vfs::resolve_nofollow(dest, path)?;
+                real_dir(&inode)?;
+                inode
+            }
+            result => result?,
+        },
+        MemberKind::File => {
+            remove_existing(dest, path)?;
+            vfs::create(dest, path, InodeKind::File, member.mode)?
+        }
+        MemberKind::HardLink => {
+            let target = clean_path(&member.link)?.ok_or(FsError::InvalidPath)?;
+            check_parents(dest, target)?;
+            remove_existing(dest, path)?;
+            vfs::link(dest, target, dest, path, false)?;
+            vfs::resolve_nofollow(dest, path)?
+        }
+        MemberKind::Symlink => {
+            remove_existing(dest, path)?;
+            vfs::symlink(&member.link, dest, path)?
+        }
+        MemberKind::CharDevice => {
+            remove_existing(dest, path)?;
+            vfs::mknod(dest, path, member.mode, member.rdev)?
+        }
+    };
+    if member.size > 0 && matches!(member.kind, MemberKind::File | MemberKind::HardLink) {
+        inode.truncate(0)?;
+        let mut buf = [0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < member.size {
+            let chunk = core::cmp::min(BLOCK_SIZE, member.size - done);
+            read_exact(source, member.data_offset + done, &mut buf[..chunk])?;
+            inode.write_at(done, &buf[..chunk])?;
+            done += chunk;
+        }
+    }
+    inode.set_attr(&SetAttr {
+        mode: if member.kind == MemberKind::Symlink { None } else { Some(member.mode & 0o7777) },
+        uid: Some(member.uid),
+        gid: Some(member.gid),
+        atime: Some(member.mtime),
+        mtime: Some(member.mtime),
+    })?;
+    Ok(inode)
+}
+
+/// Parses an octal field, or GNU base-256 when the top bit is set.
+fn parse_number(field: &[u8]) -> Result<u64, FsError> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let mut value = (field[0] & 0x7f) as u64;
+        for &b in &field[1..] {
+            value = value.checked_mul(256).ok_or(FsError::InvalidArgument)? | b as u64;
+        }
+        return Ok(value);
+    }
+    let text = core::str::from_utf8(field).map_err(|_| FsError::InvalidArgument)?;
+    let text = text.trim_matches(|c| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| FsError::InvalidArgument)
+}
+
+fn field_str(field: &[u8]) -> Result<&str, FsError> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).map_err(|_| FsError::InvalidArgument)
+}
+
+fn checksum(header: &[u8; BLOCK_SIZE]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum()
+}
+
+/// Checks whether `data` starts with a ustar (or GNU) header, to tell a
+/// tarball from a cpio archive.
+pub fn is_tar(data: &[u8]) -> bool {
+    data.len() >= BLOCK_SIZE && &data[257..262] == b"ustar"
+}
+
+/// Only POSIX ustar has the `prefix` field; GNU keeps other data there.
+fn has_prefix_field(header: &[u8; BLOCK_SIZE]) -> bool {
+    &header[257..263] == b"ustar\0"
+}
+
+/// Extended attributes from pax headers. Only the keys that map onto
+/// inode metadata are kept.
+#[derive(Default, Clone)]
+struct PaxAttrs {
+    path: Option<String>,
+    linkpath: Option<String>,
+    size: Option<usize>,
+    mtime: Option<Timespec>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl PaxAttrs {
+    fn parse(mut data: &[u8], into: &mut PaxAttrs) -> Result<(), FsError> {
+        while !data.is_empty() {
+            let space = data.iter().position(|&b| b == b' ').ok_or(FsError::InvalidArgument)?;
+            let len: usize = core::str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(FsError::InvalidArgument)?;
+            if len <= space + 1 || len > data.len() {
+                return Err(FsError::InvalidArgument);
+            }
+            let record = core::str::from_utf8(&data[space + 1..len - 1]).map_err(|_| FsError::InvalidArgument)?;
+            if let Some((key, value)) = record.split_once('=') {
+                match key {
+                    "path" => into.path = Some(value.into()),
+                    "linkpath" => into.linkpath = Some(value.into()),
+                    "size" => into.size = value.parse().ok(),
+                    "uid" => into.uid = value.parse().ok(),
+                    "gid" => into.gid = value.parse().ok(),
+                    "mtime" => into.mtime = parse_pax_time(value),
+                    _ => {}
+                }
+            }
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    fn merge(&self, local: &PaxAttrs) -> PaxAttrs {
+        PaxAttrs {
+            path: local.path.clone().or_else(|| self.path.clone()),
+            linkpath: local.linkpath.clone().or_else(|| self.linkpath.clone()),
+            size: local.size.or(self.size),
+            mtime: local.mtime.or(self.mtime),
+            uid: local.uid.or(self.uid),
+            gid: local.gid.or(self.gid),
+        }
+    }
+}
+
+/// Parses `seconds[.fraction]`.
+fn parse_pax_time(value: &str) -> Option<Timespec> {
+    let (sec, frac) = value.split_once('.').unwrap_or((value, ""));
+    let mut nsec = 0u32;
+    for (i, digit) in frac.bytes().take(9).enumerate() {
+        nsec += (digit.checked_sub(b'0')? as u32) * 10u32.pow(8 - i as u32);
+    }
+    Some(Timespec::new(sec.parse().ok()?, nsec))
+}
+
+/// Walks the members of a ustar archive, applying pax and GNU long-name
+/// headers to the member that follows them.
+pub struct TarReader<'a, S: Source + ?Sized> {
+    source: &'a S,
+    pos: usize,
+    global: PaxAttrs,
+    done: bool,
+}
+
+impl<'a, S: Source + ?Sized> TarReader<'a, S> {
+    pub fn new(source: &'a S) -> Self {
+        Self { source, pos: 0, global: PaxAttrs::default(), done: false }
+    }
+
+    fn read_data(&self, offset: usize, size: usize) -> Result<Vec<u8>, FsError> {
+        let mut data = alloc::vec![0u8; size];
+        read_exact(self.source, offset, &mut data)?;
+        Ok(data)
+    }
+
+    fn next_member(&mut self) -> Result<Option<Member>, FsError> {
+        let mut local = PaxAttrs::default();
+        loop {
+            let mut header = [0u8; BLOCK_SIZE];
+            if self.source.read_at(self.pos, &mut header)? < BLOCK_SIZE || header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            if parse_number(&header[148..156])? != checksum(&header) {
+                return Err(FsError::InvalidArgument);
+            }
+            let data_offset = self.pos + BLOCK_SIZE;
+            let attrs = self.global.merge(&local);
+            let size = match attrs.size {
+                Some(size) if header[156] != TYPE_PAX_LOCAL && header[156] != TYPE_PAX_GLOBAL => size,
+                _ => parse_number(&header[124..136])? as usize,
+            };
+            self.pos = data_offset + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+            let kind = match header[156] {
+                TYPE_PAX_LOCAL => {
+                    PaxAttrs::parse(&self.read_data(data_offset, size)?, &mut local)?;
+                    continue;
+                }
+                TYPE_PAX_GLOBAL => {
+                    PaxAttrs::parse(&self.read_data(data_offset, size)?, &mut self.global)?;
+                    continue;
+                }
+                TYPE_GNU_LONG_NAME | TYPE_GNU_LONG_LINK => {
+                    let data = self.read_data(data_offset, size)?;
+                    let text = String::from(field_str(&data)?);
+                    if header[156] == TYPE_GNU_LONG_NAME {
+                        local.path = Some(text);
+                    } else {
+                        local.linkpath = Some(text);
+                    }
+                    continue;
+                }
+                TYPE_FILE | TYPE_FILE_OLD => MemberKind::File,
+                TYPE_HARD_LINK => MemberKind::HardLink,
+                TYPE_SYMLINK => MemberKind::Symlink,
+                TYPE_DIRECTORY => MemberKind::Directory,
+                TYPE_CHAR_DEVICE => MemberKind::CharDevice,
+                // Block devices, FIFOs and anything unknown are skipped.
+                _ => continue,
+            };
+
+            let path = match attrs.path {
+                Some(path) => path,
+                None => {
+                    let name = field_str(&header[0..100])?;
+                    let prefix = if has_prefix_field(&header) { field_str(&header[345..500])? } else { "" };
+                    if prefix.is_empty() { String::from(name) } else { format!("{}/{}", prefix, name) }
+                }
+            };
+            let link = match attrs.linkpath {
+                Some(link) => link,
+                None => String::from(field_str(&header[157..257])?),
+            };
+            let rdev = if kind == MemberKind::CharDevice {
+                vfs::makedev(parse_number(&header[329..337])? as u32, parse_number(&header[337..345])? as u32)
+            } else {
+                0
+            };
+            let mtime = attrs
+                .mtime
+                .unwrap_or_else(|| Timespec::new(parse_number(&header[136..148]).unwrap_or(0) as i64, 0));
+            return Ok(Some(Member {
+                path,
+                kind,
+                link,
+                mode: parse_number(&header[100..108])? as u32 & 0o7777,
+                uid: attrs.uid.unwrap_or(parse_number(&header[108..116])? as u32),
+                gid: attrs.gid.unwrap_or(parse_number(&header[116..124])? as u32),
+                mtime,
+                data_offset,
+                size: if kind == MemberKind::File { size } else { 0 },
+                rdev,
+            }));
+        }
+    }
+}
+
+impl<'a, S: Source + ?Sized> Iterator for TarReader<'a, S> {
+    type Item = Result<Member, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_member().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Extracts a tar archive below `dest` and returns the number of members
+/// written.
+pub fn extract<S: Source + ?Sized>(source: &S, dest: &InodeRef) -> Result<usize, FsError> {
+    let mut count = 0;
+    for member in TarReader::new(source) {
+        let member = member?;
+        if clean_path(&member.path)?.is_some() {
+            extract_member(dest, &member, source)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes ustar members to a file, one block at a time.
+struct TarWriter<'a> {
+    out: &'a InodeRef,
+    pos: usize,
+}
+
+impl TarWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<(), FsError> {
+        self.out.write_at(self.pos, data)?;
+        self.pos += data.len();
+        Ok(())
+    }
+
+    fn pad(&mut self) -> Result<(), FsError> {
+        let partial = self.pos % BLOCK_SIZE;
+        if partial != 0 {
+            self.write(&[0u8; BLOCK_SIZE][..BLOCK_SIZE - partial])?;
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, name: &str, prefix: &str, link: &str, typeflag: u8, meta: &vfs::Metadata, size: usize) -> Result<(), FsError> {
+        let mut header = [0u8; BLOCK_SIZE];
+        put_str(&mut header[0..100], name);
+        put_octal(&mut header[100..108], (meta.mode & 0o7777) as u64);
+        put_octal(&mut header[108..116], meta.uid as u64);
+        put_octal(&mut header[116..124], meta.gid as u64);
+        put_octal(&mut header[124..136], size as u64);
+        put_octal(&mut header[136..148], meta.mtime.sec.max(0) as u64);
+        header[156] = typeflag;
+        put_str(&mut header[157..257], link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        if typeflag == TYPE_CHAR_DEVICE {
+            put_octal(&mut header[329..337], vfs::major(meta.rdev) as u64);
+            put_octal(&mut header[337..345], vfs::minor(meta.rdev) as u64);
+        }
+        put_str(&mut header[345..500], prefix);
+        let sum = checksum(&header);
+        put_octal(&mut header[148..155], sum);
+        header[155] = b' ';
+        self.write(&header)
+    }
+
+    /// Emits a pax header carrying whatever does not fit ustar, then the
+    /// member's own header.
+    fn member(&mut self, path: &str, link: &str, typeflag: u8, meta: &vfs::Metadata, size: usize) -> Result<(), FsError> {
+        let split = split_ustar_path(path);
+        let mut records = String::new();
+        if split.is_none() {
+            push_pax_record(&mut records, "path", path);
+        }
+        if link.len() > 100 {
+            push_pax_record(&mut records, "linkpath", link);
+        }
+        if !records.is_empty() {
+            self.header("././@PaxHeader", "", "", TYPE_PAX_LOCAL, meta, records.len())?;
+            self.write(records.as_bytes())?;
+            self.pad()?;
+        }
+        let (prefix, name) = split.unwrap_or_else(|| {
+            let mut start = path.len() - 100;
+            while !path.is_char_boundary(start) {
+                start += 1;
+            }
+            ("", &path[start..])
+        });
+        let link = if link.len() > 100 { "" } else { link };
+        self.header(name, prefix, link, typeflag, meta, size)
+    }
+}
+
+fn put_str(field: &mut [u8], value: &str) {
+    let len = core::cmp::min(field.len(), value.len());
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+/// Zero-padded octal, NUL-terminated, filling the field.
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = digits);
+    put_str(field, &text[text.len().saturating_sub(digits)..]);
+}
+
+/// Splits a path into ustar's 155-byte prefix and 100-byte name, breaking
+/// at a slash. `None` means it needs a pax `path` record.
+fn split_ustar_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    path.char_indices()
+        .filter(|&(_, c)| c == '/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+}
+
+/// Appends `"<len> key=value\n"`, where `len` counts its own digits.
+fn push_pax_record(records: &mut String, key: &str, value: &str) {
+    let body = key.len() + value.len() + 3;
+    let mut len = body + 1;
+    while format!("{}", len).len() + body != len {
+        len += 1;
+    }
+    records.push_str(&format!("{} {}={}\n", len, key, value));
+}
+
+/// Archives the tree under `src` into `out`, which is truncated to the
+/// archive length. Member paths are relative to `src`; mounts below it and
+/// `out` itself are skipped. Files with several names are stored once and
+/// then as hard links. Returns the number of members written.
+pub fn create(src: &InodeRef, out: &InodeRef) -> Result<usize, FsError> {
+    if !vfs::is_dir(src) {
+        return Err(FsError::NotADirectory);
+    }
+    let mut writer = TarWriter { out, pos: 0 };
+    let mut seen: BTreeMap<u64, String> = BTreeMap::new();
+    let mut count = 0;
+    let mut stack: Vec<(InodeRef, String)> = alloc::vec![(src.clone(), String::new())];
+    let dev = src.metadata().dev;
+    while let Some((dir, prefix)) = stack.pop() {
+        let mut subdirs = Vec::new();
+        for entry in dir.read_dir()?.into_iter().filter(|e| e.name != "." && e.name != "..") {
+            let inode = dir.lookup(&entry.name)?;
+            let meta = inode.metadata();
+            if meta.dev != dev || vfs::same_inode(&inode, out) {
+                continue;
+            }
+            let path = format!("{}{}", prefix, entry.name);
+            match meta.kind {
+                InodeKind::Directory => {
+                    writer.member(&format!("{}/", path), "", TYPE_DIRECTORY, &meta, 0)?;
+                    subdirs.push((inode, format!("{}/", path)));
+                }
+                InodeKind::Symlink => {
+                    writer.member(&path, &inode.read_link()?, TYPE_SYMLINK, &meta, 0)?;
+                }
+                InodeKind::CharDevice => {
+                    writer.member(&path, "", TYPE_CHAR_DEVICE, &meta, 0)?;
+                }
+                InodeKind::File => {
+                    if let Some(first) = seen.get(&meta.ino) {
+                        writer.member(&path, first, TYPE_HARD_LINK, &meta, 0)?;
+                    } else {
+                        if meta.nlink > 1 {
+                            seen.insert(meta.ino, path.clone());
+                        }
+                        writer.member(&path, "", TYPE_FILE, &meta, meta.size)?;
+                        let mut buf = [0u8; BLOCK_SIZE];
+                        let mut done = 0;
+                        while done < meta.size {
+                            let chunk = core::cmp::min(BLOCK_SIZE, meta.size - done);
+                            read_exact(&inode, done, &mut buf[..chunk])?;
+                            writer.write(&buf[..chunk])?;
+                            done += chunk;
+                        }
+                        writer.pad()?;
+                    }
+                }
+            }
+            count += 1;
+        }
+        // Reverse so directories come out in the order they were listed.
+        stack.extend(subdirs.into_iter().rev());
+    }
+    writer.write(&[0u8; BLOCK_SIZE * 2])?;
+    out.truncate(writer.pos)?;
+    Ok(count)
+}
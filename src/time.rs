@@ -0,0 +1,125 @@
+/// Frequency of the `time` CSR when the device tree does not give one, as
+/// on QEMU `virt`.
+const DEFAULT_TIMEBASE_FREQ: u64 = 10_000_000;
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+static mut TIMEBASE_FREQ: u64 = DEFAULT_TIMEBASE_FREQ;
+
+/// Realtime when the `time` CSR read zero, in nanoseconds since the epoch.
+static mut REALTIME_OFFSET: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    pub const fn new(sec: i64, nsec: u32) -> Self {
+        Self { sec, nsec }
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self::from_nanos((ticks as u128 * NSEC_PER_SEC as u128 / timebase_freq() as u128) as u64)
+    }
+
+    pub fn from_nanos(ns: u64) -> Self {
+        Self {
+            sec: (ns / NSEC_PER_SEC) as i64,
+            nsec: (ns % NSEC_PER_SEC) as u32,
+        }
+    }
+
+    /// Nanoseconds since the epoch; times before it clamp to zero.
+    pub fn as_nanos(&self) -> u64 {
+        if self.sec < 0 {
+            return 0;
+        }
+        (self.sec as u64).saturating_mul(NSEC_PER_SEC).saturating_add(self.nsec as u64)
+    }
+}
+
+pub fn timebase_freq() -> u64 {
+    unsafe { TIMEBASE_FREQ }
+}
+
+pub fn read_time() -> u64 {
+    let ticks: u64;
+    unsafe {
+        core::arch::asm!("rdtime {}", out(reg) ticks);
+    }
+    ticks
+}
+
+/// Time since boot. Never jumps, whatever happens to the wall clock.
+pub fn monotonic() -> Timespec {
+    Timespec::from_ticks(read_time())
+}
+
+/// Wall-clock time: the RTC reading at boot carried forward by the `time`
+/// CSR.
+pub fn realtime() -> Timespec {
+    let offset = unsafe { REALTIME_OFFSET };
+    Timespec::from_nanos(offset.saturating_add(monotonic().as_nanos()))
+}
+
+/// Sets the wall clock, and the RTC with it so the time survives a reboot.
+pub fn set_realtime(time: Timespec) {
+    let ns = time.as_nanos();
+    unsafe {
+        REALTIME_OFFSET = ns.saturating_sub(monotonic().as_nanos());
+    }
+    if let Some(rtc) = crate::rtc::rtc() {
+        rtc.set_ns(ns);
+    }
+}
+
+/// Current time for inode timestamps.
+pub fn now() -> Timespec {
+    realtime()
+}
+
+/// Takes the timebase from `/cpus` and the epoch from the RTC, if there is
+/// one; without it realtime starts at 1970-01-01.
+pub fn init_time() -> bool {
+    let freq = crate::fdt::tree()
+        .and_then(|tree| tree.find("/cpus"))
+        .and_then(|cpus| cpus.prop_u32("timebase-frequency"))
+        .map_or(DEFAULT_TIMEBASE_FREQ, |freq| freq as u64);
+    unsafe {
+        TIMEBASE_FREQ = freq.max(1);
+    }
+    if let Some(rtc) = crate::rtc::rtc() {
+        let boot = rtc.read_ns();
+        unsafe {
+            REALTIME_OFFSET = boot.saturating_sub(monotonic().as_nanos());
+        }
+    }
+    crate::print_ok!("Timekeeping: timebase {} Hz, realtime {}", freq, realtime().sec);
+    true
+}
+
+/// Converts days since 1970-01-01 to a `(year, month, day)` date in the
+/// proleptic Gregorian calendar.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
@@ -56,10 +56,14 @@ pub extern "C" fn trap_handler() {
     let scause = read_csr!("scause");
     let sepc = read_csr!("sepc");
     let sstatus = read_csr!("sstatus");
-    let _stval = read_csr!("stval");
+    let stval = read_csr!("stval");
 
     let is_interrupt = (scause & 0x8000_0000_0000_0000u128 as usize) != 0;
     let code = scause & 0xff;
+    crate::interrupts::count_trap(is_interrupt, code);
+    if is_interrupt {
+        crate::random::add_interrupt_jitter(code);
+    }
 
     if is_interrupt {
         match code {
@@ -74,6 +78,27 @@ pub extern "C" fn trap_handler() {
                 let next = crate::scheduler::next_task();
                 crate::scheduler::switch_to_task(next);
             }
+            INTERRUPT_SUPERVISOR_EXTERNAL => {
+                unsafe {
+                    let cur = crate::scheduler::CURRENT_TASK;
+                    let tasks = &mut crate::scheduler::TASKS;
+                    crate::scheduler::save_context(&mut tasks[cur].ctx, &regs, sepc, regs[2], sstatus);
+                }
+
+                // A handler may have killed or stopped the task, through a
+                // signal character on the console.
+                crate::interrupts::handle_external_interrupt();
+                let next = unsafe {
+                    let cur = crate::scheduler::CURRENT_TASK;
+                    let task = &(*core::ptr::addr_of!(crate::scheduler::TASKS))[cur];
+                    if task.active && task.state == crate::scheduler::TaskState::Ready {
+                        cur
+                    } else {
+                        crate::scheduler::next_task()
+                    }
+                };
+                crate::scheduler::switch_to_task(next);
+            }
             _ => {
                 crate::println!("Unhandled interrupt code: {}", code);
                 unsafe {
@@ -92,7 +117,10 @@ pub extern "C" fn trap_handler() {
             let arg0 = regs[10];
             let arg1 = regs[11];
             let arg2 = regs[12];
-            let ret = crate::syscall::handle_syscall(syscall_num, arg0, arg1, arg2);
+            let arg3 = regs[13];
+            let arg4 = regs[14];
+            let arg5 = regs[15];
+            let ret = crate::syscall::handle_syscall(syscall_num, arg0, arg1, arg2, arg3, arg4, arg5);
             regs[10] = ret;
             let new_sepc = sepc + 4;
             unsafe {
@@ -102,6 +130,21 @@ pub extern "C" fn trap_handler() {
             }
             let next = crate::scheduler::next_task();
             crate::scheduler::switch_to_task(next);
+        } else if exception_code == EXCEPTION_INSTRUCTION_PAGE_FAULT
+            || exception_code == EXCEPTION_LOAD_PAGE_FAULT
+            || exception_code == EXCEPTION_STORE_PAGE_FAULT
+        {
+            unsafe {
+                let cur = crate::scheduler::CURRENT_TASK;
+                let tasks = &mut crate::scheduler::TASKS;
+                crate::scheduler::save_context(&mut tasks[cur].ctx, &regs, sepc, regs[2], sstatus);
+            }
+            if crate::vm::handle_page_fault(stval, exception_code) {
+                unsafe { crate::scheduler::switch_to_task(crate::scheduler::CURRENT_TASK) }
+            }
+            crate::println!("Page fault at {:#x} (scause = {:#x}), dropping to kernel", stval, scause);
+            let next = crate::scheduler::next_task();
+            crate::scheduler::switch_to_task(next);
         } else {
             unsafe {
                 let cur = crate::scheduler::CURRENT_TASK;
@@ -0,0 +1,699 @@
+use alloc::sync::Arc;
+use core::cell::RefCell;
+use crate::chardev::CharDevice;
+use crate::signal::{SIGHUP, SIGINT, SIGQUIT, SIGTSTP, SIGWINCH};
+use crate::time::{read_time, timebase_freq};
+use crate::vfs::{FsError, POLLIN, POLLOUT};
+
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSW: usize = 0x5403;
+pub const TCSETSF: usize = 0x5404;
+pub const TCFLSH: usize = 0x540b;
+pub const TIOCSCTTY: usize = 0x540e;
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const TIOCSWINSZ: usize = 0x5414;
+pub const FIONREAD: usize = 0x541b;
+pub const TIOCNOTTY: usize = 0x5422;
+
+/// `c_iflag` bits.
+pub const ISTRIP: u32 = 0o40;
+pub const INLCR: u32 = 0o100;
+pub const IGNCR: u32 = 0o200;
+pub const ICRNL: u32 = 0o400;
+pub const IXON: u32 = 0o2000;
+
+/// `c_oflag` bits.
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+
+/// `c_cflag` bits.
+pub const B38400: u32 = 0o17;
+pub const CS8: u32 = 0o60;
+pub const CREAD: u32 = 0o200;
+pub const HUPCL: u32 = 0o2000;
+
+/// `c_lflag` bits.
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const ECHONL: u32 = 0o100;
+pub const NOFLSH: u32 = 0o200;
+pub const ECHOCTL: u32 = 0o1000;
+pub const ECHOKE: u32 = 0o4000;
+pub const IEXTEN: u32 = 0o100000;
+
+/// Indices into `c_cc`. A zero entry disables the character.
+pub const NCCS: usize = 19;
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VREPRINT: usize = 12;
+pub const VWERASE: usize = 14;
+pub const VLNEXT: usize = 15;
+pub const VEOL2: usize = 16;
+
+/// Longest line canonical mode will edit, newline included.
+const LINE_MAX: usize = 1024;
+const INPUT_SIZE: usize = 4096;
+const MAX_LINES: usize = 64;
+
+/// The kernel's `struct termios`, as `TCGETS` and `TCSETS` copy it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    /// Cooked mode with echo, as Linux sets up a new terminal.
+    pub const fn new() -> Self {
+        Termios {
+            iflag: ICRNL | IXON,
+            oflag: OPOST | ONLCR,
+            cflag: B38400 | CS8 | CREAD | HUPCL,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+            line: 0,
+            cc: [
+                0x03, 0x1c, 0x7f, 0x15, 0x04, 0, 1, 0, 0x11, 0x13, 0x1a, 0, 0x12, 0x0f, 0x17, 0x16, 0, 0, 0,
+            ],
+        }
+    }
+
+    fn is(&self, index: usize, ch: u8) -> bool {
+        self.cc[index] != 0 && self.cc[index] == ch
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Winsize {
+    pub row: u16,
+    pub col: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+/// A fixed-size FIFO.
+pub struct Queue<T, const N: usize> {
+    buf: [T; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub fn new(fill: T) -> Self {
+        Queue { buf: [fill; N], head: 0, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn push(&mut self, value: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = value;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn front_mut(&mut self) -> Option<&mut T> {
+        if self.len == 0 { None } else { Some(&mut self.buf[self.head]) }
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+/// What a terminal sits on: the console hardware, or the other end of a
+/// pseudo-terminal.
+pub trait TtyPort {
+    /// Sends output that has already been through `c_oflag` processing.
+    fn write(&self, data: &[u8]);
+
+    /// The next received byte, if one has arrived.
+    fn read_byte(&self) -> Option<u8>;
+
+    /// Waits for more input to arrive, or fails the read when none can.
+    fn wait(&self) -> Result<(), FsError>;
+}
+
+struct State {
+    termios: Termios,
+    winsize: Winsize,
+    /// Foreground process group and session, once a task has made this
+    /// its controlling terminal.
+    pgrp: Option<usize>,
+    session: Option<usize>,
+    /// The line being edited in canonical mode.
+    line: [u8; LINE_MAX],
+    line_len: usize,
+    /// Input ready for `read`: whole lines in canonical mode, bytes as they
+    /// arrive otherwise.
+    input: Queue<u8, INPUT_SIZE>,
+    /// Lengths of the lines in `input` in canonical mode. An empty line is
+    /// end of file.
+    lines: Queue<usize, MAX_LINES>,
+    /// Set by `VLNEXT`: the next byte is taken literally.
+    literal_next: bool,
+    /// A signal character arrived for the group the reader is in.
+    interrupted: bool,
+    /// When the last byte arrived, for `VTIME`.
+    last_input: u64,
+}
+
+impl State {
+    fn canonical(&self) -> bool {
+        self.termios.lflag & ICANON != 0
+    }
+
+    fn flush_input(&mut self) {
+        self.line_len = 0;
+        self.input.clear();
+        self.lines.clear();
+        self.literal_next = false;
+    }
+
+    /// Moves the edited line to `input`, or drops it when there is no room.
+    fn commit_line(&mut self) {
+        if self.input.free() >= self.line_len && self.lines.free() > 0 {
+            for i in 0..self.line_len {
+                self.input.push(self.line[i]);
+            }
+            self.lines.push(self.line_len);
+        }
+        self.line_len = 0;
+    }
+
+    /// Copies ready input into `buf`, or returns `None` when the read has
+    /// to wait for more. `start` is when the read began, for `VTIME`.
+    fn take_input(&mut self, buf: &mut [u8], start: u64) -> Option<usize> {
+        if buf.is_empty() {
+            return Some(0);
+        }
+        let count = if self.canonical() {
+            let len = *self.lines.front_mut()?;
+            let count = len.min(buf.len());
+            if count == len {
+                self.lines.pop();
+            } else {
+                *self.lines.front_mut().unwrap() -= count;
+            }
+            count
+        } else {
+            let vmin = self.termios.cc[VMIN] as usize;
+            let vtime = self.termios.cc[VTIME] as u64 * timebase_freq() / 10;
+            let available = self.input.len();
+            let timed_out = match (vmin, vtime) {
+                (_, 0) => false,
+                (0, timeout) => read_time().wrapping_sub(start) >= timeout,
+                (_, timeout) => available > 0 && read_time().wrapping_sub(self.last_input) >= timeout,
+            };
+            let enough = available >= vmin.max(1).min(buf.len());
+            if !enough && !timed_out && !(vmin == 0 && vtime == 0) {
+                return None;
+            }
+            available.min(buf.len())
+        };
+        for byte in &mut buf[..count] {
+            *byte = self.input.pop().unwrap();
+        }
+        Some(count)
+    }
+
+    /// Applies new settings. Leaving canonical mode hands the edited line
+    /// to the reader as it is; entering it turns pending input into a line.
+    fn set_termios(&mut self, termios: Termios, flush: bool) {
+        let was_canonical = self.canonical();
+        self.termios = termios;
+        if flush {
+            self.flush_input();
+        } else if was_canonical && !self.canonical() {
+            self.lines.clear();
+            for i in 0..self.line_len {
+                self.input.push(self.line[i]);
+            }
+            self.line_len = 0;
+        } else if !was_canonical && self.canonical() {
+            self.lines.clear();
+            if !self.input.is_empty() {
+                self.lines.push(self.input.len());
+            }
+        }
+    }
+}
+
+fn is_control(ch: u8) -> bool {
+    (ch < 0x20 && ch != b'\n' && ch != b'\t') || ch == 0x7f
+}
+
+fn is_blank(ch: u8) -> bool {
+    ch == b' ' || ch == b'\t'
+}
+
+/// A terminal: the line discipline between a port and the tasks reading
+/// and writing it.
+pub struct Tty {
+    port: Arc<dyn TtyPort>,
+    state: RefCell<State>,
+}
+
+impl Tty {
+    pub fn new(port: Arc<dyn TtyPort>) -> Arc<Self> {
+        Arc::new(Tty {
+            port,
+            state: RefCell::new(State {
+                termios: Termios::new(),
+                winsize: Winsize { row: 24, col: 80, xpixel: 0, ypixel: 0 },
+                pgrp: None,
+                session: None,
+                line: [0; LINE_MAX],
+                line_len: 0,
+                input: Queue::new(0),
+                lines: Queue::new(0),
+                literal_next: false,
+                interrupted: false,
+                last_input: 0,
+            }),
+        })
+    }
+
+    /// Puts the terminal back in its initial state for a new user.
+    pub fn reset(&self) {
+        let mut st = self.state.borrow_mut();
+        st.flush_input();
+        st.termios = Termios::new();
+        st.winsize = Winsize { row: 24, col: 80, xpixel: 0, ypixel: 0 };
+        st.pgrp = None;
+        st.session = None;
+        st.interrupted = false;
+    }
+
+    /// The other end has gone away: the foreground group gets `SIGHUP` and
+    /// the terminal stops being anyone's controlling terminal. Skipped
+    /// when the hangup comes from a signal this terminal is delivering.
+    pub fn hangup(&self) {
+        if let Ok(mut st) = self.state.try_borrow_mut() {
+            if let Some(pgrp) = st.pgrp.take() {
+                crate::signal::send_group(pgrp, SIGHUP);
+            }
+            st.session = None;
+        }
+    }
+
+    /// Runs whatever the port has received through the line discipline
+    /// now rather than at the next read, so that it is echoed and signal
+    /// characters act right away. Called from interrupt handlers too, so
+    /// it backs off when the terminal is already busy: whoever holds it
+    /// pulls the input in themselves.
+    pub fn process_input(&self) {
+        if let Ok(mut st) = self.state.try_borrow_mut() {
+            self.pull(&mut st);
+        }
+    }
+
+    /// Writes `data` to the port, turning `\n` into `\r\n` under
+    /// `OPOST | ONLCR`.
+    fn output(&self, termios: &Termios, data: &[u8]) {
+        if termios.oflag & (OPOST | ONLCR) != OPOST | ONLCR {
+            self.port.write(data);
+            return;
+        }
+        for chunk in data.split_inclusive(|&b| b == b'\n') {
+            match chunk.strip_suffix(b"\n") {
+                Some(text) => {
+                    self.port.write(text);
+                    self.port.write(b"\r\n");
+                }
+                None => self.port.write(chunk),
+            }
+        }
+    }
+
+    /// Echoes `ch`, control characters as `^X` under `ECHOCTL`.
+    fn echo(&self, st: &State, ch: u8) {
+        if st.termios.lflag & ECHOCTL != 0 && is_control(ch) {
+            self.output(&st.termios, &[b'^', ch ^ 0x40]);
+        } else {
+            self.output(&st.termios, &[ch]);
+        }
+    }
+
+    /// Drops the last character of the line, and from the screen under
+    /// `ECHOE`.
+    fn erase_char(&self, st: &mut State) {
+        if st.line_len == 0 {
+            return;
+        }
+        st.line_len -= 1;
+        let ch = st.line[st.line_len];
+        let lflag = st.termios.lflag;
+        if lflag & ECHO == 0 {
+            return;
+        }
+        if lflag & ECHOE != 0 {
+            let width = if lflag & ECHOCTL != 0 && is_control(ch) { 2 } else { 1 };
+            for _ in 0..width {
+                self.port.write(b"\x08 \x08");
+            }
+        } else {
+            self.echo(st, st.termios.cc[VERASE]);
+        }
+    }
+
+    fn kill_line(&self, st: &mut State, ch: u8) {
+        let lflag = st.termios.lflag;
+        if lflag & ECHO != 0 && lflag & ECHOKE != 0 && lflag & ECHOE != 0 {
+            while st.line_len > 0 {
+                self.erase_char(st);
+            }
+            return;
+        }
+        st.line_len = 0;
+        if lflag & ECHO != 0 {
+            self.echo(st, ch);
+            if lflag & ECHOK != 0 {
+                self.output(&st.termios, b"\n");
+            }
+        }
+    }
+
+    fn erase_word(&self, st: &mut State) {
+        while st.line_len > 0 && is_blank(st.line[st.line_len - 1]) {
+            self.erase_char(st);
+        }
+        while st.line_len > 0 && !is_blank(st.line[st.line_len - 1]) {
+            self.erase_char(st);
+        }
+    }
+
+    /// Adds an ordinary character to the line or the raw input, echoing it.
+    fn store(&self, st: &mut State, ch: u8) {
+        let stored = if st.canonical() {
+            // Keep room for the newline that ends the line.
+            if st.line_len + 1 < LINE_MAX {
+                st.line[st.line_len] = ch;
+                st.line_len += 1;
+                true
+            } else {
+                false
+            }
+        } else {
+            st.input.push(ch)
+        };
+        if stored && st.termios.lflag & ECHO != 0 {
+            self.echo(st, ch);
+        }
+    }
+
+    /// Sends `sig` to the foreground group. A read by a task in that group
+    /// is cut short; with no group to signal, so is any read.
+    fn signal(&self, st: &mut State, sig: u32) {
+        match st.pgrp {
+            Some(pgrp) => {
+                if crate::scheduler::current_task().pgid == pgrp {
+                    st.interrupted = true;
+                }
+                crate::signal::send_group(pgrp, sig);
+            }
+            None => st.interrupted = true,
+        }
+    }
+
+    /// Runs one received byte through the line discipline.
+    fn receive(&self, st: &mut State, mut ch: u8) {
+        let t = st.termios;
+        st.last_input = read_time();
+        if t.iflag & ISTRIP != 0 {
+            ch &= 0x7f;
+        }
+        if st.literal_next {
+            st.literal_next = false;
+            self.store(st, ch);
+            return;
+        }
+        if ch == b'\r' {
+            if t.iflag & IGNCR != 0 {
+                return;
+            }
+            if t.iflag & ICRNL != 0 {
+                ch = b'\n';
+            }
+        } else if ch == b'\n' && t.iflag & INLCR != 0 {
+            ch = b'\r';
+        }
+
+        if t.lflag & ISIG != 0 {
+            let sig = if t.is(VINTR, ch) {
+                Some(SIGINT)
+            } else if t.is(VQUIT, ch) {
+                Some(SIGQUIT)
+            } else if t.is(VSUSP, ch) {
+                Some(SIGTSTP)
+            } else {
+                None
+            };
+            if let Some(sig) = sig {
+                if t.lflag & NOFLSH == 0 {
+                    st.flush_input();
+                }
+                if t.lflag & ECHO != 0 {
+                    self.echo(st, ch);
+                }
+                self.signal(st, sig);
+                return;
+            }
+        }
+
+        if !st.canonical() {
+            self.store(st, ch);
+            return;
+        }
+        if t.is(VERASE, ch) {
+            self.erase_char(st);
+        } else if t.is(VKILL, ch) {
+            self.kill_line(st, ch);
+        } else if t.lflag & IEXTEN != 0 && t.is(VWERASE, ch) {
+            self.erase_word(st);
+        } else if t.lflag & IEXTEN != 0 && t.is(VLNEXT, ch) {
+            st.literal_next = true;
+            if t.lflag & ECHO != 0 && t.lflag & ECHOCTL != 0 {
+                self.port.write(b"^\x08");
+            }
+        } else if t.lflag & IEXTEN != 0 && t.is(VREPRINT, ch) {
+            if t.lflag & ECHO != 0 {
+                self.echo(st, ch);
+                self.output(&t, b"\n");
+                for i in 0..st.line_len {
+                    self.echo(st, st.line[i]);
+                }
+            }
+        } else if t.is(VEOF, ch) {
+            st.commit_line();
+        } else if ch == b'\n' || t.is(VEOL, ch) || (t.lflag & IEXTEN != 0 && t.is(VEOL2, ch)) {
+            st.line[st.line_len] = ch;
+            st.line_len += 1;
+            if t.lflag & ECHO != 0 || (ch == b'\n' && t.lflag & ECHONL != 0) {
+                self.echo(st, ch);
+            }
+            st.commit_line();
+        } else {
+            self.store(st, ch);
+        }
+    }
+
+    /// Feeds everything the port has received through `receive`.
+    fn pull(&self, st: &mut State) {
+        while st.input.free() > 0 {
+            match self.port.read_byte() {
+                Some(ch) => self.receive(st, ch),
+                None => break,
+            }
+        }
+    }
+
+    /// Makes this the controlling terminal of the caller's session, with
+    /// the caller's group in the foreground.
+    fn set_controlling(&self, st: &mut State) {
+        let task = crate::scheduler::current_task();
+        st.session = Some(task.sid);
+        st.pgrp = Some(task.pgid);
+    }
+}
+
+/// Copies an ioctl argument in from the caller.
+pub fn read_user<T: Copy>(arg: usize) -> Result<T, FsError> {
+    if arg == 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(unsafe { core::ptr::read_unaligned(arg as *const T) })
+}
+
+/// Copies an ioctl result out to the caller.
+pub fn write_user<T: Copy>(arg: usize, value: T) -> Result<usize, FsError> {
+    if arg == 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    unsafe { core::ptr::write_unaligned(arg as *mut T, value) };
+    Ok(0)
+}
+
+impl CharDevice for Tty {
+    /// Blocks according to the mode: for a whole line in canonical mode,
+    /// by `VMIN` and `VTIME` otherwise.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let start = read_time();
+        loop {
+            let timed = {
+                let mut st = self.state.borrow_mut();
+                self.pull(&mut st);
+                if core::mem::take(&mut st.interrupted) {
+                    return Err(FsError::Interrupted);
+                }
+                if let Some(count) = st.take_input(buf, start) {
+                    return Ok(count);
+                }
+                !st.canonical() && st.termios.cc[VTIME] != 0
+            };
+            // A timed read has to watch the clock rather than sleep.
+            if timed {
+                core::hint::spin_loop();
+            } else {
+                self.port.wait()?;
+            }
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        let termios = self.state.borrow().termios;
+        self.output(&termios, data);
+        Ok(data.len())
+    }
+
+    fn ioctl(&self, cmd: usize, arg: usize) -> Result<usize, FsError> {
+        let mut st = self.state.borrow_mut();
+        match cmd {
+            TCGETS => write_user(arg, st.termios),
+            TCSETS | TCSETSW | TCSETSF => {
+                let termios = read_user::<Termios>(arg)?;
+                st.set_termios(termios, cmd == TCSETSF);
+                Ok(0)
+            }
+            TCFLSH => {
+                // Output is never buffered here, so only input needs it.
+                if arg != 1 {
+                    st.flush_input();
+                }
+                Ok(0)
+            }
+            TIOCGWINSZ => write_user(arg, st.winsize),
+            TIOCSWINSZ => {
+                st.winsize = read_user::<Winsize>(arg)?;
+                if let Some(pgrp) = st.pgrp {
+                    crate::signal::send_group(pgrp, SIGWINCH);
+                }
+                Ok(0)
+            }
+            TIOCGPGRP => match st.pgrp {
+                Some(pgrp) => write_user(arg, pgrp as i32),
+                None => Err(FsError::NotSupported),
+            },
+            TIOCSPGRP => {
+                let pgrp = read_user::<i32>(arg)?;
+                if pgrp < 0 || !crate::signal::group_exists(pgrp as usize) {
+                    return Err(FsError::InvalidArgument);
+                }
+                if st.session.is_none() {
+                    self.set_controlling(&mut st);
+                }
+                st.pgrp = Some(pgrp as usize);
+                Ok(0)
+            }
+            TIOCSCTTY => {
+                self.set_controlling(&mut st);
+                Ok(0)
+            }
+            TIOCNOTTY => {
+                st.session = None;
+                st.pgrp = None;
+                Ok(0)
+            }
+            FIONREAD => write_user(arg, st.input.len() as i32),
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn poll(&self) -> u16 {
+        let mut st = self.state.borrow_mut();
+        self.pull(&mut st);
+        let readable = if st.canonical() { !st.lines.is_empty() } else { !st.input.is_empty() };
+        if readable { POLLIN | POLLOUT } else { POLLOUT }
+    }
+}
+
+/// The system console: the UART, or the SBI console before it is up.
+struct ConsolePort;
+
+impl TtyPort for ConsolePort {
+    fn write(&self, data: &[u8]) {
+        crate::print::write_bytes(data);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        crate::print::try_getchar()
+    }
+
+    fn wait(&self) -> Result<(), FsError> {
+        crate::print::wait_for_input();
+        Ok(())
+    }
+}
+
+static mut CONSOLE: Option<Arc<Tty>> = None;
+
+/// The terminal on the system console, behind `/dev/console` and
+/// `/dev/tty`.
+pub fn console() -> Arc<Tty> {
+    unsafe {
+        (*core::ptr::addr_of_mut!(CONSOLE))
+            .get_or_insert_with(|| Tty::new(Arc::new(ConsolePort)))
+            .clone()
+    }
+}
@@ -0,0 +1,268 @@
+/// Register indices; the device tree's `reg-shift` spaces them out.
+const REG_RBR: usize = 0;
+const REG_THR: usize = 0;
+const REG_DLL: usize = 0;
+const REG_IER: usize = 1;
+const REG_DLM: usize = 1;
+const REG_FCR: usize = 2;
+const REG_LCR: usize = 3;
+const REG_MCR: usize = 4;
+const REG_LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_TX_EMPTY: u8 = 0x02;
+
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const FCR_CLEAR_TX: u8 = 0x04;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+
+/// DTR and RTS, plus OUT2 which gates the interrupt line on PC-style parts.
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TX_IDLE: u8 = 0x40;
+
+const FIFO_DEPTH: usize = 16;
+const BAUD_RATE: u32 = 115_200;
+
+const RX_RING_SIZE: usize = 1024;
+const TX_RING_SIZE: usize = 4096;
+
+/// A fixed-size byte queue.
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Ring { buf: [0; N], head: 0, len: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// An NS16550A-compatible UART. Received bytes are moved into `rx` by the
+/// interrupt handler; output queues in `tx` and is fed to the FIFO as it
+/// drains, with the TX-empty interrupt on while anything is queued.
+pub struct Uart {
+    base: usize,
+    shift: u32,
+    /// Access width in bytes, from `reg-io-width`.
+    width: u32,
+    irq: u32,
+    rx: Ring<RX_RING_SIZE>,
+    tx: Ring<TX_RING_SIZE>,
+    /// Whether the PLIC delivers our interrupts; without them input has to
+    /// be polled.
+    irq_enabled: bool,
+}
+
+impl Uart {
+    fn read(&self, reg: usize) -> u8 {
+        let addr = self.base + (reg << self.shift);
+        unsafe {
+            match self.width {
+                4 => core::ptr::read_volatile(addr as *const u32) as u8,
+                _ => core::ptr::read_volatile(addr as *const u8),
+            }
+        }
+    }
+
+    fn write(&self, reg: usize, value: u8) {
+        let addr = self.base + (reg << self.shift);
+        unsafe {
+            match self.width {
+                4 => core::ptr::write_volatile(addr as *mut u32, value as u32),
+                _ => core::ptr::write_volatile(addr as *mut u8, value),
+            }
+        }
+    }
+
+    /// 8N1 at `BAUD_RATE` (when the input clock is known), FIFOs on and
+    /// cleared, receive interrupts on.
+    fn init_hw(&self, clock: Option<u32>) {
+        self.write(REG_IER, 0);
+        if let Some(clock) = clock {
+            let divisor = core::cmp::max(clock / (16 * BAUD_RATE), 1);
+            self.write(REG_LCR, LCR_DLAB);
+            self.write(REG_DLL, divisor as u8);
+            self.write(REG_DLM, (divisor >> 8) as u8);
+        }
+        self.write(REG_LCR, LCR_8N1);
+        self.write(REG_FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+        self.write(REG_MCR, MCR_DTR_RTS_OUT2);
+        self.write(REG_IER, IER_RX_AVAILABLE);
+    }
+
+    fn receive(&mut self) {
+        while self.read(REG_LSR) & LSR_DATA_READY != 0 {
+            // Input nobody has read for a whole ring is dropped.
+            let byte = self.read(REG_RBR);
+            self.rx.push(byte);
+        }
+    }
+
+    /// Refills the FIFO once it has emptied and keeps the TX-empty
+    /// interrupt on only while output is still queued.
+    fn transmit(&mut self) {
+        if self.read(REG_LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..FIFO_DEPTH {
+                match self.tx.pop() {
+                    Some(byte) => self.write(REG_THR, byte),
+                    None => break,
+                }
+            }
+        }
+        let tx_interrupt = if self.tx.is_empty() { 0 } else { IER_TX_EMPTY };
+        self.write(REG_IER, IER_RX_AVAILABLE | tx_interrupt);
+    }
+}
+
+static mut UART: Option<Uart> = None;
+
+fn uart() -> Option<&'static mut Uart> {
+    unsafe { (*core::ptr::addr_of_mut!(UART)).as_mut() }
+}
+
+/// Whether console I/O goes through this driver yet. Until it does,
+/// `print` falls back to the SBI console.
+pub fn is_ready() -> bool {
+    uart().is_some()
+}
+
+/// Input goes straight through the console's line discipline, so Ctrl-C
+/// and friends reach the foreground group even when nothing is reading.
+fn handle_irq(_irq: u32) {
+    if let Some(uart) = uart() {
+        uart.receive();
+        uart.transmit();
+    }
+    crate::tty::console().process_input();
+}
+
+/// Queues `data` for output, waiting for the FIFO only when the ring is
+/// full.
+pub fn write(data: &[u8]) {
+    let uart = match uart() {
+        Some(uart) => uart,
+        None => return,
+    };
+    for &byte in data {
+        while !uart.tx.push(byte) {
+            uart.transmit();
+            core::hint::spin_loop();
+        }
+    }
+    uart.transmit();
+}
+
+/// Waits until every queued byte has left the UART, for when nothing will
+/// run the interrupt handler again (panics and shutdown).
+pub fn flush() {
+    let uart = match uart() {
+        Some(uart) => uart,
+        None => return,
+    };
+    while !uart.tx.is_empty() || uart.read(REG_LSR) & LSR_TX_IDLE == 0 {
+        uart.transmit();
+        core::hint::spin_loop();
+    }
+}
+
+/// The next received byte, if any. Pending interrupts are serviced first,
+/// since the kernel runs with them masked.
+pub fn getchar() -> Option<u8> {
+    let uart = uart()?;
+    if uart.rx.is_empty() {
+        crate::plic::dispatch();
+        uart.receive();
+    }
+    uart.rx.pop()
+}
+
+/// Sleeps until an interrupt is pending, or just spins when interrupts
+/// are not delivered. With `sstatus.SIE` clear `wfi` does not trap; the
+/// caller dispatches whatever woke it.
+pub fn wait() {
+    match uart() {
+        Some(uart) if uart.irq_enabled => unsafe { core::arch::asm!("wfi") },
+        _ => core::hint::spin_loop(),
+    }
+}
+
+/// The console UART: the one `/chosen/stdout-path` names, or else the
+/// first 16550 in the tree.
+fn probe() -> Option<Uart> {
+    let tree = crate::fdt::tree()?;
+    let stdout = tree
+        .find("/chosen")
+        .and_then(|chosen| chosen.prop_str("stdout-path"))
+        .and_then(|path| tree.find(path.split(':').next().unwrap_or(path)))
+        .filter(|node| node.is_compatible("ns16550a") || node.is_compatible("ns16550"));
+    let node = stdout
+        .or_else(|| tree.find_compatible("ns16550a").next())
+        .or_else(|| tree.find_compatible("ns16550").next())?;
+    let uart = Uart {
+        base: node.reg()?.0,
+        shift: node.prop_u32("reg-shift").unwrap_or(0),
+        width: node.prop_u32("reg-io-width").unwrap_or(1),
+        irq: node.interrupt().unwrap_or(0),
+        rx: Ring::new(),
+        tx: Ring::new(),
+        irq_enabled: false,
+    };
+    uart.init_hw(node.prop_u32("clock-frequency"));
+    Some(uart)
+}
+
+pub fn init_uart() -> bool {
+    let device = match probe() {
+        Some(device) => device,
+        None => {
+            crate::print_info!("No 16550 UART in the device tree, staying on the SBI console");
+            return true;
+        }
+    };
+    let (base, irq) = (device.base, device.irq);
+    unsafe {
+        UART = Some(device);
+    }
+    if crate::plic::register_irq(irq, handle_irq) {
+        uart().unwrap().irq_enabled = true;
+        crate::print_ok!("UART at {:#x}, irq {}", base, irq);
+    } else {
+        crate::print_ok!("UART at {:#x}, no usable irq: input is polled", base);
+    }
+    true
+}
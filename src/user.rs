@@ -162,89 +162,249 @@ fn syscall(num: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
     }
 }
 
-fn read_line(buf: &mut [u8]) -> usize {
-    let mut i = 0;
-    while i < buf.len() {
-        let ch = crate::user_loader::getchar();
-        
-        match ch {
-            b'\r' | b'\n' => {
-                crate::print::sbi_putchar(b'\n');
-                break;
-            }
-            b'\x08' | b'\x7f' => {
-                if i > 0 {
-                    i -= 1;
-                    crate::print::sbi_putchar(b'\x08');
-                    crate::print::sbi_putchar(b' ');
-                    crate::print::sbi_putchar(b'\x08');
-                }
-            }
-            b'\x03' => {
-                while i > 0 {
-                    i -= 1;
-                    crate::print::sbi_putchar(b'\x08');
-                    crate::print::sbi_putchar(b' ');
-                    crate::print::sbi_putchar(b'\x08');
-                }
-            }
-            _ => {
-                if ch >= 32 && ch <= 126 {
-                    buf[i] = ch;
-                    i += 1;
-                    crate::print::sbi_putchar(ch);
-                }
-            }
-        }
+/// Reads a line from the console terminal, which does the editing. `None`
+/// when Ctrl-C threw the line away.
+fn read_line(buf: &mut [u8]) -> Option<usize> {
+    use crate::chardev::CharDevice;
+    match crate::tty::console().read(buf) {
+        Ok(len) => Some(buf[..len].strip_suffix(b"\n").map_or(len, |line| line.len())),
+        Err(_) => None,
     }
-    i
+}
+
+fn print_fs_error(path: &str, err: crate::vfs::FsError) {
+    crate::println!("{}: {}", path, err.to_string());
 }
 
 fn handle_command(input: &str) -> bool {
-    if input == "ls" {
-        let fs = crate::ramfs::ramfs_mut();
-        let files = fs.list_files_detailed();
-        crate::println!("{:<20} {:<8} {:<12} {}", "NAME", "TYPE", "SIZE (bytes)", "CREATED");
-        crate::println!("{}", "-".repeat(50));
-        for file in files {
-            crate::println!("{:<20} {:<8} {:<12} {}", 
-                file.name, 
-                file.file_type.to_string(), 
-                file.size,
-                file.created_at
-            );
+    use crate::vfs;
+    let cwd = vfs::cwd();
+    if input == "ls" || input.starts_with("ls ") {
+        let path = input.strip_prefix("ls").unwrap().trim();
+        let path = if path.is_empty() { "." } else { path };
+        match vfs::resolve(&cwd, path).and_then(|dir| dir.read_dir().map(|e| (dir, e))) {
+            Ok((dir, entries)) => {
+                crate::println!("{:<10} {:>5} {:>5} {:>5} {:>10} {:>12}  {}", "MODE", "LINKS", "UID", "GID", "SIZE", "MODIFIED", "NAME");
+                crate::println!("{}", "-".repeat(64));
+                for entry in entries.iter().skip(2) {
+                    if let Ok(inode) = vfs::resolve_nofollow(&dir, &entry.name) {
+                        let file = inode.metadata();
+                        let target = inode.read_link().map(|t| alloc::format!(" -> {}", t)).unwrap_or_default();
+                        // Device nodes show their number where the size would go.
+                        let size = if file.kind == vfs::InodeKind::CharDevice {
+                            alloc::format!("{}, {}", vfs::major(file.rdev), vfs::minor(file.rdev))
+                        } else {
+                            alloc::format!("{}", file.size)
+                        };
+                        crate::println!("{:<10} {:>5} {:>5} {:>5} {:>10} {:>12}  {}{}",
+                            file.mode_string(),
+                            file.nlink,
+                            file.uid,
+                            file.gid,
+                            size,
+                            file.mtime.sec,
+                            entry.name,
+                            target
+                        );
+                    }
+                }
+            }
+            Err(err) => print_fs_error(path, err),
         }
         crate::println!();
     } else if input.starts_with("cat ") {
         let filename = input.strip_prefix("cat ").unwrap().trim();
-        let fs = crate::ramfs::ramfs_mut();
-        if let Some(data) = fs.read_file(filename) {
-            if let Ok(s) = core::str::from_utf8(data) {
-                crate::println!("{}", s);
-            } else {
-                crate::println!("(binary data)");
+        match vfs::read_file(&cwd, filename) {
+            Ok(data) => {
+                if let Ok(s) = core::str::from_utf8(&data) {
+                    crate::println!("{}", s);
+                } else {
+                    crate::println!("(binary data)");
+                }
             }
-        } else {
-            crate::println!("File not found: {}", filename);
+            Err(err) => print_fs_error(filename, err),
         }
         crate::println!();
     } else if input.starts_with("echo ") {
         let text = input.strip_prefix("echo ").unwrap();
-        crate::println!("{}", text);
-        crate::println!();
+        // `echo text > file` overwrites, `echo text >> file` appends.
+        let redirect = match text.find(">>") {
+            Some(pos) => Some((pos, 2, vfs::O_APPEND)),
+            None => text.find('>').map(|pos| (pos, 1, vfs::O_TRUNC)),
+        };
+        match redirect {
+            Some((pos, len, mode_flag)) => {
+                let path = text[pos + len..].trim();
+                let mut line = alloc::string::String::from(text[..pos].trim());
+                line.push('\n');
+                let flags = vfs::O_WRONLY | vfs::O_CREAT | mode_flag;
+                if let Err(err) = vfs::open(&cwd, path, flags, 0o644).and_then(|f| f.write(line.as_bytes())) {
+                    print_fs_error(path, err);
+                }
+            }
+            None => {
+                crate::println!("{}", text);
+                crate::println!();
+            }
+        }
     } else if input.starts_with("info ") {
         let filename = input.strip_prefix("info ").unwrap().trim();
-        let fs = crate::ramfs::ramfs_mut();
-        if let Some(file) = fs.get_file_info(filename) {
-            crate::println!("File Information:");
-            crate::println!("  Name: {}", file.name);
-            crate::println!("  Type: {}", file.file_type.to_string());
-            crate::println!("  Size: {} bytes", file.size);
-            crate::println!("  Created: {}", file.created_at);
-        } else {
-            crate::println!("File not found: {}", filename);
+        match vfs::resolve_nofollow(&cwd, filename) {
+            Ok(inode) => {
+                let file = inode.metadata();
+                crate::println!("File Information:");
+                crate::println!("  Name: {}", filename);
+                if let Ok(target) = inode.read_link() {
+                    crate::println!("  Target: {}", target);
+                }
+                crate::println!("  Inode: {} (dev {})", file.ino, file.dev);
+                crate::println!("  Mode: {:04o} ({})", file.mode, file.mode_string());
+                crate::println!("  Links: {}", file.nlink);
+                crate::println!("  Owner: uid {} gid {}", file.uid, file.gid);
+                crate::println!("  Size: {} bytes", file.size);
+                if file.kind == vfs::InodeKind::CharDevice {
+                    crate::println!("  Device: {}, {}", vfs::major(file.rdev), vfs::minor(file.rdev));
+                }
+                crate::println!("  Access: {}.{:09}", file.atime.sec, file.atime.nsec);
+                crate::println!("  Modify: {}.{:09}", file.mtime.sec, file.mtime.nsec);
+                crate::println!("  Change: {}.{:09}", file.ctime.sec, file.ctime.nsec);
+            }
+            Err(err) => print_fs_error(filename, err),
         }
         crate::println!();
+    } else if input == "pwd" {
+        crate::println!("{}", vfs::path_of(&cwd).unwrap_or_default());
+    } else if input == "cd" || input.starts_with("cd ") {
+        let path = input.strip_prefix("cd").unwrap().trim();
+        let path = if path.is_empty() { "/" } else { path };
+        match vfs::resolve(&cwd, path) {
+            Ok(inode) if vfs::is_dir(&inode) => {
+                crate::scheduler::current_task().cwd = Some(inode);
+            }
+            Ok(_) => print_fs_error(path, vfs::FsError::NotADirectory),
+            Err(err) => print_fs_error(path, err),
+        }
+    } else if input.starts_with("mkdir ") {
+        let path = input.strip_prefix("mkdir ").unwrap().trim();
+        if let Err(err) = vfs::mkdir(&cwd, path, 0o755) {
+            print_fs_error(path, err);
+        }
+    } else if input.starts_with("rmdir ") {
+        let path = input.strip_prefix("rmdir ").unwrap().trim();
+        if let Err(err) = vfs::unlink(&cwd, path, true) {
+            print_fs_error(path, err);
+        }
+    } else if input.starts_with("rm ") {
+        let path = input.strip_prefix("rm ").unwrap().trim();
+        if let Err(err) = vfs::unlink(&cwd, path, false) {
+            print_fs_error(path, err);
+        }
+    } else if input.starts_with("mv ") {
+        let mut args = input.strip_prefix("mv ").unwrap().split_whitespace();
+        match (args.next(), args.next()) {
+            (Some(from), Some(to)) => {
+                if let Err(err) = vfs::rename(&cwd, from, &cwd, to, 0) {
+                    print_fs_error(from, err);
+                }
+            }
+            _ => crate::println!("Usage: mv <from> <to>"),
+        }
+    } else if input.starts_with("ln ") {
+        let args = input.strip_prefix("ln ").unwrap().trim();
+        let (symbolic, args) = match args.strip_prefix("-s ") {
+            Some(rest) => (true, rest),
+            None => (false, args),
+        };
+        let mut args = args.split_whitespace();
+        match (args.next(), args.next()) {
+            (Some(target), Some(name)) => {
+                let result = if symbolic {
+                    vfs::symlink(target, &cwd, name).map(|_| ())
+                } else {
+                    vfs::link(&cwd, target, &cwd, name, false)
+                };
+                if let Err(err) = result {
+                    print_fs_error(name, err);
+                }
+            }
+            _ => crate::println!("Usage: ln [-s] <target> <name>"),
+        }
+    } else if input == "tar" || input.starts_with("tar ") {
+        let mut args = input.strip_prefix("tar").unwrap().split_whitespace();
+        let mode = args.next().unwrap_or("").trim_start_matches('-').replace(['f', 'v'], "");
+        match (mode.as_str(), args.next(), args.next()) {
+            ("c", Some(archive), Some(dir)) => {
+                let result = vfs::resolve(&cwd, dir).and_then(|src| {
+                    let flags = vfs::O_WRONLY | vfs::O_CREAT | vfs::O_TRUNC;
+                    let out = vfs::open(&cwd, archive, flags, 0o644)?.inode().ok_or(vfs::FsError::InvalidArgument)?;
+                    crate::tar::create(&src, &out)
+                });
+                match result {
+                    Ok(count) => crate::println!("{}: archived {} entries", archive, count),
+                    Err(err) => print_fs_error(archive, err),
+                }
+            }
+            ("x", Some(archive), dir) => {
+                let dir = dir.unwrap_or(".");
+                let result = vfs::resolve(&cwd, archive)
+                    .and_then(|src| vfs::resolve(&cwd, dir).and_then(|dest| crate::tar::extract(&src, &dest)));
+                match result {
+                    Ok(count) => crate::println!("{}: extracted {} entries", archive, count),
+                    Err(err) => print_fs_error(archive, err),
+                }
+            }
+            ("t", Some(archive), None) => match vfs::resolve(&cwd, archive) {
+                Ok(src) => {
+                    for member in crate::tar::TarReader::new(&src) {
+                        match member {
+                            Ok(member) => crate::println!("{:04o} {:>10}  {}", member.mode, member.size, member.path),
+                            Err(err) => {
+                                print_fs_error(archive, err);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(err) => print_fs_error(archive, err),
+            },
+            _ => crate::println!("Usage: tar -c <archive> <dir> | tar -x <archive> [dir] | tar -t <archive>"),
+        }
+    } else if input == "mount" {
+        for mount in vfs::mounts() {
+            let options = if mount.read_only { "ro" } else { "rw" };
+            crate::println!("{} on {} type {} ({})", mount.source, mount.path, mount.fs.name(), options);
+        }
+    } else if input.starts_with("mount ") {
+        let mut args = input.strip_prefix("mount ").unwrap().split_whitespace();
+        match (args.next(), args.next(), args.next()) {
+            (Some(fstype), Some(source), Some(target)) => {
+                if let Err(err) = vfs::mount(source, target, fstype, "") {
+                    print_fs_error(target, err);
+                }
+            }
+            _ => crate::println!("Usage: mount <type> <source> <dir>"),
+        }
+    } else if input.starts_with("umount ") {
+        let path = input.strip_prefix("umount ").unwrap().trim();
+        if let Err(err) = vfs::umount(path) {
+            print_fs_error(path, err);
+        }
+    } else if input == "lsblk" {
+        for dev in crate::block::block_devices() {
+            let kib = dev.sector_count() * crate::block::SECTOR_SIZE as u64 / 1024;
+            crate::println!("{:<8} {:>10} KiB{}", dev.name(), kib, if dev.read_only() { "  ro" } else { "" });
+        }
+    } else if input == "sync" {
+        if let Err(err) = vfs::sync_all() {
+            print_fs_error("sync", err);
+        }
+        if crate::snapshot::configured() {
+            crate::snapshot::report(crate::snapshot::save());
+        }
+    } else if input == "shutdown" || input == "poweroff" {
+        crate::power::shutdown(crate::power::PowerAction::PowerOff);
+    } else if input == "reboot" {
+        crate::power::shutdown(crate::power::PowerAction::Reboot);
     } else if input == "exit" {
         crate::println!("Bye!");
         return true;
@@ -260,8 +420,14 @@ pub fn launch_shell() {
 
     loop {
         crate::print!("> ");
-        let mut buf = [0u8; 64];
-        let len = read_line(&mut buf);
+        let mut buf = [0u8; 256];
+        let len = match read_line(&mut buf) {
+            Some(len) => len,
+            None => {
+                crate::println!();
+                continue;
+            }
+        };
         let input = core::str::from_utf8(&buf[..len]).unwrap_or("").trim();
         
         if handle_command(input) {
@@ -1,4 +1,5 @@
 use core::arch::asm;
+use crate::memory::Frame;
 
 pub static USER_PROG: [u32; 2] = [
     0x00000013, // nop
@@ -12,9 +13,42 @@ pub static USER_PROG2: [u32; 2] = [
 
 pub const USER_PROG_BASE: usize = 0x8040_0000;
 pub const USER_PROG2_BASE: usize = 0x8040_1000;
-pub const USER_STACK_BASE: usize = 0x8050_0000;
 pub const USER_STACK_SIZE: usize = 0x1000;
 
+/// Auxiliary vector keys placed on the initial stack.
+const AT_NULL: usize = 0;
+const AT_PAGESZ: usize = 6;
+const AT_RANDOM: usize = 25;
+
+/// How far below the top of its stack the first task may start, in
+/// 16-byte steps.
+const STACK_RANDOM_SLOTS: usize = 64;
+
+/// The first task's stack. It is a frame of its own rather than a fixed
+/// address, which is where the frame allocator hands out pages.
+static mut INITIAL_STACK: Option<Frame> = None;
+
+/// Builds the stack the first task starts on, as Linux lays it out: argc,
+/// empty argv and envp, and an auxiliary vector whose `AT_RANDOM` points
+/// at 16 random bytes at the very top. The frame starts a random distance
+/// below those. Returns the stack pointer.
+pub fn initial_stack() -> Option<usize> {
+    let frame = Frame::alloc_zeroed()?;
+    let top = frame.addr() + USER_STACK_SIZE;
+    let random_bytes = top - 16;
+    crate::random::fill(&mut frame.as_mut_slice()[USER_STACK_SIZE - 16..]);
+    let words = [0, 0, 0, AT_RANDOM, random_bytes, AT_PAGESZ, crate::memory::PAGE_SIZE, AT_NULL, 0];
+    let gap = crate::random::below(STACK_RANDOM_SLOTS) * 16;
+    let sp = (random_bytes - gap - words.len() * 8) & !15;
+    for (i, word) in words.iter().enumerate() {
+        unsafe { core::ptr::write((sp + i * 8) as *mut usize, *word) };
+    }
+    unsafe {
+        INITIAL_STACK = Some(frame);
+    }
+    Some(sp)
+}
+
 pub fn load_user_programs() -> bool {
     unsafe {
         let code_addr = USER_PROG_BASE as *mut u8;
@@ -29,8 +63,6 @@ pub fn load_user_programs() -> bool {
             code_addr2,
             USER_PROG2.len() * core::mem::size_of::<u32>(),
         );
-        let stack_addr = USER_STACK_BASE as *mut u8;
-        core::ptr::write_bytes(stack_addr, 0, USER_STACK_SIZE);
     }
     true
 }
@@ -38,7 +70,7 @@ pub fn load_user_programs() -> bool {
 pub fn run_user_program() -> ! {
     unsafe {
         let entry = USER_PROG_BASE;
-        let stack = USER_STACK_BASE + USER_STACK_SIZE;
+        let stack = initial_stack().expect("no memory for the user stack");
 
         asm!(
             "mv sp, {stack}",
@@ -52,19 +84,3 @@ pub fn run_user_program() -> ! {
         );
     }
 }
-
-pub fn sbi_getchar() -> i32 {
-    crate::print::sbi_getchar()
-}
-
-pub fn getchar() -> u8 {
-    loop {
-        let result = sbi_getchar();
-        if result >= 0 {
-            return result as u8;
-        }
-        unsafe {
-            core::arch::asm!("nop");
-        }
-    }
-}
\ No newline at end of file
@@ -0,0 +1,964 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::Cell;
+use crate::memory::Frame;
+use crate::time::Timespec;
+
+pub const O_RDONLY: usize = 0o0;
+pub const O_WRONLY: usize = 0o1;
+pub const O_RDWR: usize = 0o2;
+pub const O_ACCMODE: usize = 0o3;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+pub const O_DIRECTORY: usize = 0o200000;
+pub const O_NOFOLLOW: usize = 0o400000;
+
+pub const POLLIN: u16 = 0x001;
+pub const POLLOUT: u16 = 0x004;
+pub const POLLNVAL: u16 = 0x020;
+
+pub const RENAME_NOREPLACE: usize = 1 << 0;
+pub const RENAME_EXCHANGE: usize = 1 << 1;
+
+pub const MAX_NAME_LEN: usize = 255;
+/// Symlinks followed during one lookup before giving up with `TooManyLinks`.
+pub const MAX_SYMLINKS: usize = 40;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+pub const MAY_READ: u32 = 4;
+pub const MAY_WRITE: u32 = 2;
+pub const MAY_EXEC: u32 = 1;
+
+pub type InodeRef = Arc<dyn Inode>;
+pub type FileRef = Arc<dyn File>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    InvalidPath,
+    InvalidArgument,
+    NoSpace,
+    Busy,
+    CrossDevice,
+    NotSupported,
+    PermissionDenied,
+    FileTooLarge,
+    NotSeekable,
+    TooManyLinks,
+    NoDevice,
+    Io,
+    ReadOnly,
+    Interrupted,
+    WouldBlock,
+}
+
+impl FsError {
+    pub fn to_string(self) -> &'static str {
+        match self {
+            FsError::NotFound => "No such file or directory",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::AlreadyExists => "File exists",
+            FsError::NotEmpty => "Directory not empty",
+            FsError::InvalidPath => "Invalid path",
+            FsError::InvalidArgument => "Invalid argument",
+            FsError::NoSpace => "No space left on device",
+            FsError::Busy => "Resource busy",
+            FsError::CrossDevice => "Cross-device link",
+            FsError::NotSupported => "Operation not supported",
+            FsError::PermissionDenied => "Permission denied",
+            FsError::FileTooLarge => "File too large",
+            FsError::NotSeekable => "Illegal seek",
+            FsError::TooManyLinks => "Too many levels of symbolic links",
+            FsError::NoDevice => "No such device",
+            FsError::Io => "Input/output error",
+            FsError::ReadOnly => "Read-only file system",
+            FsError::Interrupted => "Interrupted system call",
+            FsError::WouldBlock => "Resource temporarily unavailable",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InodeKind {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+}
+
+impl InodeKind {
+    /// The `S_IFMT` bits of `st_mode` for this kind of inode.
+    pub fn mode_bits(&self) -> u32 {
+        match self {
+            InodeKind::File => S_IFREG,
+            InodeKind::Directory => S_IFDIR,
+            InodeKind::Symlink => S_IFLNK,
+            InodeKind::CharDevice => S_IFCHR,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub dev: usize,
+    pub ino: u64,
+    pub kind: InodeKind,
+    /// Permission bits only (`0o7777`); see `st_mode` for the full value.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub size: usize,
+    /// Device number for device nodes, encoded as by `makedev`.
+    pub rdev: u64,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+}
+
+impl Metadata {
+    pub fn st_mode(&self) -> u32 {
+        self.kind.mode_bits() | (self.mode & 0o7777)
+    }
+
+    /// Renders the mode like `ls -l`, e.g. `drwxr-xr-x`.
+    pub fn mode_string(&self) -> String {
+        let mut out = String::with_capacity(10);
+        out.push(match self.kind {
+            InodeKind::File => '-',
+            InodeKind::Directory => 'd',
+            InodeKind::Symlink => 'l',
+            InodeKind::CharDevice => 'c',
+        });
+        for shift in [6, 3, 0] {
+            let bits = (self.mode >> shift) & 7;
+            out.push(if bits & 4 != 0 { 'r' } else { '-' });
+            out.push(if bits & 2 != 0 { 'w' } else { '-' });
+            out.push(if bits & 1 != 0 { 'x' } else { '-' });
+        }
+        out
+    }
+}
+
+/// Packs a device number the way glibc's `makedev` does, which is what
+/// `st_rdev` carries.
+pub const fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xffff_f000) << 32) | ((major & 0xfff) << 8) | ((minor & 0xffff_ff00) << 12) | (minor & 0xff)
+}
+
+pub const fn major(dev: u64) -> u32 {
+    (((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff)) as u32
+}
+
+pub const fn minor(dev: u64) -> u32 {
+    (((dev >> 12) & 0xffff_ff00) | (dev & 0xff)) as u32
+}
+
+/// Attribute changes for `Inode::set_attr`; `None` leaves a field alone.
+#[derive(Debug, Clone, Default)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub kind: InodeKind,
+}
+
+/// A mounted filesystem instance.
+pub trait FileSystem {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> InodeRef;
+    /// Device number shared by every inode of this filesystem.
+    fn dev(&self) -> usize;
+    /// Whether the filesystem refuses changes, asked for with `ro` or
+    /// forced by the device.
+    fn read_only(&self) -> bool {
+        false
+    }
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A file or directory inside a filesystem. Directory operations take a
+/// single name; path walking and mount crossing happen in this module.
+pub trait Inode {
+    fn metadata(&self) -> Metadata;
+
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn truncate(&self, _len: usize) -> Result<(), FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<InodeRef, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates `name` with permission bits `mode`, owned by the caller.
+    fn create(&self, _name: &str, _kind: InodeKind, _mode: u32) -> Result<InodeRef, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Applies attribute changes and bumps `ctime`.
+    fn set_attr(&self, _attr: &SetAttr) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Adds `name` as another hard link to `target`, which lives on the
+    /// same filesystem.
+    fn link(&self, _name: &str, _target: &InodeRef) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates a device node for device number `rdev`.
+    fn mknod(&self, _name: &str, _mode: u32, _rdev: u64) -> Result<InodeRef, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn unlink(&self, _name: &str, _remove_dir: bool) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Moves `old_name` in this directory to `new_name` in `new_dir`, which
+    /// is guaranteed to live on the same filesystem.
+    fn rename(&self, _old_name: &str, _new_dir: &InodeRef, _new_name: &str, _flags: usize) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Returns the frames backing `count` pages starting at page `first`,
+    /// for filesystems whose data can be mapped into user space directly.
+    fn map_pages(&self, _first: usize, _count: usize) -> Result<Vec<Option<Arc<Frame>>>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// An open file description, shared between descriptors after `fork`.
+pub trait File {
+    fn flags(&self) -> usize;
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError>;
+
+    /// Reads at `offset` without moving the file offset.
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSeekable)
+    }
+
+    /// Writes at `offset` without moving the file offset.
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSeekable)
+    }
+
+    fn inode(&self) -> Option<InodeRef> {
+        None
+    }
+
+    fn offset(&self) -> usize {
+        0
+    }
+
+    fn set_offset(&self, _offset: usize) {}
+
+    fn ioctl(&self, _cmd: usize, _arg: usize) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Returns which of `POLLIN`/`POLLOUT` would not block right now.
+    fn poll(&self) -> u16 {
+        let mut events = 0;
+        if self.readable() {
+            events |= POLLIN;
+        }
+        if self.writable() {
+            events |= POLLOUT;
+        }
+        events
+    }
+
+    fn readable(&self) -> bool {
+        self.flags() & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags() & O_ACCMODE != O_RDONLY
+    }
+}
+
+/// The `File` used for regular files and directories on any filesystem.
+pub struct InodeFile {
+    inode: InodeRef,
+    offset: Cell<usize>,
+    flags: usize,
+}
+
+impl InodeFile {
+    pub fn new(inode: InodeRef, flags: usize) -> Self {
+        Self { inode, offset: Cell::new(0), flags }
+    }
+}
+
+impl File for InodeFile {
+    fn flags(&self) -> usize {
+        self.flags
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let n = self.inode.read_at(self.offset.get(), buf)?;
+        self.offset.set(self.offset.get() + n);
+        Ok(n)
+    }
+
+    /// With `O_APPEND` every write goes to the current end of the file.
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        let offset = if self.flags & O_APPEND != 0 {
+            self.inode.metadata().size
+        } else {
+            self.offset.get()
+        };
+        let n = self.inode.write_at(offset, data)?;
+        self.offset.set(offset + n);
+        Ok(n)
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.inode.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        self.inode.write_at(offset, data)
+    }
+
+    fn inode(&self) -> Option<InodeRef> {
+        Some(self.inode.clone())
+    }
+
+    fn offset(&self) -> usize {
+        self.offset.get()
+    }
+
+    fn set_offset(&self, offset: usize) {
+        self.offset.set(offset);
+    }
+}
+
+pub struct Mount {
+    pub source: String,
+    pub path: String,
+    pub fs: Arc<dyn FileSystem>,
+    /// The directory this filesystem is mounted over; `None` for `/`.
+    pub mountpoint: Option<InodeRef>,
+    pub read_only: bool,
+}
+
+pub type MountFn = fn(source: &str, data: &str) -> Result<Arc<dyn FileSystem>, FsError>;
+
+pub struct Vfs {
+    pub mounts: Vec<Mount>,
+    pub fs_types: Vec<(&'static str, MountFn)>,
+    next_dev: usize,
+}
+
+pub static mut VFS: Option<Vfs> = None;
+
+pub fn init_vfs() -> bool {
+    unsafe {
+        VFS = Some(Vfs {
+            mounts: Vec::new(),
+            fs_types: Vec::new(),
+            next_dev: 1,
+        });
+    }
+    crate::print_ok!("VFS initialized");
+    true
+}
+
+fn vfs() -> &'static mut Vfs {
+    unsafe { VFS.as_mut().unwrap() }
+}
+
+pub fn alloc_dev() -> usize {
+    let vfs = vfs();
+    let dev = vfs.next_dev;
+    vfs.next_dev += 1;
+    dev
+}
+
+pub fn register_filesystem(name: &'static str, mount: MountFn) {
+    vfs().fs_types.push((name, mount));
+}
+
+pub fn mounts() -> &'static [Mount] {
+    &vfs().mounts
+}
+
+pub fn mount_root(source: &str, fs: Arc<dyn FileSystem>) {
+    vfs().mounts.insert(0, Mount {
+        source: source.into(),
+        path: String::from("/"),
+        read_only: fs.read_only(),
+        fs,
+        mountpoint: None,
+    });
+}
+
+pub fn root() -> InodeRef {
+    vfs().mounts[0].fs.root()
+}
+
+/// The working directory of the current task.
+pub fn cwd() -> InodeRef {
+    crate::scheduler::current_task().cwd.clone().unwrap_or_else(root)
+}
+
+/// The `(uid, gid)` new inodes are owned by and access is checked against.
+pub fn current_cred() -> (u32, u32) {
+    let task = crate::scheduler::current_task();
+    (task.uid, task.gid)
+}
+
+/// Checks `want` (a mask of `MAY_*`) against the owner, group or other bits
+/// of `inode`. Root passes everything except exec on non-executable files.
+pub fn check_access(inode: &InodeRef, want: u32) -> Result<(), FsError> {
+    let meta = inode.metadata();
+    let (uid, gid) = current_cred();
+    if uid == 0 {
+        if want & MAY_EXEC != 0 && meta.kind == InodeKind::File && meta.mode & 0o111 == 0 {
+            return Err(FsError::PermissionDenied);
+        }
+        return Ok(());
+    }
+    let bits = if uid == meta.uid {
+        meta.mode >> 6
+    } else if gid == meta.gid {
+        meta.mode >> 3
+    } else {
+        meta.mode
+    } & 7;
+    if bits & want == want {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+fn check_owner(inode: &InodeRef) -> Result<(), FsError> {
+    let (uid, _) = current_cred();
+    if uid == 0 || uid == inode.metadata().uid {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+pub fn same_inode(a: &InodeRef, b: &InodeRef) -> bool {
+    let (a, b) = (a.metadata(), b.metadata());
+    a.dev == b.dev && a.ino == b.ino
+}
+
+pub fn is_dir(inode: &InodeRef) -> bool {
+    inode.metadata().kind == InodeKind::Directory
+}
+
+pub fn is_symlink(inode: &InodeRef) -> bool {
+    inode.metadata().kind == InodeKind::Symlink
+}
+
+/// Follows mounts stacked on `inode` to the root of the topmost one.
+fn enter_mounts(mut inode: InodeRef) -> InodeRef {
+    while let Some(mount) = vfs()
+        .mounts
+        .iter()
+        .rev()
+        .find(|m| m.mountpoint.as_ref().is_some_and(|mp| same_inode(mp, &inode)))
+    {
+        inode = mount.fs.root();
+    }
+    inode
+}
+
+/// If `inode` is the root of a mounted filesystem, returns the directory it
+/// is mounted on.
+fn covered_mountpoint(inode: &InodeRef) -> Option<InodeRef> {
+    vfs()
+        .mounts
+        .iter()
+        .find(|m| m.mountpoint.is_some() && same_inode(&m.fs.root(), inode))
+        .and_then(|m| m.mountpoint.clone())
+}
+
+pub fn is_mount_boundary(inode: &InodeRef) -> bool {
+    vfs().mounts.iter().any(|m| {
+        same_inode(&m.fs.root(), inode)
+            || m.mountpoint.as_ref().is_some_and(|mp| same_inode(mp, inode))
+    })
+}
+
+fn step(current: &InodeRef, name: &str) -> Result<InodeRef, FsError> {
+    if is_dir(current) {
+        check_access(current, MAY_EXEC)?;
+    }
+    match name {
+        "" | "." => Ok(current.clone()),
+        ".." => {
+            let mut node = current.clone();
+            while let Some(mountpoint) = covered_mountpoint(&node) {
+                node = mountpoint;
+            }
+            Ok(enter_mounts(node.lookup("..")?))
+        }
+        _ => Ok(enter_mounts(current.lookup(name)?)),
+    }
+}
+
+/// Resolves an absolute path, or a relative one starting at `base`,
+/// following symlinks all the way.
+pub fn resolve(base: &InodeRef, path: &str) -> Result<InodeRef, FsError> {
+    walk(base, path, true, &mut 0)
+}
+
+/// Like `resolve`, but a symlink in the last component is returned itself
+/// rather than followed, as `lstat` and `readlink` want.
+pub fn resolve_nofollow(base: &InodeRef, path: &str) -> Result<InodeRef, FsError> {
+    walk(base, path, false, &mut 0)
+}
+
+/// Walks `path` one component at a time. Symlinks in the middle are always
+/// expanded relative to the directory holding them; the last component is
+/// expanded only when `follow` is set or the path ends in a slash. `links`
+/// counts expansions across nested calls so that loops end in
+/// `TooManyLinks`.
+fn walk(base: &InodeRef, path: &str, follow: bool, links: &mut usize) -> Result<InodeRef, FsError> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    let mut current = if path.starts_with('/') { root() } else { base.clone() };
+    let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        let next = step(&current, component)?;
+        let last = components.peek().is_none();
+        if is_symlink(&next) && (!last || follow || path.ends_with('/')) {
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            current = walk(&current, &next.read_link()?, true, links)?;
+        } else {
+            current = next;
+        }
+    }
+    if path.ends_with('/') && !is_dir(&current) {
+        return Err(FsError::NotADirectory);
+    }
+    Ok(current)
+}
+
+/// Splits `path` into the directory that should contain its last component
+/// and that component's name.
+pub fn resolve_parent<'p>(base: &InodeRef, path: &'p str) -> Result<(InodeRef, &'p str), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir_path, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(pos) => (&trimmed[..pos], &trimmed[pos + 1..]),
+        None => ("", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME_LEN {
+        return Err(FsError::InvalidPath);
+    }
+    let dir = if dir_path.is_empty() { base.clone() } else { resolve(base, dir_path)? };
+    if !is_dir(&dir) {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((dir, name))
+}
+
+pub fn create(base: &InodeRef, path: &str, kind: InodeKind, mode: u32) -> Result<InodeRef, FsError> {
+    let (dir, name) = resolve_parent(base, path)?;
+    check_access(&dir, MAY_WRITE | MAY_EXEC)?;
+    dir.create(name, kind, mode & 0o7777)
+}
+
+pub fn mkdir(base: &InodeRef, path: &str, mode: u32) -> Result<InodeRef, FsError> {
+    create(base, path, InodeKind::Directory, mode)
+}
+
+pub fn unlink(base: &InodeRef, path: &str, remove_dir: bool) -> Result<(), FsError> {
+    let (dir, name) = resolve_parent(base, path)?;
+    check_access(&dir, MAY_WRITE | MAY_EXEC)?;
+    if is_mount_boundary(&step(&dir, name)?) {
+        return Err(FsError::Busy);
+    }
+    dir.unlink(name, remove_dir)
+}
+
+pub fn rename(old_base: &InodeRef, old: &str, new_base: &InodeRef, new: &str, flags: usize) -> Result<(), FsError> {
+    let (old_dir, old_name) = resolve_parent(old_base, old)?;
+    let (new_dir, new_name) = resolve_parent(new_base, new)?;
+    if old_dir.metadata().dev != new_dir.metadata().dev {
+        return Err(FsError::CrossDevice);
+    }
+    check_access(&old_dir, MAY_WRITE | MAY_EXEC)?;
+    check_access(&new_dir, MAY_WRITE | MAY_EXEC)?;
+    if is_mount_boundary(&step(&old_dir, old_name)?) {
+        return Err(FsError::Busy);
+    }
+    if let Ok(target) = step(&new_dir, new_name) {
+        if is_mount_boundary(&target) {
+            return Err(FsError::Busy);
+        }
+    }
+    old_dir.rename(old_name, &new_dir, new_name, flags)
+}
+
+/// Opens `path`, creating it with `O_CREAT`. A symlink as the last
+/// component is followed unless `O_NOFOLLOW` (or `O_EXCL`, which never
+/// follows) is given, in which case it is an error.
+pub fn open(base: &InodeRef, path: &str, flags: usize, mode: u32) -> Result<FileRef, FsError> {
+    let exclusive = flags & O_CREAT != 0 && flags & O_EXCL != 0;
+    let found = if flags & O_NOFOLLOW != 0 || exclusive {
+        resolve_nofollow(base, path)
+    } else {
+        resolve(base, path)
+    };
+    let (inode, created) = match found {
+        Ok(_) if exclusive => return Err(FsError::AlreadyExists),
+        Ok(inode) if is_symlink(&inode) => return Err(FsError::TooManyLinks),
+        Ok(inode) => (inode, false),
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+            (create(base, path, InodeKind::File, mode)?, true)
+        }
+        Err(err) => return Err(err),
+    };
+    let file = InodeFile::new(inode.clone(), flags);
+    if !created {
+        let mut want = 0;
+        if file.readable() {
+            want |= MAY_READ;
+        }
+        if file.writable() {
+            want |= MAY_WRITE;
+        }
+        check_access(&inode, want)?;
+    }
+    let dir = is_dir(&inode);
+    if dir && file.writable() {
+        return Err(FsError::IsADirectory);
+    }
+    if !dir && flags & O_DIRECTORY != 0 {
+        return Err(FsError::NotADirectory);
+    }
+    if inode.metadata().kind == InodeKind::CharDevice {
+        return crate::chardev::open(inode, flags);
+    }
+    if !dir && flags & O_TRUNC != 0 && file.writable() {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(file))
+}
+
+pub fn read_file(base: &InodeRef, path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = resolve(base, path)?;
+    let mut data = alloc::vec![0u8; inode.metadata().size];
+    let n = inode.read_at(0, &mut data)?;
+    data.truncate(n);
+    Ok(data)
+}
+
+/// Replaces the contents of an existing file.
+pub fn write_file(base: &InodeRef, path: &str, data: &[u8]) -> Result<(), FsError> {
+    let inode = resolve(base, path)?;
+    inode.write_at(0, data)?;
+    inode.truncate(data.len())
+}
+
+/// `truncate(2)`: needs write permission on the file itself.
+pub fn truncate(base: &InodeRef, path: &str, len: usize) -> Result<(), FsError> {
+    let inode = resolve(base, path)?;
+    if is_dir(&inode) {
+        return Err(FsError::IsADirectory);
+    }
+    check_access(&inode, MAY_WRITE)?;
+    inode.truncate(len)
+}
+
+/// Creates a character device node; only root may do this.
+pub fn mknod(base: &InodeRef, path: &str, mode: u32, rdev: u64) -> Result<InodeRef, FsError> {
+    if current_cred().0 != 0 {
+        return Err(FsError::PermissionDenied);
+    }
+    let (dir, name) = resolve_parent(base, path)?;
+    check_access(&dir, MAY_WRITE | MAY_EXEC)?;
+    dir.mknod(name, mode & 0o7777, rdev)
+}
+
+pub fn create_file(base: &InodeRef, path: &str, data: &[u8]) -> Result<InodeRef, FsError> {
+    let inode = create(base, path, InodeKind::File, 0o644)?;
+    inode.write_at(0, data)?;
+    Ok(inode)
+}
+
+/// Gives the inode at `old` the additional name `new`. Directories cannot
+/// be hard linked; `follow` decides whether a symlink at `old` is linked
+/// itself or the file it points to.
+pub fn link(old_base: &InodeRef, old: &str, new_base: &InodeRef, new: &str, follow: bool) -> Result<(), FsError> {
+    let target = if follow { resolve(old_base, old)? } else { resolve_nofollow(old_base, old)? };
+    if is_dir(&target) {
+        return Err(FsError::PermissionDenied);
+    }
+    let (dir, name) = resolve_parent(new_base, new)?;
+    if dir.metadata().dev != target.metadata().dev {
+        return Err(FsError::CrossDevice);
+    }
+    check_access(&dir, MAY_WRITE | MAY_EXEC)?;
+    dir.link(name, &target)
+}
+
+/// Creates a symlink at `path` whose contents are `target`. The target is
+/// stored as given and need not exist.
+pub fn symlink(target: &str, base: &InodeRef, path: &str) -> Result<InodeRef, FsError> {
+    if target.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    let (dir, name) = resolve_parent(base, path)?;
+    check_access(&dir, MAY_WRITE | MAY_EXEC)?;
+    dir.symlink(name, target)
+}
+
+pub fn read_link(base: &InodeRef, path: &str) -> Result<String, FsError> {
+    resolve_nofollow(base, path)?.read_link()
+}
+
+pub fn chmod(inode: &InodeRef, mode: u32) -> Result<(), FsError> {
+    check_owner(inode)?;
+    inode.set_attr(&SetAttr { mode: Some(mode & 0o7777), ..SetAttr::default() })
+}
+
+/// Changes owner and/or group; only root may do this.
+pub fn chown(inode: &InodeRef, uid: Option<u32>, gid: Option<u32>) -> Result<(), FsError> {
+    if current_cred().0 != 0 {
+        return Err(FsError::PermissionDenied);
+    }
+    inode.set_attr(&SetAttr { uid, gid, ..SetAttr::default() })
+}
+
+/// Sets access and modification times. Explicit times need ownership;
+/// setting both to "now" only needs write access.
+pub fn set_times(inode: &InodeRef, atime: Option<Timespec>, mtime: Option<Timespec>, explicit: bool) -> Result<(), FsError> {
+    if explicit {
+        check_owner(inode)?;
+    } else if check_owner(inode).is_err() {
+        check_access(inode, MAY_WRITE)?;
+    }
+    inode.set_attr(&SetAttr { atime, mtime, ..SetAttr::default() })
+}
+
+/// Builds an absolute path for a directory by walking `..` up to the root,
+/// stepping out of mounted filesystems on the way.
+pub fn path_of(inode: &InodeRef) -> Option<String> {
+    let root = root();
+    let mut names: Vec<String> = Vec::new();
+    let mut node = inode.clone();
+    while !same_inode(&node, &root) {
+        while let Some(mountpoint) = covered_mountpoint(&node) {
+            node = mountpoint;
+        }
+        if same_inode(&node, &root) {
+            break;
+        }
+        let parent = node.lookup("..").ok()?;
+        let ino = node.metadata().ino;
+        let entry = parent.read_dir().ok()?.into_iter().find(|e| e.ino == ino && e.name != "." && e.name != "..")?;
+        names.push(entry.name);
+        node = parent;
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+fn fs_type(fstype: &str) -> Result<MountFn, FsError> {
+    vfs()
+        .fs_types
+        .iter()
+        .find(|(name, _)| *name == fstype)
+        .map(|(_, mount_fn)| *mount_fn)
+        .ok_or(FsError::NotSupported)
+}
+
+pub fn mount(source: &str, target: &str, fstype: &str, data: &str) -> Result<(), FsError> {
+    if current_cred().0 != 0 {
+        return Err(FsError::PermissionDenied);
+    }
+    let mount_fn = fs_type(fstype)?;
+    let mountpoint = resolve(&cwd(), target)?;
+    if !is_dir(&mountpoint) {
+        return Err(FsError::NotADirectory);
+    }
+    let fs = mount_fn(source, data)?;
+    let path = path_of(&mountpoint).unwrap_or_else(|| target.into());
+    vfs().mounts.push(Mount {
+        source: source.into(),
+        path,
+        read_only: fs.read_only(),
+        fs,
+        mountpoint: Some(mountpoint),
+    });
+    Ok(())
+}
+
+/// Replaces the root filesystem with `source` mounted as `fstype`.
+pub fn switch_root(source: &str, fstype: &str, data: &str) -> Result<(), FsError> {
+    replace_root(source, fs_type(fstype)?(source, data)?)
+}
+
+/// Makes `fs` the root filesystem. Mounts sitting on the old root move to
+/// the same paths in the new one, whose directories are created if
+/// missing; nothing changes if any of that fails.
+pub fn replace_root(source: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let new_root = fs.root();
+    let old_dev = vfs().mounts[0].fs.dev();
+    let mut moved = Vec::new();
+    for (index, mount) in vfs().mounts.iter().enumerate() {
+        if mount.mountpoint.as_ref().is_none_or(|mp| mp.metadata().dev != old_dev) {
+            continue;
+        }
+        let path = mount.path.trim_start_matches('/');
+        let mountpoint = match resolve(&new_root, path) {
+            Err(FsError::NotFound) => mkdir(&new_root, path, 0o755)?,
+            result => result?,
+        };
+        if !is_dir(&mountpoint) {
+            return Err(FsError::NotADirectory);
+        }
+        moved.push((index, mountpoint));
+    }
+    let mounts = &mut vfs().mounts;
+    for (index, mountpoint) in moved {
+        mounts[index].mountpoint = Some(mountpoint);
+    }
+    mounts[0] = Mount {
+        source: source.into(),
+        path: String::from("/"),
+        read_only: fs.read_only(),
+        fs,
+        mountpoint: None,
+    };
+    Ok(())
+}
+
+/// Switches to the root filesystem named by `root=` on the command line,
+/// mounted as `rootfstype=` (ext2 by default) and read-only with `ro`.
+/// Without `root=` the initramfs stays the root.
+pub fn init_boot_root() -> bool {
+    let Some(source) = crate::fdt::bootarg("root") else {
+        return true;
+    };
+    let fstype = crate::fdt::bootarg("rootfstype").unwrap_or("ext2");
+    let data = if crate::fdt::bootarg("ro").is_some() { "ro" } else { "" };
+    match switch_root(source, fstype, data) {
+        Ok(()) => {
+            crate::print_ok!("Root filesystem: {} ({})", source, fstype);
+        }
+        Err(err) => {
+            crate::print_fail!("Cannot mount root {}: {}, staying on the initramfs", source, err.to_string());
+        }
+    }
+    true
+}
+
+/// Writes back every mounted filesystem, carrying on past failures and
+/// returning the first.
+pub fn sync_all() -> Result<(), FsError> {
+    let mut result = Ok(());
+    for mount in &vfs().mounts {
+        if let Err(err) = mount.fs.sync() {
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
+/// Whether any task has a file open, its working directory or a mapping on
+/// filesystem `dev`.
+fn dev_in_use(dev: usize) -> bool {
+    let tasks = unsafe { &*core::ptr::addr_of!(crate::scheduler::TASKS) };
+    tasks.iter().filter(|t| t.active).any(|task| {
+        let on_dev = |inode: &InodeRef| inode.metadata().dev == dev;
+        task.fds.iter().flatten().filter_map(|file| file.inode()).any(|inode| on_dev(&inode))
+            || task.cwd.as_ref().is_some_and(on_dev)
+            || task.mm.as_ref().is_some_and(|mm| {
+                mm.regions.iter().any(|region| match &region.backing {
+                    crate::vm::Backing::File { inode, .. } => on_dev(inode),
+                    crate::vm::Backing::Anonymous => false,
+                })
+            })
+    })
+}
+
+pub fn umount(target: &str) -> Result<(), FsError> {
+    if current_cred().0 != 0 {
+        return Err(FsError::PermissionDenied);
+    }
+    let root = resolve(&cwd(), target)?;
+    let mounts = &mut vfs().mounts;
+    let index = mounts
+        .iter()
+        .rposition(|m| m.mountpoint.is_some() && same_inode(&m.fs.root(), &root))
+        .ok_or(FsError::InvalidArgument)?;
+    let dev = mounts[index].fs.dev();
+    if mounts.iter().any(|m| m.mountpoint.as_ref().is_some_and(|mp| mp.metadata().dev == dev)) || dev_in_use(dev) {
+        return Err(FsError::Busy);
+    }
+    mounts[index].fs.sync()?;
+    mounts.remove(index);
+    Ok(())
+}
@@ -0,0 +1,553 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use crate::memory::PAGE_SIZE;
+
+/// QEMU `virt` has eight virtio-mmio slots from here, one page apart, on
+/// interrupts 1-8.
+const DEFAULT_MMIO_BASE: usize = 0x1000_1000;
+const DEFAULT_MMIO_SLOTS: usize = 8;
+const DEFAULT_MMIO_IRQ: u32 = 1;
+
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_ALIGN: usize = 0x03c;
+const REG_QUEUE_PFN: usize = 0x040;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_CONFIG_GENERATION: usize = 0x0fc;
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// Required by every modern (version 2) device.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const DEVICE_NET: u32 = 1;
+pub const DEVICE_BLOCK: u32 = 2;
+pub const DEVICE_CONSOLE: u32 = 3;
+pub const DEVICE_RNG: u32 = 4;
+pub const DEVICE_INPUT: u32 = 18;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Largest queue we set up; the devices we drive never need more in flight.
+pub const MAX_QUEUE_SIZE: u16 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VirtioError {
+    BadMagic,
+    UnsupportedVersion,
+    FeaturesRejected,
+    QueueUnavailable,
+    QueueFull,
+    NoMemory,
+    DeviceError,
+}
+
+impl VirtioError {
+    pub fn to_string(self) -> &'static str {
+        match self {
+            VirtioError::BadMagic => "Not a virtio device",
+            VirtioError::UnsupportedVersion => "Unsupported virtio-mmio version",
+            VirtioError::FeaturesRejected => "Device rejected features",
+            VirtioError::QueueUnavailable => "Queue not available",
+            VirtioError::QueueFull => "Queue full",
+            VirtioError::NoMemory => "Out of memory",
+            VirtioError::DeviceError => "Device reported an error",
+        }
+    }
+}
+
+pub fn device_name(id: u32) -> &'static str {
+    match id {
+        DEVICE_NET => "network",
+        DEVICE_BLOCK => "block",
+        DEVICE_CONSOLE => "console",
+        DEVICE_RNG => "entropy",
+        DEVICE_INPUT => "input",
+        _ => "unknown",
+    }
+}
+
+/// One virtio-mmio register window. Version 1 is the legacy interface QEMU
+/// still defaults to; version 2 is the modern one.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+    device_id: u32,
+    irq: u32,
+}
+
+impl MmioTransport {
+    /// Checks the magic value and version. Returns `Ok(None)` for an empty
+    /// slot, which reports device id 0.
+    pub fn probe(base: usize, irq: u32) -> Result<Option<Self>, VirtioError> {
+        let mut transport = Self { base, version: 0, device_id: 0, irq };
+        if transport.read(REG_MAGIC) != MAGIC_VALUE {
+            return Err(VirtioError::BadMagic);
+        }
+        transport.version = transport.read(REG_VERSION);
+        if transport.version != 1 && transport.version != 2 {
+            return Err(VirtioError::UnsupportedVersion);
+        }
+        transport.device_id = transport.read(REG_DEVICE_ID);
+        Ok(if transport.device_id == 0 { None } else { Some(transport) })
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn set_status(&self, bits: u32) {
+        self.write(REG_STATUS, self.read(REG_STATUS) | bits);
+    }
+
+    /// Resets the device and offers it the subset of `supported` it also
+    /// has, returning what was agreed. Modern devices must accept
+    /// `VIRTIO_F_VERSION_1`, which is added here.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.write(REG_STATUS, 0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+
+        let mut device = 0u64;
+        for sel in 0..2 {
+            self.write(REG_DEVICE_FEATURES_SEL, sel);
+            device |= (self.read(REG_DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+        let wanted = if self.is_legacy() { supported } else { supported | VIRTIO_F_VERSION_1 };
+        let features = device & wanted;
+        for sel in 0..2 {
+            self.write(REG_DRIVER_FEATURES_SEL, sel);
+            self.write(REG_DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+        }
+
+        if self.is_legacy() {
+            self.write(REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            if features & VIRTIO_F_VERSION_1 == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+            self.set_status(STATUS_FEATURES_OK);
+            if self.read(REG_STATUS) & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Tells the device the driver is ready; queues must be set up first.
+    pub fn finish_init(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+        crate::plic::register_irq(self.irq, handle_irq);
+    }
+
+    /// Resets the device, so it lets go of any queues it was given, and
+    /// marks it failed.
+    pub fn fail(&self) {
+        self.write(REG_STATUS, 0);
+        self.set_status(STATUS_FAILED);
+    }
+
+    /// Allocates queue `index` and hands its rings to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.write(REG_QUEUE_SEL, index as u32);
+        let already_used = if self.is_legacy() { self.read(REG_QUEUE_PFN) } else { self.read(REG_QUEUE_READY) };
+        if already_used != 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let max = self.read(REG_QUEUE_NUM_MAX);
+        if max == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let size = core::cmp::min(max, MAX_QUEUE_SIZE as u32) as u16;
+        let queue = Virtqueue::new(index, size)?;
+        self.write(REG_QUEUE_NUM, size as u32);
+        if self.is_legacy() {
+            self.write(REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(REG_QUEUE_PFN, (queue.base / PAGE_SIZE) as u32);
+        } else {
+            let pairs = [
+                (REG_QUEUE_DESC_LOW, REG_QUEUE_DESC_HIGH, queue.desc as usize),
+                (REG_QUEUE_DRIVER_LOW, REG_QUEUE_DRIVER_HIGH, queue.avail as usize),
+                (REG_QUEUE_DEVICE_LOW, REG_QUEUE_DEVICE_HIGH, queue.used as usize),
+            ];
+            for (low, high, addr) in pairs {
+                self.write(low, addr as u32);
+                self.write(high, (addr as u64 >> 32) as u32);
+            }
+            self.write(REG_QUEUE_READY, 1);
+        }
+        Ok(queue)
+    }
+
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        self.write(REG_QUEUE_NOTIFY, queue.index as u32);
+    }
+
+    /// Acknowledges whatever the device signalled and returns the bits:
+    /// 1 for used buffers, 2 for a configuration change.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(REG_INTERRUPT_ACK, status);
+        }
+        status
+    }
+
+    /// Reads the device-specific configuration, retrying while the device
+    /// changes it underneath us.
+    pub fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        loop {
+            let before = if self.is_legacy() { 0 } else { self.read(REG_CONFIG_GENERATION) };
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = unsafe { core::ptr::read_volatile((self.base + REG_CONFIG + offset + i) as *const u8) };
+            }
+            if self.is_legacy() || self.read(REG_CONFIG_GENERATION) == before {
+                break;
+            }
+        }
+    }
+
+    /// Adds a chain, notifies the device and waits for it to come back,
+    /// returning the number of bytes the device wrote. The used ring is
+    /// only looked at again once the device has interrupted; without an
+    /// interrupt handler in place it is polled.
+    pub fn submit_and_wait(&self, queue: &mut Virtqueue, buffers: &[Buffer]) -> Result<u32, VirtioError> {
+        let head = queue.add(buffers)?;
+        let use_irq = crate::plic::irq_counts().any(|(irq, _)| irq == self.irq);
+        take_used_signal(self.irq);
+        self.notify(queue);
+        loop {
+            while let Some((id, len)) = queue.pop_used() {
+                if id == head {
+                    return Ok(len);
+                }
+            }
+            if !use_irq {
+                core::hint::spin_loop();
+                continue;
+            }
+            // Interrupts stay masked in the kernel: `wfi` still wakes on a
+            // pending one, which is then dispatched by hand.
+            while !take_used_signal(self.irq) {
+                unsafe { core::arch::asm!("wfi") };
+                crate::plic::dispatch();
+            }
+        }
+    }
+}
+
+/// One element of a descriptor chain: a physical range the device reads
+/// from, or writes into when `writable` is set.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: usize,
+    pub len: usize,
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn readable(addr: usize, len: usize) -> Self {
+        Self { addr, len, writable: false }
+    }
+
+    pub fn writable(addr: usize, len: usize) -> Self {
+        Self { addr, len, writable: true }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A split virtqueue. The descriptor table, available ring and used ring
+/// share one contiguous allocation laid out the way the legacy interface
+/// requires, with the used ring on its own page boundary; modern devices
+/// are simply given the three addresses.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    base: usize,
+    desc: *mut Descriptor,
+    /// `flags`, `idx`, then `size` ring entries.
+    avail: *mut u16,
+    /// `flags`, `idx`, then `size` used elements.
+    used: *mut u16,
+    /// Pages in the allocation at `base`.
+    pages: usize,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        let n = size as usize;
+        let avail_end = 16 * n + 6 + 2 * n;
+        let used_offset = (avail_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let used_len = 6 + 8 * n;
+        let pages = (used_offset + used_len).div_ceil(PAGE_SIZE);
+        let base = crate::memory::alloc_contiguous(pages).ok_or(VirtioError::NoMemory)?;
+        let mut queue = Self {
+            index,
+            size,
+            base,
+            desc: base as *mut Descriptor,
+            avail: (base + 16 * n) as *mut u16,
+            used: (base + used_offset) as *mut u16,
+            pages,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            queue.descriptor(i).next = (i + 1) % size;
+        }
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&mut self, i: u16) -> &mut Descriptor {
+        unsafe { &mut *self.desc.add(i as usize) }
+    }
+
+    /// Links `buffers` into a descriptor chain and makes it available,
+    /// returning the head descriptor. The device is not notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = self.descriptor(current);
+            desc.addr = buffer.addr as u64;
+            desc.len = buffer.len as u32;
+            desc.flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            current = desc.next;
+        }
+        self.free_head = current;
+        self.num_free -= buffers.len() as u16;
+
+        unsafe {
+            let slot = self.avail.add(2 + (self.avail_idx % self.size) as usize);
+            core::ptr::write_volatile(slot, head);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            core::ptr::write_volatile(self.avail.add(1), self.avail_idx);
+        }
+        Ok(head)
+    }
+
+    /// Whether the device has returned a chain we have not collected yet.
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        unsafe { core::ptr::read_volatile(self.used.add(1)) != self.last_used_idx }
+    }
+
+    /// Collects one returned chain, freeing its descriptors. Yields the
+    /// head descriptor and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        let elem = unsafe {
+            let ring = self.used.add(2) as *const UsedElem;
+            core::ptr::read_volatile(ring.add((self.last_used_idx % self.size) as usize))
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        let head = elem.id as u16;
+        let mut tail = head;
+        let mut count = 1;
+        while self.descriptor(tail).flags & DESC_F_NEXT != 0 {
+            tail = self.descriptor(tail).next;
+            count += 1;
+        }
+        self.descriptor(tail).next = self.free_head;
+        self.free_head = head;
+        self.num_free += count;
+        Some((head, elem.len))
+    }
+}
+
+/// Only dropped when a driver gives up on its device, which `fail` has
+/// made forget the rings.
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        for page in 0..self.pages {
+            crate::memory::dealloc_page(self.base + page * PAGE_SIZE);
+        }
+    }
+}
+
+/// A populated slot, as found at boot.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    transport: MmioTransport,
+    driver: Option<&'static str>,
+    /// Set by the interrupt handler when the device has returned buffers.
+    used: bool,
+}
+
+/// A device driver: `probe` is called once for every slot holding a
+/// device of type `device_id`, and owns the transport from then on.
+pub struct VirtioDriver {
+    pub name: &'static str,
+    pub device_id: u32,
+    pub probe: fn(MmioTransport) -> Result<(), VirtioError>,
+}
+
+/// Drivers tried against each device, in order.
+const DRIVERS: &[VirtioDriver] = &[
+    VirtioDriver { name: "virtio-blk", device_id: DEVICE_BLOCK, probe: crate::virtio_blk::probe },
+    VirtioDriver { name: "virtio-rng", device_id: DEVICE_RNG, probe: crate::virtio_rng::probe },
+    VirtioDriver { name: "virtio-console", device_id: DEVICE_CONSOLE, probe: crate::virtio_console::probe },
+];
+
+static mut SLOTS: Vec<Slot> = Vec::new();
+
+fn slots() -> &'static mut Vec<Slot> {
+    unsafe { &mut *core::ptr::addr_of_mut!(SLOTS) }
+}
+
+/// Acknowledges an interrupt raised by one of our devices, recording used
+/// buffers for whoever is waiting on them.
+pub fn handle_irq(irq: u32) {
+    if let Some(slot) = slots().iter_mut().find(|slot| slot.transport.irq == irq) {
+        if slot.transport.ack_interrupt() & INTERRUPT_USED_BUFFER != 0 {
+            slot.used = true;
+        }
+    }
+}
+
+/// Whether the device on `irq` has returned buffers since last asked.
+fn take_used_signal(irq: u32) -> bool {
+    match slots().iter_mut().find(|slot| slot.transport.irq == irq) {
+        Some(slot) => core::mem::replace(&mut slot.used, false),
+        None => true,
+    }
+}
+
+/// The `(base, irq)` of every virtio-mmio slot, from the device tree or
+/// QEMU's fixed layout.
+fn mmio_slots() -> Vec<(usize, u32)> {
+    let mut found: Vec<(usize, u32)> = crate::fdt::tree()
+        .map(|tree| {
+            tree.find_compatible("virtio,mmio")
+                .filter_map(|node| Some((node.reg()?.0, node.interrupt()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    if found.is_empty() {
+        found = (0..DEFAULT_MMIO_SLOTS)
+            .map(|i| (DEFAULT_MMIO_BASE + i * PAGE_SIZE, DEFAULT_MMIO_IRQ + i as u32))
+            .collect();
+    }
+    found.sort();
+    found
+}
+
+pub fn init_virtio() -> bool {
+    for (base, irq) in mmio_slots() {
+        let transport = match MmioTransport::probe(base, irq) {
+            Ok(Some(transport)) => transport,
+            Ok(None) => continue,
+            Err(err) => {
+                crate::print_fail!("Virtio slot {:#x}: {}", base, err.to_string());
+                continue;
+            }
+        };
+        // The slot goes in first: probing waits on the device, and its
+        // interrupts are only acknowledged for transports in `SLOTS`.
+        slots().push(Slot { transport, driver: None, used: false });
+        let index = slots().len() - 1;
+        for driver in DRIVERS.iter().filter(|d| d.device_id == transport.device_id) {
+            match (driver.probe)(transport) {
+                Ok(()) => {
+                    slots()[index].driver = Some(driver.name);
+                    break;
+                }
+                Err(err) => {
+                    crate::print_fail!("{} at {:#x}: {}", driver.name, base, err.to_string());
+                }
+            }
+        }
+        // A driver that gave up may have got as far as taking the interrupt.
+        if slots()[index].driver.is_none() {
+            crate::plic::unregister_irq(irq);
+        }
+        crate::print_info!(
+            "Virtio {} device at {:#x} (v{}, irq {}): {}",
+            device_name(transport.device_id),
+            base,
+            transport.version,
+            irq,
+            slots()[index].driver.unwrap_or("no driver")
+        );
+    }
+    crate::print_ok!("Virtio bus scanned: {} devices", slots().len());
+    true
+}
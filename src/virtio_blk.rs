@@ -0,0 +1,154 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cell::RefCell;
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::memory::Frame;
+use crate::vfs::FsError;
+use crate::virtio::{Buffer, MmioTransport, Virtqueue, VirtioError};
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Offset of the capacity (in 512-byte sectors) in the config space.
+const CONFIG_CAPACITY: usize = 0;
+
+/// Largest transfer put in a single request; bigger ones are split.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// The request header sits at the start of the header frame with the
+/// status byte right after it.
+const HEADER_LEN: usize = 16;
+const STATUS_OFFSET: usize = HEADER_LEN;
+
+/// Disks found so far, used to name the next one `vda`, `vdb`, ...
+static mut DISK_COUNT: u8 = 0;
+
+/// A virtio block device. Requests are issued one at a time: header,
+/// data and status go out as one chain and the caller waits for it.
+pub struct VirtioBlk {
+    name: String,
+    transport: MmioTransport,
+    queue: RefCell<Virtqueue>,
+    /// Holds the request header and the status byte the device writes.
+    header: Frame,
+    capacity: u64,
+    read_only: bool,
+    flush: bool,
+}
+
+impl VirtioBlk {
+    /// Sends one request and waits for its status. `data` is read by the
+    /// device for writes and written by it for reads.
+    fn request(&self, kind: u32, sector: u64, data: Option<(usize, usize)>) -> Result<(), FsError> {
+        let page = self.header.as_mut_slice();
+        page[0..4].copy_from_slice(&kind.to_le_bytes());
+        page[4..8].copy_from_slice(&0u32.to_le_bytes());
+        page[8..16].copy_from_slice(&sector.to_le_bytes());
+        page[STATUS_OFFSET] = 0xff;
+
+        let base = self.header.addr();
+        let header = Buffer::readable(base, HEADER_LEN);
+        let status = Buffer::writable(base + STATUS_OFFSET, 1);
+        let mut queue = self.queue.borrow_mut();
+        let result = match data {
+            Some((addr, len)) => {
+                let data = if kind == VIRTIO_BLK_T_IN { Buffer::writable(addr, len) } else { Buffer::readable(addr, len) };
+                self.transport.submit_and_wait(&mut queue, &[header, data, status])
+            }
+            None => self.transport.submit_and_wait(&mut queue, &[header, status]),
+        };
+        match result {
+            Ok(_) if self.header.as_slice()[STATUS_OFFSET] == VIRTIO_BLK_S_OK => Ok(()),
+            _ => Err(FsError::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        block::check_range(self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let start = sector + (i * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.request(VIRTIO_BLK_T_IN, start, Some((chunk.as_mut_ptr() as usize, chunk.len())))?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::PermissionDenied);
+        }
+        block::check_range(self, sector, data.len())?;
+        for (i, chunk) in data.chunks(MAX_TRANSFER).enumerate() {
+            let start = sector + (i * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.request(VIRTIO_BLK_T_OUT, start, Some((chunk.as_ptr() as usize, chunk.len())))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), FsError> {
+        if self.flush && !self.read_only {
+            self.request(VIRTIO_BLK_T_FLUSH, 0, None)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+pub fn probe(transport: MmioTransport) -> Result<(), VirtioError> {
+    let features = transport.begin_init(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+    let queue = transport.setup_queue(0)?;
+    let header = match Frame::alloc_zeroed() {
+        Some(frame) => frame,
+        None => {
+            transport.fail();
+            return Err(VirtioError::NoMemory);
+        }
+    };
+    transport.finish_init();
+
+    let mut capacity = [0u8; 8];
+    transport.read_config(CONFIG_CAPACITY, &mut capacity);
+    let index = unsafe {
+        let index = DISK_COUNT;
+        DISK_COUNT += 1;
+        index
+    };
+    let disk = VirtioBlk {
+        name: format!("vd{}", (b'a' + index) as char),
+        transport,
+        queue: RefCell::new(queue),
+        header,
+        capacity: u64::from_le_bytes(capacity),
+        read_only: features & VIRTIO_BLK_F_RO != 0,
+        flush: features & VIRTIO_BLK_F_FLUSH != 0,
+    };
+    crate::print_info!(
+        "{}: {} sectors ({} KiB){}",
+        disk.name,
+        disk.capacity,
+        disk.capacity * SECTOR_SIZE as u64 / 1024,
+        if disk.read_only { ", read-only" } else { "" }
+    );
+    block::register_disk(Arc::new(disk));
+    Ok(())
+}
@@ -0,0 +1,497 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use crate::chardev::{self, CharDevice, DeviceRef};
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::tty::Queue;
+use crate::vfs::{FsError, POLLIN, POLLOUT};
+use crate::virtio::{Buffer, MmioTransport, Virtqueue, VirtioError, MAX_QUEUE_SIZE};
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+/// Offset of `max_nr_ports` in the config space.
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+/// Control messages, both ways.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// `id`, `event` and `value`; a port name follows `PORT_NAME`.
+const CONTROL_HEADER_LEN: usize = 8;
+const CONTROL_BUFFER_LEN: usize = 256;
+
+/// Dynamic on Linux; taken from the range set aside for local use here.
+pub const VPORT_MAJOR: u32 = 240;
+
+/// Ports beyond this are refused; each costs a few frames up front.
+const MAX_PORTS: usize = 8;
+
+const PORT_BUFFER_LEN: usize = 1024;
+const INPUT_SIZE: usize = 4096;
+
+/// Receive buffers carved out of one frame and kept posted on a queue.
+/// Each is put back as soon as what the device wrote has been copied out.
+struct RxRing {
+    queue: RefCell<Virtqueue>,
+    frame: Frame,
+    len: usize,
+    /// Buffer address by head descriptor.
+    heads: RefCell<[usize; MAX_QUEUE_SIZE as usize]>,
+}
+
+impl RxRing {
+    fn new(queue: Virtqueue, len: usize) -> Option<Self> {
+        Some(Self {
+            queue: RefCell::new(queue),
+            frame: Frame::alloc_zeroed()?,
+            len,
+            heads: RefCell::new([0; MAX_QUEUE_SIZE as usize]),
+        })
+    }
+
+    fn post(&self, addr: usize) {
+        if let Ok(head) = self.queue.borrow_mut().add(&[Buffer::writable(addr, self.len)]) {
+            self.heads.borrow_mut()[head as usize] = addr;
+        }
+    }
+
+    fn post_all(&self, transport: &MmioTransport) {
+        let count = (PAGE_SIZE / self.len).min(self.queue.borrow().size() as usize);
+        for i in 0..count {
+            self.post(self.frame.addr() + i * self.len);
+        }
+        transport.notify(&self.queue.borrow());
+    }
+
+    /// Hands the oldest filled buffer to `consume`, then posts it again.
+    fn pop(&self, transport: &MmioTransport, consume: impl FnOnce(&[u8])) -> bool {
+        let (head, len) = match self.queue.borrow_mut().pop_used() {
+            Some(used) => used,
+            None => return false,
+        };
+        let addr = self.heads.borrow()[head as usize];
+        let len = (len as usize).min(self.len);
+        consume(unsafe { core::slice::from_raw_parts(addr as *const u8, len) });
+        self.post(addr);
+        transport.notify(&self.queue.borrow());
+        true
+    }
+
+    fn has_used(&self) -> bool {
+        self.queue.borrow().has_used()
+    }
+}
+
+/// Copies `data` into `frame` and waits for the device to take it.
+fn transmit(transport: &MmioTransport, queue: &RefCell<Virtqueue>, frame: &Frame, data: &[u8]) -> Result<(), FsError> {
+    frame.as_mut_slice()[..data.len()].copy_from_slice(data);
+    transport
+        .submit_and_wait(&mut queue.borrow_mut(), &[Buffer::readable(frame.addr(), data.len())])
+        .map(|_| ())
+        .map_err(|_| FsError::Io)
+}
+
+/// The control queues of a multiport device.
+struct Control {
+    rx: RxRing,
+    tx: RefCell<Virtqueue>,
+    tx_buffer: Frame,
+}
+
+struct Console {
+    index: usize,
+    transport: MmioTransport,
+    control: Option<Control>,
+    ports: Vec<Arc<Port>>,
+}
+
+impl Console {
+    fn send_control(&self, id: u32, event: u16, value: u16) {
+        let control = match &self.control {
+            Some(control) => control,
+            None => return,
+        };
+        let mut message = [0u8; CONTROL_HEADER_LEN];
+        message[0..4].copy_from_slice(&id.to_le_bytes());
+        message[4..6].copy_from_slice(&event.to_le_bytes());
+        message[6..8].copy_from_slice(&value.to_le_bytes());
+        let _ = transmit(&self.transport, &control.tx, &control.tx_buffer, &message);
+    }
+
+    /// Handles every control message waiting. Answering one can make the
+    /// device queue more straight away, so this runs until none are left.
+    fn process_control(&self) {
+        let control = match &self.control {
+            Some(control) => control,
+            None => return,
+        };
+        let mut message = [0u8; CONTROL_BUFFER_LEN];
+        let mut len = 0;
+        while control.rx.pop(&self.transport, |data| {
+            len = data.len();
+            message[..len].copy_from_slice(data);
+        }) {
+            if len >= CONTROL_HEADER_LEN {
+                self.handle_control(&message[..len]);
+            }
+        }
+    }
+
+    fn handle_control(&self, message: &[u8]) {
+        let id = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        let value = u16::from_le_bytes([message[6], message[7]]);
+        let port = match self.ports.get(id as usize) {
+            Some(port) => port,
+            None => {
+                if event == VIRTIO_CONSOLE_DEVICE_ADD {
+                    crate::print_fail!("virtio-console: port {} is beyond the {} supported", id, MAX_PORTS);
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_READY, 0);
+                }
+                return;
+            }
+        };
+        match event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                let ready = port.add(&self.transport).is_ok();
+                self.send_control(id, VIRTIO_CONSOLE_PORT_READY, ready as u16);
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => port.remove(),
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                port.is_console.set(true);
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => port.host_connected.set(value != 0),
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let name = &message[CONTROL_HEADER_LEN..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                if let Ok(name) = core::str::from_utf8(name) {
+                    port.set_name(name);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+static mut CONSOLES: Vec<Console> = Vec::new();
+
+fn consoles() -> &'static Vec<Console> {
+    unsafe { &*core::ptr::addr_of!(CONSOLES) }
+}
+
+/// Set while the queues are being worked on, so an interrupt taken while
+/// waiting on the device leaves them alone.
+static mut BUSY: bool = false;
+
+fn enter() -> bool {
+    unsafe {
+        if BUSY {
+            return false;
+        }
+        BUSY = true;
+        true
+    }
+}
+
+fn leave() {
+    unsafe {
+        BUSY = false;
+    }
+}
+
+/// Takes in control messages and received data on every device.
+fn service() {
+    if !enter() {
+        return;
+    }
+    for console in consoles() {
+        console.process_control();
+        for port in &console.ports {
+            port.drain_rx(&console.transport);
+        }
+    }
+    leave();
+}
+
+fn handle_irq(irq: u32) {
+    crate::virtio::handle_irq(irq);
+    service();
+}
+
+/// One port. Ports exist from probe on, one per queue pair the device has;
+/// the device adds and removes them, which creates and removes their
+/// nodes.
+pub struct Port {
+    console: usize,
+    id: u32,
+    rx: RxRing,
+    tx: RefCell<Virtqueue>,
+    tx_buffer: Frame,
+    /// Received bytes not yet read.
+    input: RefCell<Queue<u8, INPUT_SIZE>>,
+    present: Cell<bool>,
+    rx_posted: Cell<bool>,
+    host_connected: Cell<bool>,
+    guest_open: Cell<bool>,
+    is_console: Cell<bool>,
+    name: RefCell<Option<String>>,
+}
+
+impl Port {
+    fn node_name(&self) -> String {
+        format!("vport{}p{}", self.console, self.id)
+    }
+
+    fn minor(&self) -> u32 {
+        (self.console * MAX_PORTS) as u32 + self.id
+    }
+
+    fn console(&self) -> &'static Console {
+        &consoles()[self.console]
+    }
+
+    fn add(self: &Arc<Self>, transport: &MmioTransport) -> Result<(), FsError> {
+        if self.present.get() {
+            return Ok(());
+        }
+        if !self.rx_posted.replace(true) {
+            self.rx.post_all(transport);
+        }
+        let device: DeviceRef = self.clone();
+        chardev::register_char_device(&self.node_name(), VPORT_MAJOR, self.minor(), 0o600, device)?;
+        self.present.set(true);
+        Ok(())
+    }
+
+    fn remove(&self) {
+        if !self.present.replace(false) {
+            return;
+        }
+        self.host_connected.set(false);
+        self.is_console.set(false);
+        if let Some(name) = self.name.borrow_mut().take() {
+            let _ = crate::devfs::remove_node(&format!("virtio-ports/{}", name));
+        }
+        let _ = chardev::unregister_char_device(&self.node_name(), VPORT_MAJOR, self.minor());
+    }
+
+    /// Links `/dev/virtio-ports/<name>` to the port's node, as udev does.
+    fn set_name(&self, name: &str) {
+        if name.is_empty() || name.contains('/') || !self.present.get() || self.name.borrow().is_some() {
+            return;
+        }
+        let link = format!("virtio-ports/{}", name);
+        if crate::devfs::add_symlink(&link, &format!("../{}", self.node_name())).is_ok() {
+            crate::print_info!("virtio-console: {} is \"{}\"", self.node_name(), name);
+            *self.name.borrow_mut() = Some(String::from(name));
+        }
+    }
+
+    /// Moves received data into the input queue while it has room for a
+    /// whole buffer; the rest waits on the device.
+    fn drain_rx(&self, transport: &MmioTransport) {
+        while self.present.get() && self.input.borrow().free() >= PORT_BUFFER_LEN && self.rx.has_used() {
+            self.rx.pop(transport, |data| {
+                let mut input = self.input.borrow_mut();
+                for &byte in data {
+                    input.push(byte);
+                }
+            });
+        }
+    }
+}
+
+impl CharDevice for Port {
+    /// Empty until the host sends something; end of file once the host
+    /// side is closed and everything it sent has been read.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        service();
+        let mut input = self.input.borrow_mut();
+        if input.is_empty() && !buf.is_empty() {
+            return if self.host_connected.get() { Err(FsError::WouldBlock) } else { Ok(0) };
+        }
+        let count = input.len().min(buf.len());
+        for byte in &mut buf[..count] {
+            *byte = input.pop().unwrap();
+        }
+        Ok(count)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        service();
+        if !self.present.get() {
+            return Err(FsError::NoDevice);
+        }
+        if !self.host_connected.get() {
+            return Err(FsError::WouldBlock);
+        }
+        let transport = &self.console().transport;
+        for chunk in data.chunks(PAGE_SIZE) {
+            transmit(transport, &self.tx, &self.tx_buffer, chunk)?;
+        }
+        Ok(data.len())
+    }
+
+    fn poll(&self) -> u16 {
+        service();
+        let mut events = 0;
+        if !self.input.borrow().is_empty() || !self.host_connected.get() {
+            events |= POLLIN;
+        }
+        if self.host_connected.get() {
+            events |= POLLOUT;
+        }
+        events
+    }
+
+    /// One open at a time, as on Linux. The device is told the guest side
+    /// is open until the file is dropped.
+    fn open(&self) -> Result<Option<DeviceRef>, FsError> {
+        let console = self.console();
+        let port = console.ports[self.id as usize].clone();
+        if !port.present.get() {
+            return Err(FsError::NoDevice);
+        }
+        if port.guest_open.replace(true) {
+            return Err(FsError::Busy);
+        }
+        if enter() {
+            console.send_control(port.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            leave();
+        }
+        Ok(Some(Arc::new(PortFile { port })))
+    }
+}
+
+/// A port as opened by a task.
+struct PortFile {
+    port: Arc<Port>,
+}
+
+impl CharDevice for PortFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.port.read(buf)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        self.port.write(data)
+    }
+
+    fn poll(&self) -> u16 {
+        self.port.poll()
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        self.port.guest_open.set(false);
+        if self.port.present.get() && enter() {
+            self.port.console().send_control(self.port.id, VIRTIO_CONSOLE_PORT_OPEN, 0);
+            leave();
+        }
+    }
+}
+
+/// Queue pair of port `id`: 0 and 1 for port 0, then 4 and 5 for port 1
+/// and so on, the control queues taking 2 and 3.
+fn port_queues(id: usize) -> (u16, u16) {
+    let rx = if id == 0 { 0 } else { 2 * (id + 1) };
+    (rx as u16, rx as u16 + 1)
+}
+
+fn setup_port(transport: &MmioTransport, console: usize, id: usize) -> Result<Arc<Port>, VirtioError> {
+    let (rx, tx) = port_queues(id);
+    let rx = RxRing::new(transport.setup_queue(rx)?, PORT_BUFFER_LEN).ok_or(VirtioError::NoMemory)?;
+    let tx = transport.setup_queue(tx)?;
+    Ok(Arc::new(Port {
+        console,
+        id: id as u32,
+        rx,
+        tx: RefCell::new(tx),
+        tx_buffer: Frame::alloc_zeroed().ok_or(VirtioError::NoMemory)?,
+        input: RefCell::new(Queue::new(0)),
+        present: Cell::new(false),
+        rx_posted: Cell::new(false),
+        host_connected: Cell::new(false),
+        guest_open: Cell::new(false),
+        is_console: Cell::new(false),
+        name: RefCell::new(None),
+    }))
+}
+
+fn setup_queues(transport: &MmioTransport, index: usize, multiport: bool) -> Result<Console, VirtioError> {
+    let nr_ports = if multiport {
+        let mut max = [0u8; 4];
+        transport.read_config(CONFIG_MAX_NR_PORTS, &mut max);
+        (u32::from_le_bytes(max) as usize).clamp(1, MAX_PORTS)
+    } else {
+        1
+    };
+    let mut ports = Vec::with_capacity(nr_ports);
+    ports.push(setup_port(transport, index, 0)?);
+    let control = if multiport {
+        let rx = RxRing::new(transport.setup_queue(2)?, CONTROL_BUFFER_LEN).ok_or(VirtioError::NoMemory)?;
+        let tx = transport.setup_queue(3)?;
+        Some(Control { rx, tx: RefCell::new(tx), tx_buffer: Frame::alloc_zeroed().ok_or(VirtioError::NoMemory)? })
+    } else {
+        None
+    };
+    for id in 1..nr_ports {
+        ports.push(setup_port(transport, index, id)?);
+    }
+    Ok(Console { index, transport: *transport, control, ports })
+}
+
+pub fn probe(transport: MmioTransport) -> Result<(), VirtioError> {
+    let features = transport.begin_init(VIRTIO_CONSOLE_F_MULTIPORT)?;
+    let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    let index = consoles().len();
+    let console = match setup_queues(&transport, index, multiport) {
+        Ok(console) => console,
+        Err(err) => {
+            transport.fail();
+            return Err(err);
+        }
+    };
+    transport.finish_init();
+    crate::plic::register_irq(transport.irq(), handle_irq);
+    unsafe { (*core::ptr::addr_of_mut!(CONSOLES)).push(console) };
+    let console = &consoles()[index];
+
+    if !enter() {
+        return Err(VirtioError::DeviceError);
+    }
+    match &console.control {
+        // The device announces its ports in answer to DEVICE_READY.
+        Some(control) => {
+            control.rx.post_all(&console.transport);
+            console.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+            console.process_control();
+        }
+        // A single port, there from the start and always connected.
+        None => {
+            let port = &console.ports[0];
+            if port.add(&console.transport).is_ok() {
+                port.host_connected.set(true);
+            }
+        }
+    }
+    leave();
+    let present = console.ports.iter().filter(|port| port.present.get()).count();
+    crate::print_info!(
+        "virtio-console {}: {} port{}{}",
+        console.index,
+        present,
+        if present == 1 { "" } else { "s" },
+        if multiport { "" } else { " (no multiport)" }
+    );
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use core::cell::RefCell;
+use crate::memory::Frame;
+use crate::virtio::{Buffer, MmioTransport, Virtqueue, VirtioError};
+
+/// Largest request made of the device at once; it may return less.
+const MAX_REQUEST: usize = 64;
+
+/// A virtio entropy device. It has a single queue, and fills whatever
+/// writable buffer is put on it.
+pub struct VirtioRng {
+    transport: MmioTransport,
+    queue: RefCell<Virtqueue>,
+    /// The buffer handed to the device; output is copied out of it.
+    buffer: Frame,
+}
+
+impl VirtioRng {
+    /// Reads up to `buf.len()` bytes, stopping early if the device comes
+    /// back empty.
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut queue = self.queue.borrow_mut();
+        let mut filled = 0;
+        while filled < buf.len() {
+            let want = (buf.len() - filled).min(MAX_REQUEST);
+            let len = match self.transport.submit_and_wait(&mut queue, &[Buffer::writable(self.buffer.addr(), want)]) {
+                Ok(len) => (len as usize).min(want),
+                Err(_) => break,
+            };
+            if len == 0 {
+                break;
+            }
+            buf[filled..filled + len].copy_from_slice(&self.buffer.as_slice()[..len]);
+            filled += len;
+        }
+        filled
+    }
+}
+
+static mut RNG: Option<VirtioRng> = None;
+
+/// Fills as much of `buf` as the device will, returning how much that was;
+/// nothing without a device.
+pub fn read(buf: &mut [u8]) -> usize {
+    match unsafe { (*core::ptr::addr_of!(RNG)).as_ref() } {
+        Some(rng) => rng.read(buf),
+        None => 0,
+    }
+}
+
+pub fn probe(transport: MmioTransport) -> Result<(), VirtioError> {
+    transport.begin_init(0)?;
+    let queue = transport.setup_queue(0)?;
+    let buffer = match Frame::alloc_zeroed() {
+        Some(frame) => frame,
+        None => {
+            transport.fail();
+            return Err(VirtioError::NoMemory);
+        }
+    };
+    transport.finish_init();
+    unsafe {
+        RNG = Some(VirtioRng { transport, queue: RefCell::new(queue), buffer });
+    }
+
+    let mut seed = [0u8; 32];
+    let count = read(&mut seed);
+    crate::random::add_entropy(&seed[..count], count * 8);
+    crate::print_info!(
+        "virtio-rng: {} bytes into the entropy pool, {}",
+        count,
+        if crate::random::is_ready() { "seeded" } else { "not yet seeded" }
+    );
+    Ok(())
+}
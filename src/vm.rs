@@ -1,4 +1,8 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::arch::asm;
+use crate::memory::Frame;
+use crate::vfs::InodeRef;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_BITS: usize = 12;
@@ -14,7 +18,28 @@ pub const PTE_G: usize = 1 << 5;
 pub const PTE_A: usize = 1 << 6;
 pub const PTE_D: usize = 1 << 7;
 
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// User mappings are placed from here upwards, clear of the identity-mapped
+/// MMIO (0-1 GiB) and RAM (2-3 GiB) gigapages.
+pub const MMAP_BASE: usize = 0x20_0000_0000;
+pub const USER_VA_END: usize = 0x40_0000_0000;
+
+/// Each address space starts searching for free space a random number of
+/// pages above `MMAP_BASE`, up to 16 GiB.
+const MMAP_RANDOM_PAGES: usize = 1 << 22;
+
+const KERNEL_FLAGS: usize = PTE_R | PTE_W | PTE_X | PTE_G | PTE_A | PTE_D;
+
 #[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
 pub struct PageTableEntry {
     pub bits: usize,
 }
@@ -44,6 +69,10 @@ impl PageTableEntry {
         (self.bits & PTE_U) != 0
     }
     
+    pub fn is_leaf(&self) -> bool {
+        (self.bits & (PTE_R | PTE_W | PTE_X)) != 0
+    }
+    
     pub fn get_ppn(&self) -> usize {
         (self.bits >> 10) & ((1 << PPN_BITS) - 1)
     }
@@ -73,45 +102,83 @@ impl PageTableEntry {
     }
     
     pub fn set_flags(&mut self, flags: usize) {
-        self.bits = (self.bits & 0x3FF) | (flags & 0xFF);
+        self.bits = (self.bits & !0xFF) | (flags & 0xFF);
     }
 }
 
+fn alloc_table_page() -> Option<usize> {
+    let page = crate::memory::alloc_page()?;
+    unsafe {
+        core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE);
+    }
+    Some(page >> PAGE_BITS)
+}
+
+fn table_at(ppn: usize) -> &'static mut [PageTableEntry; 512] {
+    unsafe { &mut *((ppn << PAGE_BITS) as *mut [PageTableEntry; 512]) }
+}
+
+/// An Sv39 page table. Table pages come from the page allocator and are
+/// addressed physically, which works because the kernel itself runs on an
+/// identity mapping.
 pub struct PageTable {
     pub root_ppn: usize,
-    pub entries: [PageTableEntry; 512],
 }
 
 impl PageTable {
     pub fn new() -> Option<Self> {
-        if let Some(page) = crate::memory::alloc_page() {
-            let root_ppn = page >> PAGE_BITS;
-            Some(Self {
-                root_ppn,
-                entries: [PageTableEntry::new(); 512],
-            })
-        } else {
-            None
+        alloc_table_page().map(|root_ppn| Self { root_ppn })
+    }
+    
+    /// Returns the leaf entry for `vpn`, creating intermediate tables when
+    /// `create` is set. Fails if the address is covered by a huge page.
+    fn walk(&mut self, vpn: usize, create: bool) -> Option<&'static mut PageTableEntry> {
+        let mut table = table_at(self.root_ppn);
+        for level in [2, 1] {
+            let pte = &mut table[(vpn >> (level * VPN_BITS)) & 0x1FF];
+            if !pte.is_valid() {
+                if !create {
+                    return None;
+                }
+                *pte = PageTableEntry::new();
+                pte.set_ppn(alloc_table_page()?);
+                pte.set_valid(true);
+            } else if pte.is_leaf() {
+                return None;
+            }
+            table = table_at(pte.get_ppn());
         }
+        Some(&mut table[vpn & 0x1FF])
     }
     
     pub fn map_page(&mut self, vpn: usize, ppn: usize, flags: usize) -> bool {
-        let _vpn_l2 = (vpn >> 18) & 0x1FF;
-        let _vpn_l1 = (vpn >> 9) & 0x1FF;
-        let _vpn_l0 = vpn & 0x1FF;
-        
-        let mut pte = PageTableEntry::new();
-        pte.set_valid(true);
-        pte.set_ppn(ppn);
-        pte.set_flags(flags);
-        
-        crate::print_info!("Mapping VPN {:#x} to PPN {:#x} with flags {:#x}", vpn, ppn, flags);
-        true
+        match self.walk(vpn, true) {
+            Some(pte) => {
+                *pte = PageTableEntry::new();
+                pte.set_ppn(ppn);
+                pte.set_flags(flags | PTE_V);
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Maps the 1 GiB region starting at `vpn2 << 30` onto itself.
+    pub fn map_identity_gigapage(&mut self, vpn2: usize, flags: usize) {
+        let pte = &mut table_at(self.root_ppn)[vpn2 & 0x1FF];
+        *pte = PageTableEntry::new();
+        pte.set_ppn(vpn2 << (2 * VPN_BITS));
+        pte.set_flags(flags | PTE_V);
     }
     
     pub fn unmap_page(&mut self, vpn: usize) -> bool {
-        crate::print_info!("Unmapping VPN {:#x}", vpn);
-        true
+        match self.walk(vpn, false) {
+            Some(pte) if pte.is_valid() => {
+                *pte = PageTableEntry::new();
+                true
+            }
+            _ => false,
+        }
     }
     
     pub fn get_satp(&self) -> usize {
@@ -119,9 +186,31 @@ impl PageTable {
     }
 }
 
+/// Frees the table at `ppn` and every table below it. Leaves are left
+/// alone: the pages they map belong to whoever mapped them, and the
+/// identity gigapages are not pages at all.
+fn free_table(ppn: usize, level: usize) {
+    if level > 0 {
+        for pte in table_at(ppn).iter() {
+            if pte.is_valid() && !pte.is_leaf() {
+                free_table(pte.get_ppn(), level - 1);
+            }
+        }
+    }
+    crate::memory::dealloc_page(ppn << PAGE_BITS);
+}
+
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        free_table(self.root_ppn, 2);
+    }
+}
+
 pub struct VMManager {
     pub kernel_page_table: PageTable,
-    pub user_page_tables: alloc::vec::Vec<PageTable>,
+    /// Indexed by page table id; a slot is emptied when its address space
+    /// goes away, and reused by the next one.
+    pub user_page_tables: alloc::vec::Vec<Option<PageTable>>,
 }
 
 impl VMManager {
@@ -138,16 +227,30 @@ impl VMManager {
     
     pub fn create_user_page_table(&mut self) -> Option<usize> {
         if let Some(user_pt) = PageTable::new() {
-            let id = self.user_page_tables.len();
-            self.user_page_tables.push(user_pt);
-            Some(id)
+            match self.user_page_tables.iter().position(|pt| pt.is_none()) {
+                Some(id) => {
+                    self.user_page_tables[id] = Some(user_pt);
+                    Some(id)
+                }
+                None => {
+                    self.user_page_tables.push(Some(user_pt));
+                    Some(self.user_page_tables.len() - 1)
+                }
+            }
         } else {
             None
         }
     }
     
     pub fn get_user_page_table(&mut self, id: usize) -> Option<&mut PageTable> {
-        self.user_page_tables.get_mut(id)
+        self.user_page_tables.get_mut(id).and_then(|pt| pt.as_mut())
+    }
+
+    /// Frees page table `id` and makes its slot available again.
+    pub fn release_user_page_table(&mut self, id: usize) {
+        if let Some(slot) = self.user_page_tables.get_mut(id) {
+            *slot = None;
+        }
     }
     
     pub fn switch_to_kernel(&self) {
@@ -158,7 +261,7 @@ impl VMManager {
     }
     
     pub fn switch_to_user(&self, page_table_id: usize) {
-        if let Some(user_pt) = self.user_page_tables.get(page_table_id) {
+        if let Some(Some(user_pt)) = self.user_page_tables.get(page_table_id) {
             unsafe {
                 asm!("csrw satp, {}", in(reg) user_pt.get_satp());
                 asm!("sfence.vma");
@@ -208,4 +311,340 @@ pub fn switch_to_user(page_table_id: usize) {
     if let Some(vm_manager) = get_vm_manager() {
         vm_manager.switch_to_user(page_table_id);
     }
-}
\ No newline at end of file
+}
+pub const SSTATUS_SUM: usize = 1 << 18;
+
+fn page_round_up(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn flush_tlb() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone)]
+pub enum Backing {
+    Anonymous,
+    File { inode: InodeRef, offset: usize },
+}
+
+/// A contiguous range of user virtual memory created by `mmap`.
+#[derive(Clone)]
+pub struct VmRegion {
+    pub start: usize,
+    pub end: usize,
+    pub prot: usize,
+    pub flags: usize,
+    pub backing: Backing,
+    /// The frame currently mapped at each page, `None` until first touched.
+    pub frames: Vec<Option<Arc<Frame>>>,
+}
+
+impl VmRegion {
+    pub fn is_shared(&self) -> bool {
+        self.flags & MAP_SHARED != 0
+    }
+
+    /// A private page stays read-only while its frame is also held by the
+    /// file or by another address space; the first write then copies it.
+    fn page_writable(&self, frame: &Arc<Frame>) -> bool {
+        self.is_shared() || Arc::strong_count(frame) == 1
+    }
+
+    fn pte_flags(&self, writable: bool) -> usize {
+        let mut flags = PTE_U | PTE_A | PTE_D;
+        if self.prot & (PROT_READ | PROT_WRITE) != 0 {
+            flags |= PTE_R;
+        }
+        if self.prot & PROT_WRITE != 0 && writable {
+            flags |= PTE_W;
+        }
+        if self.prot & PROT_EXEC != 0 {
+            flags |= PTE_X;
+        }
+        flags
+    }
+
+    fn slice(&self, start: usize, end: usize) -> VmRegion {
+        let first = (start - self.start) / PAGE_SIZE;
+        let last = (end - self.start) / PAGE_SIZE;
+        let backing = match &self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { inode, offset } => Backing::File {
+                inode: inode.clone(),
+                offset: offset + (start - self.start),
+            },
+        };
+        VmRegion {
+            start,
+            end,
+            prot: self.prot,
+            flags: self.flags,
+            backing,
+            frames: self.frames[first..last].to_vec(),
+        }
+    }
+}
+
+/// The user half of a task's address space. Every user page table also maps
+/// the MMIO and RAM gigapages one-to-one (supervisor only), so traps and
+/// syscalls keep running on the identity mapping after `satp` is switched.
+pub struct AddressSpace {
+    pub page_table_id: usize,
+    pub regions: Vec<VmRegion>,
+    /// Where mappings without an address start; randomised per address
+    /// space, and kept across `fork`.
+    pub mmap_base: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        let id = create_user_page_table()?;
+        let page_table = get_vm_manager()?.get_user_page_table(id)?;
+        page_table.map_identity_gigapage(0, KERNEL_FLAGS);
+        page_table.map_identity_gigapage(2, KERNEL_FLAGS);
+        Some(Self {
+            page_table_id: id,
+            regions: Vec::new(),
+            mmap_base: MMAP_BASE + crate::random::below(MMAP_RANDOM_PAGES) * PAGE_SIZE,
+        })
+    }
+
+    fn page_table(&self) -> &'static mut PageTable {
+        get_vm_manager()
+            .and_then(|vm| vm.get_user_page_table(self.page_table_id))
+            .expect("address space without page table")
+    }
+
+    pub fn satp(&self) -> usize {
+        self.page_table().get_satp()
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.regions.iter().any(|r| r.start < end && start < r.end)
+    }
+
+    /// The first gap of `len` bytes from `mmap_base` up, or failing that
+    /// from `MMAP_BASE`.
+    fn find_free(&self, len: usize) -> Option<usize> {
+        self.find_free_from(self.mmap_base, len).or_else(|| self.find_free_from(MMAP_BASE, len))
+    }
+
+    fn find_free_from(&self, start: usize, len: usize) -> Option<usize> {
+        let mut candidate = start;
+        for region in &self.regions {
+            if region.end <= candidate {
+                continue;
+            }
+            if region.start >= candidate + len {
+                break;
+            }
+            candidate = region.end;
+        }
+        if candidate + len <= USER_VA_END {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    fn map_region_pages(&self, region: &VmRegion) {
+        if region.prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 {
+            return;
+        }
+        let page_table = self.page_table();
+        for (index, frame) in region.frames.iter().enumerate() {
+            if let Some(frame) = frame {
+                let vpn = (region.start >> PAGE_BITS) + index;
+                page_table.map_page(vpn, frame.ppn(), region.pte_flags(region.page_writable(frame)));
+            }
+        }
+    }
+
+    /// Creates a mapping of `len` bytes. `frames` holds the initial frame of
+    /// each page (file pages for a file mapping); missing pages are filled
+    /// with zeroes on first access.
+    pub fn mmap(
+        &mut self,
+        addr: usize,
+        len: usize,
+        prot: usize,
+        flags: usize,
+        backing: Backing,
+        mut frames: Vec<Option<Arc<Frame>>>,
+    ) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+        let len = page_round_up(len);
+        let in_range = |start: usize| {
+            start.is_multiple_of(PAGE_SIZE) && start >= MMAP_BASE && start + len <= USER_VA_END
+        };
+        let start = if flags & MAP_FIXED != 0 {
+            if !in_range(addr) {
+                return None;
+            }
+            self.munmap(addr, len);
+            addr
+        } else if addr != 0 && in_range(addr) && !self.overlaps(addr, addr + len) {
+            addr
+        } else {
+            self.find_free(len)?
+        };
+
+        frames.resize(len / PAGE_SIZE, None);
+        let region = VmRegion {
+            start,
+            end: start + len,
+            prot,
+            flags,
+            backing,
+            frames,
+        };
+        self.map_region_pages(&region);
+        flush_tlb();
+
+        let pos = self.regions.iter().position(|r| r.start > start).unwrap_or(self.regions.len());
+        self.regions.insert(pos, region);
+        Some(start)
+    }
+
+    pub fn munmap(&mut self, addr: usize, len: usize) -> bool {
+        if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+            return false;
+        }
+        let end = addr.saturating_add(page_round_up(len));
+        let page_table = self.page_table();
+        let mut kept = Vec::with_capacity(self.regions.len());
+        for region in self.regions.drain(..) {
+            if region.end <= addr || region.start >= end {
+                kept.push(region);
+                continue;
+            }
+            let cut_start = core::cmp::max(region.start, addr);
+            let cut_end = core::cmp::min(region.end, end);
+            for va in (cut_start..cut_end).step_by(PAGE_SIZE) {
+                page_table.unmap_page(va >> PAGE_BITS);
+            }
+            if region.start < cut_start {
+                kept.push(region.slice(region.start, cut_start));
+            }
+            if cut_end < region.end {
+                kept.push(region.slice(cut_end, region.end));
+            }
+        }
+        self.regions = kept;
+        flush_tlb();
+        true
+    }
+
+    /// Resolves a page fault at `addr`. Returns `false` if the access is
+    /// outside every region or not permitted by the region's protection.
+    pub fn handle_fault(&mut self, addr: usize, access: Access) -> bool {
+        let page_table = self.page_table();
+        let region = match self.regions.iter_mut().find(|r| r.start <= addr && addr < r.end) {
+            Some(region) => region,
+            None => return false,
+        };
+        let required = match access {
+            Access::Read => PROT_READ,
+            Access::Write => PROT_WRITE,
+            Access::Execute => PROT_EXEC,
+        };
+        if region.prot & required == 0 {
+            return false;
+        }
+
+        let index = (addr - region.start) / PAGE_SIZE;
+        let frame = match &region.frames[index] {
+            None => match Frame::alloc_zeroed() {
+                Some(frame) => Arc::new(frame),
+                None => return false,
+            },
+            Some(frame) if access == Access::Write && !region.page_writable(frame) => {
+                match Frame::alloc_copy(frame) {
+                    Some(copy) => Arc::new(copy),
+                    None => return false,
+                }
+            }
+            Some(frame) => frame.clone(),
+        };
+        let ppn = frame.ppn();
+        region.frames[index] = Some(frame);
+
+        let writable = region.frames[index].as_ref().is_some_and(|f| region.page_writable(f));
+        let vpn = (region.start >> PAGE_BITS) + index;
+        if !page_table.map_page(vpn, ppn, region.pte_flags(writable)) {
+            return false;
+        }
+        flush_tlb();
+        true
+    }
+
+    /// Duplicates the address space for `fork`. Shared regions keep pointing
+    /// at the same frames; private pages become copy-on-write in both.
+    /// Untouched pages of shared anonymous regions get their frames first,
+    /// or each side would fault in a page of its own.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        for region in self.regions.iter_mut() {
+            if !region.is_shared() || !matches!(region.backing, Backing::Anonymous) {
+                continue;
+            }
+            for frame in region.frames.iter_mut().filter(|f| f.is_none()) {
+                *frame = Some(Arc::new(Frame::alloc_zeroed()?));
+            }
+        }
+        child.regions = self.regions.clone();
+        child.mmap_base = self.mmap_base;
+        for region in &self.regions {
+            self.map_region_pages(region);
+        }
+        for region in &child.regions {
+            child.map_region_pages(region);
+        }
+        flush_tlb();
+        Some(child)
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Gives the page table back. A task dropping its own address space, on
+    /// exit or exec, is still running on it, so it drops to bare mode first
+    /// as tasks without one do.
+    fn drop(&mut self) {
+        let current: usize;
+        unsafe {
+            asm!("csrr {}, satp", out(reg) current);
+        }
+        if current == self.satp() {
+            unsafe {
+                asm!("csrw satp, zero", "sfence.vma");
+            }
+        }
+        if let Some(vm) = get_vm_manager() {
+            vm.release_user_page_table(self.page_table_id);
+        }
+    }
+}
+
+pub fn handle_page_fault(addr: usize, exception_code: usize) -> bool {
+    let access = match exception_code {
+        crate::interrupts::EXCEPTION_STORE_PAGE_FAULT => Access::Write,
+        crate::interrupts::EXCEPTION_INSTRUCTION_PAGE_FAULT => Access::Execute,
+        _ => Access::Read,
+    };
+    match crate::scheduler::current_task().mm.as_mut() {
+        Some(mm) => mm.handle_fault(addr, access),
+        None => false,
+    }
+}